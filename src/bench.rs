@@ -0,0 +1,304 @@
+//! `doh bench`: time the servers themselves rather than their answers, over a series of queries
+//! to each, and rank them by latency percentiles and failure rate. With `--warmup` each server
+//! keeps one connection, set up by a first query timed on its own, so the percentiles measure the
+//! queries rather than the handshakes. `--connections` keeps several queries in flight, each over
+//! a connection of its own that stays, and `--qps` paces them to a rate, for load testing a server
+//! of one's own. `--cold` times queries
+//! for names no cache has next to ones of names asked before, telling a server's cache from its
+//! upstream connectivity.
+use std::time::{Duration, Instant};
+
+use futures::future;
+use futures::stream::{self, StreamExt};
+use rand::random;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tabled::builder::Builder;
+use tabled::object::{Columns, Rows};
+use tabled::{Alignment, Modify, Style};
+use tokio::time;
+use tracing::debug;
+
+use crate::config::Server;
+use crate::output::{self, Output};
+use crate::top::{DEFAULT_NAMES, FAILURE_PENALTY_MS};
+use crate::{exchange, interrupt, Stats, Transport};
+
+/// How one server did over the run.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Bench {
+    pub rank: usize,
+    pub server: String,
+    pub url: String,
+    /// Queries sent, fewer than asked for when interrupted.
+    pub queries: usize,
+    pub failures: usize,
+    pub p50_ms: Option<f64>,
+    pub p90_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+    pub mean_ms: Option<f64>,
+    /// Answered queries per second over the run, which fall behind `--qps` once the server
+    /// can't keep up.
+    pub qps: f64,
+    /// The p50 of the `--cold` queries, for random names under the given ones that no cache has.
+    pub cold_p50_ms: Option<f64>,
+    /// `--cold` queries sent and failed, which don't count towards the rank.
+    pub cold_queries: usize,
+    pub cold_failures: usize,
+    /// The warmup query of `--warmup`, which connected; absent without or when it failed.
+    pub warmup_ms: Option<f64>,
+    /// What the queries went over, `HTTP/2` or `DoT` say; several when it changed.
+    pub protocols: Vec<String>,
+}
+
+impl Bench {
+    /// Lower is better, with failures counted as very slow queries as in `doh top`.
+    fn score(&self) -> f64 {
+        let failure = self.failures as f64 / self.queries.max(1) as f64;
+        self.p50_ms.unwrap_or(FAILURE_PENALTY_MS) + failure * FAILURE_PENALTY_MS
+    }
+}
+
+/// How each server is queried.
+#[derive(Debug, Clone, Copy)]
+pub struct Plan {
+    pub queries: u32,
+    /// Set up a connection with a first query, timed apart, and send the others over it.
+    pub warmup: bool,
+    /// Ask every name once before the timed queries, and for a random name under it along with
+    /// each of them.
+    pub cold: bool,
+    /// Start this many queries a second, rather than each as soon as one is answered.
+    pub qps: Option<u32>,
+    /// Queries in flight at once.
+    pub connections: usize,
+}
+
+impl Plan {
+    fn loaded(&self) -> bool {
+        self.qps.is_some() || self.connections > 1
+    }
+}
+
+/// Time one query for `name`, none when interrupted.
+async fn timed(
+    name: &str,
+    server: &Server,
+    transport: &Transport,
+) -> Option<Result<(f64, &'static str), Box<dyn std::error::Error>>> {
+    let start = Instant::now();
+    let lookup = tokio::select! {
+        lookup = exchange(name, &server.url, "A", transport) => lookup,
+        _ = interrupt::token().cancelled() => return None,
+    };
+    match lookup {
+        Ok(lookup) => Some(Ok((
+            start.elapsed().as_secs_f64() * 1000.0,
+            lookup.protocol,
+        ))),
+        Err(_) if interrupt::interrupted() => None,
+        Err(e) => Some(Err(e)),
+    }
+}
+
+/// Query `server` following `plan`, cycling through `names`.
+async fn measure(names: &[&str], plan: Plan, server: &Server, transport: &Transport) -> Bench {
+    let warmup_ms = match plan.warmup {
+        true => match timed(names[0], server, transport).await {
+            Some(Ok((ms, _))) => Some(ms),
+            Some(Err(e)) => {
+                debug!("warmup query to {}: {}", server.tag, e);
+                None
+            }
+            None => None,
+        },
+        false => None,
+    };
+    if plan.cold {
+        // so the warm queries are of names the server has answered before
+        for name in &names[usize::from(plan.warmup)..] {
+            if timed(name, server, transport).await.is_none() {
+                break;
+            }
+        }
+    }
+    let mut queries: Vec<(String, bool)> = vec![];
+    for name in names.iter().cycle().take(plan.queries as usize) {
+        if plan.cold {
+            queries.push((format!("{:08x}.{}", random::<u32>(), name), true));
+        }
+        queries.push((String::from(*name), false));
+    }
+    let mut latencies = vec![];
+    let mut failures = 0;
+    let mut cold = vec![];
+    let mut cold_failures = 0;
+    let mut protocols: Vec<String> = vec![];
+    let begin = time::Instant::now();
+    let mut timings = stream::iter(queries.iter().enumerate())
+        .map(|(idx, (name, cold))| async move {
+            if let Some(qps) = plan.qps {
+                let start = begin + Duration::from_secs_f64(idx as f64 / qps as f64);
+                tokio::select! {
+                    _ = time::sleep_until(start) => {}
+                    _ = interrupt::token().cancelled() => return None,
+                }
+            }
+            Some((*cold, timed(name, server, transport).await?))
+        })
+        .buffer_unordered(plan.connections)
+        .take_while(|timing| future::ready(timing.is_some()));
+    while let Some(timing) = timings.next().await {
+        match timing {
+            Some((true, Ok((ms, _)))) => cold.push(ms),
+            Some((true, Err(_))) => cold_failures += 1,
+            Some((false, Ok((ms, protocol)))) => {
+                latencies.push(ms);
+                if !protocols.iter().any(|p| p == protocol) {
+                    protocols.push(String::from(protocol));
+                }
+            }
+            Some((false, Err(_))) => failures += 1,
+            None => break,
+        }
+    }
+    latencies.sort_by(f64::total_cmp);
+    cold.sort_by(f64::total_cmp);
+    Bench {
+        rank: 0,
+        server: server.tag.clone(),
+        url: server.url.clone(),
+        queries: latencies.len() + failures,
+        failures,
+        p50_ms: Stats::percentile(&latencies, 50.0),
+        p90_ms: Stats::percentile(&latencies, 90.0),
+        p99_ms: Stats::percentile(&latencies, 99.0),
+        mean_ms: (!latencies.is_empty())
+            .then(|| latencies.iter().sum::<f64>() / latencies.len() as f64),
+        qps: (latencies.len() + cold.len()) as f64 / begin.elapsed().as_secs_f64(),
+        cold_p50_ms: Stats::percentile(&cold, 50.0),
+        cold_queries: cold.len() + cold_failures,
+        cold_failures,
+        warmup_ms,
+        protocols,
+    }
+}
+
+fn format_ms(ms: Option<f64>) -> String {
+    ms.map_or_else(|| String::from("/"), |ms| format!("{:.0}ms", ms))
+}
+
+pub async fn run(
+    names: &[String],
+    plan: Plan,
+    servers: &[Server],
+    transport: &Transport,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let names: Vec<&str> = if names.is_empty() {
+        DEFAULT_NAMES.to_vec()
+    } else {
+        names.iter().map(String::as_str).collect()
+    };
+    let names = &names;
+    // a load test measures the server, not a handshake per query: each of the connections stays
+    let transport = &Transport {
+        keep_alive: plan.warmup || plan.loaded(),
+        ..transport.clone()
+    };
+    let mut results = future::join_all(
+        servers
+            .iter()
+            .map(|server| measure(names, plan, server, transport)),
+    )
+    .await;
+    results.sort_by(|a, b| a.score().total_cmp(&b.score()));
+    for (idx, result) in results.iter_mut().enumerate() {
+        result.rank = idx + 1;
+    }
+    if !output.is_table() {
+        return output.json(&results);
+    }
+
+    let mut columns = vec!["#", "DoH"];
+    if plan.cold {
+        columns.extend(["Warm p50", "Cold p50"]);
+    } else {
+        columns.push("p50");
+    }
+    columns.extend(["p90", "p99", "Mean"]);
+    if plan.loaded() {
+        columns.push("QPS");
+    }
+    if plan.warmup {
+        columns.push("Warmup");
+    }
+    columns.extend(["Failed", "Protocol"]);
+    let mut builder = Builder::default();
+    builder.set_columns(columns);
+    let hidden = output.truncate(&mut results);
+    for result in results {
+        let failed = result.failures as f64 / result.queries.max(1) as f64;
+        let mut record = vec![
+            result.rank.to_string(),
+            result.server,
+            format_ms(result.p50_ms),
+        ];
+        if plan.cold {
+            let mut cold = format_ms(result.cold_p50_ms);
+            if result.cold_failures > 0 {
+                cold = output.paint.status(
+                    false,
+                    format!(
+                        "{} ({}/{} failed)",
+                        cold, result.cold_failures, result.cold_queries
+                    ),
+                );
+            }
+            record.push(cold);
+        }
+        record.extend([
+            format_ms(result.p90_ms),
+            format_ms(result.p99_ms),
+            format_ms(result.mean_ms),
+        ]);
+        if plan.loaded() {
+            record.push(format!("{:.1}", result.qps));
+        }
+        if plan.warmup {
+            record.push(format_ms(result.warmup_ms));
+        }
+        record.extend([
+            output.paint.status(
+                result.failures == 0,
+                format!(
+                    "{:.0}% ({}/{})",
+                    failed * 100.0,
+                    result.failures,
+                    result.queries
+                ),
+            ),
+            if result.protocols.is_empty() {
+                String::from("-")
+            } else {
+                result.protocols.join(", ")
+            },
+        ]);
+        builder.add_record(record);
+    }
+    let mut table = builder.build();
+    table
+        .with(Style::modern())
+        .with(
+            Modify::new(Columns::new(
+                2..7 + usize::from(plan.cold)
+                    + usize::from(plan.loaded())
+                    + usize::from(plan.warmup),
+            ))
+            .with(Alignment::right()),
+        )
+        .with(Modify::new(Rows::first()).with(Alignment::center()));
+    output::fit(&mut table, &[]);
+    output.print(&table.to_string(), hidden);
+    Ok(())
+}
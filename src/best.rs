@@ -0,0 +1,120 @@
+//! `--best`: pick the single fastest address out of every server's answers and print only it, so
+//! the result can be used directly, as in `ssh $(doh --best --host example.com)`.
+use std::iter;
+use std::net::IpAddr;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::Row;
+
+/// Default of `--max-loss`, in percent.
+pub const MAX_LOSS: u8 = 20;
+
+/// An address some server answered with, as considered for `--best`.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Candidate {
+    pub address: IpAddr,
+    /// Servers that returned it.
+    pub servers: Vec<String>,
+    pub avg_ms: Option<i32>,
+    pub loss: f32,
+    /// Why it can't be picked, absent for eligible addresses.
+    pub disqualified: Option<String>,
+}
+
+/// Gather the probed addresses of `rows`, one candidate per address.
+fn candidates(rows: &[(&str, Row)], max_loss: f32) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = vec![];
+    for (tag, row) in rows {
+        // with `--group-by ip`, a row stands for every server that returned its address
+        let tags = iter::once(*tag).chain(row.also.iter().map(String::as_str));
+        let Ok(address) = row.answer.data.parse::<IpAddr>() else {
+            continue;
+        };
+        if !row.probed || row.error.is_some() {
+            continue;
+        }
+        if let Some(known) = candidates.iter_mut().find(|c| c.address == address) {
+            for tag in tags {
+                if !known.servers.iter().any(|s| s == tag) {
+                    known.servers.push(String::from(tag));
+                }
+            }
+            continue;
+        }
+        let disqualified = if !row.complete {
+            Some(String::from("probing was interrupted"))
+        } else if row.mean == -1 {
+            Some(String::from("no reply"))
+        } else if row.lost > max_loss {
+            Some(format!(
+                "{:.0}% loss is above {:.0}%",
+                row.lost * 100.0,
+                max_loss * 100.0
+            ))
+        } else {
+            None
+        };
+        candidates.push(Candidate {
+            address,
+            servers: tags.map(String::from).collect(),
+            avg_ms: (row.mean != -1).then_some(row.mean),
+            loss: row.lost,
+            disqualified,
+        });
+    }
+    // eligible first, then by latency, loss and how many servers agree
+    candidates.sort_by(|a, b| {
+        (a.disqualified.is_some(), a.avg_ms)
+            .cmp(&(b.disqualified.is_some(), b.avg_ms))
+            .then(a.loss.total_cmp(&b.loss))
+            .then(b.servers.len().cmp(&a.servers.len()))
+    });
+    candidates
+}
+
+/// The best address of `rows`, if any is eligible.
+pub fn choose(rows: &[(&str, Row)], max_loss: f32) -> Option<IpAddr> {
+    candidates(rows, max_loss)
+        .into_iter()
+        .find(|c| c.disqualified.is_none())
+        .map(|c| c.address)
+}
+
+/// `--explain`: a machine-readable account of the choice among the addresses of one host, to
+/// audit it.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Explanation {
+    pub host: String,
+    pub best: Option<IpAddr>,
+    /// What set the best address apart from the runner-up: `latency`, `loss`, `servers` (more
+    /// servers returned it), `tie` when nothing did, or `only candidate`.
+    pub decided_by: Option<String>,
+    /// Highest loss ratio an eligible address may have.
+    pub max_loss: f32,
+    /// Every address, eligible ones best first.
+    pub candidates: Vec<Candidate>,
+}
+
+/// Why `choose` picks what it does among the addresses `host` resolved to in `rows`: the first
+/// metric of the ordering in which the best address beats the runner-up.
+pub fn explain(host: &str, rows: &[(&str, Row)], max_loss: f32) -> Explanation {
+    let candidates = candidates(rows, max_loss);
+    let mut eligible = candidates.iter().filter(|c| c.disqualified.is_none());
+    let best = eligible.next();
+    let decided_by = best.map(|best| match eligible.next() {
+        None => "only candidate",
+        Some(next) if next.avg_ms != best.avg_ms => "latency",
+        Some(next) if next.loss != best.loss => "loss",
+        Some(next) if next.servers.len() != best.servers.len() => "servers",
+        Some(_) => "tie",
+    });
+    Explanation {
+        host: String::from(host),
+        best: best.map(|c| c.address),
+        decided_by: decided_by.map(String::from),
+        max_loss,
+        candidates,
+    }
+}
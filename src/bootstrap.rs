@@ -0,0 +1,96 @@
+//! Addresses of DoH and DoT server host names found without the system resolver, which may be the
+//! broken or poisoned one the servers are meant to get around: pinned with `ip = ...` in the
+//! config, or asked of the plain DNS resolver given with `--bootstrap`.
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use tracing::debug;
+
+use crate::source::Source;
+use crate::{plain, wire};
+
+/// How server host names are resolved: the system resolver unless they are pinned or a resolver
+/// is set.
+#[derive(Debug, Clone, Default)]
+pub struct Bootstrap {
+    /// Addresses set with `ip = ...` in the config, by lowercased host name.
+    pinned: BTreeMap<String, IpAddr>,
+    /// The resolver of `--bootstrap`.
+    resolver: Option<SocketAddr>,
+    /// What the resolver answered, so each host name is only asked for once.
+    resolved: Arc<Mutex<BTreeMap<String, IpAddr>>>,
+}
+
+fn key(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl Bootstrap {
+    /// Connect to `ip` for the host of the server at `url`.
+    pub fn pin(&mut self, url: &str, ip: IpAddr) {
+        if let Some(host) = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(key))
+        {
+            self.pinned.insert(host, ip);
+        }
+    }
+
+    /// Resolve the server host names that aren't pinned through `resolver`, over UDP.
+    pub fn set_resolver(&mut self, resolver: SocketAddr) {
+        self.resolver = Some(resolver);
+    }
+
+    /// The address to connect to for `host` instead of the system resolver's, if it is pinned or
+    /// a resolver was set, asked from `source`; `None` for IP literals and when the system
+    /// resolver is to be used.
+    pub async fn address(
+        &self,
+        host: &str,
+        source: &Source,
+    ) -> Result<Option<IpAddr>, Box<dyn std::error::Error>> {
+        if host.parse::<IpAddr>().is_ok() {
+            return Ok(None);
+        }
+        let host = key(host);
+        if let Some(ip) = self.pinned.get(&host) {
+            return Ok(Some(*ip));
+        }
+        let Some(resolver) = self.resolver else {
+            return Ok(None);
+        };
+        if let Some(ip) = self.resolved.lock().unwrap().get(&host) {
+            return Ok(Some(*ip));
+        }
+        // IPv4 first, as most networks still have it
+        for qtype in [1, 28] {
+            let message = plain::ask(resolver, &wire::encode(&host, qtype)?, source).await?;
+            let found = message
+                .answers
+                .iter()
+                .filter(|a| a.r#type == u32::from(qtype))
+                .find_map(|a| a.data.parse::<IpAddr>().ok());
+            if let Some(ip) = found {
+                debug!("{} is {}, through {}", host, ip, resolver.ip());
+                self.resolved.lock().unwrap().insert(host, ip);
+                return Ok(Some(ip));
+            }
+        }
+        Err(format!("cannot resolve {} through {}", host, resolver.ip()).into())
+    }
+
+    /// The addresses of `host` on `port`: the one [`Bootstrap::address`] gives, or the system
+    /// resolver's.
+    pub async fn lookup(
+        &self,
+        host: &str,
+        port: u16,
+        source: &Source,
+    ) -> Result<Vec<SocketAddr>, Box<dyn std::error::Error>> {
+        if let Some(ip) = self.address(host, source).await? {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        Ok(tokio::net::lookup_host((host, port)).await?.collect())
+    }
+}
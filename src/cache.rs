@@ -0,0 +1,153 @@
+//! Answers kept for as long as their TTL, by server, name and type, so that repeated queries
+//! don't go to the servers again while they are fresh: within a run such as `--watch`, and
+//! across runs with the cache file.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{Answer, Lookup, Transport};
+
+/// How long NOERROR and NXDOMAIN responses without any answer stay cached.
+const NEGATIVE_TTL: u32 = 60;
+
+/// Whether queries go through the cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Always ask the servers.
+    #[default]
+    Off,
+    /// Answer from the cache when fresh, asking the servers otherwise.
+    On,
+    /// Only answer from the cache, failing the queries it has nothing fresh for.
+    Only,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Cached {
+    status: u32,
+    authenticated: bool,
+    answers: Vec<Answer>,
+    #[serde(default)]
+    comment: Option<String>,
+    /// Seconds since the Unix epoch.
+    fetched: u64,
+    /// Seconds it stays fresh, the lowest TTL of the answers.
+    ttl: u32,
+}
+
+impl Cached {
+    fn age(&self) -> u64 {
+        now().saturating_sub(self.fetched)
+    }
+
+    fn fresh(&self) -> bool {
+        self.age() < self.ttl as u64
+    }
+}
+
+static CACHE: Mutex<BTreeMap<String, Cached>> = Mutex::new(BTreeMap::new());
+/// Where the cache is saved, when it outlives the run.
+static FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// What a query is cached under; the options that change the answers are part of it.
+pub(crate) fn key(server: &str, hostname: &str, rtype: &str, transport: &Transport) -> String {
+    let name = hostname.trim_end_matches('.').to_ascii_lowercase();
+    let mut key = format!("{} {} {}", server, name, rtype.to_ascii_uppercase());
+    if transport.dnssec {
+        key.push_str(" +dnssec");
+    }
+    if let Some(subnet) = transport.subnet {
+        key.push_str(&format!(" +subnet={}", subnet));
+    }
+    key
+}
+
+/// The fresh lookup cached under `key`, with the TTLs counted down since it was fetched.
+pub(crate) fn get(key: &str) -> Option<Lookup> {
+    let cache = CACHE.lock().unwrap();
+    let cached = cache.get(key).filter(|c| c.fresh())?;
+    let age = cached.age() as u32;
+    Some(Lookup {
+        status: cached.status,
+        authenticated: cached.authenticated,
+        protocol: "cache",
+        answers: cached
+            .answers
+            .iter()
+            .map(|a| Answer {
+                TTL: a.TTL.saturating_sub(age),
+                ..a.clone()
+            })
+            .collect(),
+        comment: cached.comment.clone(),
+        timing: None,
+    })
+}
+
+/// Keep a successful or NXDOMAIN `lookup` under `key` for its lowest TTL.
+pub(crate) fn put(key: String, lookup: &Lookup) {
+    if !matches!(lookup.status, 0 | 3) {
+        return;
+    }
+    let ttl = lookup
+        .answers
+        .iter()
+        .map(|a| a.TTL)
+        .min()
+        .unwrap_or(NEGATIVE_TTL);
+    if ttl == 0 {
+        return;
+    }
+    let cached = Cached {
+        status: lookup.status,
+        authenticated: lookup.authenticated,
+        answers: lookup.answers.clone(),
+        comment: lookup.comment.clone(),
+        fetched: now(),
+        ttl,
+    };
+    CACHE.lock().unwrap().insert(key, cached);
+}
+
+/// Load the cache saved in `path`, and save it back there with [`save`]. A missing or unreadable
+/// file is an empty cache.
+pub fn open(path: &Path) {
+    let saved: BTreeMap<String, Cached> = std::fs::read_to_string(path)
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default();
+    CACHE
+        .lock()
+        .unwrap()
+        .extend(saved.into_iter().filter(|(_, c)| c.fresh()));
+    *FILE.lock().unwrap() = Some(path.to_path_buf());
+}
+
+/// Write the fresh entries to the file given to [`open`], if any.
+pub fn save() {
+    let Some(path) = FILE.lock().unwrap().clone() else {
+        return;
+    };
+    let mut cache = CACHE.lock().unwrap();
+    cache.retain(|_, c| c.fresh());
+    let write = || -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, serde_json::to_string(&*cache)?)
+    };
+    // the next run only has to ask the servers again
+    if let Err(e) = write() {
+        warn!("cannot save the DNS cache to {}: {}", path.display(), e);
+    }
+}
@@ -0,0 +1,115 @@
+//! ANSI coloring of latency/loss cells, with `NO_COLOR` support and a few themes.
+use std::io::IsTerminal;
+
+use clap::ValueEnum;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Theme {
+    /// Bright colors for dark terminals
+    Dark,
+    /// Darker shades that stay readable on light backgrounds
+    Light,
+    /// Blue/orange/magenta, distinguishable with red-green color blindness
+    Colorblind,
+}
+
+/// Latency below this is shown as good.
+const GOOD_MS: i32 = 100;
+/// Latency below this is shown as degraded, above it as bad.
+const WARN_MS: i32 = 300;
+/// Loss ratio up to this is shown as degraded, above it as bad.
+const WARN_LOSS: f32 = 0.2;
+/// TTL freshness from this up is shown as good.
+const GOOD_FRESHNESS: f32 = 0.5;
+/// TTL freshness from this up is shown as degraded, below it as stale.
+const WARN_FRESHNESS: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Paint {
+    enabled: bool,
+    theme: Theme,
+}
+
+impl Paint {
+    pub fn new(choice: ColorChoice, theme: Theme) -> Self {
+        let enabled = match choice {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                    && std::env::var("TERM").map_or(true, |t| t != "dumb")
+                    && std::io::stdout().is_terminal()
+            }
+        };
+        Self { enabled, theme }
+    }
+
+    /// ANSI codes for (good, degraded, bad).
+    fn codes(&self) -> [&'static str; 3] {
+        match self.theme {
+            Theme::Dark => ["92", "93", "91"],
+            Theme::Light => ["38;5;28", "38;5;130", "38;5;124"],
+            Theme::Colorblind => ["38;5;33", "38;5;214", "38;5;201"],
+        }
+    }
+
+    fn paint(&self, level: usize, text: String) -> String {
+        if self.enabled {
+            format!("\x1b[{}m{}\x1b[0m", self.codes()[level], text)
+        } else {
+            text
+        }
+    }
+
+    /// Color a latency cell; `-1` means no reply at all.
+    pub fn latency(&self, ms: i32, text: String) -> String {
+        let level = match ms {
+            -1 => 2,
+            ms if ms < GOOD_MS => 0,
+            ms if ms < WARN_MS => 1,
+            _ => 2,
+        };
+        self.paint(level, text)
+    }
+
+    /// Color a packet loss cell.
+    pub fn loss(&self, lost: f32, text: String) -> String {
+        let level = match lost {
+            0.0 => 0,
+            lost if lost <= WARN_LOSS => 1,
+            _ => 2,
+        };
+        self.paint(level, text)
+    }
+
+    /// Color a TTL freshness cell.
+    pub fn freshness(&self, ratio: f32, text: String) -> String {
+        let level = match ratio {
+            ratio if ratio >= GOOD_FRESHNESS => 0,
+            ratio if ratio >= WARN_FRESHNESS => 1,
+            _ => 2,
+        };
+        self.paint(level, text)
+    }
+
+    /// Bold the cell of the best answer.
+    pub fn best(&self, text: String) -> String {
+        if self.enabled {
+            format!("\x1b[1m{}\x1b[22m", text)
+        } else {
+            text
+        }
+    }
+
+    /// Color a pass/fail cell.
+    pub fn status(&self, ok: bool, text: String) -> String {
+        self.paint(if ok { 0 } else { 2 }, text)
+    }
+}
@@ -0,0 +1,395 @@
+//! Cross-server views of the answers: which server returned which address, and how much the
+//! servers agree.
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use ipnet::IpNet;
+use tabled::builder::Builder;
+use tabled::object::{Columns, Rows};
+use tabled::{Alignment, Modify, Style, Table};
+
+use crate::color::Paint;
+use crate::config::Server;
+use crate::output::Output;
+use crate::wire;
+use crate::{format_lost, format_mean, Row};
+
+/// The probed addresses in order of first appearance, each with the first row that carried it.
+fn addresses<'a>(rows: &'a [(&str, Row)]) -> Vec<&'a Row> {
+    let mut unique: Vec<&Row> = vec![];
+    for (_, row) in rows {
+        if row.error.is_none() && !unique.iter().any(|r| r.answer.data == row.answer.data) {
+            unique.push(row);
+        }
+    }
+    unique
+}
+
+/// A named set of addresses given with `--range name=cidr`.
+#[derive(Debug, Clone)]
+pub struct NamedRange {
+    pub name: String,
+    pub net: IpNet,
+}
+
+/// Parse a `--range` value such as `prod=203.0.113.0/24`.
+pub fn parse_range(value: &str) -> Result<NamedRange, String> {
+    let (name, net) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=CIDR, got `{}`", value))?;
+    if name.is_empty() {
+        return Err(format!("missing range name in `{}`", value));
+    }
+    Ok(NamedRange {
+        name: String::from(name),
+        net: parse_expected(net)?,
+    })
+}
+
+/// The name of the first range containing `addr`, if any.
+pub fn range_of<'a>(ranges: &'a [NamedRange], addr: &str) -> Option<&'a str> {
+    let ip = IpAddr::from_str(addr).ok()?;
+    ranges
+        .iter()
+        .find(|range| range.net.contains(&ip))
+        .map(|range| range.name.as_str())
+}
+
+/// A grid with one row per unique address and one column per server, marking who returned it.
+pub fn matrix(
+    rows: &[(&str, Row)],
+    servers: &[Server],
+    ranges: &[NamedRange],
+    output: &Output,
+) -> (Table, usize) {
+    let mut builder = Builder::default();
+    let mut columns = vec![String::from("Address")];
+    if !ranges.is_empty() {
+        columns.push(String::from("Range"));
+    }
+    columns.extend(servers.iter().map(|s| s.tag.clone()));
+    columns.push(String::from("Avg"));
+    columns.push(String::from("Lost"));
+    builder.set_columns(columns);
+
+    let mut unique = addresses(rows);
+    let hidden = output.truncate(&mut unique);
+    for row in unique {
+        let mut record = vec![row.answer.data.clone()];
+        if !ranges.is_empty() {
+            record.push(String::from(
+                range_of(ranges, &row.answer.data).unwrap_or("-"),
+            ));
+        }
+        record.extend(servers.iter().map(|server| {
+            let returned = rows
+                .iter()
+                .any(|(tag, r)| *tag == server.tag && r.answer.data == row.answer.data);
+            String::from(if returned { "●" } else { "" })
+        }));
+        record.push(
+            output
+                .paint
+                .latency(row.mean, format_mean(row.mean, row.complete)),
+        );
+        record.push(
+            output
+                .paint
+                .loss(row.lost, format_lost(row.lost, row.complete, row.reachable)),
+        );
+        builder.add_record(record);
+    }
+
+    let first = if ranges.is_empty() { 1 } else { 2 };
+    let width = servers.len() + first + 2;
+    let mut table = builder.build();
+    table
+        .with(Style::modern())
+        .with(Modify::new(Columns::new(first..width - 2)).with(Alignment::center()))
+        .with(Modify::new(Columns::new(width - 2..)).with(Alignment::right()))
+        .with(Modify::new(Rows::first()).with(Alignment::center()));
+    (table, hidden)
+}
+
+/// Addresses returned by every responding server, by only one, and the union of all of them.
+pub fn summary(rows: &[(&str, Row)], responding: &[&str]) -> String {
+    let unique = addresses(rows);
+    let returned_by = |addr: &str| -> Vec<&str> {
+        responding
+            .iter()
+            .copied()
+            .filter(|tag| {
+                rows.iter()
+                    .any(|(t, r)| t == tag && r.error.is_none() && r.answer.data == addr)
+            })
+            .collect()
+    };
+
+    let mut everywhere = vec![];
+    let mut single: Vec<(&str, &str)> = vec![];
+    for row in &unique {
+        let addr = row.answer.data.as_str();
+        let tags = returned_by(addr);
+        if tags.len() == responding.len() {
+            everywhere.push(addr);
+        }
+        if tags.len() == 1 && responding.len() > 1 {
+            single.push((tags[0], addr));
+        }
+    }
+
+    let percent = if unique.is_empty() {
+        100.0
+    } else {
+        everywhere.len() as f32 * 100.0 / unique.len() as f32
+    };
+    let mut text = format!(
+        "Agreement: {} of {} addresses returned by all {} responding servers ({:.0}%)\n",
+        everywhere.len(),
+        unique.len(),
+        responding.len(),
+        percent
+    );
+    if !everywhere.is_empty() {
+        text.push_str(&format!("  all servers: {}\n", everywhere.join(", ")));
+    }
+    for tag in responding {
+        let only: Vec<&str> = single
+            .iter()
+            .filter(|(t, _)| t == tag)
+            .map(|(_, addr)| *addr)
+            .collect();
+        if !only.is_empty() {
+            text.push_str(&format!("  only {}: {}\n", tag, only.join(", ")));
+        }
+    }
+    text.push_str(&format!("  union: {} addresses", unique.len()));
+    text
+}
+
+/// Compare the address sets of the responding servers for `--check`: report the addresses only one
+/// of them returned and the pairs that share no address at all, the clearest sign of a hijacked
+/// or stale resolver. False when the share of addresses not returned by all of them is above
+/// `threshold` percent, which isn't 0 by default since CDNs and round-robin pools make some
+/// divergence normal.
+pub fn check(
+    rows: &[(&str, Row)],
+    responding: &[&str],
+    threshold: u8,
+    paint: &Paint,
+) -> (String, bool) {
+    let unique = addresses(rows);
+    let set = |tag: &str| -> Vec<&str> {
+        rows.iter()
+            .filter(|(t, r)| *t == tag && r.error.is_none())
+            .map(|(_, r)| r.answer.data.as_str())
+            .collect()
+    };
+    let sets: Vec<(&str, Vec<&str>)> = responding.iter().map(|tag| (*tag, set(tag))).collect();
+    let returned_by = |addr: &str| sets.iter().filter(|(_, s)| s.contains(&addr)).count();
+
+    let divergent = unique
+        .iter()
+        .filter(|r| returned_by(&r.answer.data) < responding.len())
+        .count();
+    let divergence = if unique.is_empty() {
+        0.0
+    } else {
+        divergent as f32 * 100.0 / unique.len() as f32
+    };
+    let ok = divergence <= threshold as f32;
+    let mut text = paint.status(
+        ok,
+        format!(
+            "Divergence: {} of {} addresses not returned by all {} responding servers ({:.0}%, threshold {}%)",
+            divergent,
+            unique.len(),
+            responding.len(),
+            divergence,
+            threshold
+        ),
+    );
+    if responding.len() > 1 {
+        for (tag, set) in &sets {
+            let only: Vec<&str> = set
+                .iter()
+                .copied()
+                .filter(|addr| returned_by(addr) == 1)
+                .collect();
+            if !only.is_empty() {
+                text.push_str(&format!("\n  only {}: {}", tag, only.join(", ")));
+            }
+        }
+    }
+    for (idx, (a, set_a)) in sets.iter().enumerate() {
+        for (b, set_b) in &sets[idx + 1..] {
+            if !set_a.is_empty() && !set_b.is_empty() && !set_a.iter().any(|x| set_b.contains(x)) {
+                let line = format!("  disjoint: {} and {} share no address", a, b);
+                text.push('\n');
+                text.push_str(&paint.status(false, line));
+            }
+        }
+    }
+    (text, ok)
+}
+
+/// For every row, its remaining TTL as a share of the highest TTL any server gave the same record,
+/// from 0 to 1: a resolver whose cache is much older than the others' scores low. `None` for
+/// records only one server returned, which have nothing to compare to.
+pub fn freshness(rows: &[(&str, Row)]) -> Vec<Option<f32>> {
+    let same = |a: &Row, b: &Row| {
+        a.answer.r#type == b.answer.r#type
+            && a.answer.data == b.answer.data
+            && a.answer.name.eq_ignore_ascii_case(&b.answer.name)
+    };
+    rows.iter()
+        .map(|(tag, row)| {
+            let others: Vec<u32> = rows
+                .iter()
+                .filter(|(t, r)| t != tag && same(r, row))
+                .map(|(_, r)| r.answer.TTL)
+                .collect();
+            let freshest = others.iter().copied().chain([row.answer.TTL]).max()?;
+            if others.is_empty() || freshest == 0 {
+                return None;
+            }
+            Some(row.answer.TTL as f32 / freshest as f32)
+        })
+        .collect()
+}
+
+/// Mean freshness of each responding server's records, for those that have one.
+pub fn freshness_summary(
+    rows: &[(&str, Row)],
+    freshness: &[Option<f32>],
+    responding: &[&str],
+) -> String {
+    let servers: Vec<String> = responding
+        .iter()
+        .filter_map(|tag| {
+            let values: Vec<f32> = rows
+                .iter()
+                .zip(freshness)
+                .filter(|((t, _), _)| t == tag)
+                .filter_map(|(_, f)| *f)
+                .collect();
+            if values.is_empty() {
+                return None;
+            }
+            let mean = values.iter().sum::<f32>() / values.len() as f32;
+            Some(format!("{} {:.0}%", tag, mean * 100.0))
+        })
+        .collect();
+    if servers.is_empty() {
+        String::from("TTL freshness: no record was returned by more than one server")
+    } else {
+        format!("TTL freshness: {}", servers.join(", "))
+    }
+}
+
+/// How strictly `--assert-consistent` compares the answer sets of two servers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Consistency {
+    /// Same records, in any order, whatever their TTL
+    Set,
+    /// Same records in the same order
+    Ordered,
+    /// Same records in the same order with the same TTLs
+    Exact,
+}
+
+/// Check that every server returned the same answers, describing the first mismatch otherwise.
+/// A server that failed to answer counts as a mismatch.
+pub fn check_consistent(
+    rows: &[(&str, Row)],
+    responding: &[&str],
+    failed: &[&str],
+    mode: Consistency,
+) -> Result<(), String> {
+    if !failed.is_empty() {
+        return Err(format!("no answer from {}", failed.join(", ")));
+    }
+    let answers = |tag: &str| -> Vec<String> {
+        let mut answers: Vec<String> = rows
+            .iter()
+            .filter(|(t, _)| *t == tag)
+            .map(|(_, r)| match mode {
+                Consistency::Exact => {
+                    let rtype = wire::type_name(r.answer.r#type);
+                    format!("{} {} {}", rtype, r.answer.data, r.answer.TTL)
+                }
+                _ => format!("{} {}", wire::type_name(r.answer.r#type), r.answer.data),
+            })
+            .collect();
+        if mode == Consistency::Set {
+            answers.sort();
+            answers.dedup();
+        }
+        answers
+    };
+
+    let Some((first, others)) = responding.split_first() else {
+        return Ok(());
+    };
+    let expected = answers(first);
+    for tag in others {
+        let got = answers(tag);
+        if got != expected {
+            return Err(format!(
+                "{} returned [{}] but {} returned [{}]",
+                first,
+                expected.join(", "),
+                tag,
+                got.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Parse an `--expect` value: a single address or a CIDR range.
+pub fn parse_expected(value: &str) -> Result<IpNet, String> {
+    if let Ok(addr) = IpAddr::from_str(value) {
+        return Ok(IpNet::from(addr));
+    }
+    IpNet::from_str(value)
+        .map_err(|_| format!("`{}` is neither an address nor a CIDR range", value))
+}
+
+/// Check each server's addresses against the expected ones; a server passes when it returned
+/// at least one address and all of them match.
+pub fn check_expected<'a>(
+    rows: &[(&str, Row)],
+    responding: &[&'a str],
+    failed: &[&'a str],
+    expected: &[IpNet],
+) -> Vec<(&'a str, Result<(), String>)> {
+    let mut report = vec![];
+    for tag in responding {
+        let addrs: Vec<&str> = rows
+            .iter()
+            .filter(|(t, r)| t == tag && r.error.is_none())
+            .map(|(_, r)| r.answer.data.as_str())
+            .collect();
+        let unexpected: Vec<&str> = addrs
+            .iter()
+            .copied()
+            .filter(|addr| {
+                !IpAddr::from_str(addr).is_ok_and(|ip| expected.iter().any(|net| net.contains(&ip)))
+            })
+            .collect();
+        let result = if addrs.is_empty() {
+            Err(String::from("no addresses returned"))
+        } else if !unexpected.is_empty() {
+            Err(format!("still returns {}", unexpected.join(", ")))
+        } else {
+            Ok(())
+        };
+        report.push((*tag, result));
+    }
+    for tag in failed {
+        report.push((*tag, Err(String::from("query failed"))));
+    }
+    report
+}
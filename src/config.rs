@@ -0,0 +1,816 @@
+//! Configuration file: DoH servers and probe settings, validated before anything runs.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use reqwest::Url;
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::{dialect, dirs, plain, system, wire, Api};
+
+/// A DoH server to query.
+#[derive(Debug, Clone)]
+pub struct Server {
+    /// Short name shown in the DoH column.
+    pub tag: String,
+    pub url: String,
+    /// The only API to query it through, instead of negotiating one.
+    pub api: Option<Api>,
+    /// Proxy to reach it through instead of `--proxy`, `none` to go direct.
+    pub proxy: Option<String>,
+    /// Address to connect to for its host name, instead of resolving it.
+    pub ip: Option<IpAddr>,
+    /// HTTP headers to send it on top of `--header`, `User-Agent` for `user_agent = ...`.
+    pub headers: Vec<(String, String)>,
+    /// How to read its JSON API responses, instead of finding out.
+    pub json_dialect: Option<dialect::Kind>,
+}
+
+/// What a probe of an address measures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProbeMethod {
+    /// ICMP echo round-trip time.
+    #[default]
+    Icmp,
+    /// TCP connect time to this port, or to 443 then 80.
+    Tcp(Option<u16>),
+    /// Time to the first byte of a `HEAD /` request on port 443, split into TCP connect, TLS
+    /// handshake and the response.
+    Https,
+}
+
+/// Parse a `--probe` value: `icmp`, `tcp`, `tcp:PORT` or `https`.
+pub fn parse_method(value: &str) -> Result<ProbeMethod, String> {
+    match value.split_once(':') {
+        None if value == "icmp" => Ok(ProbeMethod::Icmp),
+        None if value == "tcp" => Ok(ProbeMethod::Tcp(None)),
+        None if value == "https" => Ok(ProbeMethod::Https),
+        Some(("tcp", port)) => port
+            .parse()
+            .ok()
+            .filter(|port| *port != 0)
+            .map(|port| ProbeMethod::Tcp(Some(port)))
+            .ok_or_else(|| format!("`{}` is not a port", port)),
+        _ => Err(format!(
+            "expected icmp, tcp, tcp:PORT or https, got `{}`",
+            value
+        )),
+    }
+}
+
+/// How each resolved address is pinged.
+#[derive(Debug, Clone, Copy)]
+pub struct ProbeSettings {
+    pub method: ProbeMethod,
+    /// Echo requests sent per address.
+    pub count: u16,
+    /// Delay between two echo requests.
+    pub interval: Duration,
+    /// Echo requests in flight at once, 1 to wait for each reply before the next request.
+    pub window: usize,
+    /// How long to wait for each reply.
+    pub timeout: Duration,
+    /// ICMP payload size in bytes.
+    pub size: usize,
+    /// NAT64 prefix to reach IPv4 addresses through on an IPv6-only network.
+    pub nat64: Option<Ipv6Addr>,
+    /// Also ping the `ipv4hint` and `ipv6hint` addresses of HTTPS and SVCB answers.
+    pub hints: bool,
+}
+
+impl Default for ProbeSettings {
+    fn default() -> Self {
+        Self {
+            method: ProbeMethod::Icmp,
+            count: 10,
+            interval: Duration::from_secs(1),
+            window: 1,
+            timeout: Duration::from_secs(1),
+            size: 56,
+            nat64: None,
+            hints: false,
+        }
+    }
+}
+
+/// The formats `--output` takes, which `[defaults] output` may name.
+pub const OUTPUT_FORMATS: &[&str] = &[
+    "table",
+    "json",
+    "ndjson",
+    "csv",
+    "xlsx",
+    "openmetrics",
+    "hosts",
+];
+
+/// Values for flags left off the command line.
+#[derive(Debug, Clone, Default)]
+pub struct Defaults {
+    /// Record types to query, as with `--type`.
+    pub rtypes: Vec<String>,
+    /// Name of the output format, as with `--output`.
+    pub output: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub servers: Vec<Server>,
+    pub probe: ProbeSettings,
+    pub defaults: Defaults,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            servers: default_servers(),
+            probe: ProbeSettings::default(),
+            defaults: Defaults::default(),
+        }
+    }
+}
+
+/// The servers used when the config file doesn't list any.
+pub fn default_servers() -> Vec<Server> {
+    [
+        ("1.1.1.1", "https://1.1.1.1/dns-query"),
+        ("9.9.9.9", "https://9.9.9.9:5053/dns-query"),
+        ("aliyun", "https://dns.alidns.com/resolve"),
+    ]
+    .into_iter()
+    .map(|(tag, url)| Server {
+        tag: String::from(tag),
+        url: String::from(url),
+        api: None,
+        proxy: None,
+        ip: None,
+        headers: vec![],
+        json_dialect: None,
+    })
+    .collect()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    #[serde(default)]
+    servers: Vec<RawServer>,
+    #[serde(default)]
+    probe: RawProbe,
+    #[serde(default)]
+    defaults: RawDefaults,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawServer {
+    tag: Option<Spanned<String>>,
+    url: Spanned<String>,
+    api: Option<Spanned<Api>>,
+    proxy: Option<Spanned<String>>,
+    ip: Option<Spanned<IpAddr>>,
+    headers: Option<Spanned<BTreeMap<String, String>>>,
+    user_agent: Option<Spanned<String>>,
+    json_dialect: Option<Spanned<dialect::Kind>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawDefaults {
+    #[serde(rename = "type")]
+    rtype: Option<Spanned<String>>,
+    output: Option<Spanned<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawProbe {
+    count: Option<Spanned<u16>>,
+    /// Seconds.
+    interval: Option<Spanned<f64>>,
+    /// Seconds.
+    timeout: Option<Spanned<f64>>,
+    size: Option<Spanned<usize>>,
+}
+
+/// A problem found in the config file, pointing at the offending line.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    /// The offending source line.
+    pub source: String,
+}
+
+impl Diagnostic {
+    fn new(text: &str, span: Range<usize>, message: impl Into<String>) -> Self {
+        let start = span.start.min(text.len());
+        let line_start = text[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = text[start..].find('\n').map_or(text.len(), |i| start + i);
+        Self {
+            line: text[..start].matches('\n').count() + 1,
+            column: text[line_start..start].chars().count() + 1,
+            message: message.into(),
+            source: String::from(text[line_start..line_end].trim_end()),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}:{}: {}", self.line, self.column, self.message)?;
+        let gutter = self.line.to_string();
+        writeln!(f, "{} | {}", gutter, self.source)?;
+        write!(
+            f,
+            "{} | {}^",
+            " ".repeat(gutter.len()),
+            " ".repeat(self.column - 1)
+        )
+    }
+}
+
+/// All problems found in a config file.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub path: String,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} problem(s) in {}", self.diagnostics.len(), self.path)?;
+        for diagnostic in &self.diagnostics {
+            write!(f, "\n{}:{}", self.path, diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Check that `url` is something we can send DoH, or plain DNS, queries to.
+fn check_url(url: &str) -> Result<(), String> {
+    if system::is_system(url) {
+        return Ok(());
+    }
+    let parsed = Url::parse(url).map_err(|e| format!("invalid server URL `{}`: {}", url, e))?;
+    if !matches!(parsed.scheme(), "https" | "http" | "udp" | "tcp" | "tls") {
+        return Err(format!(
+            "unsupported scheme `{}` in `{}`, expected https, tls, udp or tcp",
+            parsed.scheme(),
+            url
+        ));
+    }
+    if parsed.host_str().is_none() {
+        return Err(format!("server URL `{}` has no host", url));
+    }
+    Ok(())
+}
+
+/// Parse a `--bootstrap` value: a resolver's address, on port 53 unless it has one.
+pub fn parse_bootstrap(value: &str) -> Result<SocketAddr, String> {
+    value
+        .parse::<SocketAddr>()
+        .or_else(|_| value.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| format!("expected an IP address, or IP:PORT, got `{}`", value))
+}
+
+/// Parse a `--proxy` or `proxy = ...` value: an `http://`, `https://`, `socks5://` or
+/// `socks5h://` URL, or `none`.
+pub fn parse_proxy(value: &str) -> Result<String, String> {
+    if value == "none" {
+        return Ok(String::from(value));
+    }
+    match value.split_once("://") {
+        Some(("http" | "https" | "socks5" | "socks5h", _)) => reqwest::Proxy::all(value)
+            .map(|_| String::from(value))
+            .map_err(|e| format!("invalid proxy `{}`: {}", value, e)),
+        _ => Err(format!(
+            "expected an http://, https://, socks5:// or socks5h:// proxy URL or none, got `{}`",
+            value
+        )),
+    }
+}
+
+/// Check that `name: value` can be sent as an HTTP header.
+fn check_header(name: &str, value: &str) -> Result<(), String> {
+    reqwest::header::HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("invalid HTTP header name `{}`", name))?;
+    reqwest::header::HeaderValue::from_str(value)
+        .map_err(|_| format!("invalid value for HTTP header {}", name))?;
+    Ok(())
+}
+
+/// Parse a `--header` value, `Name: value`.
+pub fn parse_header(value: &str) -> Result<(String, String), String> {
+    let (name, content) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected `Name: value`, got `{}`", value))?;
+    let (name, content) = (name.trim(), content.trim());
+    check_header(name, content)?;
+    Ok((String::from(name), String::from(content)))
+}
+
+/// Parse a `--user-agent` value.
+pub fn parse_user_agent(value: &str) -> Result<String, String> {
+    check_header("User-Agent", value)?;
+    Ok(String::from(value))
+}
+
+/// The tag of a server given without one: its host.
+fn default_tag(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(String::from))
+        .unwrap_or_else(|| String::from(url))
+}
+
+/// Parse a `--server` value, `URL` or `URL=TAG`.
+pub fn parse_server(value: &str) -> Result<Server, String> {
+    // a `=` in the query string is part of the URL, not a tag, unless the last parameter
+    // already has its own
+    let (url, tag) = match value.rsplit_once('=') {
+        Some((url, tag))
+            if !tag.is_empty()
+                && !tag.contains(['/', '?', '&'])
+                && url
+                    .rsplit_once(['?', '&'])
+                    .is_none_or(|(_, param)| param.contains('=')) =>
+        {
+            (url, String::from(tag))
+        }
+        _ => (value, default_tag(value)),
+    };
+    check_url(url)?;
+    Ok(Server {
+        tag,
+        url: String::from(url),
+        api: None,
+        proxy: None,
+        ip: None,
+        headers: vec![],
+        json_dialect: None,
+    })
+}
+
+/// Read a `--servers-file`: one `URL` or `URL=TAG` per line, `#` starts a comment.
+pub fn servers_file(path: &str) -> Result<Vec<Server>, Box<dyn std::error::Error>> {
+    let text =
+        crate::read_input(path).map_err(|e| format!("cannot read servers file {}: {}", path, e))?;
+    let mut servers = vec![];
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let server = parse_server(line).map_err(|e| format!("{}:{}: {}", path, idx + 1, e))?;
+        servers.push(server);
+    }
+    Ok(servers)
+}
+
+/// Read the certificates of a `--ca-cert` PEM file, one block each.
+pub fn certificates(path: &str) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("cannot read CA certificates {}: {}", path, e))?;
+    let mut certs = vec![];
+    let mut rest = text.as_str();
+    while let Some(start) = rest.find(BEGIN) {
+        let end = rest[start..]
+            .find(END)
+            .map(|end| start + end + END.len())
+            .ok_or_else(|| format!("{}: unterminated certificate", path))?;
+        let pem = &rest.as_bytes()[start..end];
+        reqwest::Certificate::from_pem(pem).map_err(|e| format!("{}: {}", path, e))?;
+        certs.push(pem.to_vec());
+        rest = &rest[end..];
+    }
+    if certs.is_empty() {
+        return Err(format!("no PEM certificate in {}", path).into());
+    }
+    Ok(certs)
+}
+
+/// Query `given` servers instead of the configured ones, or as well with `keep`.
+pub fn use_servers(
+    servers: &mut Vec<Server>,
+    given: Vec<Server>,
+    keep: bool,
+) -> Result<(), String> {
+    if given.is_empty() {
+        return Ok(());
+    }
+    if !keep {
+        servers.clear();
+    }
+    for server in given {
+        if servers.iter().any(|s| s.tag == server.tag) {
+            return Err(format!(
+                "duplicate server tag `{}`, name it with URL=TAG",
+                server.tag
+            ));
+        }
+        servers.push(server);
+    }
+    Ok(())
+}
+
+fn seconds(
+    text: &str,
+    value: &Spanned<f64>,
+    name: &str,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Option<Duration> {
+    let secs = *value.get_ref();
+    if secs > 0.0 && secs <= 60.0 {
+        Some(Duration::from_secs_f64(secs))
+    } else {
+        diagnostics.push(Diagnostic::new(
+            text,
+            value.span(),
+            format!(
+                "probe.{} must be between 0 and 60 seconds, got {}",
+                name, secs
+            ),
+        ));
+        None
+    }
+}
+
+/// Parse and validate a config file, collecting every problem rather than stopping at the first.
+pub fn parse(text: &str) -> Result<Config, Vec<Diagnostic>> {
+    let raw: RawConfig = toml::from_str(text).map_err(|e| {
+        vec![Diagnostic::new(
+            text,
+            e.span().unwrap_or(0..0),
+            e.message().to_string(),
+        )]
+    })?;
+
+    let mut diagnostics = vec![];
+    let mut servers: Vec<Server> = vec![];
+    for server in raw.servers {
+        let url = server.url.get_ref();
+        if let Err(message) = check_url(url) {
+            diagnostics.push(Diagnostic::new(text, server.url.span(), message));
+            continue;
+        }
+        let tag = match &server.tag {
+            Some(tag) => tag.get_ref().clone(),
+            None => default_tag(url),
+        };
+        if servers.iter().any(|s| s.tag == tag) {
+            let span = server.tag.as_ref().unwrap_or(&server.url).span();
+            diagnostics.push(Diagnostic::new(
+                text,
+                span,
+                format!("duplicate server tag `{}`", tag),
+            ));
+            continue;
+        }
+        let doh = !plain::is_plain(url) && !system::is_system(url);
+        if let Some(api) = server.api.as_ref().filter(|_| !doh) {
+            diagnostics.push(Diagnostic::new(
+                text,
+                api.span(),
+                "`api` only applies to DoH servers",
+            ));
+            continue;
+        }
+        if let Some(kind) = server.json_dialect.as_ref().filter(|_| !doh) {
+            diagnostics.push(Diagnostic::new(
+                text,
+                kind.span(),
+                "`json_dialect` only applies to DoH servers",
+            ));
+            continue;
+        }
+        if let Some(proxy) = &server.proxy {
+            let checked = if !doh {
+                Err(String::from("`proxy` only applies to DoH servers"))
+            } else {
+                parse_proxy(proxy.get_ref()).map(|_| ())
+            };
+            if let Err(message) = checked {
+                diagnostics.push(Diagnostic::new(text, proxy.span(), message));
+                continue;
+            }
+        }
+        let literal = Url::parse(url)
+            .ok()
+            .and_then(|u| {
+                u.host_str()
+                    .map(|h| h.trim_matches(['[', ']']).parse::<IpAddr>())
+            })
+            .is_some_and(|h| h.is_ok());
+        if let Some(ip) = server
+            .ip
+            .as_ref()
+            .filter(|_| literal || system::is_system(url))
+        {
+            diagnostics.push(Diagnostic::new(
+                text,
+                ip.span(),
+                "`ip` only applies to servers given by host name",
+            ));
+            continue;
+        }
+        let mut headers = vec![];
+        let mut invalid = None;
+        if let Some(table) = &server.headers {
+            for (name, value) in table.get_ref() {
+                match check_header(name, value) {
+                    Ok(()) => headers.push((name.clone(), value.clone())),
+                    Err(message) => invalid = Some((table.span(), message)),
+                }
+            }
+        }
+        if let Some(agent) = &server.user_agent {
+            match check_header("User-Agent", agent.get_ref()) {
+                Ok(()) => headers.push((String::from("User-Agent"), agent.get_ref().clone())),
+                Err(message) => invalid = Some((agent.span(), message)),
+            }
+        }
+        let given = server.headers.as_ref().map(Spanned::span);
+        if let Some(span) = given.or(server.user_agent.as_ref().map(Spanned::span)) {
+            if !doh {
+                invalid = Some((span, String::from("HTTP headers only apply to DoH servers")));
+            }
+        }
+        if let Some((span, message)) = invalid {
+            diagnostics.push(Diagnostic::new(text, span, message));
+            continue;
+        }
+        servers.push(Server {
+            tag,
+            url: url.clone(),
+            api: server.api.map(Spanned::into_inner),
+            proxy: server.proxy.map(Spanned::into_inner),
+            ip: server.ip.map(Spanned::into_inner),
+            headers,
+            json_dialect: server.json_dialect.map(Spanned::into_inner),
+        });
+    }
+
+    let mut probe = ProbeSettings::default();
+    if let Some(count) = &raw.probe.count {
+        match *count.get_ref() {
+            1..=1000 => probe.count = *count.get_ref(),
+            n => diagnostics.push(Diagnostic::new(
+                text,
+                count.span(),
+                format!("probe.count must be between 1 and 1000, got {}", n),
+            )),
+        }
+    }
+    if let Some(value) = &raw.probe.interval {
+        probe.interval =
+            seconds(text, value, "interval", &mut diagnostics).unwrap_or(probe.interval);
+    }
+    if let Some(value) = &raw.probe.timeout {
+        probe.timeout = seconds(text, value, "timeout", &mut diagnostics).unwrap_or(probe.timeout);
+    }
+    if let Some(size) = &raw.probe.size {
+        match *size.get_ref() {
+            0..=65500 => probe.size = *size.get_ref(),
+            n => diagnostics.push(Diagnostic::new(
+                text,
+                size.span(),
+                format!("probe.size must be at most 65500 bytes, got {}", n),
+            )),
+        }
+    }
+
+    let mut defaults = Defaults::default();
+    if let Some(rtype) = &raw.defaults.rtype {
+        // a comma-separated list, as on the command line
+        let parsed: Result<Vec<String>, String> = rtype
+            .get_ref()
+            .split(',')
+            .map(|t| wire::parse_type(t.trim()))
+            .collect();
+        match parsed {
+            Ok(rtypes) => defaults.rtypes = rtypes,
+            Err(message) => diagnostics.push(Diagnostic::new(text, rtype.span(), message)),
+        }
+    }
+    if let Some(output) = &raw.defaults.output {
+        let name = output.get_ref();
+        if OUTPUT_FORMATS.contains(&name.to_lowercase().as_str()) {
+            defaults.output = Some(name.clone());
+        } else {
+            diagnostics.push(Diagnostic::new(
+                text,
+                output.span(),
+                format!(
+                    "unknown output format `{}`, expected one of: {}",
+                    name,
+                    OUTPUT_FORMATS.join(", ")
+                ),
+            ));
+        }
+    }
+
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    if servers.is_empty() {
+        servers = default_servers();
+    }
+    Ok(Config {
+        servers,
+        probe,
+        defaults,
+    })
+}
+
+/// The config file to use: `--config` if given, else the platform one when it exists.
+pub fn path(explicit: Option<&str>) -> Option<PathBuf> {
+    match explicit {
+        Some(path) => Some(PathBuf::from(path)),
+        None => dirs::config_file().filter(|path| path.exists()),
+    }
+}
+
+/// Load the config file at `path`, or the built-in defaults when there is none.
+pub fn load(path: Option<&Path>) -> Result<Config, Box<dyn std::error::Error>> {
+    let Some(path) = path else {
+        return Ok(Config::default());
+    };
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("cannot read config file {}: {}", path.display(), e))?;
+    parse(&text).map_err(|diagnostics| {
+        ConfigError {
+            path: path.display().to_string(),
+            diagnostics,
+        }
+        .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The only problem `parse` finds in `text`.
+    fn problem(text: &str) -> Diagnostic {
+        let mut diagnostics = parse(text).expect_err("a problem");
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        diagnostics.remove(0)
+    }
+
+    #[test]
+    fn parses_servers_and_settings() {
+        let config = parse(
+            r#"
+[[servers]]
+url = "https://dns.example/dns-query"
+headers = { X-Test = "1" }
+
+[[servers]]
+tag = "plain"
+url = "udp://192.0.2.53"
+
+[probe]
+count = 3
+timeout = 0.5
+
+[defaults]
+type = "A, AAAA"
+output = "json"
+"#,
+        )
+        .unwrap();
+        let tags: Vec<&str> = config.servers.iter().map(|s| s.tag.as_str()).collect();
+        assert_eq!(tags, ["dns.example", "plain"]);
+        assert_eq!(
+            config.servers[0].headers,
+            [(String::from("X-Test"), String::from("1"))]
+        );
+        assert_eq!(config.probe.count, 3);
+        assert_eq!(config.probe.timeout, Duration::from_millis(500));
+        assert_eq!(config.defaults.rtypes, ["A", "AAAA"]);
+        assert_eq!(config.defaults.output.as_deref(), Some("json"));
+    }
+
+    #[test]
+    fn an_empty_file_keeps_the_default_servers() {
+        let config = parse("").unwrap();
+        assert_eq!(config.servers.len(), default_servers().len());
+    }
+
+    #[test]
+    fn points_at_invalid_toml() {
+        let diagnostic = problem("[probe]\ncount = ");
+        assert_eq!(diagnostic.line, 2);
+        let diagnostic = problem("[probe]\ncolor = true\n");
+        assert_eq!(
+            (diagnostic.line, diagnostic.source.as_str()),
+            (2, "color = true")
+        );
+    }
+
+    #[test]
+    fn points_at_the_offending_value() {
+        let diagnostic = problem("[defaults]\n\noutput = \"yaml\"\n");
+        assert_eq!((diagnostic.line, diagnostic.column), (3, 10));
+        assert!(diagnostic
+            .message
+            .starts_with("unknown output format `yaml`"));
+        let shown = diagnostic.to_string();
+        assert!(
+            shown.ends_with("3 | output = \"yaml\"\n  |          ^"),
+            "{}",
+            shown
+        );
+    }
+
+    #[test]
+    fn collects_every_problem() {
+        let diagnostics = parse("[probe]\ncount = 0\nsize = 70000\ninterval = 61\n")
+            .err()
+            .unwrap();
+        let lines: Vec<usize> = diagnostics.iter().map(|d| d.line).collect();
+        assert_eq!(lines, [2, 4, 3]);
+    }
+
+    #[test]
+    fn rejects_malformed_servers() {
+        let cases = [
+            ("url = \"not a url\"", "invalid server URL"),
+            ("url = \"ftp://dns.example\"", "unsupported scheme `ftp`"),
+            (
+                "url = \"udp://192.0.2.53\"\napi = \"json\"",
+                "`api` only applies",
+            ),
+            (
+                "url = \"https://192.0.2.53/dns-query\"\nip = \"192.0.2.1\"",
+                "`ip` only applies",
+            ),
+            (
+                "url = \"https://a.example\"\nheaders = { \"Bad Name\" = \"1\" }",
+                "invalid HTTP header name",
+            ),
+            (
+                "url = \"https://a.example\"\nuser_agent = \"a\\nb\"",
+                "invalid value",
+            ),
+            (
+                "url = \"tcp://192.0.2.53\"\nuser_agent = \"doh\"",
+                "HTTP headers only apply",
+            ),
+        ];
+        for (server, message) in cases {
+            let diagnostic = problem(&format!("[[servers]]\n{}\n", server));
+            assert!(
+                diagnostic.message.contains(message),
+                "{}",
+                diagnostic.message
+            );
+        }
+        let twice = "[[servers]]\nurl = \"https://a.example\"\n".repeat(2);
+        assert_eq!(problem(&twice).message, "duplicate server tag `a.example`");
+    }
+
+    #[test]
+    fn parses_command_line_values() {
+        let server = parse_server("https://dns.example/resolve?ct=json=mine").unwrap();
+        assert_eq!(
+            (server.url.as_str(), server.tag.as_str()),
+            ("https://dns.example/resolve?ct=json", "mine")
+        );
+        let server = parse_server("https://dns.example/resolve?ct=json").unwrap();
+        assert_eq!(server.tag, "dns.example");
+        assert!(parse_server("dns.example").is_err());
+
+        assert_eq!(
+            parse_header("X-Test:  a: b ").unwrap(),
+            (String::from("X-Test"), String::from("a: b"))
+        );
+        assert!(parse_header("X-Test").is_err());
+        assert!(parse_header(": value").is_err());
+
+        assert_eq!(parse_bootstrap("192.0.2.53").unwrap().port(), 53);
+        assert_eq!(parse_bootstrap("[2001:db8::53]:5353").unwrap().port(), 5353);
+        assert!(parse_bootstrap("dns.example").is_err());
+
+        assert!(parse_proxy("socks5h://127.0.0.1:9050").is_ok());
+        assert!(parse_proxy("none").is_ok());
+        assert!(parse_proxy("ftp://127.0.0.1").is_err());
+
+        assert_eq!(
+            parse_method("tcp:853").unwrap(),
+            ProbeMethod::Tcp(Some(853))
+        );
+        assert!(parse_method("tcp:0").is_err());
+        assert!(parse_method("udp").is_err());
+    }
+}
@@ -0,0 +1,103 @@
+//! Explain replies that are not DNS answers at all: captive portals, filtered networks and
+//! provider error pages, instead of a bare decode error.
+use std::fmt;
+
+use crate::Reply;
+
+/// Longest part of the body quoted in a diagnostic.
+const SNIPPET_CHARS: usize = 80;
+
+const PORTAL_HINTS: &[&str] = &[
+    "captive",
+    "portal",
+    "hotspot",
+    "wi-fi",
+    "wifi",
+    "log in",
+    "login",
+    "sign in",
+    "terms of use",
+    "accept the terms",
+];
+
+const BLOCKED_HINTS: &[&str] = &[
+    "blocked",
+    "access denied",
+    "forbidden",
+    "not allowed",
+    "filtered",
+    "prohibited",
+    "firewall",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// A network filter or the provider refused the request.
+    Blocked,
+    /// A login page intercepted the request.
+    Portal,
+    /// The server failed, or doesn't accept this request.
+    Provider,
+    /// A working web server, but not a DoH endpoint.
+    NotDoh,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Blocked => "blocked",
+            Kind::Portal => "captive portal",
+            Kind::Provider => "provider error",
+            Kind::NotDoh => "not a DoH endpoint",
+        })
+    }
+}
+
+/// The body as a single line of text with HTML tags dropped, `None` if it isn't text.
+fn text(body: &[u8]) -> Option<String> {
+    let body = std::str::from_utf8(body).ok()?;
+    if body.chars().any(|c| c.is_control() && !c.is_whitespace()) {
+        return None;
+    }
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in body.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    Some(text.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+pub fn classify(reply: &Reply) -> Kind {
+    let lower = text(&reply.body).unwrap_or_default().to_lowercase();
+    let hinted = |hints: &[&str]| hints.iter().any(|hint| lower.contains(hint));
+    match reply.status {
+        403 | 451 => Kind::Blocked,
+        // portals typically answer 200 or 511 with their own page
+        511 => Kind::Portal,
+        _ if hinted(PORTAL_HINTS) => Kind::Portal,
+        _ if hinted(BLOCKED_HINTS) => Kind::Blocked,
+        200..=299 => Kind::NotDoh,
+        _ => Kind::Provider,
+    }
+}
+
+/// `HTTP <status>, <kind>: "<snippet>"` for a reply that couldn't be decoded.
+pub fn describe(reply: &Reply) -> String {
+    let mut message = format!("HTTP {}, {}", reply.status, classify(reply));
+    if let Some(text) = text(&reply.body).filter(|t| !t.is_empty()) {
+        let mut snippet: String = text.chars().take(SNIPPET_CHARS).collect();
+        if snippet.len() < text.len() {
+            snippet.push('…');
+        }
+        message.push_str(&format!(": \"{}\"", snippet));
+    }
+    message
+}
@@ -0,0 +1,152 @@
+//! Dialects of the JSON API. Google and Cloudflare set the shape most servers follow, but some,
+//! such as Quad9 on port 5053, differ in details: lowercase keys, a lone answer object instead of
+//! a list, TTLs as strings or types as names. A server's dialect is set with `json_dialect = ...`
+//! in the config or in the provider database, and otherwise found by trying them in turn.
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tracing::debug;
+
+use crate::quirks::Quirk;
+use crate::{wire, Answer, DnsResponse};
+
+/// A way to read a JSON API response.
+pub trait Dialect: Sync {
+    fn name(&self) -> &'static str;
+
+    /// The response in `body`, or why it isn't one of this dialect.
+    fn parse(&self, body: &[u8]) -> Result<DnsResponse, String>;
+}
+
+/// Which dialect a server speaks, as `json_dialect = ...` names it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// Google's, then Quad9's when a response doesn't fit it.
+    Auto,
+    Google,
+    Quad9,
+}
+
+/// The shape of Google's and Cloudflare's responses, read strictly.
+pub struct Google;
+
+impl Dialect for Google {
+    fn name(&self) -> &'static str {
+        "Google"
+    }
+
+    fn parse(&self, body: &[u8]) -> Result<DnsResponse, String> {
+        serde_json::from_slice::<DnsResponse>(body).map_err(|e| e.to_string())
+    }
+}
+
+/// Any casing of the keys, a list or a single object of answers or none at all, and numbers as
+/// strings, as Quad9 and a few others answer.
+pub struct Quad9;
+
+/// The value of `key` in `object`, whatever its casing.
+fn field<'a>(object: &'a Map<String, Value>, key: &str) -> Option<&'a Value> {
+    object
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        .map(|(_, value)| value)
+}
+
+/// A number, or a string of one.
+fn number(value: &Value) -> Option<u32> {
+    match value {
+        Value::Number(n) => n.as_u64().and_then(|n| u32::try_from(n).ok()),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// A string, or anything else as its JSON text.
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn answer(value: &Value) -> Result<Answer, String> {
+    let object = value.as_object().ok_or("an answer is not an object")?;
+    let get = |key: &str| field(object, key).ok_or_else(|| format!("an answer has no {}", key));
+    let r#type = get("type")?;
+    // a mnemonic, such as "A", from some servers
+    let r#type = number(r#type)
+        .or_else(|| r#type.as_str().and_then(wire::type_code))
+        .ok_or_else(|| format!("unknown answer type {}", r#type))?;
+    Ok(Answer {
+        name: text(get("name")?),
+        r#type,
+        TTL: field(object, "TTL").and_then(number).unwrap_or_default(),
+        data: text(get("data")?),
+    })
+}
+
+impl Dialect for Quad9 {
+    fn name(&self) -> &'static str {
+        "Quad9"
+    }
+
+    fn parse(&self, body: &[u8]) -> Result<DnsResponse, String> {
+        let value: Value = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+        let object = value.as_object().ok_or("the response is not an object")?;
+        let status = field(object, "Status")
+            .and_then(number)
+            .ok_or("the response has no Status")?;
+        let answers = match field(object, "Answer") {
+            Some(Value::Array(answers)) => answers.iter().map(answer).collect::<Result<_, _>>()?,
+            Some(Value::Null) | None => vec![],
+            Some(one) => vec![answer(one)?],
+        };
+        let comment = match field(object, "Comment") {
+            Some(Value::Array(lines)) => {
+                Some(lines.iter().map(text).collect::<Vec<_>>().join("; "))
+            }
+            Some(Value::Null) | None => None,
+            Some(one) => Some(text(one)),
+        };
+        Ok(DnsResponse {
+            Status: status,
+            AD: match field(object, "AD") {
+                Some(Value::Bool(ad)) => *ad,
+                Some(other) => number(other).is_some_and(|ad| ad != 0),
+                None => false,
+            },
+            Answer: Some(answers),
+            Comment: comment.filter(|text| !text.trim().is_empty()),
+        })
+    }
+}
+
+/// Google's dialect first, then Quad9's.
+pub struct Auto;
+
+impl Dialect for Auto {
+    fn name(&self) -> &'static str {
+        "auto"
+    }
+
+    fn parse(&self, body: &[u8]) -> Result<DnsResponse, String> {
+        Google.parse(body).or_else(|strict| {
+            let response = Quad9.parse(body).map_err(|_| strict)?;
+            debug!(
+                "read a JSON response {}'s dialect rejects as {}'s",
+                Google.name(),
+                Quad9.name()
+            );
+            Ok(response)
+        })
+    }
+}
+
+/// The dialect of a server: the one `set` in the config, else the one of its `quirk`, else both.
+pub fn of(set: Option<Kind>, quirk: Option<&Quirk>) -> &'static dyn Dialect {
+    match set.or_else(|| quirk.and_then(|q| q.json_dialect)) {
+        Some(Kind::Google) => &Google,
+        Some(Kind::Quad9) => &Quad9,
+        Some(Kind::Auto) | None => &Auto,
+    }
+}
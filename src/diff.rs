@@ -0,0 +1,257 @@
+//! `doh diff`: what two servers answered for the same name, or what the servers answer now
+//! against a run saved earlier, such as before a resolver migration: the records only one side
+//! has, and the ones both do.
+use std::path::Path;
+
+use futures::future;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tabled::builder::Builder;
+use tabled::object::Rows;
+use tabled::{Alignment, Modify, Style};
+use tracing::{info, warn};
+
+use crate::config::Server;
+use crate::output::{self, Output};
+use crate::{exchange, utc_now, wire, Transport};
+
+/// Record type of the DNSSEC signatures, which differ between any two servers.
+const RRSIG: u32 = 46;
+
+/// The answers of a run, as `--save` writes them and `--against` reads them back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub host: String,
+    /// When the run was, in UTC.
+    pub taken: String,
+    pub records: Vec<Saved>,
+}
+
+/// One record a server answered.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Saved {
+    pub server: String,
+    #[serde(rename = "type")]
+    pub r#type: u32,
+    pub data: String,
+    pub ttl: u32,
+}
+
+/// Which side of the comparison has a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Both,
+    Left,
+    Right,
+}
+
+/// Machine-readable result of one record.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Difference {
+    #[serde(rename = "type")]
+    pub r#type: u32,
+    pub data: String,
+    pub side: Side,
+    /// The first `--server`, or `saved` with `--against`.
+    pub left: String,
+    /// The second `--server`, or `now` with `--against`.
+    pub right: String,
+    /// The TTL the left side gave the record, absent when it didn't return it.
+    pub left_ttl: Option<u32>,
+    pub right_ttl: Option<u32>,
+}
+
+/// Every record `servers` answered for `host` to queries of `rtypes`; a server that fails fails
+/// the whole run, as half an answer can't be compared.
+async fn answers(
+    host: &str,
+    servers: &[Server],
+    rtypes: &[String],
+    transport: &Transport,
+) -> Result<Vec<Saved>, Box<dyn std::error::Error>> {
+    let lookups = future::join_all(servers.iter().flat_map(|server| {
+        rtypes.iter().map(move |rtype| async move {
+            let lookup = exchange(host, &server.url, rtype, transport).await;
+            (server, rtype, lookup)
+        })
+    }))
+    .await;
+    let mut records: Vec<Saved> = vec![];
+    for (server, rtype, lookup) in lookups {
+        let lookup =
+            lookup.map_err(|e| format!("{} error, {} {}: {}", server.tag, host, rtype, e))?;
+        for answer in lookup.answers {
+            let known = records.iter().any(|r| {
+                (r.server.as_str(), r.r#type, r.data.as_str())
+                    == (server.tag.as_str(), answer.r#type, answer.data.as_str())
+            });
+            if answer.r#type != RRSIG && !known {
+                records.push(Saved {
+                    server: server.tag.clone(),
+                    r#type: answer.r#type,
+                    data: answer.data,
+                    ttl: answer.TTL,
+                });
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// The records of `left` and `right`, the ones both have first, then the ones only either has,
+/// each in the order they were answered.
+fn compare(left: &[&Saved], right: &[&Saved], names: (&str, &str)) -> Vec<Difference> {
+    let mut differences: Vec<Difference> = vec![];
+    for (record, is_left) in left
+        .iter()
+        .map(|r| (r, true))
+        .chain(right.iter().map(|r| (r, false)))
+    {
+        let known = differences
+            .iter_mut()
+            .find(|d| d.r#type == record.r#type && d.data == record.data);
+        let difference = match known {
+            Some(difference) => difference,
+            None => {
+                differences.push(Difference {
+                    r#type: record.r#type,
+                    data: record.data.clone(),
+                    side: if is_left { Side::Left } else { Side::Right },
+                    left: String::from(names.0),
+                    right: String::from(names.1),
+                    left_ttl: None,
+                    right_ttl: None,
+                });
+                differences.last_mut().unwrap()
+            }
+        };
+        let ttl = if is_left {
+            &mut difference.left_ttl
+        } else {
+            &mut difference.right_ttl
+        };
+        // the lowest when several servers of a side returned it
+        *ttl = Some(ttl.map_or(record.ttl, |t| t.min(record.ttl)));
+        if difference.left_ttl.is_some() && difference.right_ttl.is_some() {
+            difference.side = Side::Both;
+        }
+    }
+    differences.sort_by_key(|d| d.side as u8);
+    differences
+}
+
+/// Compare two servers, or the run with the one saved in `against`, saving it to `save`; true
+/// when both sides had the same records.
+pub async fn run(
+    host: &str,
+    rtypes: &[String],
+    save: Option<&Path>,
+    against: Option<&Path>,
+    servers: &[Server],
+    transport: &Transport,
+    output: &Output,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let saved = match against {
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+            let snapshot: Snapshot = serde_json::from_str(&text)
+                .map_err(|e| format!("{} is not a saved doh diff run: {}", path.display(), e))?;
+            if !snapshot.host.eq_ignore_ascii_case(host) {
+                warn!(
+                    "{} was saved for {}, not {}",
+                    path.display(),
+                    snapshot.host,
+                    host
+                );
+            }
+            Some(snapshot)
+        }
+        None if servers.len() == 2 || save.is_some() => None,
+        None => {
+            let count = servers.len();
+            let hint = "give --server twice, or --against a saved run";
+            return Err(format!("doh diff compares two servers, not {}: {}", count, hint).into());
+        }
+    };
+    let records = answers(host, servers, rtypes, transport).await?;
+    if let Some(path) = save {
+        let snapshot = Snapshot {
+            host: String::from(host),
+            taken: utc_now(),
+            records: records.clone(),
+        };
+        std::fs::write(path, serde_json::to_string_pretty(&snapshot)? + "\n")
+            .map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+        info!("saved {} records to {}", records.len(), path.display());
+        if saved.is_none() && servers.len() != 2 {
+            return Ok(true);
+        }
+    }
+
+    let (differences, names) = match &saved {
+        Some(snapshot) => {
+            let before: Vec<&Saved> = snapshot.records.iter().collect();
+            let now: Vec<&Saved> = records.iter().collect();
+            (compare(&before, &now, ("saved", "now")), ("saved", "now"))
+        }
+        None => {
+            let (a, b) = (servers[0].tag.as_str(), servers[1].tag.as_str());
+            let of = |tag: &str| {
+                records
+                    .iter()
+                    .filter(|r| r.server == tag)
+                    .collect::<Vec<_>>()
+            };
+            (compare(&of(a), &of(b), (a, b)), (a, b))
+        }
+    };
+    let same = differences.iter().all(|d| d.side == Side::Both);
+    if !output.is_table() {
+        output.json(&differences)?;
+        return Ok(same);
+    }
+
+    let left = match &saved {
+        Some(snapshot) => format!("saved {}", snapshot.taken),
+        None => String::from(names.0),
+    };
+    let mut builder = Builder::default();
+    builder.set_columns([
+        String::from("Type"),
+        String::from("Data"),
+        left,
+        String::from(names.1),
+    ]);
+    let mut rows: Vec<&Difference> = differences.iter().collect();
+    let hidden = output.truncate(&mut rows);
+    for d in rows {
+        let ttl = |ttl: Option<u32>| match ttl {
+            Some(ttl) => format!("TTL {}", ttl),
+            None => output.paint.status(false, String::from("missing")),
+        };
+        builder.add_record([
+            wire::type_name(d.r#type),
+            d.data.clone(),
+            ttl(d.left_ttl),
+            ttl(d.right_ttl),
+        ]);
+    }
+    let mut table = builder.build();
+    table
+        .with(Style::modern())
+        .with(Modify::new(Rows::first()).with(Alignment::center()));
+    output::fit(&mut table, &[]);
+    output.print(&table.to_string(), hidden);
+    let count = |side: Side| differences.iter().filter(|d| d.side == side).count();
+    println!(
+        "{} in common, {} only in {}, {} only in {}",
+        count(Side::Both),
+        count(Side::Left),
+        names.0,
+        count(Side::Right),
+        names.1
+    );
+    Ok(same)
+}
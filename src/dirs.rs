@@ -0,0 +1,53 @@
+//! Platform-specific locations (XDG on Linux, `Library` on macOS, `AppData` on Windows).
+use std::path::PathBuf;
+
+use directories::ProjectDirs;
+
+fn project() -> Option<ProjectDirs> {
+    ProjectDirs::from("", "", "doh")
+}
+
+/// The config file inside the platform config directory, e.g. `~/.config/doh/config.toml`.
+pub fn config_file() -> Option<PathBuf> {
+    project().map(|dirs| dirs.config_dir().join("config.toml"))
+}
+
+/// The provider quirks database fetched by `doh servers refresh`, in the platform data directory.
+pub fn quirks_file() -> Option<PathBuf> {
+    project().map(|dirs| dirs.data_dir().join("quirks.toml"))
+}
+
+/// Server scores learned across runs, in the platform data directory.
+pub fn ranking_file() -> Option<PathBuf> {
+    project().map(|dirs| dirs.data_dir().join("ranking.json"))
+}
+
+/// Results appended by `--record` and read by `doh history`, in the platform data directory.
+pub fn history_file() -> Option<PathBuf> {
+    project().map(|dirs| dirs.data_dir().join("history.db"))
+}
+
+/// Where `--geo` looks for MaxMind databases, `*.mmdb`, in the platform data directory.
+pub fn geo_dir() -> Option<PathBuf> {
+    project().map(|dirs| dirs.data_dir().join("geoip"))
+}
+
+/// Cached DNS answers of `--cache-file`, in the platform cache directory.
+pub fn dns_cache() -> Option<PathBuf> {
+    project().map(|dirs| dirs.cache_dir().join("cache.json"))
+}
+
+/// Cached online lookups of `--geo`, in the platform cache directory.
+pub fn geo_cache() -> Option<PathBuf> {
+    project().map(|dirs| dirs.cache_dir().join("geo.json"))
+}
+
+/// Where `doh daemon --install --user` writes its units, e.g. `~/.config/systemd/user`.
+pub fn systemd_user_dir() -> Option<PathBuf> {
+    directories::BaseDirs::new().map(|dirs| dirs.config_dir().join("systemd").join("user"))
+}
+
+/// Cached RDAP lookups of `--rdap`, in the platform cache directory.
+pub fn rdap_cache() -> Option<PathBuf> {
+    project().map(|dirs| dirs.cache_dir().join("rdap.json"))
+}
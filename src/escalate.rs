@@ -0,0 +1,73 @@
+//! Re-probe addresses that lost every ping over TCP and TLS, so a host that drops ICMP isn't
+//! reported the same way as a host that is down.
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_native_tls::{native_tls, TlsConnector};
+
+use crate::source::Source;
+
+/// Ports tried for the TCP probe, the first one also for TLS.
+pub const PORTS: [u16; 2] = [443, 80];
+
+/// The highest layer that answered an address without ICMP replies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Layer {
+    /// A TLS handshake on port 443 completed.
+    Tls,
+    /// A TCP connection was accepted on port 443 or 80, but no TLS handshake.
+    Tcp,
+    /// Nothing answered: the host is likely down or unreachable.
+    None,
+}
+
+impl Layer {
+    pub fn label(self) -> &'static str {
+        match self {
+            Layer::Tls => "TLS ok",
+            Layer::Tcp => "TCP ok",
+            Layer::None => "down",
+        }
+    }
+}
+
+/// Whether a TLS handshake completes on `stream`; the certificate isn't checked,
+/// any server speaking TLS counts.
+async fn handshake(stream: TcpStream, server_name: &str) -> bool {
+    let Ok(connector) = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .danger_accept_invalid_hostnames(true)
+        .build()
+    else {
+        return false;
+    };
+    TlsConnector::from(connector)
+        .connect(server_name, stream)
+        .await
+        .is_ok()
+}
+
+/// Probe `addr` from `source` over TCP, then TLS with `server_name` as SNI, each attempt bounded
+/// by `timeout`.
+pub async fn probe(addr: IpAddr, server_name: &str, timeout: Duration, source: &Source) -> Layer {
+    for port in PORTS {
+        let connect = source.connect(SocketAddr::new(addr, port));
+        let Ok(Ok(stream)) = time::timeout(timeout, connect).await else {
+            continue;
+        };
+        if port == PORTS[0]
+            && time::timeout(timeout, handshake(stream, server_name))
+                .await
+                .unwrap_or(false)
+        {
+            return Layer::Tls;
+        }
+        return Layer::Tcp;
+    }
+    Layer::None
+}
@@ -0,0 +1,169 @@
+//! `doh exporter`: resolve and probe a few names on an interval and serve the latest results as
+//! OpenMetrics on `/metrics`, for Prometheus to scrape.
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, MissedTickBehavior};
+use tracing::info;
+
+use crate::config::{ProbeSettings, Server};
+use crate::systemd::Watchdog;
+use crate::{gather, interrupt, metrics, systemd, webhook, Entry, Probes, Resolved, Transport};
+
+/// Longest a scraper may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest request head read; the rest is ignored.
+const MAX_REQUEST: usize = 8192;
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Resolve and probe every one of `hosts` on every server, as OpenMetrics text.
+async fn scrape(
+    hosts: &[String],
+    servers: &[Server],
+    probe: &ProbeSettings,
+    transport: &Transport,
+) -> String {
+    // pinged afresh every time, but only once per address within a scrape
+    let probes = &Probes::default();
+    let many = hosts.len() > 1;
+    let resolved: Vec<Resolved> = future::join_all(hosts.iter().map(|host| async move {
+        let bodies = gather(
+            host,
+            servers,
+            &["A", "AAAA"],
+            Some(probe),
+            probes,
+            transport,
+            1,
+        )
+        .await;
+        Resolved::new(host, bodies, many)
+    }))
+    .await;
+    let entries: Vec<Entry> = resolved.iter().flat_map(Resolved::entries).collect();
+    webhook::post(&resolved).await;
+    metrics::query(&entries, &resolved)
+}
+
+/// Answer one HTTP request with the latest `metrics`.
+async fn serve(mut stream: TcpStream, metrics: Arc<Mutex<String>>) {
+    let mut head = vec![0; MAX_REQUEST];
+    let mut len = 0;
+    while len < head.len() && !head[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+        match time::timeout(REQUEST_TIMEOUT, stream.read(&mut head[len..])).await {
+            Ok(Ok(0)) | Ok(Err(_)) | Err(_) => return,
+            Ok(Ok(n)) => len += n,
+        }
+    }
+    let head = String::from_utf8_lossy(&head[..len]);
+    let mut request = head.split_whitespace();
+    let (method, path) = (request.next(), request.next().map(|p| p.split('?').next()));
+    let (status, content_type, body) = match (method, path.flatten()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", CONTENT_TYPE, metrics.lock().unwrap().clone())
+        }
+        (Some("GET"), Some("/")) => (
+            "200 OK",
+            "text/plain; charset=utf-8",
+            String::from("doh exporter, metrics are on /metrics\n"),
+        ),
+        (Some("GET"), _) => (
+            "404 Not Found",
+            "text/plain; charset=utf-8",
+            String::from("not found\n"),
+        ),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain; charset=utf-8",
+            String::from("only GET is supported\n"),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+/// Serve metrics on `listen`, refreshed every `every` until Ctrl+C.
+pub async fn run(
+    listen: SocketAddr,
+    hosts: &[String],
+    every: Duration,
+    servers: &[Server],
+    probe: &ProbeSettings,
+    transport: &Transport,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // the socket of a systemd socket unit stands in for --listen
+    let listener = match systemd::activated().tcp {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)?
+        }
+        None => TcpListener::bind(listen)
+            .await
+            .map_err(|e| format!("cannot listen on {}: {}", listen, e))?,
+    };
+    info!(
+        "serving metrics on http://{}/metrics, refreshed every {:?}",
+        listener.local_addr()?,
+        every
+    );
+    // nothing to report until the first round is done
+    let metrics = Arc::new(Mutex::new(String::from("# EOF\n")));
+    let mut watchdog = Watchdog::from_env();
+    let server = {
+        let metrics = metrics.clone();
+        let mut heartbeat = watchdog.heartbeat();
+        tokio::spawn(async move {
+            loop {
+                heartbeat.beat();
+                let accepted = tokio::select! {
+                    accepted = listener.accept() => accepted,
+                    _ = heartbeat.due() => continue,
+                };
+                if let Ok((stream, _)) = accepted {
+                    tokio::spawn(serve(stream, metrics.clone()));
+                }
+            }
+        })
+    };
+
+    let mut heartbeat = watchdog.heartbeat();
+    let watchdog = tokio::spawn(watchdog.watch());
+    systemd::ready();
+    let mut ticker = time::interval(every);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        heartbeat.beat();
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = heartbeat.due() => continue,
+            _ = interrupt::token().cancelled() => break,
+        }
+        // a round may outlast the watchdog's, which only needs to hear that it goes on
+        let scraping = scrape(hosts, servers, probe, transport);
+        tokio::pin!(scraping);
+        let text = loop {
+            tokio::select! {
+                text = &mut scraping => break text,
+                _ = heartbeat.due() => heartbeat.beat(),
+            }
+        };
+        if interrupt::interrupted() {
+            break;
+        }
+        *metrics.lock().unwrap() = text;
+    }
+    systemd::stopping();
+    watchdog.abort();
+    server.abort();
+    Ok(())
+}
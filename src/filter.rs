@@ -0,0 +1,247 @@
+//! `doh filter-test`: query known ad/malware/adult test domains on every server and report which
+//! categories each resolver blocks, and how.
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use futures::future;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tabled::builder::Builder;
+use tabled::object::Rows;
+use tabled::{Alignment, Modify, Style};
+use tracing::{error, warn};
+
+use crate::config::Server;
+use crate::output::{self, Output};
+use crate::{exchange, read_input, Transport};
+
+/// Category of domains every resolver should answer, to tell filtering from breakage.
+const CONTROL: &str = "control";
+
+/// Test domains used without `--list`, as `(category, domain)`.
+const DEFAULT_DOMAINS: &[(&str, &str)] = &[
+    ("ads", "doubleclick.net"),
+    ("ads", "pagead2.googlesyndication.com"),
+    ("malware", "malware.testcategory.com"),
+    ("phishing", "phishing.testcategory.com"),
+    ("adult", "nudity.testcategory.com"),
+    ("adult", "pornhub.com"),
+    (CONTROL, "example.com"),
+];
+
+/// How a server answered a test domain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Resolves,
+    /// Blocked by claiming the name doesn't exist.
+    Nxdomain,
+    /// Blocked by refusing to answer.
+    Refused,
+    /// Blocked by answering 0.0.0.0, `::` or a loopback address.
+    NullAddress,
+    /// Blocked by an empty answer.
+    NoData,
+    /// Rewritten to an address the server also returns for other blocked categories.
+    BlockPage,
+    /// The query failed.
+    Error,
+}
+
+impl Verdict {
+    fn blocked(self) -> bool {
+        !matches!(self, Verdict::Resolves | Verdict::Error)
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Verdict::Resolves => "resolves",
+            Verdict::Nxdomain => "NXDOMAIN",
+            Verdict::Refused => "REFUSED",
+            Verdict::NullAddress => "0.0.0.0",
+            Verdict::NoData => "no data",
+            Verdict::BlockPage => "block page",
+            Verdict::Error => "error",
+        }
+    }
+}
+
+/// Machine-readable result of one test domain on one server.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Check {
+    pub category: String,
+    pub domain: String,
+    pub server: String,
+    pub verdict: Verdict,
+    pub addresses: Vec<IpAddr>,
+}
+
+/// Parse `category domain` lines, skipping blanks and `#` comments.
+fn parse(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut domains = vec![];
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [category, domain] => domains.push((String::from(category), String::from(domain))),
+            _ => {
+                return Err(format!(
+                    "line {}: expected `category domain`, got `{}`",
+                    idx + 1,
+                    line
+                ))
+            }
+        }
+    }
+    Ok(domains)
+}
+
+fn is_null(ip: &IpAddr) -> bool {
+    ip.is_unspecified() || ip.is_loopback()
+}
+
+/// Flag answers rewritten to a block page: the same address returned by one server for domains
+/// of different categories, and by no other server for those domains.
+fn mark_block_pages(checks: &mut [Check]) {
+    let mut categories: BTreeMap<(&str, IpAddr), Vec<&str>> = BTreeMap::new();
+    for check in checks.iter().filter(|c| c.verdict == Verdict::Resolves) {
+        for ip in &check.addresses {
+            let seen = categories.entry((&check.server, *ip)).or_default();
+            if !seen.contains(&check.category.as_str()) {
+                seen.push(&check.category);
+            }
+        }
+    }
+    let pages: Vec<(String, IpAddr)> = categories
+        .into_iter()
+        .filter(|(_, categories)| categories.len() > 1)
+        .filter(|((server, ip), _)| {
+            !checks
+                .iter()
+                .any(|c| c.server != *server && c.addresses.contains(ip))
+        })
+        .map(|((server, ip), _)| (String::from(server), ip))
+        .collect();
+    for check in checks.iter_mut() {
+        let rewritten = pages
+            .iter()
+            .any(|(server, ip)| *server == check.server && check.addresses.contains(ip));
+        if check.verdict == Verdict::Resolves && check.category != CONTROL && rewritten {
+            check.verdict = Verdict::BlockPage;
+        }
+    }
+}
+
+pub async fn run(
+    list: Option<&str>,
+    servers: &[Server],
+    transport: &Transport,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let domains = match list {
+        Some(path) => parse(&read_input(path)?)?,
+        None => DEFAULT_DOMAINS
+            .iter()
+            .map(|(category, domain)| (String::from(*category), String::from(*domain)))
+            .collect(),
+    };
+
+    let mut checks = future::join_all(domains.iter().flat_map(|(category, domain)| {
+        servers.iter().map(move |server| async move {
+            let (verdict, addresses) = match exchange(domain, &server.url, "A", transport).await {
+                Ok(lookup) => {
+                    let addresses: Vec<IpAddr> = lookup
+                        .answers
+                        .iter()
+                        .filter(|a| a.r#type == 1)
+                        .filter_map(|a| a.data.parse().ok())
+                        .collect();
+                    let verdict = match lookup.status {
+                        3 => Verdict::Nxdomain,
+                        5 => Verdict::Refused,
+                        _ if addresses.is_empty() => Verdict::NoData,
+                        _ if addresses.iter().all(is_null) => Verdict::NullAddress,
+                        _ => Verdict::Resolves,
+                    };
+                    (verdict, addresses)
+                }
+                Err(e) => {
+                    error!("{} error: {}: {}", server.tag, domain, e);
+                    (Verdict::Error, vec![])
+                }
+            };
+            Check {
+                category: category.clone(),
+                domain: domain.clone(),
+                server: server.tag.clone(),
+                verdict,
+                addresses,
+            }
+        })
+    }))
+    .await;
+    mark_block_pages(&mut checks);
+
+    for check in &checks {
+        if check.category == CONTROL && check.verdict.blocked() {
+            warn!(
+                "{} blocks the control domain {}, its other results are unreliable",
+                check.server, check.domain
+            );
+        }
+    }
+    if !output.is_table() {
+        return output.json(&checks);
+    }
+
+    let mut builder = Builder::default();
+    let mut columns: Vec<String> = vec!["Category".into(), "Domain".into()];
+    columns.extend(servers.iter().map(|s| s.tag.clone()));
+    builder.set_columns(columns);
+    let mut rows: Vec<&[Check]> = checks.chunks(servers.len().max(1)).collect();
+    let hidden = output.truncate(&mut rows);
+    for row in rows {
+        let mut record = vec![row[0].category.clone(), row[0].domain.clone()];
+        record.extend(row.iter().map(|c| {
+            let text = String::from(c.verdict.label());
+            if c.verdict.blocked() {
+                output.paint.status(false, text)
+            } else {
+                text
+            }
+        }));
+        builder.add_record(record);
+    }
+    let mut table = builder.build();
+    table
+        .with(Style::modern())
+        .with(Modify::new(Rows::first()).with(Alignment::center()));
+    output::fit(&mut table, &[]);
+    output.print(&table.to_string(), hidden);
+
+    let mut categories: Vec<&str> = vec![];
+    for (category, _) in &domains {
+        if category != CONTROL && !categories.contains(&category.as_str()) {
+            categories.push(category);
+        }
+    }
+    for server in servers {
+        let blocks: Vec<String> = categories
+            .iter()
+            .map(|category| {
+                let of = |c: &&Check| c.server == server.tag && c.category == *category;
+                let total = checks.iter().filter(of).count();
+                let blocked = checks
+                    .iter()
+                    .filter(of)
+                    .filter(|c| c.verdict.blocked())
+                    .count();
+                format!("{} {}/{}", category, blocked, total)
+            })
+            .collect();
+        println!("{} blocks: {}", server.tag, blocks.join(", "));
+    }
+    Ok(())
+}
@@ -0,0 +1,496 @@
+//! `--geo`: the country, city and network (ASN) of each answer from local MaxMind databases, such
+//! as the free GeoLite2 City and ASN ones, to see which PoP or provider an address belongs to.
+//! Without any, the addresses are looked up at an online IP-info API instead, cached on disk.
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use maxminddb::Reader;
+use reqwest::StatusCode;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{error, info, warn};
+
+use crate::{dirs, Transport};
+
+/// Default of `--geo-api`, the free ipinfo.io one; `{ip}` stands for the address.
+pub const API: &str = "https://ipinfo.io/{ip}/json";
+/// Parse a `--geo-api` value: an http:// or https:// URL with `{ip}` in it, or `none`.
+pub fn parse_api(value: &str) -> Result<String, String> {
+    let url = reqwest::Url::parse(value).ok();
+    if value == "none"
+        || url.is_some_and(|url| matches!(url.scheme(), "http" | "https") && value.contains("{ip}"))
+    {
+        Ok(String::from(value))
+    } else {
+        Err(format!(
+            "expected an http:// or https:// URL with {{ip}} in it, or none, got `{}`",
+            value
+        ))
+    }
+}
+
+/// Locations rarely change; refetch a week-old entry.
+const MAX_AGE: Duration = Duration::from_secs(7 * 24 * 3600);
+/// Time between two online lookups, which free APIs only allow a few of a second.
+const SPACING: Duration = Duration::from_millis(250);
+
+/// Where an address is, as far as the databases know.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct Geo {
+    /// ISO 3166 code, e.g. `US`.
+    pub country: Option<String>,
+    pub city: Option<String>,
+    pub asn: Option<u32>,
+    /// Holder of the autonomous system, e.g. `CLOUDFLARENET`.
+    pub organization: Option<String>,
+}
+
+impl Geo {
+    /// The country as its flag emoji and code, e.g. `🇺🇸 US`, for `--geo-flags`.
+    pub fn flag_label(&self) -> Option<String> {
+        let code = self.country.as_deref()?;
+        if code.len() != 2 || !code.bytes().all(|b| b.is_ascii_alphabetic()) {
+            return None;
+        }
+        let code = code.to_ascii_uppercase();
+        // a flag is the pair of regional indicator symbols spelling its code
+        let flag: String = code
+            .bytes()
+            .filter_map(|b| char::from_u32(0x1F1E6 + u32::from(b - b'A')))
+            .collect();
+        Some(format!("{} {}", flag, code))
+    }
+
+    /// `AS13335 CLOUDFLARENET`, or `-` when unknown.
+    pub fn asn_label(&self) -> String {
+        match (self.asn, &self.organization) {
+            (Some(asn), Some(org)) => format!("AS{} {}", asn, org),
+            (Some(asn), None) => format!("AS{}", asn),
+            (None, Some(org)) => org.clone(),
+            (None, None) => String::from("-"),
+        }
+    }
+}
+
+/// The fields read from a record of a City, Country or ASN database, each holding some of them.
+#[derive(Deserialize)]
+struct Record<'a> {
+    #[serde(borrow)]
+    country: Option<Country<'a>>,
+    city: Option<City<'a>>,
+    autonomous_system_number: Option<u32>,
+    autonomous_system_organization: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct Country<'a> {
+    iso_code: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct City<'a> {
+    #[serde(borrow)]
+    names: Option<BTreeMap<&'a str, &'a str>>,
+}
+
+/// Every `.mmdb` file in `dir`.
+fn found(dir: &Path) -> Vec<PathBuf> {
+    let mut found: Vec<PathBuf> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "mmdb"))
+        .collect();
+    found.sort();
+    found
+}
+
+/// Where each of `addrs` is: from the databases given with `--geo-db`, else from those in the
+/// platform data directory, else from `api` unless it is `none`.
+pub async fn lookup(
+    addrs: &[IpAddr],
+    given: &[PathBuf],
+    api: &str,
+    transport: &Transport,
+) -> Result<BTreeMap<IpAddr, Geo>, String> {
+    if !given.is_empty() {
+        return local(addrs, given.to_vec());
+    }
+    let dir = dirs::geo_dir().ok_or("no platform data directory for the GeoIP databases")?;
+    let databases = found(&dir);
+    if !databases.is_empty() {
+        return local(addrs, databases);
+    }
+    if api == "none" {
+        return Err(format!(
+            "no GeoIP database in {}, download GeoLite2 City and ASN there or pass --geo-db",
+            dir.display()
+        ));
+    }
+    info!(
+        "no GeoIP database in {}, looking the addresses up at {}",
+        dir.display(),
+        reqwest::Url::parse(api)
+            .ok()
+            .and_then(|url| url.host_str().map(String::from))
+            .unwrap_or_else(|| String::from(api))
+    );
+    Ok(online(addrs, api, transport).await)
+}
+
+/// Where each of `addrs` is, from every database that knows something about it; a City or
+/// Country database gives the location and an ASN one the network.
+fn local(addrs: &[IpAddr], paths: Vec<PathBuf>) -> Result<BTreeMap<IpAddr, Geo>, String> {
+    let databases = paths
+        .into_iter()
+        .map(|path| {
+            let reader = Reader::open_readfile(&path)
+                .map_err(|e| format!("{} is not a MaxMind database: {}", path.display(), e))?;
+            Ok((path, reader))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let mut found = BTreeMap::new();
+    for addr in addrs {
+        let mut geo = Geo::default();
+        for (path, database) in &databases {
+            let record = database
+                .lookup(*addr)
+                .and_then(|result| result.decode::<Record>())
+                .map_err(|e| format!("cannot look up {} in {}: {}", addr, path.display(), e))?;
+            let Some(record) = record else {
+                continue;
+            };
+            geo.country = geo.country.or_else(|| {
+                let country = record.country.as_ref()?;
+                country.iso_code.map(String::from)
+            });
+            geo.city = geo.city.or_else(|| {
+                let names = record.city.as_ref()?.names.as_ref()?;
+                names.get("en").map(|name| String::from(*name))
+            });
+            geo.asn = geo.asn.or(record.autonomous_system_number);
+            geo.organization = geo
+                .organization
+                .or_else(|| record.autonomous_system_organization.map(String::from));
+        }
+        if geo.country.is_some() || geo.city.is_some() || geo.asn.is_some() {
+            found.insert(*addr, geo);
+        }
+    }
+    Ok(found)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Cached {
+    geo: Geo,
+    /// Seconds since the Unix epoch.
+    fetched: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn load() -> BTreeMap<IpAddr, Cached> {
+    dirs::geo_cache()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+fn save(cache: &BTreeMap<IpAddr, Cached>) -> Result<(), Box<dyn std::error::Error>> {
+    let path = dirs::geo_cache().ok_or("cannot determine the platform cache directory")?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_string(cache)?)?;
+    Ok(())
+}
+
+/// An ipinfo.io answer: `{"country": "US", "city": "...", "org": "AS15169 Google LLC"}`.
+fn parse(info: &Value) -> Geo {
+    let field = |name: &str| {
+        info.get(name)
+            .and_then(Value::as_str)
+            .filter(|value| !value.is_empty())
+    };
+    let org = field("org");
+    let (asn, organization) = match org.and_then(|org| org.split_once(' ')) {
+        Some((asn, name)) if asn.starts_with("AS") => (asn[2..].parse().ok(), Some(name)),
+        _ => (None, org),
+    };
+    Geo {
+        country: field("country").map(String::from),
+        city: field("city").map(String::from),
+        asn,
+        organization: organization.map(String::from),
+    }
+}
+
+enum Failed {
+    /// The API allows no more lookups for now.
+    Limited,
+    Other(Box<dyn std::error::Error>),
+}
+
+async fn fetch(addr: IpAddr, api: &str, transport: &Transport) -> Result<Geo, Failed> {
+    let url = api.replace("{ip}", &addr.to_string());
+    let response = transport
+        .client(&url)
+        .map_err(Failed::Other)?
+        .get(&url)
+        .header("Accept", "application/json")
+        .send()
+        .await
+        .map_err(|e| Failed::Other(e.into()))?;
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        return Err(Failed::Limited);
+    }
+    let info: Value = response
+        .error_for_status()
+        .map_err(|e| Failed::Other(e.into()))?
+        .json()
+        .await
+        .map_err(|e| Failed::Other(e.into()))?;
+    Ok(parse(&info))
+}
+
+/// Where each of `addrs` is according to `api`, from the cache when fresh enough. Lookups go one
+/// at a time, [`SPACING`] apart, and stop for the run once the API says there were too many;
+/// failed ones are reported and fall back to the cached entry, if any.
+async fn online(addrs: &[IpAddr], api: &str, transport: &Transport) -> BTreeMap<IpAddr, Geo> {
+    let mut cache = load();
+    let stale: Vec<IpAddr> = addrs
+        .iter()
+        .filter(|addr| {
+            cache
+                .get(addr)
+                .is_none_or(|c| now().saturating_sub(c.fetched) > MAX_AGE.as_secs())
+        })
+        .copied()
+        .collect();
+    let mut updated = false;
+    let mut pace = time::interval(SPACING);
+    pace.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    for addr in stale {
+        pace.tick().await;
+        match fetch(addr, api, transport).await {
+            Ok(geo) => {
+                cache.insert(
+                    addr,
+                    Cached {
+                        geo,
+                        fetched: now(),
+                    },
+                );
+                updated = true;
+            }
+            Err(Failed::Limited) => {
+                warn!("the GeoIP API is rate-limiting the lookups, skipping the other addresses");
+                break;
+            }
+            Err(Failed::Other(e)) => error!("geo error: {}: {}", addr, e),
+        }
+    }
+    if updated {
+        if let Err(e) = save(&cache) {
+            warn!("cannot save the GeoIP cache: {}", e);
+        }
+    }
+    addrs
+        .iter()
+        .filter_map(|addr| Some((*addr, cache.get(addr)?.geo.clone())))
+        .filter(|(_, geo)| geo.country.is_some() || geo.city.is_some() || geo.asn.is_some())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn string(text: &str) -> Vec<u8> {
+        // lengths from 29 take an extra byte
+        let mut bytes = match text.len() {
+            len @ 0..=28 => vec![0x40 | len as u8],
+            len => vec![0x40 | 29, (len - 29) as u8],
+        };
+        bytes.extend_from_slice(text.as_bytes());
+        bytes
+    }
+
+    fn uint(type_bits: u8, value: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![type_bits | value.len() as u8];
+        bytes.extend_from_slice(value);
+        bytes
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut bytes = vec![0xE0 | entries.len() as u8];
+        for (key, value) in entries {
+            bytes.extend(string(key));
+            bytes.extend_from_slice(value);
+        }
+        bytes
+    }
+
+    /// An IPv4 database in `dir` holding `record` for 127.0.0.0/8 only.
+    fn database(dir: &Path, name: &str, record: Vec<u8>) -> PathBuf {
+        // one node per bit of 127, the last one pointing at the record, the others at nothing
+        let nodes = 8u32;
+        let mut tree = vec![];
+        for (idx, bit) in format!("{:08b}", 127).bytes().enumerate() {
+            let next = if idx as u32 + 1 < nodes {
+                idx as u32 + 1
+            } else {
+                nodes + 16
+            };
+            let (left, right) = if bit == b'1' {
+                (nodes, next)
+            } else {
+                (next, nodes)
+            };
+            tree.extend_from_slice(&left.to_be_bytes()[1..]);
+            tree.extend_from_slice(&right.to_be_bytes()[1..]);
+        }
+        let metadata = map(&[
+            ("node_count", uint(0xC0, &nodes.to_be_bytes())),
+            ("record_size", uint(0xA0, &[24])),
+            ("ip_version", uint(0xA0, &[4])),
+            ("database_type", string("Test")),
+            ("binary_format_major_version", uint(0xA0, &[2])),
+            ("binary_format_minor_version", uint(0xA0, &[0])),
+            ("build_epoch", vec![0x01, 0x02, 0x01]),
+            ("description", map(&[("en", string("test"))])),
+            ("languages", [vec![0x01, 0x04], string("en")].concat()),
+        ]);
+        let mut bytes = tree;
+        bytes.extend_from_slice(&[0; 16]);
+        bytes.extend(record);
+        bytes.extend_from_slice(b"\xAB\xCD\xEFMaxMind.com");
+        bytes.extend(metadata);
+        write(dir, name, &bytes)
+    }
+
+    fn write(dir: &Path, name: &str, bytes: &[u8]) -> PathBuf {
+        let path = dir.join(format!("{}.mmdb", name));
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn city(dir: &Path) -> PathBuf {
+        database(
+            dir,
+            "city",
+            map(&[
+                (
+                    "city",
+                    map(&[("names", map(&[("en", string("Testville"))]))]),
+                ),
+                ("country", map(&[("iso_code", string("ZZ"))])),
+            ]),
+        )
+    }
+
+    fn asn(dir: &Path) -> PathBuf {
+        database(
+            dir,
+            "asn",
+            map(&[
+                (
+                    "autonomous_system_number",
+                    uint(0xC0, &64500u32.to_be_bytes()[1..]),
+                ),
+                ("autonomous_system_organization", string("TESTNET")),
+            ]),
+        )
+    }
+
+    #[test]
+    fn merges_the_databases() {
+        let (inside, outside) = (
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let found = local(&[inside, outside], vec![city(dir.path()), asn(dir.path())]).unwrap();
+        assert_eq!(found.len(), 1);
+        let geo = &found[&inside];
+        assert_eq!(geo.country.as_deref(), Some("ZZ"));
+        assert_eq!(geo.city.as_deref(), Some("Testville"));
+        assert_eq!(geo.asn_label(), "AS64500 TESTNET");
+    }
+
+    #[test]
+    fn spells_flags() {
+        let geo = |country: &str| Geo {
+            country: Some(String::from(country)),
+            ..Geo::default()
+        };
+        assert_eq!(geo("DE").flag_label().as_deref(), Some("🇩🇪 DE"));
+        assert_eq!(geo("us").flag_label().as_deref(), Some("🇺🇸 US"));
+        assert_eq!(geo("EU1").flag_label(), None);
+        assert_eq!(Geo::default().flag_label(), None);
+    }
+
+    #[test]
+    fn reads_ip_info() {
+        let geo = parse(&serde_json::json!({
+            "ip": "8.8.8.8",
+            "city": "Mountain View",
+            "country": "US",
+            "org": "AS15169 Google LLC",
+        }));
+        assert_eq!(geo.country.as_deref(), Some("US"));
+        assert_eq!(geo.city.as_deref(), Some("Mountain View"));
+        assert_eq!(geo.asn_label(), "AS15169 Google LLC");
+        let geo = parse(&serde_json::json!({ "bogon": true, "org": "" }));
+        assert_eq!(
+            (geo.country.as_deref(), geo.asn_label().as_str()),
+            (None, "-")
+        );
+        assert_eq!(
+            parse(&serde_json::json!({ "org": "Example" })).asn_label(),
+            "Example"
+        );
+    }
+
+    #[test]
+    fn checks_the_api() {
+        assert!(parse_api("none").is_ok());
+        assert!(parse_api("https://geo.example/{ip}").is_ok());
+        assert!(parse_api("https://geo.example/").is_err());
+        assert!(parse_api("ftp://geo.example/{ip}").is_err());
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let garbage = write(dir.path(), "garbage", b"not a database at all");
+        let error = local(&[], vec![garbage.clone()]).err().unwrap();
+        assert!(error.starts_with(&format!("{} is not a MaxMind database", garbage.display())));
+        let missing = dir.path().join("missing.mmdb");
+        assert!(local(&[], vec![missing]).is_err());
+    }
+
+    #[test]
+    fn survives_truncated_databases() {
+        let dir = tempfile::tempdir().unwrap();
+        let bytes = std::fs::read(city(dir.path())).unwrap();
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let path = dir.path().join("truncated.mmdb");
+        for len in 0..bytes.len() {
+            std::fs::write(&path, &bytes[..len]).unwrap();
+            // an error, or at worst nothing found, but no panic
+            if let Ok(found) = local(&[addr], vec![path.clone()]) {
+                assert!(found.get(&addr).is_none_or(|geo| geo.country.is_some()));
+            }
+        }
+    }
+}
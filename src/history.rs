@@ -0,0 +1,219 @@
+//! `--record` and `doh history`: every run's answers and their latency, added to an SQLite
+//! database in the platform data directory, and read back as trends over time.
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use rusqlite::{params, Connection, OpenFlags};
+use schemars::JsonSchema;
+use serde::Serialize;
+use tabled::builder::Builder;
+use tabled::object::{Columns, Rows};
+use tabled::{Alignment, Modify, Style};
+
+use crate::output::{self, Output};
+use crate::{unix_now, utc, Resolved};
+
+/// One answer of one server in one run, or its failure.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Sample {
+    /// When the run was, in seconds since the Unix epoch.
+    pub time: u64,
+    pub host: String,
+    pub server: String,
+    #[serde(rename = "type")]
+    pub r#type: u32,
+    /// The address, or the data of another record; empty when the server gave no answer.
+    pub address: String,
+    /// Mean round-trip time, absent when no probe was answered or the answer wasn't probed.
+    pub avg_ms: Option<i32>,
+    /// Ratio of lost probes, from 0 to 1, absent when the answer wasn't probed.
+    pub loss: Option<f32>,
+    /// The DNS response code, absent when the query failed.
+    pub rcode: Option<u32>,
+    /// Why the server gave no answer.
+    pub error: Option<String>,
+}
+
+/// The table the samples are kept in, and the index `doh history` looks them up by.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS samples (
+        time INTEGER NOT NULL,
+        host TEXT NOT NULL,
+        server TEXT NOT NULL,
+        type INTEGER NOT NULL,
+        address TEXT NOT NULL,
+        avg_ms INTEGER,
+        loss REAL,
+        rcode INTEGER,
+        error TEXT
+    );
+    CREATE INDEX IF NOT EXISTS samples_by_host ON samples (host COLLATE NOCASE, time);
+";
+
+/// Add the results of this run to the database at `path`.
+pub fn record(path: &Path, resolved: &[Resolved]) -> Result<(), Box<dyn std::error::Error>> {
+    let time = unix_now();
+    let mut samples = vec![];
+    for resolved in resolved {
+        let rcode = |tag: &str| {
+            resolved
+                .statuses
+                .iter()
+                .find(|(t, _)| *t == tag)
+                .map(|(_, code)| *code)
+        };
+        let answers = resolved.rows.iter().map(|(tag, row)| {
+            let probed = row.probed && row.error.is_none();
+            Sample {
+                time,
+                host: String::from(resolved.host),
+                server: String::from(*tag),
+                r#type: row.answer.r#type,
+                address: row.answer.data.clone(),
+                avg_ms: (row.mean != -1).then_some(row.mean),
+                loss: probed.then_some(row.lost),
+                rcode: rcode(tag),
+                error: row.error.clone(),
+            }
+        });
+        let failed = resolved.errors.iter().map(|(tag, reason)| Sample {
+            time,
+            host: String::from(resolved.host),
+            server: String::from(*tag),
+            r#type: 0,
+            address: String::new(),
+            avg_ms: None,
+            loss: None,
+            rcode: rcode(tag),
+            error: Some(reason.clone()),
+        });
+        samples.extend(answers.chain(failed));
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let insert = || -> rusqlite::Result<()> {
+        let mut db = Connection::open(path)?;
+        db.execute_batch(SCHEMA)?;
+        // all of the run or none of it, even when it is killed halfway
+        let run = db.transaction()?;
+        {
+            let mut insert = run.prepare(
+                "INSERT INTO samples (time, host, server, type, address, avg_ms, loss, rcode, error)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?;
+            for s in &samples {
+                insert.execute(params![
+                    s.time, s.host, s.server, s.r#type, s.address, s.avg_ms, s.loss, s.rcode,
+                    s.error
+                ])?;
+            }
+        }
+        run.commit()
+    };
+    insert().map_err(|e| format!("cannot record the run in {}: {}", path.display(), e).into())
+}
+
+/// The samples of `host` in the database at `path`, oldest first.
+fn samples(host: &str, path: &Path) -> rusqlite::Result<Vec<Sample>> {
+    let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut select = db.prepare(
+        "SELECT time, host, server, type, address, avg_ms, loss, rcode, error FROM samples
+         WHERE host = ?1 COLLATE NOCASE ORDER BY time",
+    )?;
+    let rows = select.query_map([host], |row| {
+        Ok(Sample {
+            time: row.get(0)?,
+            host: row.get(1)?,
+            server: row.get(2)?,
+            r#type: row.get(3)?,
+            address: row.get(4)?,
+            avg_ms: row.get(5)?,
+            loss: row.get(6)?,
+            rcode: row.get(7)?,
+            error: row.get(8)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Mean of the `values` there are.
+fn mean<T: Into<f64>>(values: impl Iterator<Item = T>) -> Option<f64> {
+    let values: Vec<f64> = values.map(Into::into).collect();
+    (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+}
+
+/// Show the runs recorded in `path` for `host`, latest first: how many servers answered, the
+/// addresses, their latency and loss, and what changed since the run before.
+pub fn run(host: &str, path: &Path, output: &Output) -> Result<(), Box<dyn std::error::Error>> {
+    let samples = samples(host, path).map_err(|e| {
+        format!(
+            "cannot read {}: {} (record runs with --record)",
+            path.display(),
+            e
+        )
+    })?;
+    if samples.is_empty() {
+        return Err(format!("no run for {} recorded in {}", host, path.display()).into());
+    }
+    if !output.is_table() {
+        return output.json(&samples);
+    }
+
+    let times: BTreeSet<u64> = samples.iter().map(|s| s.time).collect();
+    let mut runs = vec![];
+    let mut previous: Option<BTreeSet<&str>> = None;
+    for time in times {
+        let run: Vec<&Sample> = samples.iter().filter(|s| s.time == time).collect();
+        let servers: BTreeSet<&str> = run.iter().map(|s| s.server.as_str()).collect();
+        let responding: BTreeSet<&str> = run
+            .iter()
+            .filter(|s| s.rcode.is_some())
+            .map(|s| s.server.as_str())
+            .collect();
+        let addresses: BTreeSet<&str> = run
+            .iter()
+            .filter(|s| !s.address.is_empty())
+            .map(|s| s.address.as_str())
+            .collect();
+        let changes = match &previous {
+            Some(before) => {
+                let added = addresses.difference(before).map(|a| format!("+{}", a));
+                let removed = before.difference(&addresses).map(|a| format!("-{}", a));
+                let changes: Vec<String> = added.chain(removed).collect();
+                if changes.is_empty() {
+                    String::from("-")
+                } else {
+                    changes.join(" ")
+                }
+            }
+            None => String::from("-"),
+        };
+        let avg = mean(run.iter().filter_map(|s| s.avg_ms));
+        let loss = mean(run.iter().filter_map(|s| s.loss));
+        runs.push([
+            utc(time),
+            format!("{}/{}", responding.len(), servers.len()),
+            addresses.iter().copied().collect::<Vec<_>>().join(", "),
+            avg.map_or_else(|| String::from("-"), |ms| format!("{:.0}ms", ms)),
+            loss.map_or_else(|| String::from("-"), |lost| format!("{:.0}%", lost * 100.0)),
+            changes,
+        ]);
+        previous = Some(addresses);
+    }
+    runs.reverse();
+    let hidden = output.truncate(&mut runs);
+    let mut builder = Builder::default();
+    builder.set_columns(["Time", "Servers", "Answers", "Avg", "Lost", "Changes"]);
+    for run in runs {
+        builder.add_record(run);
+    }
+    let mut table = builder.build();
+    table
+        .with(Style::modern())
+        .with(Modify::new(Rows::first()).with(Alignment::center()))
+        .with(Modify::new(Columns::new(3..5)).with(Alignment::right()));
+    output::fit(&mut table, &[]);
+    output.print(&table.to_string(), hidden);
+    Ok(())
+}
@@ -0,0 +1,194 @@
+//! `doh hosts`: pin names to their fastest address in the system hosts file, inside a block of
+//! its own so entries added by hand are left alone.
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+/// First and last line of the entries managed by `doh hosts --write`.
+const BEGIN: &str = "# BEGIN doh";
+const END: &str = "# END doh";
+
+/// The system hosts file.
+pub fn default_path() -> PathBuf {
+    if cfg!(windows) {
+        PathBuf::from(r"C:\Windows\System32\drivers\etc\hosts")
+    } else {
+        PathBuf::from("/etc/hosts")
+    }
+}
+
+/// Names an entry line maps, without its address and comment.
+fn names(line: &str) -> Vec<&str> {
+    let line = line.split('#').next().unwrap_or_default();
+    line.split_whitespace().skip(1).collect()
+}
+
+/// The lines of `text` before, inside and after the managed block.
+fn split(text: &str) -> (Vec<&str>, Vec<&str>, Vec<&str>) {
+    let lines: Vec<&str> = text.lines().collect();
+    let begin = lines.iter().position(|l| l.trim() == BEGIN);
+    let end = begin.and_then(|b| {
+        lines[b..]
+            .iter()
+            .position(|l| l.trim() == END)
+            .map(|e| b + e)
+    });
+    match (begin, end) {
+        (Some(begin), Some(end)) => (
+            lines[..begin].to_vec(),
+            lines[begin + 1..end].to_vec(),
+            lines[end + 1..].to_vec(),
+        ),
+        _ => (lines, vec![], vec![]),
+    }
+}
+
+/// `text` with the managed block mapping each of `entries`, keeping its entries for other names.
+fn update(text: &str, entries: &[(String, IpAddr)]) -> (String, Vec<String>, Vec<String>) {
+    let (before, block, after) = split(text);
+    let replaced = |line: &&str| {
+        names(line)
+            .iter()
+            .any(|name| entries.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)))
+    };
+    let kept: Vec<String> = block
+        .iter()
+        .filter(|l| !replaced(l))
+        .map(|l| l.to_string())
+        .collect();
+    let new: Vec<String> = kept
+        .iter()
+        .cloned()
+        .chain(
+            entries
+                .iter()
+                .map(|(name, addr)| format!("{} {}", addr, name)),
+        )
+        .collect();
+    let removed = block
+        .iter()
+        .filter(|l| !new.iter().any(|n| n == *l))
+        .map(|l| l.to_string())
+        .collect();
+    let added = new
+        .iter()
+        .filter(|n| !block.contains(&n.as_str()))
+        .cloned()
+        .collect();
+
+    let mut lines: Vec<String> = before.iter().map(|l| l.to_string()).collect();
+    lines.push(String::from(BEGIN));
+    lines.extend(new);
+    lines.push(String::from(END));
+    lines.extend(after.iter().map(|l| l.to_string()));
+    let newline = if text.contains("\r\n") { "\r\n" } else { "\n" };
+    let mut text = lines.join(newline);
+    text.push_str(newline);
+    (text, removed, added)
+}
+
+/// `entries` as a block to paste into a hosts file, between the lines `doh hosts --write` manages
+/// and stamped with the time they were `generated`.
+pub fn block(entries: &[(String, IpAddr)], generated: &str) -> String {
+    let mut lines = vec![
+        String::from(BEGIN),
+        format!("# generated by doh on {}", generated),
+    ];
+    lines.extend(
+        entries
+            .iter()
+            .map(|(name, addr)| format!("{} {}", addr, name)),
+    );
+    lines.push(String::from(END));
+    lines.join("\n") + "\n"
+}
+
+/// Warn about lines outside the managed block that map one of `entries` too, since the first
+/// matching line wins.
+fn shadowing(path: &Path, text: &str, entries: &[(String, IpAddr)]) {
+    let mut inside = false;
+    for (idx, line) in text.lines().enumerate() {
+        match line.trim() {
+            BEGIN => inside = true,
+            END => inside = false,
+            _ => {}
+        }
+        if inside {
+            continue;
+        }
+        for name in names(line) {
+            if entries.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)) {
+                warn!(
+                    "{}:{} also maps {} outside the doh block",
+                    path.display(),
+                    idx + 1,
+                    name
+                );
+            }
+        }
+    }
+}
+
+/// Print `entries` as hosts lines, or put them in the managed block of `path` with `write`, or
+/// show what that would change with `dry_run`.
+pub fn run(
+    entries: &[(String, IpAddr)],
+    path: &Path,
+    write: bool,
+    dry_run: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !write && !dry_run {
+        for (name, addr) in entries {
+            println!("{} {}", addr, name);
+        }
+        return Ok(());
+    }
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("cannot read {}: {}", path.display(), e).into()),
+    };
+    shadowing(path, &text, entries);
+    let (updated, removed, added) = update(&text, entries);
+    if updated == text {
+        println!("{} is up to date", path.display());
+        return Ok(());
+    }
+    for line in &removed {
+        println!("- {}", line);
+    }
+    for line in &added {
+        println!("+ {}", line);
+    }
+    if dry_run {
+        return Ok(());
+    }
+
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".doh.bak");
+    let backup = PathBuf::from(backup);
+    if !text.is_empty() {
+        std::fs::copy(path, &backup)
+            .map_err(|e| format!("cannot back up {}: {}", path.display(), e))?;
+    }
+    // written in place rather than renamed over, which keeps its permissions and works on
+    // bind-mounted hosts files in containers
+    std::fs::write(path, updated).map_err(|e| {
+        format!(
+            "cannot write {}: {} (it usually takes root or administrator rights)",
+            path.display(),
+            e
+        )
+    })?;
+    if !text.is_empty() {
+        println!(
+            "updated {}, previous version in {}",
+            path.display(),
+            backup.display()
+        );
+    } else {
+        println!("created {}", path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,203 @@
+//! ICMP echo requests, over a raw socket or, where opening one takes privileges doh doesn't have,
+//! over the unprivileged datagram sockets of Linux (for the groups in `net.ipv4.ping_group_range`)
+//! and macOS, the way `ping` itself gets by without setuid.
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::random;
+use surge_ping::{Client, Config, PingIdentifier, PingSequence, ICMP};
+use tokio::net::UdpSocket;
+use tokio::sync::OnceCell;
+use tokio::time;
+use tracing::debug;
+
+use crate::source::Source;
+
+/// How long the echo request to 127.0.0.1 that checks a socket may take.
+const CHECK_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// What to do to ping without the privileges it takes here.
+#[cfg(target_os = "linux")]
+pub const HINT: &str = "ICMP needs root or CAP_NET_RAW (sudo setcap cap_net_raw+ep $(which doh)), \
+    or your group in sysctl net.ipv4.ping_group_range";
+#[cfg(windows)]
+pub const HINT: &str = "ICMP needs a prompt run as administrator on Windows";
+#[cfg(not(any(target_os = "linux", windows)))]
+pub const HINT: &str = "ICMP needs root here";
+
+/// Where echo requests go out.
+#[derive(Clone)]
+pub enum Socket {
+    /// A raw socket, shared by every address.
+    Raw(Client),
+    /// A datagram socket of its own for each address, IPv6 ones with `v6`, from `source`.
+    Datagram { v6: bool, source: Source },
+}
+
+/// Echo requests to one address.
+pub enum Pinger {
+    Raw(surge_ping::Pinger),
+    Datagram {
+        socket: UdpSocket,
+        v6: bool,
+        ident: u16,
+        timeout: Duration,
+    },
+}
+
+/// Sockets opened so far, by family and source.
+type Opened = BTreeMap<(bool, Source), Arc<OnceCell<Option<Socket>>>>;
+
+/// The socket for IPv4 targets, or IPv6 ones with `v6`, from `source`, opened once: a raw one,
+/// else a datagram one, and neither when the system allows none or they get no replies.
+pub async fn socket(v6: bool, source: &Source) -> Option<Socket> {
+    static OPENED: Mutex<Opened> = Mutex::new(BTreeMap::new());
+    let cell = OPENED
+        .lock()
+        .unwrap()
+        .entry((v6, source.clone()))
+        .or_default()
+        .clone();
+    cell.get_or_init(|| open(v6, source)).await.clone()
+}
+
+async fn open(v6: bool, source: &Source) -> Option<Socket> {
+    let config = if v6 {
+        Config::builder().kind(ICMP::V6)
+    } else {
+        Config::builder()
+    };
+    match Client::new(&source.icmp(config, v6).build()) {
+        Ok(client) if works(&Socket::Raw(client.clone()), v6, source).await => {
+            return Some(Socket::Raw(client))
+        }
+        Ok(_) => debug!("the raw ICMP socket got no reply from 127.0.0.1"),
+        Err(e) => debug!("cannot open a raw ICMP socket: {}", e),
+    }
+    let datagram = Socket::Datagram {
+        v6,
+        source: source.clone(),
+    };
+    match source.icmp_datagram(v6) {
+        Ok(_) if works(&datagram, v6, source).await => {
+            debug!("pinging over unprivileged ICMP datagram sockets");
+            Some(datagram)
+        }
+        Ok(_) => {
+            debug!("the ICMP datagram socket got no reply from 127.0.0.1");
+            None
+        }
+        Err(e) => {
+            debug!("cannot open an ICMP datagram socket: {}", e);
+            None
+        }
+    }
+}
+
+/// Whether `socket` gets a reply from 127.0.0.1; one that opens but where replies never arrive,
+/// as in some containers, would otherwise lose every ping. IPv6 sockets and those bound to an
+/// address or interface, which may not reach the loopback, are taken at their word.
+async fn works(socket: &Socket, v6: bool, source: &Source) -> bool {
+    if v6 || source.bound() {
+        return true;
+    }
+    let Some(mut pinger) = socket
+        .pinger(IpAddr::V4(Ipv4Addr::LOCALHOST), CHECK_TIMEOUT)
+        .await
+    else {
+        return false;
+    };
+    pinger.ping(0, &[0; 8]).await.is_some()
+}
+
+impl Socket {
+    /// A pinger of `target`, waiting up to `timeout` for each reply.
+    pub async fn pinger(&self, target: IpAddr, timeout: Duration) -> Option<Pinger> {
+        match self {
+            Socket::Raw(client) => {
+                let mut pinger = client.pinger(target, PingIdentifier(random())).await;
+                pinger.timeout(timeout);
+                Some(Pinger::Raw(pinger))
+            }
+            Socket::Datagram { v6, source } => {
+                let socket = source.icmp_datagram(*v6).ok()?;
+                socket.connect((target, 0)).await.ok()?;
+                Some(Pinger::Datagram {
+                    socket,
+                    v6: *v6,
+                    ident: random(),
+                    timeout,
+                })
+            }
+        }
+    }
+}
+
+/// An echo request, with the checksum ICMPv6 leaves to the kernel.
+fn echo_request(v6: bool, ident: u16, seq: u16, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![if v6 { 128 } else { 8 }, 0, 0, 0];
+    packet.extend(ident.to_be_bytes());
+    packet.extend(seq.to_be_bytes());
+    packet.extend(payload);
+    if !v6 {
+        let mut sum: u32 = packet
+            .chunks(2)
+            .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+            .sum();
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        packet[2..4].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+    }
+    packet
+}
+
+/// Whether `packet` is the echo reply to request `seq` of `ident`. Linux replaces the identifier
+/// with one of its own and only hands a socket its replies; macOS prepends the IPv4 header.
+fn is_reply(packet: &[u8], v6: bool, ident: u16, seq: u16) -> bool {
+    let skip = match packet.first() {
+        Some(first) if !v6 && first >> 4 == 4 => usize::from(first & 0x0f) * 4,
+        _ => 0,
+    };
+    let Some(icmp) = packet.get(skip..skip + 8) else {
+        return false;
+    };
+    let ours = cfg!(target_os = "linux") || icmp[4..6] == ident.to_be_bytes();
+    icmp[0] == if v6 { 129 } else { 0 } && ours && icmp[6..8] == seq.to_be_bytes()
+}
+
+impl Pinger {
+    /// Send echo request `seq` with `payload`, and time the reply, `None` when none came in time.
+    pub async fn ping(&mut self, seq: u16, payload: &[u8]) -> Option<Duration> {
+        match self {
+            Pinger::Raw(pinger) => pinger
+                .ping(PingSequence(seq), payload)
+                .await
+                .ok()
+                .map(|(_, rtt)| rtt),
+            Pinger::Datagram {
+                socket,
+                v6,
+                ident,
+                timeout,
+            } => {
+                let request = echo_request(*v6, *ident, seq, payload);
+                let start = Instant::now();
+                socket.send(&request).await.ok()?;
+                // a late reply to an earlier request is skipped
+                let reply = async {
+                    let mut buf = vec![0; request.len() + 128];
+                    loop {
+                        let len = socket.recv(&mut buf).await.ok()?;
+                        if is_reply(&buf[..len], *v6, *ident, seq) {
+                            return Some(start.elapsed());
+                        }
+                    }
+                };
+                time::timeout(*timeout, reply).await.ok().flatten()
+            }
+        }
+    }
+}
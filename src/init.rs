@@ -0,0 +1,200 @@
+//! `doh config init`: write a starter config file from a catalog of public DoH providers.
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+
+use crate::config::{self, ProbeSettings};
+use crate::dirs;
+use clap::{Args, ValueEnum};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Region {
+    /// Anycast providers reachable worldwide
+    Global,
+    /// Providers inside mainland China
+    Cn,
+}
+
+/// A well-known public DoH endpoint speaking the JSON API.
+struct Preset {
+    tag: &'static str,
+    url: &'static str,
+    region: Region,
+}
+
+const PRESETS: &[Preset] = &[
+    Preset {
+        tag: "cloudflare",
+        url: "https://1.1.1.1/dns-query",
+        region: Region::Global,
+    },
+    Preset {
+        tag: "google",
+        url: "https://dns.google/resolve",
+        region: Region::Global,
+    },
+    Preset {
+        tag: "quad9",
+        url: "https://9.9.9.9:5053/dns-query",
+        region: Region::Global,
+    },
+    Preset {
+        tag: "adguard",
+        url: "https://dns.adguard-dns.com/resolve",
+        region: Region::Global,
+    },
+    Preset {
+        tag: "aliyun",
+        url: "https://dns.alidns.com/resolve",
+        region: Region::Cn,
+    },
+    Preset {
+        tag: "rubyfish",
+        url: "https://rubyfish.cn/dns-query",
+        region: Region::Cn,
+    },
+];
+
+#[derive(Debug, Clone, Args)]
+pub struct InitArgs {
+    /// Providers to include, comma-separated (cloudflare, google, quad9, adguard, aliyun, rubyfish)
+    #[arg(long, value_delimiter = ',')]
+    preset: Vec<String>,
+    /// Only include providers of this region
+    #[arg(long, value_enum)]
+    region: Option<Region>,
+    /// Echo requests per address
+    #[arg(long)]
+    count: Option<u16>,
+    /// Seconds between echo requests
+    #[arg(long)]
+    interval: Option<f64>,
+    /// Seconds to wait for each reply
+    #[arg(long)]
+    timeout: Option<f64>,
+    /// Overwrite an existing config file
+    #[arg(long)]
+    force: bool,
+}
+
+impl InitArgs {
+    fn is_empty(&self) -> bool {
+        self.preset.is_empty()
+            && self.region.is_none()
+            && self.count.is_none()
+            && self.interval.is_none()
+            && self.timeout.is_none()
+    }
+}
+
+/// Ask `question` on the terminal, returning the trimmed answer (empty for the default).
+fn ask(question: &str) -> std::io::Result<String> {
+    print!("{}: ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(answer.trim().to_string())
+}
+
+/// Fill in the settings interactively when no flag was given on a terminal.
+fn prompt(args: &mut InitArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let region = ask("Region [global, cn, all] (all)")?;
+    if !region.is_empty() && region != "all" {
+        args.region = Some(Region::from_str(&region, true)?);
+    }
+    let names: Vec<&str> = PRESETS
+        .iter()
+        .filter(|p| args.region.is_none_or(|r| p.region == r))
+        .map(|p| p.tag)
+        .collect();
+    let preset = ask(&format!("Providers [{}] (all)", names.join(", ")))?;
+    args.preset = preset
+        .split(',')
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty())
+        .collect();
+    let count = ask("Echo requests per address (10)")?;
+    if !count.is_empty() {
+        args.count = Some(count.parse()?);
+    }
+    Ok(())
+}
+
+/// Render the config file, with comments, for the selected providers and probe settings.
+fn render(args: &InitArgs) -> Result<String, String> {
+    for name in &args.preset {
+        if !PRESETS.iter().any(|p| p.tag == name) {
+            return Err(format!("unknown preset `{}`", name));
+        }
+    }
+    let presets: Vec<&Preset> = PRESETS
+        .iter()
+        .filter(|p| args.region.is_none_or(|r| p.region == r))
+        .filter(|p| args.preset.is_empty() || args.preset.iter().any(|n| n == p.tag))
+        .collect();
+    if presets.is_empty() {
+        return Err(String::from(
+            "no provider matches the selected presets and region",
+        ));
+    }
+
+    let defaults = ProbeSettings::default();
+    let mut text = String::from("# doh config file, see `doh config validate`\n");
+    for preset in presets {
+        text.push_str(&format!(
+            "\n[[servers]]\ntag = \"{}\"\nurl = \"{}\"\n",
+            preset.tag, preset.url
+        ));
+    }
+    text.push_str(&format!(
+        "\n[probe]\n\
+         # echo requests per address\n\
+         count = {}\n\
+         # seconds between echo requests\n\
+         interval = {}\n\
+         # seconds to wait for each reply\n\
+         timeout = {}\n\
+         # ICMP payload bytes\n\
+         size = {}\n",
+        args.count.unwrap_or(defaults.count),
+        args.interval.unwrap_or(defaults.interval.as_secs_f64()),
+        args.timeout.unwrap_or(defaults.timeout.as_secs_f64()),
+        defaults.size,
+    ));
+    Ok(text)
+}
+
+pub fn run(mut args: InitArgs, path: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let explicit = path.is_some();
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => dirs::config_file().ok_or("cannot determine the platform config directory")?,
+    };
+    if path.exists() && !args.force {
+        return Err(format!(
+            "{} already exists, use --force to overwrite",
+            path.display()
+        )
+        .into());
+    }
+
+    if args.is_empty() && std::io::stdin().is_terminal() {
+        prompt(&mut args)?;
+    }
+    let text = render(&args)?;
+    // never write a file that `config validate` would reject
+    if let Err(diagnostics) = config::parse(&text) {
+        let messages: Vec<String> = diagnostics.iter().map(|d| d.message.clone()).collect();
+        return Err(messages.join("\n").into());
+    }
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, text)?;
+    if explicit {
+        println!("Wrote {}, use it with --config {0}", path.display());
+    } else {
+        println!("Wrote {}", path.display());
+    }
+    Ok(())
+}
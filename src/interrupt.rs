@@ -0,0 +1,66 @@
+//! Ctrl+C handling: the first interrupt stops outstanding queries and probes so the partial
+//! results can still be printed, a second one exits right away. `--deadline` interrupts the run
+//! the same way once it's up, and so does SIGTERM on Unix.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+static TOKEN: OnceLock<CancellationToken> = OnceLock::new();
+
+/// Cancelled once the user pressed Ctrl+C.
+pub fn token() -> &'static CancellationToken {
+    TOKEN.get_or_init(CancellationToken::new)
+}
+
+pub fn interrupted() -> bool {
+    token().is_cancelled()
+}
+
+/// Set when the run was interrupted by its deadline rather than by the user.
+static EXPIRED: AtomicBool = AtomicBool::new(false);
+
+/// Whether [`deadline`] interrupted the run.
+pub fn expired() -> bool {
+    EXPIRED.load(Ordering::Relaxed)
+}
+
+/// Interrupt the run `after` from now, unless it's over by then.
+pub fn deadline(after: Duration) {
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tokio::time::sleep(after) => {}
+            _ = token().cancelled() => return,
+        }
+        info!("deadline of {:?} reached, printing partial results", after);
+        EXPIRED.store(true, Ordering::Relaxed);
+        token().cancel();
+    });
+}
+
+pub fn install() {
+    // how systemd and most service managers stop a service, which then shuts down like on Ctrl+C
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+        let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+            return;
+        };
+        if terminate.recv().await.is_some() {
+            info!("terminated, stopping");
+            token().cancel();
+        }
+    });
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        info!("interrupted, printing partial results (press Ctrl+C again to quit)");
+        token().cancel();
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+}
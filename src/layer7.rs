@@ -0,0 +1,92 @@
+//! `--probe https`: time a TCP connection to port 443, a TLS handshake with the queried name as
+//! SNI and a `HEAD /` request, since ICMP is often deprioritized on the way to busy hosts.
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use hyper::header::HOST;
+use hyper::{Body, Method, Request};
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::time;
+use tokio_native_tls::{native_tls, TlsConnector};
+
+use crate::source::Source;
+
+/// The port the HTTPS probe connects to.
+pub const PORT: u16 = 443;
+
+/// How long each phase of an HTTPS probe took, in milliseconds: the mean over the probes that
+/// got a response.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+pub struct Phases {
+    /// TCP connect.
+    pub connect_ms: f64,
+    /// TLS handshake, after the connection.
+    pub tls_ms: f64,
+    /// From sending the request to the first byte of the response.
+    pub ttfb_ms: f64,
+}
+
+impl Phases {
+    /// From connecting to the first byte of the response.
+    pub fn total(&self) -> Duration {
+        Duration::from_secs_f64((self.connect_ms + self.tls_ms + self.ttfb_ms) / 1000.0)
+    }
+
+    /// The mean of `samples`, `None` when there are none.
+    pub fn mean(samples: &[Phases]) -> Option<Self> {
+        let count = samples.len() as f64;
+        let mean = |pick: fn(&Phases) -> f64| samples.iter().map(pick).sum::<f64>() / count;
+        (!samples.is_empty()).then(|| Self {
+            connect_ms: mean(|p| p.connect_ms),
+            tls_ms: mean(|p| p.tls_ms),
+            ttfb_ms: mean(|p| p.ttfb_ms),
+        })
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Connect to `addr` from `source`, shake hands with `server_name` as SNI and send `HEAD /`, all
+/// within `timeout`; `None` when any of it fails. Any response counts, whatever its status, and the
+/// certificate isn't checked, only timed.
+pub async fn sample(
+    addr: IpAddr,
+    server_name: &str,
+    timeout: Duration,
+    source: &Source,
+) -> Option<Phases> {
+    let attempt = async {
+        let start = Instant::now();
+        let stream = source.connect(SocketAddr::new(addr, PORT)).await.ok()?;
+        let connect = start.elapsed();
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true)
+            .build()
+            .ok()?;
+        let stream = TlsConnector::from(connector)
+            .connect(server_name, stream)
+            .await
+            .ok()?;
+        let tls = start.elapsed() - connect;
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await.ok()?;
+        tokio::spawn(connection);
+        let request = Request::builder()
+            .method(Method::HEAD)
+            .uri("/")
+            .header(HOST, server_name)
+            .body(Body::empty())
+            .ok()?;
+        let sent = Instant::now();
+        sender.send_request(request).await.ok()?;
+        Some(Phases {
+            connect_ms: millis(connect),
+            tls_ms: millis(tls),
+            ttfb_ms: millis(sent.elapsed()),
+        })
+    };
+    time::timeout(timeout, attempt).await.ok().flatten()
+}
@@ -0,0 +1,1240 @@
+//! Query DoH servers and probe the addresses they answer with.
+//!
+//! The `doh` binary is built on this library; other programs can embed it through [`DohClient`]:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! let client = doh::DohClient::new("https://1.1.1.1/dns-query");
+//! let lookup = client.resolve("example.com", "A").await?;
+//! for answer in &lookup.answers {
+//!     if let Ok(addr) = answer.data.parse() {
+//!         let ping = client.probe(addr, &doh::config::ProbeSettings::default()).await?;
+//!         println!("{} {:?}ms {:.0}% lost", addr, ping.avg_ms, ping.loss * 100.0);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::ErrorKind;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use futures::stream::{FuturesUnordered, StreamExt};
+use ipnet::IpNet;
+
+use rand::random;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Version;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use tokio_native_tls::native_tls;
+use tracing::{debug, info, trace};
+
+pub mod bootstrap;
+pub mod cache;
+pub mod config;
+mod diagnose;
+pub mod dialect;
+pub mod dirs;
+pub mod escalate;
+pub mod icmp;
+pub mod interrupt;
+pub mod layer7;
+pub mod limit;
+pub mod nat64;
+pub mod plain;
+pub mod progress;
+pub mod quirks;
+pub mod ranking;
+pub mod source;
+pub mod svcb;
+pub mod system;
+pub mod timing;
+pub mod wire;
+
+use config::{ProbeMethod, ProbeSettings};
+use escalate::Layer;
+use layer7::Phases;
+use timing::Timing;
+
+#[allow(non_snake_case)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Answer {
+    /// The name of the record.
+    pub name: String,
+    /// The type associated with each record. To convert to a string representation use
+    /// https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#dns-parameters-4
+    pub r#type: u32,
+    /// The time to live in seconds for this record.
+    pub TTL: u32,
+    /// The data associated with the record.
+    pub data: String,
+}
+
+/// Record type of the DNSSEC signatures in answers to `--dnssec` queries.
+const RRSIG: u32 = 46;
+
+/// A response of the JSON API.
+#[allow(non_snake_case)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DnsResponse {
+    Status: u32,
+    #[serde(default)]
+    AD: bool,
+    Answer: Option<Vec<Answer>>,
+    #[serde(default, deserialize_with = "comment")]
+    Comment: Option<String>,
+}
+
+/// A `Comment`, a string or, from some servers, a list of them such as extended DNS errors.
+fn comment<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Comment {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match Option::<Comment>::deserialize(deserializer)? {
+        Some(Comment::One(text)) => Some(text),
+        Some(Comment::Many(lines)) => Some(lines.join("; ")),
+        None => None,
+    }
+    .filter(|text| !text.trim().is_empty()))
+}
+
+/// Read a whole file, or stdin when `path` is `-`.
+pub fn read_input(path: &str) -> std::io::Result<String> {
+    if path == "-" {
+        let mut buf = String::new();
+        std::io::stdin().read_to_string(&mut buf)?;
+        Ok(buf)
+    } else {
+        std::fs::read_to_string(path)
+    }
+}
+
+/// The two DoH APIs: Google-style JSON, and RFC 8484 DNS messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Api {
+    Json,
+    Wire,
+}
+
+impl Api {
+    fn other(self) -> Self {
+        match self {
+            Api::Json => Api::Wire,
+            Api::Wire => Api::Json,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Api::Json => "JSON API",
+            Api::Wire => "wire format",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Api::Json => "application/dns-json",
+            Api::Wire => "application/dns-message",
+        }
+    }
+
+    /// The URL querying `server` for `hostname`, followed by the `extra` parameters of its quirk,
+    /// and the body to POST to it instead, if any: the wire-format query with `transport.post`.
+    fn request<'a>(
+        self,
+        hostname: &str,
+        server: &str,
+        rtype: &str,
+        extra: impl IntoIterator<Item = (&'a String, &'a String)>,
+        transport: &Transport,
+    ) -> Result<(String, Option<Vec<u8>>), String> {
+        let mut url = server_url(server)?;
+        let mut body = None;
+        let mut query = url.query_pairs_mut();
+        match self {
+            Api::Json => {
+                query
+                    .append_pair("name", hostname)
+                    .append_pair("type", rtype);
+                // validation stays on (cd=0), so the AD flag means something
+                if transport.dnssec {
+                    query.append_pair("do", "1").append_pair("cd", "0");
+                }
+                if let Some(subnet) = transport.subnet {
+                    query.append_pair("edns_client_subnet", &subnet.to_string());
+                }
+            }
+            Api::Wire => {
+                let code = wire::type_code(rtype)
+                    .ok_or_else(|| format!("unknown record type `{}`", rtype))?;
+                let mut message = wire::encode(hostname, code as u16)?;
+                transport.edns(&mut message, true);
+                if transport.post {
+                    body = Some(message);
+                } else {
+                    query.append_pair("dns", &URL_SAFE_NO_PAD.encode(message));
+                }
+            }
+        }
+        query.extend_pairs(extra);
+        drop(query);
+        // a POST may have no parameters left
+        if url.query() == Some("") {
+            url.set_query(None);
+        }
+        Ok((url.into(), body))
+    }
+
+    /// Decode the answers, JSON ones as `dialect`, or say why the reply doesn't look like this API.
+    fn decode(self, reply: &Reply, dialect: &dyn dialect::Dialect) -> Result<Lookup, String> {
+        if !(200..300).contains(&reply.status) {
+            return Err(diagnose::describe(reply));
+        }
+        let expected = match self {
+            Api::Json => "json",
+            Api::Wire => "dns-message",
+        };
+        if let Some(content_type) = &reply.content_type {
+            if !content_type.contains(expected) {
+                return Err(format!(
+                    "unexpected content type {}, {}",
+                    content_type,
+                    diagnose::describe(reply)
+                ));
+            }
+        }
+        let lookup = match self {
+            Api::Json => dialect.parse(&reply.body).map(|body| Lookup {
+                status: body.Status,
+                protocol: reply.protocol,
+                authenticated: body.AD,
+                answers: body
+                    .Answer
+                    .unwrap_or_default()
+                    .into_iter()
+                    // some servers give the types they don't render as `\# len hex`
+                    .map(|answer| Answer {
+                        data: wire::present(answer.r#type, &answer.data).unwrap_or(answer.data),
+                        ..answer
+                    })
+                    .collect(),
+                comment: body.Comment,
+                timing: None,
+            }),
+            Api::Wire => wire::parse(&reply.body).map(|message| Lookup {
+                status: message.rcode as u32,
+                protocol: reply.protocol,
+                authenticated: message.authenticated,
+                answers: message.answers,
+                comment: None,
+                timing: None,
+            }),
+        };
+        lookup.map_err(|e| format!("{}, {}", e, diagnose::describe(reply)))
+    }
+}
+
+/// What a server answered to one query.
+pub struct Lookup {
+    /// The DNS response code, 3 for NXDOMAIN.
+    pub status: u32,
+    /// Whether the server validated the answers with DNSSEC, its AD flag.
+    pub authenticated: bool,
+    /// What the query went over: the HTTP version, or `UDP`, `TCP` or `DoT`.
+    pub protocol: &'static str,
+    pub answers: Vec<Answer>,
+    /// What the server said about the response, the JSON API's `Comment`.
+    pub comment: Option<String>,
+    pub timing: Option<Timing>,
+}
+
+/// A DoH server's HTTP response, whatever API it came from.
+pub struct Reply {
+    pub status: u16,
+    /// HTTP version of the response, `HTTP/2` say.
+    pub protocol: &'static str,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// Scheme, host and port of `url`, shared by every request to a server whatever its query.
+fn origin(url: &str) -> Option<String> {
+    Some(
+        reqwest::Url::parse(url)
+            .ok()?
+            .origin()
+            .ascii_serialization(),
+    )
+}
+
+/// The clients of `keep_alive` transports, by the origin of the server they connect to.
+static CLIENTS: Mutex<BTreeMap<String, reqwest::Client>> = Mutex::new(BTreeMap::new());
+
+/// How DoH requests are sent.
+#[derive(Debug, Clone, Default)]
+pub struct Transport {
+    /// Time each phase of the request, on a connection of its own.
+    pub timed: bool,
+    /// Tor SOCKS proxy to send requests through.
+    pub tor: Option<String>,
+    /// HTTP or SOCKS5 proxy to send requests through, `none` to ignore `HTTPS_PROXY` and the like,
+    /// which apply otherwise.
+    pub proxy: Option<String>,
+    /// Ask for DNSSEC records, to learn whether the server validates them.
+    pub dnssec: bool,
+    /// Network to ask on behalf of (EDNS Client Subnet), for the answers its clients would get.
+    pub subnet: Option<IpNet>,
+    /// Root certificates to trust besides the system ones, one PEM block each.
+    pub ca_certs: Vec<Vec<u8>>,
+    /// Name to send as SNI and Host instead of the server's, which is still the one connected to.
+    pub sni: Option<String>,
+    /// Skip certificate and host name checks.
+    pub insecure: bool,
+    /// Send the DoH queries over HTTP/3, with builds of the `http3` feature.
+    pub http3: bool,
+    /// POST wire-format queries, the DNS message as the body (RFC 8484 §4.1), instead of GETting
+    /// them with the message in the URL.
+    pub post: bool,
+    /// Whether answers come from the cache while fresh.
+    pub cache: cache::Mode,
+    /// Times a failed query is sent again, after a growing pause.
+    pub retries: u32,
+    /// Longest a single request may take, from connecting to the end of the response.
+    pub timeout: Option<Duration>,
+    /// Pad encrypted wire-format queries to a multiple of this many bytes (RFC 8467), so their
+    /// size says less about the name.
+    pub padding: Option<usize>,
+    /// HTTP headers to send with every request, such as a token or a `User-Agent`.
+    pub headers: Vec<(String, String)>,
+    /// The local address or interface that connections, plain DNS queries and probes go out
+    /// through.
+    pub source: source::Source,
+    /// How much is in flight at once, and how fast each server is asked.
+    pub limits: limit::Limits,
+    /// How server host names are resolved.
+    pub bootstrap: bootstrap::Bootstrap,
+    /// Count the latency and failures of every query towards the [`ranking`] of the servers.
+    pub ranking: bool,
+    /// Send the DoH queries to each server over one connection, made by the first, rather than
+    /// connecting anew for every query.
+    pub keep_alive: bool,
+    /// Wait a random time of up to this long before each query, so that when and in which order
+    /// the servers are asked says less.
+    pub jitter: Option<Duration>,
+    /// What the config file sets for each server.
+    pub servers: Servers,
+}
+
+/// The settings of single servers in the config file, and the API each last answered through.
+#[derive(Debug, Clone, Default)]
+pub struct Servers {
+    /// The API set with `api = ...` in the config, the only one tried for those servers.
+    apis: BTreeMap<String, Api>,
+    /// Proxies set with `proxy = ...` in the config, by the origin of the server they apply to.
+    routes: BTreeMap<String, String>,
+    /// HTTP headers set with `headers = ...` and `user_agent = ...` in the config, by the origin
+    /// of the server they go to.
+    server_headers: BTreeMap<String, Vec<(String, String)>>,
+    /// Dialects set with `json_dialect = ...` in the config, by server.
+    dialects: BTreeMap<String, dialect::Kind>,
+    /// The API each server last answered through, so later queries go straight to it, shared by
+    /// the clones of this transport.
+    preferred: Arc<Mutex<BTreeMap<String, Api>>>,
+}
+
+impl Servers {
+    /// Only ever query `server` through `api`.
+    pub fn pin(&mut self, server: &str, api: Api) {
+        self.apis.insert(String::from(server), api);
+    }
+
+    /// Send the requests to `server` through `proxy`, or directly when it is `none`, instead of
+    /// through `--proxy`.
+    pub fn route(&mut self, server: &str, proxy: &str) {
+        if let Some(origin) = origin(server) {
+            self.routes.insert(origin, String::from(proxy));
+        }
+    }
+
+    /// The proxy set for the server of `url` in the config, if any.
+    fn routed(&self, url: &str) -> Option<&str> {
+        self.routes.get(&origin(url)?).map(String::as_str)
+    }
+
+    /// Send `headers` with the requests to `server`, replacing the `--header` ones of the same
+    /// name.
+    pub fn set_headers(&mut self, server: &str, headers: Vec<(String, String)>) {
+        if let Some(origin) = origin(server) {
+            self.server_headers.insert(origin, headers);
+        }
+    }
+
+    /// Read the JSON responses of `server` as `kind`.
+    pub fn set_dialect(&mut self, server: &str, kind: dialect::Kind) {
+        self.dialects.insert(String::from(server), kind);
+    }
+}
+
+impl Transport {
+    /// Add the EDNS options asked for, if any, to the wire-format `query`, padding it when it
+    /// goes over an `encrypted` connection.
+    pub(crate) fn edns(&self, query: &mut Vec<u8>, encrypted: bool) {
+        let padding = self.padding.filter(|_| encrypted);
+        if self.dnssec || self.subnet.is_some() || padding.is_some() {
+            wire::edns(query, self.dnssec, self.subnet, padding);
+        }
+    }
+
+    /// The HTTP headers to send with requests to `url`: those of the server in the config, then
+    /// those of `--header` it doesn't set.
+    pub(crate) fn headers(&self, url: &str) -> Vec<(String, String)> {
+        let own = origin(url)
+            .and_then(|origin| self.servers.server_headers.get(&origin).cloned())
+            .unwrap_or_default();
+        let global = self
+            .headers
+            .iter()
+            .filter(|(name, _)| !own.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)));
+        let global: Vec<(String, String)> = global.cloned().collect();
+        own.into_iter().chain(global).collect()
+    }
+
+    /// An HTTP client for requests to `url`.
+    pub fn client(&self, url: &str) -> Result<reqwest::Client, Box<dyn std::error::Error>> {
+        Ok(self.builder(url)?.build()?)
+    }
+
+    fn builder(&self, url: &str) -> Result<reqwest::ClientBuilder, Box<dyn std::error::Error>> {
+        let mut builder = reqwest::Client::builder();
+        for pem in &self.ca_certs {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(pem)?);
+        }
+        if self.insecure {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(ip) = self.source.preferred() {
+            builder = builder.local_address(ip);
+        }
+        if let Some(proxy) = &self.tor {
+            // Tor puts streams with different SOCKS credentials on different circuits, so
+            // each server sees a different exit
+            let mut hasher = DefaultHasher::new();
+            let url = reqwest::Url::parse(url)?;
+            (url.host_str(), url.port_or_known_default()).hash(&mut hasher);
+            let proxy = format!("socks5h://doh-{:x}:doh@{}", hasher.finish(), proxy);
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        } else {
+            match self.servers.routed(url).or(self.proxy.as_deref()) {
+                Some("none") => builder = builder.no_proxy(),
+                Some(proxy) => builder = builder.proxy(reqwest::Proxy::all(proxy)?),
+                None => {}
+            }
+        }
+        Ok(builder)
+    }
+
+    /// The URL to request instead of `url` and a client for it, the one of the earlier requests to
+    /// the server with `keep_alive`.
+    async fn fronted(
+        &self,
+        url: &str,
+    ) -> Result<(reqwest::Url, reqwest::Client), Box<dyn std::error::Error>> {
+        let Some(origin) = origin(url).filter(|_| self.keep_alive) else {
+            return self.connect(url).await;
+        };
+        let kept = CLIENTS.lock().unwrap().get(&origin).cloned();
+        if let Some(client) = kept {
+            let mut parsed = reqwest::Url::parse(url)?;
+            if let Some(sni) = &self.sni {
+                parsed.set_host(Some(sni))?;
+            }
+            return Ok((parsed, client));
+        }
+        let (parsed, client) = self.connect(url).await?;
+        CLIENTS.lock().unwrap().insert(origin, client.clone());
+        Ok((parsed, client))
+    }
+
+    /// The URL to request instead of `url` and a new client for it: with `--sni` the URL names
+    /// that host, resolved to the address of the server's own so only the name sent changes.
+    async fn connect(
+        &self,
+        url: &str,
+    ) -> Result<(reqwest::Url, reqwest::Client), Box<dyn std::error::Error>> {
+        let mut parsed = reqwest::Url::parse(url)?;
+        let Some(sni) = &self.sni else {
+            // a pinned or bootstrapped address, unless Tor resolves the name at the exit
+            let host = parsed.host_str().unwrap_or_default();
+            let pinned = match self.tor {
+                Some(_) => None,
+                None => self.bootstrap.address(host, &self.source).await?,
+            };
+            let client = match pinned {
+                Some(ip) => {
+                    let port = parsed.port_or_known_default().unwrap_or(443);
+                    let addr = SocketAddr::new(ip, port);
+                    self.doh(url)?.resolve(host, addr).build()?
+                }
+                None => self.doh(url)?.build()?,
+            };
+            return Ok((parsed, client));
+        };
+        let addr = server_addr(&parsed, self).await?;
+        parsed.set_host(Some(sni))?;
+        Ok((parsed, self.doh(url)?.resolve(sni, addr).build()?))
+    }
+
+    /// A client builder for the DoH queries to `url`, over HTTP/3 with `--http3`. QUIC can't go
+    /// through proxies, which would be bypassed.
+    fn doh(&self, url: &str) -> Result<reqwest::ClientBuilder, Box<dyn std::error::Error>> {
+        let builder = self.builder(url)?;
+        if !self.http3 {
+            return Ok(builder);
+        }
+        let proxied = self.tor.is_some()
+            || self
+                .servers
+                .routed(url)
+                .or(self.proxy.as_deref())
+                .is_some_and(|proxy| proxy != "none");
+        if proxied {
+            return Err("HTTP/3 can't go through a proxy".into());
+        }
+        #[cfg(feature = "http3")]
+        return Ok(builder.use_rustls_tls().http3_prior_knowledge());
+        #[cfg(not(feature = "http3"))]
+        Err("this doh was built without HTTP/3, build it with `--features http3`".into())
+    }
+
+    /// A TLS connector for the connections made without reqwest, with the same certificates.
+    pub(crate) fn tls(&self) -> Result<native_tls::TlsConnector, Box<dyn std::error::Error>> {
+        let mut builder = native_tls::TlsConnector::builder();
+        for pem in &self.ca_certs {
+            builder.add_root_certificate(native_tls::Certificate::from_pem(pem)?);
+        }
+        if self.insecure {
+            builder
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// The first address the host of `url` resolves to, on its port.
+pub(crate) async fn server_addr(
+    url: &reqwest::Url,
+    transport: &Transport,
+) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+    let host = url
+        .host_str()
+        .ok_or("server URL has no host")?
+        // IPv6 literals keep their brackets in URLs
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url
+        .port_or_known_default()
+        .ok_or("server URL has no port")?;
+    transport
+        .bootstrap
+        .lookup(host, port, &transport.source)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("cannot resolve {}", host).into())
+}
+
+/// Log the body of `reply`, as text when it is, in hex otherwise.
+fn trace_reply(url: &str, reply: &Reply) {
+    let body = match std::str::from_utf8(&reply.body) {
+        Ok(text) => String::from(text),
+        Err(_) => reply.body.iter().map(|b| format!("{:02x}", b)).collect(),
+    };
+    trace!(
+        "{} {} {}, {} bytes: {}",
+        url,
+        reply.status,
+        reply
+            .content_type
+            .as_deref()
+            .unwrap_or("without a content type"),
+        reply.body.len(),
+        body
+    );
+}
+
+/// GET `url`, or POST `body` to it, asking for an `api` response.
+async fn fetch(
+    url: &str,
+    api: Api,
+    body: Option<Vec<u8>>,
+    transport: &Transport,
+) -> Result<(Reply, Option<Timing>), Box<dyn std::error::Error>> {
+    match &body {
+        Some(body) => debug!("POST {}, {} bytes", url, body.len()),
+        None => debug!("GET {}", url),
+    }
+    // a proxied request goes over the proxy's connection, which can't be timed phase by phase
+    if transport.timed && transport.servers.routed(url).is_none() {
+        // a connection of its own, so each phase of the request can be timed
+        let url = reqwest::Url::parse(url)?;
+        let request = timing::request(&url, api.content_type(), body, transport);
+        let (reply, timing) = match transport.timeout {
+            Some(timeout) => time::timeout(timeout, request)
+                .await
+                .map_err(|_| format!("no response within {:?}", timeout))??,
+            None => request.await?,
+        };
+        trace_reply(url.as_str(), &reply);
+        return Ok((reply, Some(timing)));
+    }
+    let (fronted, client) = transport.fronted(url).await?;
+    let request = match body {
+        Some(body) => client
+            .post(fronted)
+            .header(CONTENT_TYPE, api.content_type())
+            .body(body),
+        None => client.get(fronted),
+    };
+    let mut request = request.header("Accept", api.content_type());
+    if transport.http3 {
+        request = request.version(Version::HTTP_3);
+    }
+    for (name, value) in transport.headers(url) {
+        request = request.header(name, value);
+    }
+    let res = request.send().await?;
+    let protocol = match res.version() {
+        Version::HTTP_09 => "HTTP/0.9",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_2 => "HTTP/2",
+        Version::HTTP_3 => "HTTP/3",
+        _ => "HTTP/1.1",
+    };
+    let reply = Reply {
+        status: res.status().as_u16(),
+        protocol,
+        content_type: res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from),
+        body: res.bytes().await?.to_vec(),
+    };
+    trace_reply(url, &reply);
+    Ok((reply, None))
+}
+
+/// Query `server`, falling back to the other API when it rejects the one it was asked in, and
+/// trying again up to `transport.retries` times when it fails.
+pub async fn exchange(
+    hostname: &str,
+    server: &str,
+    rtype: &str,
+    transport: &Transport,
+) -> Result<Lookup, Box<dyn std::error::Error>> {
+    // the system resolver caches on its own, if at all
+    let key = (transport.cache != cache::Mode::Off && !system::is_system(server))
+        .then(|| cache::key(server, hostname, rtype, transport));
+    if let Some(key) = &key {
+        if let Some(lookup) = cache::get(key) {
+            debug!(
+                "{} {} from {}: answered from the cache",
+                hostname, rtype, server
+            );
+            return Ok(lookup);
+        }
+        if transport.cache == cache::Mode::Only {
+            return Err("no fresh answer in the cache (--cache-only)".into());
+        }
+    }
+    let (mut start, mut result) = limited(hostname, server, rtype, transport).await;
+    for retry in 0..transport.retries {
+        let Err(e) = &result else {
+            break;
+        };
+        if interrupt::interrupted() {
+            break;
+        }
+        debug!("{} {} from {}: {}, retrying", hostname, rtype, server, e);
+        tokio::select! {
+            _ = time::sleep(backoff(retry)) => {}
+            _ = interrupt::token().cancelled() => break,
+        }
+        (start, result) = limited(hostname, server, rtype, transport).await;
+    }
+    if let Ok(lookup) = &mut result {
+        // the signatures --dnssec asks for are not answers of their own
+        if rtype != "RRSIG" {
+            lookup.answers.retain(|a| a.r#type != RRSIG);
+        }
+        if let Some(key) = key {
+            cache::put(key, lookup);
+        }
+    }
+    // an interrupted query says nothing about the server
+    if transport.ranking && !interrupt::interrupted() {
+        ranking::observe(server, result.is_ok().then(|| start.elapsed()));
+    }
+    result
+}
+
+/// Pause before the first retry, doubled for each of the next ones.
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+/// How long to wait before retry number `retry`, from 0: exponential, and jittered by up to half
+/// either way so that queries failing together don't all come back at once.
+fn backoff(retry: u32) -> Duration {
+    RETRY_BACKOFF
+        .saturating_mul(1 << retry.min(10))
+        .mul_f64(0.5 + random::<f64>())
+}
+
+/// Wait a random time of up to `transport.jitter` before a query, or until interrupted.
+async fn jitter(transport: &Transport) {
+    let Some(jitter) = transport.jitter else {
+        return;
+    };
+    tokio::select! {
+        _ = time::sleep(jitter.mul_f64(random::<f64>())) => {}
+        _ = interrupt::token().cancelled() => {}
+    }
+}
+
+/// [`attempt`], once [`limit`] lets it start, and when it did.
+async fn limited(
+    hostname: &str,
+    server: &str,
+    rtype: &str,
+    transport: &Transport,
+) -> (Instant, Result<Lookup, Box<dyn std::error::Error>>) {
+    jitter(transport).await;
+    let _permit = transport.limits.query(server).await;
+    let start = Instant::now();
+    (start, attempt(hostname, server, rtype, transport).await)
+}
+
+/// `lookup`, given up on after `--query-timeout` or when interrupted.
+async fn bounded<T>(
+    lookup: impl std::future::Future<Output = Result<T, Box<dyn std::error::Error>>>,
+    transport: &Transport,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let lookup = async {
+        match transport.timeout {
+            Some(timeout) => time::timeout(timeout, lookup)
+                .await
+                .map_err(|_| format!("no response within {:?}", timeout))?,
+            None => lookup.await,
+        }
+    };
+    tokio::select! {
+        res = lookup => res,
+        _ = interrupt::token().cancelled() => Err("interrupted".into()),
+    }
+}
+
+/// Query `server` over whichever API it accepts, see [`exchange`].
+async fn attempt(
+    hostname: &str,
+    server: &str,
+    rtype: &str,
+    transport: &Transport,
+) -> Result<Lookup, Box<dyn std::error::Error>> {
+    if system::is_system(server) {
+        debug!("{} {} from the system resolver", hostname, rtype);
+        return bounded(system::lookup(hostname, rtype), transport).await;
+    }
+    if plain::is_plain(server) {
+        unproxied(transport)?;
+        debug!("{} {} from {}", hostname, rtype, server);
+        return bounded(plain::lookup(hostname, server, rtype, transport), transport).await;
+    }
+    let quirk = quirks::lookup(server);
+    let pinned = transport.servers.apis.get(server).copied().map(|api| [api]);
+    let known = match &pinned {
+        Some(pinned) => &pinned[..],
+        None => quirk.map_or(&[][..], |q| q.transports.as_slice()),
+    };
+    let preferred = transport
+        .servers
+        .preferred
+        .lock()
+        .unwrap()
+        .get(server)
+        .copied();
+    let first = preferred
+        .or_else(|| known.first().copied())
+        .unwrap_or(Api::Json);
+    let mut rejected = vec![];
+    for api in [first, first.other()] {
+        if !known.is_empty() && !known.contains(&api) {
+            continue;
+        }
+        let endpoint = quirk.map_or_else(|| String::from(server), |q| q.endpoint(server, api));
+        let params = quirk.iter().flat_map(|q| &q.params);
+        let (url, body) = match api.request(hostname, &endpoint, rtype, params, transport) {
+            Ok(request) => request,
+            Err(e) => {
+                rejected.push(format!("{}: {}", api.name(), e));
+                continue;
+            }
+        };
+        let (reply, timing) = tokio::select! {
+            res = fetch(&url, api, body, transport) => res?,
+            _ = interrupt::token().cancelled() => return Err("interrupted".into()),
+        };
+        let dialect = dialect::of(transport.servers.dialects.get(server).copied(), quirk);
+        match api.decode(&reply, dialect) {
+            Ok(lookup) => {
+                let previous = transport
+                    .servers
+                    .preferred
+                    .lock()
+                    .unwrap()
+                    .insert(String::from(server), api);
+                // concurrent queries may all fall back, only mention it once
+                if previous != Some(api) && !rejected.is_empty() {
+                    info!("{}: {}, using {}", server, rejected.join("; "), api.name());
+                }
+                return Ok(Lookup { timing, ..lookup });
+            }
+            // the other API won't get past a portal or a filter either
+            Err(e)
+                if matches!(
+                    diagnose::classify(&reply),
+                    diagnose::Kind::Blocked | diagnose::Kind::Portal
+                ) =>
+            {
+                return Err(e.into())
+            }
+            Err(e) => rejected.push(format!("{} rejected ({})", api.name(), e)),
+        }
+    }
+    let mut message = rejected.join("; ");
+    if let Some(notes) = quirk.map(|q| q.notes()).filter(|n| !n.is_empty()) {
+        message.push_str(&format!(" (known for this provider: {})", notes.join("; ")));
+    }
+    Err(message.into())
+}
+
+/// The URL of `server`, to add the encoded parameters of a query to, after any it already has.
+fn server_url(server: &str) -> Result<reqwest::Url, String> {
+    reqwest::Url::parse(server).map_err(|e| format!("invalid server URL `{}`: {}", server, e))
+}
+
+/// Fail unless `transport` lets `udp://`, `tcp://` and `tls://` servers be reached, which
+/// `--tor` and `--proxy` have no way to.
+fn unproxied(transport: &Transport) -> Result<(), Box<dyn std::error::Error>> {
+    if transport.tor.is_some() {
+        return Err("udp://, tcp:// and tls:// servers cannot be reached through --tor".into());
+    }
+    if transport.proxy.as_deref().is_some_and(|p| p != "none") {
+        return Err("udp://, tcp:// and tls:// servers cannot be reached through --proxy".into());
+    }
+    Ok(())
+}
+
+/// Relay the DNS message `query` to `server` as is, and its response, for `doh serve`.
+pub async fn forward(
+    query: &[u8],
+    server: &str,
+    transport: &Transport,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    jitter(transport).await;
+    if plain::is_plain(server) {
+        unproxied(transport)?;
+        let start = Instant::now();
+        let result = bounded(plain::forward(query, server, transport), transport).await;
+        if transport.ranking {
+            ranking::observe(server, result.is_ok().then(|| start.elapsed()));
+        }
+        return result;
+    }
+    let quirk = quirks::lookup(server);
+    if quirk.is_some_and(|q| !q.transports.is_empty() && !q.transports.contains(&Api::Wire)) {
+        return Err("the server only has the JSON API".into());
+    }
+    let endpoint = quirk.map_or_else(|| String::from(server), |q| q.endpoint(server, Api::Wire));
+    // RFC 8484 asks for ID 0, which caches better; the caller puts the client's back
+    let mut message = query.to_vec();
+    message[..2].fill(0);
+    let mut url = server_url(&endpoint)?;
+    let body = if transport.post {
+        Some(message)
+    } else {
+        url.query_pairs_mut()
+            .append_pair("dns", &URL_SAFE_NO_PAD.encode(message));
+        None
+    };
+    if let Some(params) = quirk.map(|q| &q.params).filter(|p| !p.is_empty()) {
+        url.query_pairs_mut().extend_pairs(params);
+    }
+    let url = String::from(url);
+    let start = Instant::now();
+    let result = tokio::select! {
+        res = fetch(&url, Api::Wire, body, transport) => res,
+        _ = interrupt::token().cancelled() => return Err("interrupted".into()),
+    };
+    let result = result.and_then(|(reply, _)| {
+        if !(200..300).contains(&reply.status) {
+            return Err(diagnose::describe(&reply).into());
+        }
+        wire::parse(&reply.body).map_err(|e| format!("{}, {}", e, diagnose::describe(&reply)))?;
+        Ok(reply.body)
+    });
+    if transport.ranking {
+        ranking::observe(server, result.is_ok().then(|| start.elapsed()));
+    }
+    result
+}
+
+/// Just the answers of [`exchange`].
+pub async fn query(
+    hostname: &str,
+    server: &str,
+    rtype: &str,
+    transport: &Transport,
+) -> Result<Vec<Answer>, Box<dyn std::error::Error>> {
+    Ok(exchange(hostname, server, rtype, transport).await?.answers)
+}
+
+fn mean(data: &[i32]) -> Option<f32> {
+    let sum = data.iter().sum::<i32>() as f32;
+    let count = data.len();
+
+    match count {
+        positive if positive > 0 => Some(sum / count as f32),
+        _ => None,
+    }
+}
+
+/// The ICMP socket for IPv4 targets, or IPv6 ones with `v6`, from the source of `transport`;
+/// `None` when probing over TCP, or when neither a raw nor a datagram socket can be opened (see
+/// [`icmp::HINT`]).
+pub async fn icmp_client(
+    probe: &ProbeSettings,
+    v6: bool,
+    transport: &Transport,
+) -> Option<icmp::Socket> {
+    if probe.method != ProbeMethod::Icmp {
+        return None;
+    }
+    icmp::socket(v6, &transport.source).await
+}
+
+/// Time a TCP connection to `addr` from `source`, trying `ports` in turn and sticking to the
+/// first that answers; a refused connection is an answer too.
+async fn connect(
+    addr: IpAddr,
+    ports: &mut Vec<u16>,
+    timeout: Duration,
+    source: &source::Source,
+) -> Option<Duration> {
+    for port in ports.clone() {
+        let start = Instant::now();
+        match time::timeout(timeout, source.connect(SocketAddr::new(addr, port))).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) if e.kind() == ErrorKind::ConnectionRefused => {}
+            _ => continue,
+        }
+        *ports = vec![port];
+        return Some(start.elapsed());
+    }
+    None
+}
+
+/// How the round-trip times of one address spread, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+pub struct Stats {
+    pub min_ms: f64,
+    pub max_ms: f64,
+    /// Median.
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    /// Standard deviation.
+    pub stddev_ms: f64,
+    /// Mean difference between consecutive round trips.
+    pub jitter_ms: f64,
+}
+
+impl Stats {
+    /// The stats of `samples`, in the order they were measured; `None` when there are none.
+    pub fn new(samples: &[f64]) -> Option<Self> {
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let count = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / count;
+        let variance = samples.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / count;
+        let jitter =
+            samples.windows(2).map(|w| (w[1] - w[0]).abs()).sum::<f64>() / (count - 1.0).max(1.0);
+        Some(Self {
+            min_ms: *sorted.first()?,
+            max_ms: *sorted.last()?,
+            p50_ms: Self::percentile(&sorted, 50.0)?,
+            p95_ms: Self::percentile(&sorted, 95.0)?,
+            stddev_ms: variance.sqrt(),
+            jitter_ms: jitter,
+        })
+    }
+
+    /// The `p`th percentile of `sorted` by nearest rank, the sample it falls on; `None` when
+    /// there are none.
+    pub fn percentile(sorted: &[f64], p: f64) -> Option<f64> {
+        let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+        sorted.get(rank.max(1) - 1).copied()
+    }
+}
+
+/// Nothing could be sent to measure an address: probing over ICMP without a socket for it, or
+/// with one that opened no pinger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unavailable;
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "latency is unavailable: {}", icmp::HINT)
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+/// Ping `addr`, returning the mean RTT (-1 if nothing answered), the loss ratio, and whether
+/// all echo requests were sent before an interrupt. Without an ICMP `client` the RTT is the
+/// TCP connect time, unless probing over ICMP, where nothing can be sent: [`Unavailable`].
+/// Probes go out from the source of `transport`, within its limits.
+pub async fn ping(
+    client: Option<icmp::Socket>,
+    addr: IpAddr,
+    probe: &ProbeSettings,
+    transport: &Transport,
+) -> Result<(i32, f32, bool), Unavailable> {
+    let (mean, lost, complete, _) = ping_stats(client, addr, probe, transport).await?;
+    Ok((mean, lost, complete))
+}
+
+/// [`ping`], along with the spread of the round-trip times.
+pub async fn ping_stats(
+    client: Option<icmp::Socket>,
+    addr: IpAddr,
+    probe: &ProbeSettings,
+    transport: &Transport,
+) -> Result<(i32, f32, bool, Option<Stats>), Unavailable> {
+    let (mean, lost, complete, stats, _) =
+        ping_phases(client, addr, &addr.to_string(), probe, transport).await?;
+    Ok((mean, lost, complete, stats))
+}
+
+/// What one probe in flight goes out through: an ICMP pinger of its own, for replies told apart
+/// by sequence number, the TCP ports still worth trying, or a `--probe https` request.
+enum Slot {
+    Icmp(icmp::Pinger),
+    Tcp(Vec<u16>),
+    Https,
+}
+
+/// [`ping_stats`], with `server_name` as the SNI and host of `--probe https` requests, along
+/// with the mean of their phases. Up to `probe.window` probes are in flight at once, one every
+/// `probe.interval`.
+pub async fn ping_phases(
+    client: Option<icmp::Socket>,
+    addr: IpAddr,
+    server_name: &str,
+    probe: &ProbeSettings,
+    transport: &Transport,
+) -> Result<(i32, f32, bool, Option<Stats>, Option<Phases>), Unavailable> {
+    let _permit = transport.limits.ping().await;
+    let source = &transport.source;
+    let payload = vec![0; probe.size];
+    let target = nat64::target(addr, probe.nat64);
+    let width = probe.window.min(usize::from(probe.count)).max(1);
+    let mut idle = vec![];
+    for _ in 0..width {
+        let slot = match (&client, probe.method) {
+            (Some(client), _) => client.pinger(target, probe.timeout).await.map(Slot::Icmp),
+            (None, ProbeMethod::Icmp) => None,
+            (None, ProbeMethod::Https) => Some(Slot::Https),
+            (None, ProbeMethod::Tcp(Some(port))) => Some(Slot::Tcp(vec![port])),
+            (None, ProbeMethod::Tcp(None)) => Some(Slot::Tcp(escalate::PORTS.to_vec())),
+        };
+        idle.extend(slot);
+    }
+    // latency unavailable rather than a loss
+    if idle.is_empty() {
+        return Err(Unavailable);
+    }
+    let sample = |mut slot: Slot, seq: u16| {
+        let payload = &payload;
+        async move {
+            let (res, phases) = match &mut slot {
+                Slot::Icmp(pinger) => (pinger.ping(seq, payload).await, None),
+                Slot::Https => {
+                    let sample = layer7::sample(target, server_name, probe.timeout, source).await;
+                    (sample.as_ref().map(Phases::total), sample)
+                }
+                Slot::Tcp(ports) => (connect(target, ports, probe.timeout, source).await, None),
+            };
+            (slot, seq, res, phases)
+        }
+    };
+    let mut in_flight = FuturesUnordered::new();
+    let mut interval = time::interval(probe.interval);
+    let mut times = vec![];
+    let mut phases = vec![];
+    let mut samples = vec![];
+    let mut lost = 0.0;
+    let mut next = 0;
+    let mut sent = 0;
+    let cancelled = interrupt::token().cancelled();
+    tokio::pin!(cancelled);
+    progress::started();
+    while next < probe.count || !in_flight.is_empty() {
+        tokio::select! {
+            _ = interval.tick(), if next < probe.count && !idle.is_empty() => {
+                if let Some(slot) = idle.pop() {
+                    in_flight.push(sample(slot, next));
+                    next += 1;
+                }
+            }
+            Some((slot, seq, res, phase)) = in_flight.next() => {
+                idle.push(slot);
+                phases.extend(phase);
+                sent += 1;
+                progress::sent(sent);
+                match res {
+                    Some(dur) => {
+                        let ms = dur.as_secs_f64() * 1000.0;
+                        trace!("{} seq {}: {:.1}ms", addr, seq + 1, ms);
+                        times.push(dur.as_millis() as i32);
+                        samples.push((seq, ms));
+                    }
+                    None => {
+                        trace!("{} seq {}: no reply", addr, seq + 1);
+                        lost += 1.0;
+                    }
+                }
+            }
+            _ = &mut cancelled => break,
+        }
+    }
+    progress::done();
+    // in the order they were sent, for the jitter, whatever order the replies came in
+    samples.sort_by_key(|(seq, _)| *seq);
+    let samples: Vec<f64> = samples.into_iter().map(|(_, ms)| ms).collect();
+    Ok((
+        mean(&times[..]).map_or(-1, |i| i as i32),
+        if sent == 0 { 0.0 } else { lost / sent as f32 },
+        sent == probe.count,
+        Stats::new(&samples),
+        Phases::mean(&phases),
+    ))
+}
+
+/// Which layer still answers `addr` when it lost every ping, `None` if any ping got through.
+pub async fn reprobe(
+    addr: IpAddr,
+    server_name: &str,
+    lost: f32,
+    complete: bool,
+    probe: &ProbeSettings,
+    transport: &Transport,
+) -> Option<Layer> {
+    // what still answers over TCP is only news when ICMP didn't
+    if lost < 1.0 || !complete || probe.method != ProbeMethod::Icmp {
+        return None;
+    }
+    let target = nat64::target(addr, probe.nat64);
+    let source = &transport.source;
+    Some(escalate::probe(target, server_name, probe.timeout, source).await)
+}
+
+/// Resolves names through one DoH server, or a `udp://`, `tcp://` or `tls://` DNS one.
+#[derive(Debug, Clone)]
+pub struct DohClient {
+    server: String,
+    transport: Transport,
+}
+
+impl DohClient {
+    pub fn new(server: impl Into<String>) -> Self {
+        Self {
+            server: server.into(),
+            transport: Transport::default(),
+        }
+    }
+
+    /// Send queries through `transport`, e.g. to time them or go through Tor.
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Query `host` for records of `rtype`, a mnemonic such as `A` or `MX`.
+    pub async fn resolve(
+        &self,
+        host: &str,
+        rtype: &str,
+    ) -> Result<Lookup, Box<dyn std::error::Error>> {
+        exchange(host, &self.server, rtype, &self.transport).await
+    }
+
+    /// Ping `addr`, one of the answers say, through the same transport, see [`probe`].
+    pub async fn probe(
+        &self,
+        addr: IpAddr,
+        settings: &ProbeSettings,
+    ) -> Result<Ping, Box<dyn std::error::Error>> {
+        probe(addr, settings, &self.transport).await
+    }
+}
+
+/// What pinging an address measured.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Ping {
+    /// Mean round-trip time, absent when nothing answered.
+    pub avg_ms: Option<i32>,
+    /// Ratio of lost echo requests, from 0 to 1.
+    pub loss: f32,
+    /// False when an interrupt stopped it before all echo requests were sent.
+    pub complete: bool,
+    /// Spread of the round-trip times, absent when nothing answered.
+    pub stats: Option<Stats>,
+}
+
+/// Ping `addr` as configured in `settings`, over ICMPv6 for IPv6 or NAT64 targets, from the
+/// source of `transport`; an [`Unavailable`] error when probing over ICMP without the
+/// privileges it takes.
+pub async fn probe(
+    addr: IpAddr,
+    settings: &ProbeSettings,
+    transport: &Transport,
+) -> Result<Ping, Box<dyn std::error::Error>> {
+    let v6 = nat64::target(addr, settings.nat64).is_ipv6();
+    let client = icmp_client(settings, v6, transport).await;
+    let (mean, loss, complete, stats) = ping_stats(client, addr, settings, transport).await?;
+    Ok(Ping {
+        avg_ms: (mean != -1).then_some(mean),
+        loss,
+        complete,
+        stats,
+    })
+}
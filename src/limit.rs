@@ -0,0 +1,73 @@
+//! `--max-concurrent-queries`, `--max-concurrent-pings` and `--max-qps`: how much of a batch run
+//! is in flight at once and how fast each server is asked, so dozens of names and servers don't
+//! open hundreds of connections at once or hammer public resolvers.
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, Instant};
+
+/// The limits of a run, shared by the clones of the [`Transport`](crate::Transport) holding them;
+/// none by default.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// Queries that may be in flight at once, with `--max-concurrent-queries`.
+    queries: Option<Arc<Semaphore>>,
+    /// Addresses that may be pinged at once, with `--max-concurrent-pings`.
+    pings: Option<Arc<Semaphore>>,
+    /// Time between two queries to the same server, with `--max-qps`.
+    spacing: Option<Duration>,
+    /// When each server may next be asked, by host.
+    next: Arc<Mutex<BTreeMap<String, Instant>>>,
+}
+
+/// The host of `server`, shared by its JSON and wire-format endpoints; plain DNS servers are
+/// named by theirs too.
+fn host(server: &str) -> String {
+    reqwest::Url::parse(server)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+        .unwrap_or_else(|| String::from(server))
+}
+
+impl Limits {
+    /// Have at most `max` queries in flight at once.
+    pub fn set_queries(&mut self, max: usize) {
+        self.queries = Some(Arc::new(Semaphore::new(max)));
+    }
+
+    /// Ping at most `max` addresses at once.
+    pub fn set_pings(&mut self, max: usize) {
+        self.pings = Some(Arc::new(Semaphore::new(max)));
+    }
+
+    /// Start at most `qps` queries a second to each server.
+    pub fn set_qps(&mut self, qps: u32) {
+        self.spacing = Some(Duration::from_secs(1) / qps.max(1));
+    }
+
+    /// Wait until a query to `server` may start: its turn under `--max-qps`, then a free slot
+    /// under `--max-concurrent-queries`, held until the permit is dropped.
+    pub async fn query(&self, server: &str) -> Option<OwnedSemaphorePermit> {
+        if let Some(spacing) = self.spacing {
+            // each query books the next slot, so those waiting start one spacing apart
+            let slot = {
+                let mut next = self.next.lock().unwrap();
+                let slot = next
+                    .get(&host(server))
+                    .copied()
+                    .map_or_else(Instant::now, |at| at.max(Instant::now()));
+                next.insert(host(server), slot + spacing);
+                slot
+            };
+            time::sleep_until(slot).await;
+        }
+        self.queries.clone()?.acquire_owned().await.ok()
+    }
+
+    /// Wait for a free slot under `--max-concurrent-pings`, held until the permit is dropped.
+    pub async fn ping(&self) -> Option<OwnedSemaphorePermit> {
+        self.pings.clone()?.acquire_owned().await.ok()
+    }
+}
@@ -0,0 +1,83 @@
+//! Diagnostics on stderr through `tracing`: notices, warnings and per-server errors by default, the
+//! request URLs with `-v`, and response bodies and every ping's round trip with `-vv`.
+use std::fmt::{self, Write};
+
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+
+/// Prints the events of this crate at `level` and above, one line each.
+struct Stderr {
+    level: LevelFilter,
+}
+
+/// The message of an event, followed by its other fields as `name=value`.
+#[derive(Default)]
+struct Line(String);
+
+impl Visit for Line {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.0.insert_str(0, value);
+        } else {
+            let _ = write!(self.0, " {}={}", field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0.insert_str(0, &format!("{:?}", value));
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+impl Subscriber for Stderr {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        // not the HTTP and TLS libraries underneath
+        *metadata.level() <= self.level && metadata.target().starts_with("doh")
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.level)
+    }
+
+    // no spans are entered, only events count
+    fn new_span(&self, _: &Attributes<'_>) -> Id {
+        Id::from_u64(1)
+    }
+
+    fn record(&self, _: &Id, _: &Record<'_>) {}
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut line = Line::default();
+        event.record(&mut line);
+        // errors say so themselves, `tag error: ...`
+        let prefix = match *event.metadata().level() {
+            Level::WARN => "warning: ",
+            Level::DEBUG => "debug: ",
+            Level::TRACE => "trace: ",
+            _ => "",
+        };
+        eprintln!("{}{}", prefix, line.0);
+    }
+
+    fn enter(&self, _: &Id) {}
+
+    fn exit(&self, _: &Id) {}
+}
+
+/// Print diagnostics from here on: nothing with `quiet`, more with each `-v`.
+pub fn init(verbose: u8, quiet: bool) {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::OFF,
+        (_, 0) => LevelFilter::INFO,
+        (_, 1) => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    let _ = tracing::subscriber::set_global_default(Stderr { level });
+}
@@ -10,55 +10,845 @@ DoH Server:
 参考:
 https://help.aliyun.com/document_detail/171666.html
  */
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{IsTerminal, Write};
+use std::iter;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::ops::Range;
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use clap::Parser;
-use futures::future;
-use rand::random;
-use serde::{Deserialize, Serialize};
-use surge_ping::{Client, Config, PingIdentifier, PingSequence};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use futures::future::{self, BoxFuture, Shared};
+use futures::FutureExt;
+use ipnet::IpNet;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tabled::builder::Builder;
 use tabled::object::{Columns, Rows};
 use tabled::{Alignment, Modify, Style, Table, Tabled};
-use tokio::time;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{error, info, warn};
+
+mod bench;
+mod best;
+mod color;
+mod compare;
+mod diff;
+mod exporter;
+mod filter;
+mod geo;
+mod history;
+mod hosts;
+mod init;
+mod logging;
+mod metrics;
+mod output;
+mod privacy;
+mod propagation;
+mod rank;
+mod rdap;
+mod rebind;
+mod report;
+mod serve;
+mod server_list;
+mod systemd;
+mod top;
+mod trace;
+mod tui;
+mod variant;
+mod webhook;
+mod xlsx;
+
+use doh::{
+    cache, config, dirs, escalate, exchange, forward, icmp, icmp_client, interrupt, layer7, nat64,
+    ping, ping_phases, plain, progress, query, quirks, ranking, read_input, reprobe, svcb, system,
+    timing, wire, Answer, Api, Stats, Transport, Unavailable,
+};
+
+use color::{ColorChoice, Paint, Theme};
+use compare::{Consistency, NamedRange};
+use config::{ProbeMethod, ProbeSettings};
+use escalate::Layer;
+use geo::Geo;
+use init::InitArgs;
+use layer7::Phases;
+use output::{Format, Output};
+use rdap::Owner;
+use svcb::Svcb;
+use timing::Timing;
 
 #[derive(Debug, Clone, Parser)]
 #[command(name = "doh")]
 #[command(about = "Query the host IP address by DoH(DNS over HTTPs)", long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 struct Cli {
-    /// Query hostname
+    /// Query hostname (repeatable)
+    #[arg(long = "host", required_unless_present = "hosts_file")]
+    hosts: Vec<String>,
+    /// File with one hostname per line, `-` for stdin
+    #[arg(long, value_name = "PATH")]
+    hosts_file: Option<String>,
+    /// Record types to query, comma-separated or repeated, e.g. A,AAAA,HTTPS; only A and AAAA answers are pinged [default: A and AAAA]
+    #[arg(long = "type", value_name = "TYPE", value_delimiter = ',', value_parser = wire::parse_type)]
+    rtypes: Vec<String>,
+    /// Only query and ping IPv4 addresses
+    #[arg(short = '4', long, conflicts_with_all = ["rtypes", "ipv6"])]
+    ipv4: bool,
+    /// Only query and ping IPv6 addresses
+    #[arg(short = '6', long, conflicts_with = "rtypes")]
+    ipv6: bool,
+    /// Show a grid of unique addresses by server instead of one row per answer
+    #[arg(long)]
+    matrix: bool,
+    /// Give each address one row, with every server that returned it, instead of a row per server [default: server]
+    #[arg(long, value_enum, conflicts_with = "matrix")]
+    group_by: Option<GroupBy>,
+    /// After the table, summarize which addresses all servers agree on
+    #[arg(long)]
+    summary: bool,
+    /// Exit non-zero unless every server returned the same answers
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "set")]
+    assert_consistent: Option<Consistency>,
+    /// Compare the servers' address sets, flagging addresses only one returned and servers that share none, and exit non-zero above this share of divergent addresses, in percent [default: 50]
+    #[arg(long, value_name = "PERCENT", num_args = 0..=1, default_missing_value = "50", value_parser = clap::value_parser!(u8).range(..=100))]
+    check: Option<u8>,
+    /// Exit non-zero unless every server only returns this address or CIDR range (repeatable)
+    #[arg(long, value_parser = compare::parse_expected)]
+    expect: Vec<IpNet>,
+    /// Label answers falling into a named CIDR range, e.g. `prod=203.0.113.0/24` (repeatable)
+    #[arg(long, value_parser = compare::parse_range)]
+    range: Vec<NamedRange>,
+    /// Look up the network name and abuse contact of every answer over RDAP
+    #[arg(long)]
+    rdap: bool,
+    /// Add the Country, City and ASN of every answer from local MaxMind (GeoLite2) databases, or from an online API when there are none
     #[arg(long)]
-    host: String,
+    geo: bool,
+    /// MaxMind database to look answers up in, e.g. GeoLite2-City.mmdb (repeatable) [default: every .mmdb in the platform data directory's geoip folder]
+    #[arg(long, value_name = "PATH", requires = "geo")]
+    geo_db: Vec<PathBuf>,
+    /// Where to look answers up when no MaxMind database is found, `{ip}` standing for the address, or none to stay offline; it must answer like ipinfo.io [default: https://ipinfo.io/{ip}/json]
+    #[arg(long, value_name = "URL", value_parser = geo::parse_api, requires = "geo", conflicts_with = "geo_db")]
+    geo_api: Option<String>,
+    /// Show the Country column as the flag emoji and its ISO code, e.g. 🇩🇪 DE
+    #[arg(long, requires = "geo")]
+    geo_flags: bool,
+    /// Compare each record's remaining TTL with the other servers' to spot stale caches
+    #[arg(long)]
+    freshness: bool,
+    /// Repeat the query this many times per server and merge the rotating answers
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=100), conflicts_with = "cache_only")]
+    rounds: u32,
+    /// Ask for DNSSEC records and show which servers validated the answers (their AD flag)
+    #[arg(long)]
+    dnssec: bool,
+    /// Ask on behalf of this client network (EDNS Client Subnet), e.g. 1.2.3.0/24, to see the answers given to clients there
+    #[arg(long, value_name = "CIDR")]
+    subnet: Option<IpNet>,
+    /// Pad wire-format DoH and DoT queries to a multiple of this many bytes (RFC 8467), so their size gives less of the name away
+    #[arg(long, global = true, value_name = "BYTES", default_value_t = 128, value_parser = clap::value_parser!(u16).range(1..))]
+    padding: u16,
+    /// Send wire-format queries without padding
+    #[arg(long, global = true, conflicts_with = "padding")]
+    no_padding: bool,
+    /// Show how long name resolution, connect, TLS and the response took for each server
+    #[arg(long, conflicts_with_all = ["tor", "proxy", "cache_only", "http3"])]
+    timing: bool,
+    /// Probe IPv4 answers through this NAT64 /96 prefix [default: discovered on IPv6-only networks]
+    #[arg(long, global = true, value_name = "PREFIX", value_parser = nat64::parse_prefix)]
+    nat64: Option<Ipv6Addr>,
+    /// Append the results to this file, for `doh history` [default: history.db in the platform data directory]
+    #[arg(long, value_name = "PATH", num_args = 0..=1)]
+    record: Option<Option<PathBuf>>,
+    /// POST the results to this URL, and those of every --watch cycle and `doh exporter` round, e.g. a Slack or Discord webhook
+    #[arg(long, global = true, value_name = "URL", value_parser = webhook::parse_url)]
+    post_url: Option<String>,
+    /// What to post to --post-url
+    #[arg(long, global = true, value_enum, default_value_t = webhook::PostFormat::Json, requires = "post_url")]
+    post_format: webhook::PostFormat,
+    /// File with the body to post instead, `{{text}}` standing for the summary, escaped for a JSON string, and `{{json}}` for the JSON entries
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        requires = "post_url",
+        conflicts_with = "post_format"
+    )]
+    post_template: Option<PathBuf>,
+    /// Post every time, or only when a name's answers changed or its best latency degraded since the last cycle
+    #[arg(long, global = true, value_enum, default_value_t = webhook::PostOn::Always, requires = "post_url")]
+    post_on: webhook::PostOn,
+    /// Resolve and probe again on this interval, e.g. 30s or 5m, redrawing the table on a terminal
+    #[arg(long, value_name = "INTERVAL", value_parser = report::parse_duration)]
+    watch: Option<Duration>,
+    /// Only resolve, skipping the pings and the Avg and Lost columns
+    #[arg(long, conflicts_with_all = ["probe", "count", "interval", "fast", "ping_timeout", "size", "ping_hints"])]
+    no_ping: bool,
+    /// Also ping the ipv4hint and ipv6hint addresses of HTTPS and SVCB answers, in rows of their own
+    #[arg(long)]
+    ping_hints: bool,
+    /// Print only the answered address with the lowest mean latency, e.g. `ssh $(doh --best --host example.com)`
+    #[arg(long, conflicts_with_all = ["no_ping", "matrix"])]
+    best: bool,
+    /// Hide addresses that lost more pings than this, in percent; with --best, the most an address may lose to be picked [default: 20 with --best]
+    #[arg(long, value_parser = clap::value_parser!(u8).range(..=100))]
+    max_loss: Option<u8>,
+    /// With --best, print how each host's address was chosen instead of the address, as JSON (NDJSON with --output ndjson): the best address, every candidate's latency, loss and servers, why any was disqualified, and the metric that decided
+    #[arg(long, requires = "best")]
+    explain: bool,
+    /// Hide addresses slower than this mean latency, in milliseconds, or that didn't answer at all
+    #[arg(long, value_name = "MS", conflicts_with_all = ["no_ping", "best"])]
+    max_latency: Option<u32>,
+    /// Fail, with exit code 1, when no address of a host lost at most this many pings, in percent; hides the others like --max-loss
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(..=100), conflicts_with = "no_ping")]
+    fail_if_loss_above: Option<u8>,
+    /// Fail, with exit code 1, when no address of a host has a mean latency of at most this many milliseconds; hides the others like --max-latency
+    #[arg(long, value_name = "MS", conflicts_with = "no_ping")]
+    fail_if_latency_above: Option<u32>,
+    /// Which latency columns to show; the JSON output always has them all
+    #[arg(long, value_enum, default_value_t = StatsLevel::Basic, conflicts_with = "no_ping")]
+    stats: StatsLevel,
+    /// Show the results of one --host in an interactive terminal interface, filled in as the pings complete
+    #[arg(long, conflicts_with_all = ["hosts_file", "watch", "best", "matrix", "output"])]
+    tui: bool,
+    /// With --watch or --tui, count down in an Expires column and query again as soon as the first answer's TTL runs out; --watch's INTERVAL becomes the longest wait
+    #[arg(long)]
+    follow_ttl: bool,
+    /// With --watch and `doh serve`, wait a random time of up to MAX before each query, so the servers are asked at random times and in a random order, and move each --watch cycle by up to as much, e.g. 2s
+    #[arg(long, global = true, value_name = "MAX", value_parser = report::parse_duration)]
+    jitter: Option<Duration>,
+    /// With --watch and `doh serve`, also send this many queries for random popular names to random servers with every cycle or forwarded query, discarding their answers
+    #[arg(long, global = true, value_name = "COUNT", value_parser = clap::value_parser!(u32).range(1..=100))]
+    decoys: Option<u32>,
+    /// Print nothing but the results: no progress spinner, notices, warnings or per-server errors
+    #[arg(long, short, global = true)]
+    quiet: bool,
+    /// Print debug output: -v for the request URLs, -vv also for the response bodies and each ping
+    #[arg(long, short, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Order the rows by this instead of by server as they answered, ties going by server, type and data
+    #[arg(long, value_enum, conflicts_with = "best")]
+    sort: Option<SortKey>,
+    /// Echo requests per address [default: from the config, or 10]
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..=1000))]
+    count: Option<u16>,
+    /// Time between two echo requests, e.g. 0.5 or 200ms [default: from the config, or 1s]
+    #[arg(long, value_parser = parse_wait)]
+    interval: Option<Duration>,
+    /// How long to wait for each echo reply [default: from the config, or 1s]
+    #[arg(long, value_parser = parse_wait)]
+    ping_timeout: Option<Duration>,
+    /// Keep 3 echo requests per address in flight, 200ms apart, matching replies by sequence number, so that a run takes 2-3 seconds rather than 10
+    #[arg(long)]
+    fast: bool,
+    /// ICMP payload size in bytes [default: from the config, or 56]
+    #[arg(long, value_parser = clap::value_parser!(u16).range(..=65500))]
+    size: Option<u16>,
+    /// Measure latency as ICMP round-trip time, TCP connect time with `tcp[:PORT]`, or time to the first byte of a HEAD request over TLS with `https` [default: icmp, tcp without ICMP privileges]
+    #[arg(long, global = true, value_name = "METHOD", value_parser = config::parse_method)]
+    probe: Option<ProbeMethod>,
+    /// Send DoH queries through a Tor SOCKS proxy, on a separate circuit per server (pings still go direct)
+    #[arg(long, global = true, value_name = "ADDR", num_args = 0..=1, default_missing_value = "127.0.0.1:9050")]
+    tor: Option<String>,
+    /// Send DoH queries through this proxy, e.g. socks5://127.0.0.1:1080 or http://proxy:3128, or `none` to ignore HTTPS_PROXY and ALL_PROXY [default: from those variables]
+    #[arg(long, global = true, value_name = "URL", conflicts_with = "tor", value_parser = config::parse_proxy)]
+    proxy: Option<String>,
+    /// Send this HTTP header with every DoH request, e.g. 'Authorization: Bearer TOKEN' for a filtering service; repeatable
+    #[arg(long = "header", global = true, value_name = "NAME: VALUE", value_parser = config::parse_header)]
+    headers: Vec<(String, String)>,
+    /// Send this User-Agent header with DoH requests, which otherwise have none
+    #[arg(long, global = true, value_name = "UA", value_parser = config::parse_user_agent)]
+    user_agent: Option<String>,
+    /// Resolve the servers' host names through this plain DNS resolver, e.g. 223.5.5.5, instead of the system's, which may be the one DoH is to get around
+    #[arg(long, global = true, value_name = "IP[:PORT]", conflicts_with = "tor", value_parser = config::parse_bootstrap)]
+    bootstrap: Option<SocketAddr>,
+    /// Send queries and probes from this local address, e.g. 192.0.2.10 on a machine with several
+    #[arg(long, global = true, value_name = "IP")]
+    source: Option<IpAddr>,
+    /// Send queries and probes through this network interface, e.g. eth1, from its addresses unless --source gives one
+    #[arg(long, global = true, value_name = "NAME")]
+    interface: Option<String>,
+    /// Have at most this many DNS queries in flight at once, across every name and server [default: no limit]
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_concurrent_queries: Option<u32>,
+    /// Ping at most this many addresses at once [default: no limit]
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_concurrent_pings: Option<u32>,
+    /// Send each server at most this many queries a second [default: no limit]
+    #[arg(long, global = true, value_name = "QPS", value_parser = clap::value_parser!(u32).range(1..))]
+    max_qps: Option<u32>,
+    /// Also trust the root certificates in this PEM file, e.g. a private CA (repeatable)
+    #[arg(long, global = true, value_name = "PATH")]
+    ca_cert: Vec<String>,
+    /// Send this name as TLS SNI and HTTP Host instead of the server's, still connecting to the server's address
+    #[arg(long, global = true, value_name = "NAME")]
+    sni: Option<String>,
+    /// INSECURE: accept any TLS certificate, expired, self-signed or for another name
+    #[arg(long, global = true)]
+    insecure: bool,
+    /// Query the DoH servers over HTTP/3 (QUIC), in builds with the http3 feature
+    #[arg(long, global = true, conflicts_with_all = ["tor", "proxy"])]
+    http3: bool,
+    /// How wire-format queries are sent: GET with the message in the URL, or POST with it as the body
+    #[arg(long, value_enum, global = true, default_value_t = HttpMethod::Get)]
+    http_method: HttpMethod,
+    /// Always ask the servers, even for answers still fresh from an earlier query
+    #[arg(long, global = true, conflicts_with_all = ["cache_only", "cache_file"])]
+    no_cache: bool,
+    /// Only answer from the cache, failing the queries without a fresh answer there
+    #[arg(long, global = true)]
+    cache_only: bool,
+    /// Keep the cached answers in this file across runs [default: cache.json in the platform cache directory]
+    #[arg(long, global = true, value_name = "PATH", num_args = 0..=1)]
+    cache_file: Option<Option<PathBuf>>,
+    /// Send a failed query again up to this many times, waiting longer after each failure
+    #[arg(long, global = true, default_value_t = 0, value_parser = clap::value_parser!(u32).range(0..=10))]
+    retries: u32,
+    /// Give up on a request after this long, e.g. 3s or 500ms
+    #[arg(long, global = true, value_name = "DURATION", value_parser = report::parse_duration)]
+    query_timeout: Option<Duration>,
+    /// Stop the whole run after this long and print what's done, as with Ctrl+C, e.g. 30s
+    #[arg(long, global = true, value_name = "DURATION", value_parser = report::parse_duration)]
+    deadline: Option<Duration>,
+    /// Server to query instead of the configured ones, `URL` or `URL=TAG`, or `system` for the OS resolver (repeatable)
+    #[arg(long = "server", global = true, value_name = "URL[=TAG]", value_parser = config::parse_server)]
+    servers: Vec<config::Server>,
+    /// File with one `URL` or `URL=TAG` server per line, `-` for stdin
+    #[arg(long, global = true, value_name = "PATH")]
+    servers_file: Option<String>,
+    /// Public resolver list to query the servers of, a URL or path: the curl wiki's DoH table or
+    /// dnscrypt-proxy's public-resolvers.md
+    #[arg(long, global = true, value_name = "URL")]
+    server_list: Option<String>,
+    /// Only query --server-list servers whose name or URL contains this, ignoring case (repeatable)
+    #[arg(long, global = true, value_name = "TEXT", requires = "server_list")]
+    server_filter: Vec<String>,
+    /// Query at most this many --server-list servers, after --server-filter
+    #[arg(long, global = true, value_name = "N", requires = "server_list")]
+    max_servers: Option<usize>,
+    /// Query --server, --servers-file and --server-list servers in addition to the configured ones
+    #[arg(long, global = true)]
+    keep_servers: bool,
+    /// Config file with servers and probe settings [default: platform config directory]
+    #[arg(long, global = true)]
+    config: Option<String>,
+    /// Maximum number of table rows to print, 0 for no limit
+    #[arg(long, global = true, default_value_t = 100)]
+    limit: usize,
+    /// Page the output through $PAGER
+    #[arg(long, global = true)]
+    pager: bool,
+    /// Output format [default: table]
+    #[arg(long, global = true, value_enum)]
+    output: Option<Format>,
+    /// Write the results to this file instead of stdout, replacing it atomically
+    #[arg(long, global = true, value_name = "PATH")]
+    output_file: Option<PathBuf>,
+    /// Comma-separated JSON fields to keep; prefix with `-` to drop a field instead
+    #[arg(long, global = true, value_delimiter = ',', allow_hyphen_values = true)]
+    fields: Vec<String>,
+    /// When to colorize output (NO_COLOR is honored in auto mode)
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    /// Color theme for latency and loss cells
+    #[arg(long, global = true, value_enum, default_value_t = Theme::Dark)]
+    theme: Theme,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[allow(non_snake_case)]
-#[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct Answer {
-    /// The name of the record.
-    pub name: String,
-    /// The type associated with each record. To convert to a string representation use
-    /// https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#dns-parameters-4
-    pub r#type: u32,
-    /// The time to live in seconds for this record.
-    pub TTL: u32,
-    /// The data associated with the record.
-    pub data: String,
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    /// Check that a batch of records has propagated to every DoH server
+    Propagation {
+        /// Zone file or list of `name type expected` lines, `-` for stdin
+        file: String,
+    },
+    /// Probe candidate addresses directly, skipping DNS resolution
+    PingIps {
+        /// Addresses to probe
+        #[arg(required_unless_present = "ips_file")]
+        ips: Vec<IpAddr>,
+        /// File with one address per line, `-` for stdin
+        #[arg(long)]
+        ips_file: Option<String>,
+    },
+    /// Resolve a name iteratively from the root servers, showing each delegation step
+    Trace {
+        /// Name to resolve
+        name: String,
+        /// Record type to ask for
+        #[arg(long = "type", default_value = "A", value_parser = wire::parse_type)]
+        rtype: String,
+        /// Start from these servers instead of the root servers
+        #[arg(long)]
+        root: Vec<IpAddr>,
+    },
+    /// Check which servers block ad, malware and adult test domains
+    FilterTest {
+        /// File of `category domain` lines to test instead of the built-in list, `-` for stdin
+        #[arg(long)]
+        list: Option<String>,
+    },
+    /// Check which servers filter answers pointing at private addresses (rebinding protection)
+    RebindTest,
+    /// Sample every server for a while and check availability and latency against SLO targets
+    Report {
+        /// Name to query
+        name: String,
+        /// How long to sample for, e.g. 30s, 5m or 1h
+        #[arg(long, default_value = "5m", value_parser = report::parse_duration)]
+        duration: Duration,
+        /// Time between two samples
+        #[arg(long, default_value = "30s", value_parser = report::parse_duration)]
+        every: Duration,
+        /// Target to meet, `availability=99.9` or `p95=200ms`; repeat for several
+        #[arg(long = "slo", value_name = "TARGET", value_parser = report::parse_target)]
+        targets: Vec<report::Target>,
+    },
+    /// Live leaderboard of the servers by rolling latency, success rate and divergence
+    Top {
+        /// Names to query in turn [default: a few popular domains]
+        names: Vec<String>,
+        /// Time between two rounds of queries
+        #[arg(long, default_value = "2s", value_parser = report::parse_duration)]
+        every: Duration,
+        /// Queries per server the statistics are computed over
+        #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+        window: u64,
+    },
+    /// Map names to their fastest address in the hosts file, in a block of entries of its own
+    Hosts {
+        /// Name to resolve (repeatable)
+        #[arg(long = "host", required = true)]
+        hosts: Vec<String>,
+        /// Update the hosts file, keeping the previous version next to it as `hosts.doh.bak`
+        #[arg(long, conflicts_with = "dry_run")]
+        write: bool,
+        /// Show what --write would change without touching the file
+        #[arg(long)]
+        dry_run: bool,
+        /// Hosts file to update [default: /etc/hosts, or the one in System32 on Windows]
+        #[arg(long, value_name = "PATH")]
+        file: Option<PathBuf>,
+        /// Highest packet loss, in percent, an address may have to be picked
+        #[arg(long, default_value_t = best::MAX_LOSS, value_parser = clap::value_parser!(u8).range(..=100))]
+        max_loss: u8,
+    },
+    /// Show the records only one of two servers answered, or that changed since a saved run, and the ones they share
+    Diff {
+        /// Name to query
+        #[arg(long = "host")]
+        host: String,
+        /// Record types to query, comma-separated or repeated
+        #[arg(long = "type", value_name = "TYPE", value_delimiter = ',', default_value = "A,AAAA", value_parser = wire::parse_type)]
+        rtypes: Vec<String>,
+        /// Save the answers of this run to a file, to compare later runs with
+        #[arg(long, value_name = "PATH")]
+        save: Option<PathBuf>,
+        /// Compare the answers of every server with a run saved with --save instead of two servers with each other
+        #[arg(long, value_name = "PATH")]
+        against: Option<PathBuf>,
+    },
+    /// Send the same wire-format queries to every DoH server by GET and by POST, and compare the latency and answers
+    MethodTest {
+        /// Name to query
+        #[arg(long = "host")]
+        host: String,
+        /// Record type to query
+        #[arg(long = "type", value_name = "TYPE", default_value = "A", value_parser = wire::parse_type)]
+        rtype: String,
+        /// Queries per server and method
+        #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..=1000))]
+        queries: u32,
+    },
+    /// Send the same queries to every DoH server through its JSON API and the wire format, and compare the latency and answers
+    ApiTest {
+        /// Name to query
+        #[arg(long = "host")]
+        host: String,
+        /// Record type to query
+        #[arg(long = "type", value_name = "TYPE", default_value = "A", value_parser = wire::parse_type)]
+        rtype: String,
+        /// Queries per server and API
+        #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..=1000))]
+        queries: u32,
+    },
+    /// Show the runs recorded with --record for a name: latency, loss and how the answers changed over time
+    History {
+        /// Name to show the runs of
+        #[arg(long = "host")]
+        host: String,
+        /// File the runs were recorded in [default: history.db in the platform data directory]
+        #[arg(long, value_name = "PATH")]
+        file: Option<PathBuf>,
+    },
+    /// Time the servers themselves over a series of queries and rank them by latency
+    Bench {
+        /// Names to query in turn [default: a few popular domains]
+        names: Vec<String>,
+        /// Queries per server
+        #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..=10000))]
+        queries: u32,
+        /// Connect to each server with a first query, timed apart, and send the others over that
+        /// connection, so the handshake doesn't count towards their latency
+        #[arg(long)]
+        warmup: bool,
+        /// Also time, next to each query, one for a random name under it that no cache has, and
+        /// show the p50 of both
+        #[arg(long)]
+        cold: bool,
+        /// Queries each server is sent per second, at most, rather than each once the last is
+        /// answered
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..=100000))]
+        qps: Option<u32>,
+        /// Queries in flight to each server at once
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=1000))]
+        connections: u32,
+    },
+    /// Resolve and probe names on an interval and serve the results as Prometheus metrics
+    Exporter {
+        /// Address to serve /metrics on
+        #[arg(long, default_value = "0.0.0.0:9953")]
+        listen: SocketAddr,
+        /// Name to resolve (repeatable)
+        #[arg(long = "host", required = true)]
+        hosts: Vec<String>,
+        /// Time between two rounds of resolving and probing, e.g. 60s or 5m
+        #[arg(long, default_value = "60s", value_parser = report::parse_duration)]
+        interval: Duration,
+    },
+    /// Write systemd units running doh for good: `serve` and `exporter` as services started by
+    /// a socket on their port, any other command as a service a timer runs on an interval
+    Daemon {
+        /// Write the units into the systemd unit directory rather than print them
+        #[arg(long)]
+        install: bool,
+        /// User units, in ~/.config/systemd/user, rather than system ones in /etc/systemd/system
+        #[arg(long)]
+        user: bool,
+        /// Name of the units [default: doh-serve, doh-exporter or doh]
+        #[arg(long)]
+        name: Option<String>,
+        /// How often the timer runs a command other than `serve` and `exporter`
+        #[arg(long, default_value = "5m", value_parser = report::parse_duration)]
+        interval: Duration,
+        /// The doh arguments to run, after `--`, e.g. `-- serve --listen 127.0.0.1:53`
+        #[arg(last = true, required = true, value_name = "ARGS")]
+        args: Vec<String>,
+    },
+    /// Answer plain DNS queries over UDP and TCP by forwarding them to the DoH servers
+    Serve {
+        /// Address to answer DNS queries on
+        #[arg(long, default_value = "127.0.0.1:5353")]
+        listen: SocketAddr,
+        /// Which servers each query goes to
+        #[arg(long, value_enum, default_value_t = serve::Strategy::Race)]
+        strategy: serve::Strategy,
+    },
+    /// Print the JSON Schema of the JSON/NDJSON output
+    Schema {
+        /// Which command's output to describe
+        #[arg(value_enum, default_value_t = SchemaKind::Query)]
+        kind: SchemaKind,
+    },
+    /// Manage the provider quirks database
+    Servers {
+        #[command(subcommand)]
+        action: ServersAction,
+    },
+    /// Manage the config file
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Print the completion script for a shell, e.g. `doh completions bash > /etc/bash_completion.d/doh`
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Print the man page, in roff
+    #[command(hide = true)]
+    Man,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum ServersAction {
+    /// Download the latest provider quirks database
+    Refresh {
+        /// Where to download it from
+        #[arg(long, default_value = quirks::DEFAULT_URL)]
+        url: String,
+    },
+    /// Show the scores learned from past runs, best server first
+    Rank,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum ConfigAction {
+    /// Check the config file and report every problem
+    Validate,
+    /// Write a starter config file to --config or the platform config directory
+    Init(InitArgs),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum StatsLevel {
+    /// Average latency and loss
+    Basic,
+    /// Also the minimum, maximum, median, 95th percentile, standard deviation and jitter
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum GroupBy {
+    /// A row per server and answer
+    Server,
+    /// A row per address, or other record, listing the servers that returned it
+    Ip,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SortKey {
+    /// Lowest mean latency first, addresses that didn't answer last
+    Latency,
+    /// Lowest packet loss first
+    Loss,
+    /// Lowest TTL first, the answers that expire soonest
+    Ttl,
+    /// By server tag
+    Server,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum SchemaKind {
+    /// `doh --host`
+    Query,
+    /// `doh ping-ips`
+    PingIps,
+    /// `doh propagation`
+    Propagation,
+    /// `doh trace`
+    Trace,
+    /// `doh filter-test`
+    FilterTest,
+    /// `doh rebind-test`
+    RebindTest,
+    /// `doh report`
+    Report,
+    /// `doh bench`
+    Bench,
+    /// `doh diff`
+    Diff,
+    /// `doh history`
+    History,
+    /// `doh method-test`
+    MethodTest,
+    /// `doh api-test`
+    ApiTest,
+    /// `doh --best --explain`
+    Explain,
+}
+
+/// How wire-format queries are sent (RFC 8484 §4.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum HttpMethod {
+    Get,
+    Post,
+}
+
+#[derive(Clone)]
 pub struct Row {
     pub answer: Answer,
     pub mean: i32,
     pub lost: f32,
+    /// False when probing was interrupted before all echo requests were sent.
+    pub complete: bool,
+    /// False for records that aren't addresses, which have nothing to ping.
+    pub probed: bool,
+    /// Why the answer could not be probed; the other stats are meaningless then.
+    pub error: Option<String>,
+    /// Share of `--rounds` queries that returned this answer.
+    pub seen: Option<f32>,
+    /// Whether the server validated the answer, with `--dnssec`.
+    pub secure: Option<bool>,
+    /// What still answered when every ping was lost.
+    pub reachable: Option<Layer>,
+    /// Spread of the round-trip times, when any ping was answered.
+    pub stats: Option<Stats>,
+    /// Phases of the requests of `--probe https`, when any got a response.
+    pub phases: Option<Phases>,
+    /// Whether the address is a hint of an HTTPS or SVCB answer, with `--ping-hints`.
+    pub hint: bool,
+    /// CNAMEs followed from the name queried to this record's, in order.
+    pub via: Vec<String>,
+    /// What the server's response came over, `HTTP/2` or `DoT` say.
+    pub protocol: &'static str,
+    /// The other servers that returned the same record, merged into this row by `--group-by ip`.
+    pub also: Vec<String>,
+    /// When the answer's TTL runs out, counted from when the response came in.
+    pub expires: Option<Instant>,
 }
 
-#[allow(non_snake_case)]
-#[derive(Deserialize, Serialize, Debug, Clone)]
-struct DnsResponse {
-    Status: u32,
-    Answer: Option<Vec<Answer>>,
-    Comment: Option<String>,
+impl Row {
+    /// A row for an answer that wasn't pinged, with its chain and protocol left to fill in.
+    fn unprobed(answer: Answer, seen: Option<f32>, secure: Option<bool>) -> Self {
+        Self {
+            answer,
+            mean: -1,
+            lost: 0.0,
+            complete: true,
+            probed: false,
+            error: None,
+            seen,
+            secure,
+            reachable: None,
+            stats: None,
+            phases: None,
+            hint: false,
+            via: vec![],
+            protocol: "",
+            also: vec![],
+            expires: None,
+        }
+    }
+}
+
+/// A result row with raw values, for machine-readable output.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Entry {
+    /// The `--host` queried.
+    pub host: String,
+    pub server: String,
+    /// Every server that returned the record: `server` alone, or with `--group-by ip` each one,
+    /// `server` first.
+    pub returned_by: Vec<String>,
+    pub name: String,
+    /// CNAMEs followed from the name queried to `name`, in order, empty for a direct answer.
+    pub chain: Vec<String>,
+    #[serde(rename = "type")]
+    pub r#type: u32,
+    pub ttl: u32,
+    pub address: String,
+    /// Name of the `--range` the address falls into.
+    pub range: Option<String>,
+    /// Holder of the address's network, with `--rdap`.
+    pub owner: Option<Owner>,
+    /// Location and network of the address, with `--geo`.
+    pub geo: Option<Geo>,
+    /// Share of the `--rounds` queries that returned this answer, from 0 to 1.
+    pub seen: Option<f32>,
+    /// Remaining TTL as a share of the highest TTL another server gave, with `--freshness`.
+    pub freshness: Option<f32>,
+    /// Whether the server validated the answer with DNSSEC (its AD flag), with `--dnssec`.
+    pub secure: Option<bool>,
+    /// Mean round-trip time, absent when no probe was answered.
+    pub avg_ms: Option<i32>,
+    /// Ratio of lost probes, from 0 to 1, absent when the answer was not probed.
+    pub loss: Option<f32>,
+    /// Minimum, maximum, median, 95th percentile, standard deviation and jitter of the round
+    /// trips, absent when no probe was answered.
+    pub stats: Option<Stats>,
+    /// Mean TCP connect, TLS handshake and time to first byte, with `--probe https`.
+    pub https: Option<Phases>,
+    /// The priority, target and parameters of an HTTPS or SVCB answer.
+    pub svcb: Option<Svcb>,
+    /// Whether the address is an `ipv4hint` or `ipv6hint` of an HTTPS or SVCB answer, with
+    /// `--ping-hints`.
+    pub hint: bool,
+    /// False when the run was interrupted before all probes were sent.
+    pub complete: bool,
+    /// What still answered over TCP or TLS, when every ping was lost.
+    pub reachable: Option<Layer>,
+    /// Why the answer could not be probed, or, with an empty address, why the server gave no
+    /// answer at all: its error, or a response code such as `NXDOMAIN`.
+    pub error: Option<String>,
+    /// What the server's response came over: `HTTP/1.1`, `HTTP/2`, `HTTP/3`, `DoT`, `UDP` or `TCP`.
+    pub protocol: String,
+    /// Phases of the server's DoH request, with `--timing`.
+    pub timing: Option<Timing>,
+}
+
+impl Entry {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        host: &str,
+        tag: &str,
+        r: &Row,
+        range: Option<&str>,
+        owner: Option<&Owner>,
+        geo: Option<&Geo>,
+        freshness: Option<f32>,
+        timing: Option<Timing>,
+    ) -> Self {
+        Self {
+            host: String::from(host),
+            server: String::from(tag),
+            returned_by: iter::once(String::from(tag))
+                .chain(r.also.iter().cloned())
+                .collect(),
+            name: r.answer.name.clone(),
+            chain: r.via.clone(),
+            r#type: r.answer.r#type,
+            ttl: r.answer.TTL,
+            address: r.answer.data.clone(),
+            range: range.map(String::from),
+            owner: owner.cloned(),
+            geo: geo.cloned(),
+            seen: r.seen,
+            freshness,
+            secure: r.secure,
+            avg_ms: (r.mean != -1).then_some(r.mean),
+            loss: (r.probed && r.error.is_none()).then_some(r.lost),
+            stats: r.stats,
+            https: r.phases,
+            svcb: svcb::TYPES
+                .contains(&r.answer.r#type)
+                .then(|| Svcb::parse(&r.answer.data))
+                .flatten(),
+            hint: r.hint,
+            complete: r.complete,
+            reachable: r.reachable,
+            error: r.error.clone(),
+            protocol: String::from(r.protocol),
+            timing,
+        }
+    }
+
+    /// The entry of a server without any answer, for `reason`.
+    pub fn failed(host: &str, tag: &str, reason: &str) -> Self {
+        Self {
+            host: String::from(host),
+            server: String::from(tag),
+            returned_by: vec![String::from(tag)],
+            name: String::from(host),
+            chain: vec![],
+            r#type: 0,
+            ttl: 0,
+            address: String::new(),
+            range: None,
+            owner: None,
+            geo: None,
+            seen: None,
+            freshness: None,
+            secure: None,
+            avg_ms: None,
+            loss: None,
+            stats: None,
+            https: None,
+            svcb: None,
+            hint: false,
+            complete: true,
+            reachable: None,
+            error: Some(String::from(reason)),
+            protocol: String::new(),
+            timing: None,
+        }
+    }
 }
 
 #[allow(non_snake_case)]
@@ -68,133 +858,2151 @@ pub struct Record {
     #[tabled(rename = "Name")]
     pub name: String,
     #[tabled(rename = "Type")]
-    pub r#type: u32,
-    pub TTL: u32,
+    pub r#type: String,
+    pub TTL: String,
+    #[tabled(rename = "Expires")]
+    pub expires: String,
+    #[tabled(rename = "Priority")]
+    pub priority: String,
     #[tabled(rename = "Address")]
     pub data: String,
+    #[tabled(rename = "Range")]
+    pub range: String,
+    #[tabled(rename = "Owner")]
+    pub owner: String,
+    #[tabled(rename = "Country")]
+    pub country: String,
+    #[tabled(rename = "City")]
+    pub city: String,
+    #[tabled(rename = "ASN")]
+    pub asn: String,
+    #[tabled(rename = "Seen")]
+    pub seen: String,
+    #[tabled(rename = "Fresh")]
+    pub freshness: String,
     #[tabled(rename = "Avg")]
     pub mean: String,
     #[tabled(rename = "Lost")]
     pub lost: String,
+    #[tabled(rename = "Min")]
+    pub min: String,
+    #[tabled(rename = "Max")]
+    pub max: String,
+    #[tabled(rename = "P50")]
+    pub p50: String,
+    #[tabled(rename = "P95")]
+    pub p95: String,
+    #[tabled(rename = "StdDev")]
+    pub stddev: String,
+    #[tabled(rename = "Jitter")]
+    pub jitter: String,
+    #[tabled(rename = "TCP")]
+    pub connect: String,
+    #[tabled(rename = "TLS")]
+    pub tls: String,
+    #[tabled(rename = "TTFB")]
+    pub ttfb: String,
+    #[tabled(rename = "Secure")]
+    pub secure: String,
+    #[tabled(rename = "Protocol")]
+    pub protocol: String,
 }
 
 impl Record {
-    pub fn new(tag: &str, r: Row) -> Self {
+    pub fn new(
+        tag: &str,
+        r: Row,
+        range: Option<&str>,
+        owner: Option<&Owner>,
+        geo: Option<&Geo>,
+        freshness: Option<f32>,
+        paint: &Paint,
+    ) -> Self {
+        let (data, mean, lost) = match r.error {
+            Some(e) => (
+                format!("{} ({})", r.answer.data, e),
+                String::from("-"),
+                String::from("-"),
+            ),
+            None if !r.probed => (r.answer.data, String::from("-"), String::from("-")),
+            None => (
+                r.answer.data,
+                paint.latency(r.mean, format_mean(r.mean, r.complete)),
+                paint.loss(r.lost, format_lost(r.lost, r.complete, r.reachable)),
+            ),
+        };
+        let stat = |pick: fn(&Stats) -> f64| {
+            r.stats
+                .as_ref()
+                .map_or_else(|| String::from("-"), |s| format!("{:.1}ms", pick(s)))
+        };
+        let (min, max, p50, p95, stddev, jitter) = (
+            stat(|s| s.min_ms),
+            stat(|s| s.max_ms),
+            stat(|s| s.p50_ms),
+            stat(|s| s.p95_ms),
+            stat(|s| s.stddev_ms),
+            stat(|s| s.jitter_ms),
+        );
+        let phase = |pick: fn(&Phases) -> f64| {
+            r.phases
+                .as_ref()
+                .map_or_else(|| String::from("-"), |p| format!("{:.1}ms", pick(p)))
+        };
+        let (connect, tls, ttfb) = (
+            phase(|p| p.connect_ms),
+            phase(|p| p.tls_ms),
+            phase(|p| p.ttfb_ms),
+        );
+        // MX data is `preference exchange`, show the preference on its own
+        let (priority, data) = match (r.answer.r#type, data.split_once(' ')) {
+            (MX, Some((priority, exchange))) => (String::from(priority), String::from(exchange)),
+            _ => (String::from("-"), data),
+        };
         Self {
-            DoH: String::from(tag),
-            name: r.answer.name,
-            r#type: r.answer.r#type,
-            TTL: r.answer.TTL,
-            data: r.answer.data,
-            mean: if r.mean == -1 {
-                String::from("/")
+            DoH: iter::once(tag)
+                .chain(r.also.iter().map(String::as_str))
+                .collect::<Vec<_>>()
+                .join(", "),
+            // `www.example.com. → cdn.example.net.` for an answer reached through a CNAME
+            name: r
+                .via
+                .iter()
+                .chain([&r.answer.name])
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(" → "),
+            r#type: if r.hint {
+                format!("{} hint", wire::type_name(r.answer.r#type))
             } else {
-                format!("{}ms", r.mean)
+                wire::type_name(r.answer.r#type)
             },
-            lost: format!("{}%", (r.lost * 100.0)),
+            TTL: r.answer.TTL.to_string(),
+            expires: r.expires.map_or_else(
+                || String::from("-"),
+                |at| match at.saturating_duration_since(Instant::now()) {
+                    Duration::ZERO => String::from("expired"),
+                    left => format!("in {}s", left.as_secs_f64().ceil()),
+                },
+            ),
+            priority,
+            data,
+            range: String::from(range.unwrap_or("-")),
+            owner: owner.map_or_else(|| String::from("-"), Owner::label),
+            country: geo
+                .and_then(|g| g.country.clone())
+                .unwrap_or_else(|| String::from("-")),
+            city: geo
+                .and_then(|g| g.city.clone())
+                .unwrap_or_else(|| String::from("-")),
+            asn: geo.map_or_else(|| String::from("-"), Geo::asn_label),
+            seen: r
+                .seen
+                .map_or_else(|| String::from("-"), |s| format!("{:.0}%", s * 100.0)),
+            freshness: freshness.map_or_else(
+                || String::from("-"),
+                |f| paint.freshness(f, format!("{:.0}%", f * 100.0)),
+            ),
+            mean,
+            lost,
+            min,
+            max,
+            p50,
+            p95,
+            stddev,
+            jitter,
+            connect,
+            tls,
+            ttfb,
+            secure: r.secure.map_or_else(
+                || String::from("-"),
+                |s| paint.status(s, String::from(if s { "yes" } else { "no" })),
+            ),
+            protocol: String::from(r.protocol),
+        }
+    }
+
+    /// The row of a server without any answer, for `reason`.
+    pub fn failed(tag: &str, reason: &str, paint: &Paint) -> Self {
+        let none = || String::from("-");
+        Self {
+            DoH: String::from(tag),
+            name: none(),
+            r#type: none(),
+            TTL: none(),
+            expires: none(),
+            priority: none(),
+            data: paint.status(false, String::from(reason)),
+            range: none(),
+            owner: none(),
+            country: none(),
+            city: none(),
+            asn: none(),
+            seen: none(),
+            freshness: none(),
+            mean: none(),
+            lost: none(),
+            min: none(),
+            max: none(),
+            p50: none(),
+            p95: none(),
+            stddev: none(),
+            jitter: none(),
+            connect: none(),
+            tls: none(),
+            ttfb: none(),
+            secure: none(),
+            protocol: none(),
         }
     }
 }
 
-async fn get_ip(hostname: &str, server: &str) -> Result<Vec<Row>, Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
-    let url = format!("{}?name={}&type={}", server, hostname, "A");
-    let res = client
-        .get(url)
-        .header("Accept", "application/dns-json")
-        .send()
-        .await?;
+/// Raw probe result of a single address, for machine-readable output.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ProbeEntry {
+    pub address: IpAddr,
+    pub avg_ms: Option<i32>,
+    /// Ratio of lost pings, absent when none could be sent (ICMP unavailable).
+    pub loss: Option<f32>,
+    pub complete: bool,
+    /// What still answered over TCP or TLS, when every ping was lost.
+    pub reachable: Option<Layer>,
+    /// Spread of the round-trip times, absent when no ping was answered.
+    pub stats: Option<Stats>,
+}
 
-    let mut data: Vec<Answer> = vec![];
-    let body = res.json::<DnsResponse>().await?;
-    if let Some(answer) = body.Answer {
-        data.extend(answer);
+/// Record type codes of addresses, the only answers that get pinged.
+const A: u32 = 1;
+const AAAA: u32 = 28;
+const CNAME: u32 = 5;
+const MX: u32 = 15;
+
+/// Marks cells of rows whose probing was cut short by Ctrl+C.
+const PARTIAL: &str = "*";
+/// Echo requests in flight at once per address with `--fast`...
+const FAST_WINDOW: usize = 3;
+/// ...and the time between two of them, unless `--interval` says otherwise.
+const FAST_INTERVAL: Duration = Duration::from_millis(200);
+
+fn format_mean(mean: i32, complete: bool) -> String {
+    let mark = if complete { "" } else { PARTIAL };
+    if mean == -1 {
+        format!("/{}", mark)
+    } else {
+        format!("{}ms{}", mean, mark)
     }
+}
 
-    let mut items = vec![];
-    let client = Client::new(&Config::default())?;
-    for item in data.clone() {
-        let ip_v4 = Ipv4Addr::from_str(item.data.as_str())?;
-        let (mean, lost) = ping(client.clone(), IpAddr::V4(ip_v4)).await;
-        items.push(Row {
-            answer: item,
-            mean,
-            lost,
-        });
+fn format_lost(lost: f32, complete: bool, reachable: Option<Layer>) -> String {
+    let mark = if complete { "" } else { PARTIAL };
+    match reachable {
+        Some(layer) => format!("{}%{} ({})", (lost * 100.0), mark, layer.label()),
+        None => format!("{}%{}", (lost * 100.0), mark),
     }
-    Ok(items)
 }
 
-const MAX_PING: u16 = 10;
+/// The current time in UTC, as `2006-01-02 15:04:05 UTC`.
+fn utc_now() -> String {
+    utc(unix_now())
+}
+
+/// Seconds since the Unix epoch.
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
 
-fn mean(data: &[i32]) -> Option<f32> {
-    let sum = data.iter().sum::<i32>() as f32;
-    let count = data.len();
+/// `secs` since the Unix epoch in UTC, as `2006-01-02 15:04:05 UTC`.
+fn utc(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // civil date from days since the epoch, after Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
 
-    match count {
-        positive if positive > 0 => Some(sum / count as f32),
-        _ => None,
+/// Start the output of a `--watch` cycle: redraw from the top on a terminal, otherwise mark where
+/// the cycle starts with `stamp`.
+fn watch_heading(every: Duration, follow_ttl: bool, output: &Output, stamp: bool) {
+    if output.file.is_some() {
+        return;
+    }
+    if std::io::stdout().is_terminal() {
+        print!("\x1b[2J\x1b[H");
+        let when = if follow_ttl {
+            format!("as answers expire, at least every {:?}", every)
+        } else {
+            format!("every {:?}", every)
+        };
+        println!(
+            "doh --watch: {}, updated {} (Ctrl+C to quit)",
+            when,
+            utc_now()
+        );
+    } else if stamp {
+        println!("{}", utc_now());
     }
 }
 
-async fn ping(client: Client, addr: IpAddr) -> (i32, f32) {
-    let payload = [0; 56];
-    let mut pinger = client.pinger(addr, PingIdentifier(random())).await;
-    pinger.timeout(Duration::from_secs(1));
-    let mut interval = time::interval(Duration::from_secs(1));
-    let mut times = vec![];
-    let mut lost = 0.0;
-    for idx in 0..MAX_PING {
-        interval.tick().await;
-        let res = pinger.ping(PingSequence(idx), &payload).await;
-        if let Ok((_, dur)) = res {
-            times.push(dur.as_millis() as i32);
-        } else {
-            lost += 1.0;
+/// Footnote explaining the partial marker, if the run was interrupted.
+fn partial_note(table: String) -> String {
+    if interrupt::interrupted() {
+        format!(
+            "{}\n{} interrupted before all probes completed",
+            table, PARTIAL
+        )
+    } else {
+        table
+    }
+}
+
+/// An answer, how many rounds returned it, and whether every response with it was authenticated.
+type Pooled = (Answer, u32, bool);
+
+/// Pause between two `--rounds` queries to the same server.
+const ROUND_DELAY: Duration = Duration::from_millis(200);
+
+/// The pooled answers of one server, what its first response came over, its timing and the
+/// response code of its first round.
+type Pool = (Vec<Pooled>, &'static str, Option<Timing>, Status);
+
+/// The response code of a server's first round, with the comment it came with.
+#[derive(Debug, Clone, Default)]
+pub struct Status {
+    pub code: u32,
+    pub comment: Option<String>,
+}
+
+impl Status {
+    /// Why the server gave no answer: `NXDOMAIN` say, or `NOERROR, no records` when the name has
+    /// none of the types asked for, followed by the server's comment.
+    pub fn reason(&self) -> String {
+        let name = match self.code {
+            0 => String::from("NOERROR, no records"),
+            code => wire::rcode_name(code as u8),
+        };
+        match &self.comment {
+            Some(comment) => format!("{}: {}", name, comment),
+            None => name,
         }
     }
-    (
-        mean(&times[..]).map_or(-1, |i| i as i32),
-        lost / MAX_PING as f32,
-    )
+}
+
+/// Ask `server` for every type in `rtypes`, `rounds` times, and merge the answers, counting how
+/// many rounds returned each, since round-robin records only show part of their pool at a time.
+async fn pool(
+    hostname: &str,
+    server: &str,
+    rtypes: &[&str],
+    rounds: u32,
+    transport: &Transport,
+) -> Result<Pool, Box<dyn std::error::Error>> {
+    let mut pool: Vec<Pooled> = vec![];
+    let mut protocol = "";
+    let mut timing = None;
+    let mut status = Status::default();
+    for round in 0..rounds {
+        if round > 0 {
+            time::sleep(ROUND_DELAY).await;
+        }
+        let lookups = future::try_join_all(
+            rtypes
+                .iter()
+                .map(|rtype| exchange(hostname, server, rtype, transport)),
+        );
+        let lookups = match lookups.await {
+            Ok(lookups) => lookups,
+            Err(e) if round == 0 => return Err(e),
+            // keep the pool gathered so far
+            Err(e) => {
+                error!("{} round {} error: {}", server, round + 1, e);
+                break;
+            }
+        };
+        timing = timing.or_else(|| lookups.iter().find_map(|l| l.timing));
+        if round == 0 {
+            protocol = lookups.first().map_or("", |l| l.protocol);
+            // NXDOMAIN, say, comes back for every type
+            let first = lookups.iter().find(|l| l.status != 0);
+            status = Status {
+                code: first.map_or(0, |l| l.status),
+                comment: first
+                    .and_then(|l| l.comment.clone())
+                    .or_else(|| lookups.iter().find_map(|l| l.comment.clone())),
+            };
+        }
+        let mut counted = vec![];
+        for lookup in lookups {
+            for answer in lookup.answers {
+                let known = pool
+                    .iter()
+                    .position(|(a, ..)| a.r#type == answer.r#type && a.data == answer.data);
+                match known {
+                    Some(idx) if counted.contains(&idx) => pool[idx].2 &= lookup.authenticated,
+                    Some(idx) => {
+                        pool[idx].1 += 1;
+                        pool[idx].2 &= lookup.authenticated;
+                        counted.push(idx);
+                    }
+                    None => {
+                        counted.push(pool.len());
+                        pool.push((answer, 1, lookup.authenticated));
+                    }
+                }
+            }
+        }
+    }
+    Ok((pool, protocol, timing, status))
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+/// The CNAMEs of `aliases` that lead to `name`, starting from the one for the name queried.
+fn chain(aliases: &[Answer], name: &str) -> Vec<String> {
+    let mut via = vec![];
+    let mut name = name;
+    // bounded by the number of aliases, in case they loop
+    for _ in aliases {
+        let Some(alias) = aliases.iter().find(|a| same_name(&a.data, name)) else {
+            break;
+        };
+        via.insert(0, alias.name.clone());
+        name = &alias.name;
+    }
+    via
+}
+
+/// Fold the CNAMEs among `data` into the records they lead to, keeping only the ones a chain
+/// ends with, as when a server returned the alias but no address, unless CNAMEs were asked for.
+fn fold_aliases(data: Vec<Pooled>, rtypes: &[&str]) -> Vec<(Pooled, Vec<String>)> {
+    if rtypes.iter().any(|t| t.eq_ignore_ascii_case("CNAME")) {
+        return data.into_iter().map(|p| (p, vec![])).collect();
+    }
+    let aliases: Vec<Answer> = data
+        .iter()
+        .filter(|(a, ..)| a.r#type == CNAME)
+        .map(|(a, ..)| a.clone())
+        .collect();
+    let owners: Vec<String> = data.iter().map(|(a, ..)| a.name.clone()).collect();
+    data.into_iter()
+        .filter(|(a, ..)| a.r#type != CNAME || !owners.iter().any(|o| same_name(o, &a.data)))
+        .map(|p| {
+            let via = chain(&aliases, &p.0.name);
+            (p, via)
+        })
+        .collect()
+}
+
+/// Mean RTT, loss ratio, completeness, fallback reachability, RTT spread and `--probe https`
+/// phases of one pinged address.
+type Probed = (i32, f32, bool, Option<Layer>, Option<Stats>, Option<Phases>);
+
+/// Pings of the run by address, shared by every server, so an address several servers answered
+/// with is only pinged once.
+type Probes = Mutex<HashMap<IpAddr, Shared<BoxFuture<'static, Result<Probed, Unavailable>>>>>;
+
+/// The probed answers of one server, with the timing of its request under `--timing` and its
+/// response code.
+type Answered = Result<(Vec<Row>, Option<Timing>, Status), Box<dyn std::error::Error>>;
+
+/// Answers of every server for `hostname`, probed unless `probe` is `None`.
+async fn gather<'a>(
+    hostname: &str,
+    servers: &'a [config::Server],
+    rtypes: &[&str],
+    probe: Option<&ProbeSettings>,
+    probes: &Probes,
+    transport: &Transport,
+    rounds: u32,
+) -> Vec<(&'a str, Answered)> {
+    future::join_all(servers.iter().map(|server| async move {
+        (
+            server.tag.as_str(),
+            get_ip(
+                hostname,
+                &server.url,
+                rtypes,
+                probe,
+                probes,
+                transport,
+                rounds,
+            )
+            .await,
+        )
+    }))
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn get_ip(
+    hostname: &str,
+    server: &str,
+    rtypes: &[&str],
+    probe: Option<&ProbeSettings>,
+    probes: &Probes,
+    transport: &Transport,
+    rounds: u32,
+) -> Answered {
+    let (data, protocol, timing, status) =
+        pool(hostname, server, rtypes, rounds, transport).await?;
+    let received = Instant::now();
+    let expires = |answer: &Answer| Some(received + Duration::from_secs(answer.TTL.into()));
+    let data = fold_aliases(data, rtypes);
+    let seen = |count: u32| (rounds > 1).then(|| count as f32 / rounds as f32);
+    let secure = |authenticated: bool| transport.dnssec.then_some(authenticated);
+    let Some(probe) = probe else {
+        let rows = data
+            .into_iter()
+            .map(|((answer, count, ad), via)| Row {
+                expires: expires(&answer),
+                via,
+                protocol,
+                ..Row::unprobed(answer, seen(count), secure(ad))
+            })
+            .collect();
+        return Ok((rows, timing, status));
+    };
+
+    // probe every answer right away instead of one after another, so a server's rows are
+    // ready one probe cycle after its response, whatever the other servers are doing, joining
+    // the ping of another server that got the same address first; an answer that isn't an
+    // address gets annotated rather than failing the whole server
+    let v4 = icmp_client(probe, probe.nat64.is_some(), transport).await;
+    let v6 = icmp_client(probe, true, transport).await;
+    // with --ping-hints, the hints of an HTTPS or SVCB answer follow it as addresses of their own
+    let data = data.into_iter().flat_map(|(pooled, via)| {
+        let (answer, count, ad) = &pooled;
+        let hints = svcb::TYPES
+            .contains(&answer.r#type)
+            .then(|| Svcb::parse(&answer.data))
+            .flatten()
+            .filter(|_| probe.hints)
+            .map_or_else(Vec::new, |svcb| svcb.hints());
+        let hinted: Vec<_> = hints
+            .into_iter()
+            .map(|addr| {
+                let hint = Answer {
+                    name: answer.name.clone(),
+                    r#type: if addr.is_ipv4() { A } else { AAAA },
+                    TTL: answer.TTL,
+                    data: addr.to_string(),
+                };
+                ((hint, *count, *ad), via.clone(), true)
+            })
+            .collect();
+        iter::once((pooled, via, false)).chain(hinted)
+    });
+    let items = future::join_all(data.map(|((answer, count, ad), via, hint)| {
+        let (v4, v6) = (&v4, &v6);
+        async move {
+            if answer.r#type != A && answer.r#type != AAAA {
+                return Row {
+                    expires: expires(&answer),
+                    via,
+                    protocol,
+                    ..Row::unprobed(answer, seen(count), secure(ad))
+                };
+            }
+            match IpAddr::from_str(answer.data.as_str()) {
+                Ok(addr) => {
+                    let shared = probes
+                        .lock()
+                        .unwrap()
+                        .entry(addr)
+                        .or_insert_with(|| {
+                            let client = if addr.is_ipv4() { v4 } else { v6 }.clone();
+                            let (probe, hostname) = (*probe, String::from(hostname));
+                            let transport = transport.clone();
+                            async move {
+                                let (mean, lost, complete, stats, phases) =
+                                    ping_phases(client, addr, &hostname, &probe, &transport)
+                                        .await?;
+                                let reachable =
+                                    reprobe(addr, &hostname, lost, complete, &probe, &transport)
+                                        .await;
+                                Ok((mean, lost, complete, reachable, stats, phases))
+                            }
+                            .boxed()
+                            .shared()
+                        })
+                        .clone();
+                    let Ok((mean, lost, complete, reachable, stats, phases)) = shared.await else {
+                        return Row {
+                            expires: expires(&answer),
+                            probed: true,
+                            error: Some(String::from("ICMP unavailable")),
+                            hint,
+                            via,
+                            protocol,
+                            ..Row::unprobed(answer, seen(count), secure(ad))
+                        };
+                    };
+                    Row {
+                        expires: expires(&answer),
+                        answer,
+                        mean,
+                        lost,
+                        complete,
+                        probed: true,
+                        error: None,
+                        seen: seen(count),
+                        secure: secure(ad),
+                        reachable,
+                        stats,
+                        phases,
+                        hint,
+                        via,
+                        protocol,
+                        also: vec![],
+                    }
+                }
+                Err(_) => Row {
+                    expires: expires(&answer),
+                    answer,
+                    mean: -1,
+                    lost: 0.0,
+                    complete: true,
+                    probed: true,
+                    error: Some(String::from("not an IP address")),
+                    seen: seen(count),
+                    secure: secure(ad),
+                    reachable: None,
+                    stats: None,
+                    phases: None,
+                    hint,
+                    via,
+                    protocol,
+                    also: vec![],
+                },
+            }
+        }
+    }))
+    .await;
+    Ok((items, timing, status))
+}
+
+/// Probe `ips`, and those listed in `ips_file`, without resolving anything, into the same table
+/// as the answers of a query, with its stats, sorting and columns.
+async fn ping_ips(
+    mut ips: Vec<IpAddr>,
+    ips_file: Option<&str>,
+    probe: &ProbeSettings,
+    args: &Cli,
+    transport: &Transport,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(path) = ips_file {
+        for line in read_input(path)?.lines() {
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                ips.push(IpAddr::from_str(line)?);
+            }
+        }
+    }
+
+    let v4 = icmp_client(probe, false, transport).await;
+    let v6 = icmp_client(probe, true, transport).await;
+    let mut rows: Vec<(&str, Row)> = future::join_all(ips.into_iter().map(|addr| {
+        let target = nat64::target(addr, probe.nat64);
+        let client = if target.is_ipv4() { &v4 } else { &v6 };
+        async move {
+            let answer = Answer {
+                name: addr.to_string(),
+                r#type: if addr.is_ipv4() { A } else { AAAA },
+                TTL: 0,
+                data: addr.to_string(),
+            };
+            let Ok((mean, lost, complete, stats, phases)) =
+                ping_phases(client.clone(), addr, &addr.to_string(), probe, transport).await
+            else {
+                let row = Row {
+                    probed: true,
+                    error: Some(String::from("ICMP unavailable")),
+                    ..Row::unprobed(answer, None, None)
+                };
+                return ("-", row);
+            };
+            let reachable =
+                reprobe(addr, &addr.to_string(), lost, complete, probe, transport).await;
+            let row = Row {
+                mean,
+                lost,
+                complete,
+                probed: true,
+                reachable,
+                stats,
+                phases,
+                ..Row::unprobed(answer, None, None)
+            };
+            ("-", row)
+        }
+    }))
+    .await;
+
+    if !output.is_table() {
+        let entries: Vec<ProbeEntry> = rows
+            .into_iter()
+            .map(|(_, row)| ProbeEntry {
+                address: row
+                    .answer
+                    .data
+                    .parse()
+                    .unwrap_or(IpAddr::from([0, 0, 0, 0])),
+                avg_ms: (row.mean != -1).then_some(row.mean),
+                loss: row.error.is_none().then_some(row.lost),
+                complete: row.complete,
+                reachable: row.reachable,
+                stats: row.stats,
+            })
+            .collect();
+        return output.json(&entries);
+    }
+
+    let mut freshness = vec![None; rows.len()];
+    arrange(&mut rows, &mut freshness, args, &[]);
+    let (owners, geos) = enrich(rows.iter().map(|(_, row)| row), args, transport).await?;
+    let (mut table, hidden) = records(rows, &[], freshness, &owners, &geos, args, &[], output);
+    output::fit(&mut table, &[]);
+    output.print(&partial_note(table.to_string()), hidden);
+    Ok(())
+}
+
+/// The owners and locations of the addresses of `rows`, with `--rdap` and `--geo`.
+async fn enrich<'r>(
+    rows: impl Iterator<Item = &'r Row>,
+    args: &Cli,
+    transport: &Transport,
+) -> Result<(BTreeMap<IpAddr, Owner>, BTreeMap<IpAddr, Geo>), Box<dyn std::error::Error>> {
+    if !args.rdap && !args.geo {
+        return Ok(Default::default());
+    }
+    let mut addrs: Vec<IpAddr> = rows.filter_map(|r| r.answer.data.parse().ok()).collect();
+    addrs.sort();
+    addrs.dedup();
+    let owners = if args.rdap {
+        rdap::lookup(&addrs, transport).await
+    } else {
+        BTreeMap::new()
+    };
+    let geos = if args.geo {
+        let api = args.geo_api.as_deref().unwrap_or(geo::API);
+        geo::lookup(&addrs, &args.geo_db, api, transport).await?
+    } else {
+        BTreeMap::new()
+    };
+    Ok((owners, geos))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let servers = HashMap::from([
-        ("1.1.1.1", "https://1.1.1.1/dns-query"),
-        ("9.9.9.9", "https://9.9.9.9:5053/dns-query"),
-        ("aliyun", "https://dns.alidns.com/resolve"),
-    ]);
-
-    let args = Cli::parse();
-    let hostname = args.host.as_str();
-    // get_ip(&hostname, "server");
-    let bodies = future::join_all(
-        servers
-            .into_iter()
-            .map(|(tag, server)| async move { (tag, get_ip(hostname, server).await) }),
-    )
+    // a usage error is an error like any other, clap's 2 being NXDOMAIN here
+    let args = Cli::try_parse().unwrap_or_else(|e| {
+        let _ = e.print();
+        std::process::exit(if e.use_stderr() { 1 } else { 0 });
+    });
+    logging::init(args.verbose, args.quiet);
+    interrupt::install();
+    let result = run(args).await;
+    ranking::save();
+    cache::save();
+    if result.is_ok() && interrupt::expired() {
+        // like timeout(1)
+        std::process::exit(124);
+    }
+    if result.is_ok() && interrupt::interrupted() {
+        // partial results were printed, but scripts should still see the interrupt
+        std::process::exit(130);
+    }
+    if let Err(e) = result {
+        // shown, not debug-printed as a returned error would be, for the config file's diagnostics
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// `doh daemon`: the systemd units running `doh` with `args`, printed, or written into the unit
+/// directory with `install`.
+fn daemon(
+    install: bool,
+    user: bool,
+    name: Option<&str>,
+    interval: Duration,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let run = Cli::try_parse_from(iter::once("doh").chain(args.iter().map(String::as_str)))
+        .map_err(|e| {
+            let e = e.to_string();
+            let problem = e.lines().next().unwrap_or_default();
+            format!(
+                "cannot run `doh {}`: {}",
+                args.join(" "),
+                problem.trim_start_matches("error: ")
+            )
+        })?;
+    if run.watch.is_some() {
+        return Err("--watch never ends, the timer runs doh every --interval instead".into());
+    }
+    let (default, unit) = match run.command {
+        Some(Command::Serve { listen, .. }) => ("doh-serve", systemd::Unit::Serve(listen)),
+        Some(Command::Exporter { listen, .. }) => ("doh-exporter", systemd::Unit::Exporter(listen)),
+        Some(Command::Daemon { .. }) => return Err("doh daemon can't run itself".into()),
+        _ => ("doh", systemd::Unit::Timer(interval)),
+    };
+    let name = name.unwrap_or(default);
+    let units = systemd::units(name, &unit, &std::env::current_exe()?, args, user);
+    if !install {
+        for (file, text) in &units {
+            println!("# {}\n{}", file, text);
+        }
+        return Ok(());
+    }
+    let dir = systemd::unit_dir(user).ok_or("no platform config directory for --user units")?;
+    std::fs::create_dir_all(&dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    for (file, text) in &units {
+        let path = dir.join(file);
+        std::fs::write(&path, text)
+            .map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+        info!("wrote {}", path.display());
+    }
+    // the socket or the timer starts the service
+    let (starter, _) = &units[1];
+    let systemctl = if user {
+        "systemctl --user"
+    } else {
+        "systemctl"
+    };
+    println!("{} daemon-reload", systemctl);
+    println!("{} enable --now {}", systemctl, starter);
+    Ok(())
+}
+
+/// Whether this run answers from the cache. Only the lookups of the default command and
+/// `doh hosts` do: the other commands measure the servers, `--rounds` and `--timing` need every
+/// query to reach them, and `--tui` queries again when asked to.
+fn cache_mode(args: &Cli) -> cache::Mode {
+    let lookup = matches!(args.command, None | Some(Command::Hosts { .. }));
+    if !lookup || args.no_cache {
+        cache::Mode::Off
+    } else if args.cache_only {
+        cache::Mode::Only
+    } else if args.rounds > 1 || args.timing || args.tui {
+        cache::Mode::Off
+    } else {
+        cache::Mode::On
+    }
+}
+
+/// Parse `--interval` or `--ping-timeout`, bounded like their config file equivalents.
+fn parse_wait(value: &str) -> Result<Duration, String> {
+    let wait = report::parse_duration(value)?;
+    if wait > Duration::from_secs(60) {
+        return Err(format!("`{}` is longer than 60s", value));
+    }
+    Ok(wait)
+}
+
+/// The configured probe settings, with the NAT64 prefix to use if any.
+async fn probe_settings(
+    config: &config::Config,
+    nat64: Option<Ipv6Addr>,
+    method: Option<ProbeMethod>,
+    transport: &Transport,
+) -> ProbeSettings {
+    let mut probe = config.probe;
+    probe.method = method.unwrap_or(probe.method);
+    probe.nat64 = match nat64 {
+        Some(prefix) => Some(prefix),
+        None => nat64::detect().await,
+    };
+    if let Some(prefix) = probe.nat64 {
+        info!("probing IPv4 answers through NAT64 prefix {}/96", prefix);
+    }
+    if probe.method == ProbeMethod::Icmp
+        && icmp_client(&probe, probe.nat64.is_some(), transport)
+            .await
+            .is_none()
+    {
+        if method.is_some() {
+            warn!("{}; latency is unavailable", icmp::HINT);
+        } else {
+            warn!(
+                "{}; timing TCP connections to ports 443 and 80 instead",
+                icmp::HINT
+            );
+            probe.method = ProbeMethod::Tcp(None);
+        }
+    }
+    probe
+}
+
+async fn run(args: Cli) -> Result<(), Box<dyn std::error::Error>> {
+    match args.command {
+        Some(Command::Completions { shell }) => {
+            let mut script = vec![];
+            clap_complete::generate(shell, &mut Cli::command(), "doh", &mut script);
+            return Ok(output::stdout(&script)?);
+        }
+        Some(Command::Man) => {
+            let mut page = vec![];
+            clap_mangen::Man::new(Cli::command()).render(&mut page)?;
+            return Ok(output::stdout(&page)?);
+        }
+        Some(Command::Daemon {
+            install,
+            user,
+            ref name,
+            interval,
+            ref args,
+        }) => return daemon(install, user, name.as_deref(), interval, args),
+        _ => {}
+    }
+    if let Some(Command::Config {
+        action: ConfigAction::Init(init),
+    }) = args.command
+    {
+        return init::run(init, args.config.as_deref());
+    }
+    if args.http3 && !cfg!(feature = "http3") {
+        return Err("this doh was built without HTTP/3, build it with `--features http3`".into());
+    }
+    let config_path = config::path(args.config.as_deref());
+    let mut config = config::load(config_path.as_deref())?;
+    let mut given = args.servers.clone();
+    if let Some(path) = &args.servers_file {
+        given.extend(config::servers_file(path)?);
+    }
+    if let Some(location) = &args.server_list {
+        let listed = server_list::load(location).await?;
+        given.extend(server_list::select(
+            listed,
+            &args.server_filter,
+            args.max_servers,
+        )?);
+    }
+    config::use_servers(&mut config.servers, given, args.keep_servers)?;
+    let probe = &mut config.probe;
+    probe.count = args.count.unwrap_or(probe.count);
+    probe.interval = args.interval.unwrap_or(probe.interval);
+    if args.fast {
+        probe.window = FAST_WINDOW;
+        probe.interval = args.interval.unwrap_or(FAST_INTERVAL);
+    }
+    probe.timeout = args.ping_timeout.unwrap_or(probe.timeout);
+    probe.size = args.size.map_or(probe.size, usize::from);
+    probe.hints = args.ping_hints;
+    ranking::order(&mut config.servers);
+    let format = match (args.output, &config.defaults.output) {
+        (Some(format), _) => format,
+        (None, Some(name)) => Format::from_str(name, true)
+            .map_err(|_| format!("unknown output format `{}` in the config file", name))?,
+        (None, None) => Format::Table,
+    };
+    let output = Output {
+        format,
+        fields: args.fields.clone(),
+        limit: args.limit,
+        pager: args.pager,
+        paint: Paint::new(args.color, args.theme),
+        file: args.output_file.clone(),
+    };
+    let mut transport = Transport {
+        timed: args.timing,
+        tor: args.tor.clone(),
+        proxy: args.proxy.clone(),
+        dnssec: args.dnssec,
+        subnet: args.subnet,
+        ca_certs: args
+            .ca_cert
+            .iter()
+            .map(|path| config::certificates(path))
+            .collect::<Result<Vec<_>, _>>()?
+            .concat(),
+        sni: args.sni.clone(),
+        insecure: args.insecure,
+        http3: args.http3,
+        post: args.http_method == HttpMethod::Post,
+        cache: cache_mode(&args),
+        retries: args.retries,
+        timeout: args.query_timeout,
+        padding: (!args.no_padding).then_some(usize::from(args.padding)),
+        headers: args
+            .user_agent
+            .iter()
+            .map(|agent| (String::from("User-Agent"), agent.clone()))
+            .chain(args.headers.iter().cloned())
+            .collect(),
+        ranking: true,
+        jitter: args.jitter,
+        ..Transport::default()
+    };
+    if let Some(ip) = args.source {
+        transport.source.set_address(ip);
+    }
+    if let Some(name) = &args.interface {
+        transport.source.set_interface(name)?;
+    }
+    if let Some(max) = args.max_concurrent_queries {
+        transport.limits.set_queries(max as usize);
+    }
+    if let Some(max) = args.max_concurrent_pings {
+        transport.limits.set_pings(max as usize);
+    }
+    if let Some(qps) = args.max_qps {
+        transport.limits.set_qps(qps);
+    }
+    for server in &config.servers {
+        if let Some(api) = server.api {
+            transport.servers.pin(&server.url, api);
+        }
+        if let Some(proxy) = &server.proxy {
+            transport.servers.route(&server.url, proxy);
+        }
+        if let Some(ip) = server.ip {
+            transport.bootstrap.pin(&server.url, ip);
+        }
+        if !server.headers.is_empty() {
+            transport
+                .servers
+                .set_headers(&server.url, server.headers.clone());
+        }
+        if let Some(kind) = server.json_dialect {
+            transport.servers.set_dialect(&server.url, kind);
+        }
+    }
+    if let Some(resolver) = args.bootstrap {
+        transport.bootstrap.set_resolver(resolver);
+    }
+    if let Some(url) = &args.post_url {
+        let template = match &args.post_template {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .map_err(|e| format!("cannot read post template {}: {}", path.display(), e))?,
+            ),
+            None => None,
+        };
+        webhook::set(webhook::Webhook {
+            url: url.clone(),
+            format: args.post_format,
+            template,
+            on: args.post_on,
+        })?;
+    }
+    if let Some(path) = &args.cache_file {
+        if transport.cache != cache::Mode::Off {
+            let path = path.clone().or_else(dirs::dns_cache);
+            cache::open(&path.ok_or("no platform cache directory for --cache-file, pass a path")?);
+        }
+    }
+    if args.insecure {
+        warn!("--insecure: TLS certificates of the servers are not checked");
+    }
+    let long_running = args.watch.is_some() || matches!(args.command, Some(Command::Serve { .. }));
+    if (args.jitter.is_some() || args.decoys.is_some()) && !long_running {
+        return Err("--jitter and --decoys need --watch or `doh serve`".into());
+    }
+    if let Some(deadline) = args.deadline {
+        interrupt::deadline(deadline);
+    }
+    match args.command {
+        Some(Command::Propagation { file }) => {
+            return propagation::run(&file, &config.servers, &transport, &output).await
+        }
+        Some(Command::PingIps {
+            ref ips,
+            ref ips_file,
+        }) => {
+            let probe = probe_settings(&config, args.nat64, args.probe, &transport).await;
+            let file = ips_file.as_deref();
+            return ping_ips(ips.clone(), file, &probe, &args, &transport, &output).await;
+        }
+        Some(Command::Trace { name, rtype, root }) => {
+            return trace::run(&name, &rtype, &root, &transport, &output).await
+        }
+        Some(Command::FilterTest { list }) => {
+            return filter::run(list.as_deref(), &config.servers, &transport, &output).await
+        }
+        Some(Command::RebindTest) => {
+            return rebind::run(&config.servers, &transport, &output).await
+        }
+        Some(Command::Top {
+            names,
+            every,
+            window,
+        }) => {
+            return top::run(
+                &names,
+                every,
+                window as usize,
+                &config.servers,
+                &transport,
+                &output,
+            )
+            .await
+        }
+        Some(Command::Report {
+            name,
+            duration,
+            every,
+            targets,
+        }) => {
+            let probe = probe_settings(&config, args.nat64, args.probe, &transport).await;
+            return report::run(
+                &name,
+                duration,
+                every,
+                &targets,
+                &config.servers,
+                &probe,
+                &transport,
+                &output,
+            )
+            .await;
+        }
+        Some(Command::Diff {
+            host,
+            rtypes,
+            save,
+            against,
+        }) => {
+            // left and right in the order given, not the one rankings put them in
+            let mut servers = config.servers.clone();
+            servers.sort_by_key(|s| args.servers.iter().position(|g| g.url == s.url));
+            let same = diff::run(
+                &host,
+                &rtypes,
+                save.as_deref(),
+                against.as_deref(),
+                &servers,
+                &transport,
+                &output,
+            )
+            .await?;
+            if !same {
+                ranking::save();
+                cache::save();
+                std::process::exit(Failure::Check as i32);
+            }
+            return Ok(());
+        }
+        Some(Command::History { host, file }) => {
+            let path = file
+                .or_else(dirs::history_file)
+                .ok_or("no platform data directory for the history, pass --file")?;
+            return history::run(&host, &path, &output);
+        }
+        Some(Command::MethodTest {
+            host,
+            rtype,
+            queries,
+        }) => {
+            let servers = &config.servers;
+            let ways = [
+                variant::Way::new("GET", &transport, servers, Api::Wire, false),
+                variant::Way::new("POST", &transport, servers, Api::Wire, true),
+            ];
+            let agreed =
+                variant::run(&host, &rtype, queries, &config.servers, ways, &output).await?;
+            if !agreed {
+                ranking::save();
+                std::process::exit(Failure::Check as i32);
+            }
+            return Ok(());
+        }
+        Some(Command::ApiTest {
+            host,
+            rtype,
+            queries,
+        }) => {
+            // the wire format as --http-method asks
+            let (servers, post) = (&config.servers, transport.post);
+            let ways = [
+                variant::Way::new("JSON", &transport, servers, Api::Json, false),
+                variant::Way::new("wire", &transport, servers, Api::Wire, post),
+            ];
+            let agreed =
+                variant::run(&host, &rtype, queries, &config.servers, ways, &output).await?;
+            if !agreed {
+                ranking::save();
+                std::process::exit(Failure::Check as i32);
+            }
+            return Ok(());
+        }
+        Some(Command::Bench {
+            names,
+            queries,
+            warmup,
+            cold,
+            qps,
+            connections,
+        }) => {
+            let plan = bench::Plan {
+                queries,
+                warmup,
+                cold,
+                qps,
+                connections: connections as usize,
+            };
+            return bench::run(&names, plan, &config.servers, &transport, &output).await;
+        }
+        Some(Command::Exporter {
+            listen,
+            hosts,
+            interval,
+        }) => {
+            let probe = probe_settings(&config, args.nat64, args.probe, &transport).await;
+            // time the requests for the request duration gauge, unless they go through a proxy
+            let transport = Transport {
+                timed: transport.tor.is_none() && transport.proxy.is_none(),
+                ..transport
+            };
+            return exporter::run(
+                listen,
+                &hosts,
+                interval,
+                &config.servers,
+                &probe,
+                &transport,
+            )
+            .await;
+        }
+        Some(Command::Serve { listen, strategy }) => {
+            let decoys = args.decoys.unwrap_or(0);
+            return serve::run(listen, strategy, decoys, &config.servers, &transport).await;
+        }
+        Some(Command::Hosts {
+            hosts,
+            write,
+            dry_run,
+            file,
+            max_loss,
+        }) => {
+            let probe = probe_settings(&config, args.nat64, args.probe, &transport).await;
+            let probes = &Probes::default();
+            let mut entries = vec![];
+            let mut missing = false;
+            for host in &hosts {
+                let bodies = gather(
+                    host,
+                    &config.servers,
+                    &["A", "AAAA"],
+                    Some(&probe),
+                    probes,
+                    &transport,
+                    args.rounds,
+                )
+                .await;
+                let mut rows = vec![];
+                for (tag, items) in bodies {
+                    match items {
+                        Ok((items, ..)) => rows.extend(items.into_iter().map(|item| (tag, item))),
+                        Err(e) => error!("{} error: {}", tag, e),
+                    }
+                }
+                match best::choose(&rows, f32::from(max_loss) / 100.0) {
+                    Some(address) => entries.push((host.clone(), address)),
+                    None => {
+                        error!("no address of {} answered with acceptable loss", host);
+                        missing = true;
+                    }
+                }
+            }
+            let path = file.unwrap_or_else(hosts::default_path);
+            if !entries.is_empty() {
+                hosts::run(&entries, &path, write, dry_run)?;
+            }
+            if missing {
+                ranking::save();
+                cache::save();
+                std::process::exit(Failure::Check as i32);
+            }
+            return Ok(());
+        }
+        Some(Command::Schema { kind }) => {
+            let schema = match kind {
+                SchemaKind::Query => output::schema::<Entry>("doh query result"),
+                SchemaKind::PingIps => output::schema::<ProbeEntry>("doh ping-ips result"),
+                SchemaKind::Propagation => {
+                    output::schema::<propagation::Status>("doh propagation result")
+                }
+                SchemaKind::Trace => output::schema::<trace::Step>("doh trace step"),
+                SchemaKind::FilterTest => output::schema::<filter::Check>("doh filter-test result"),
+                SchemaKind::RebindTest => {
+                    output::schema::<rebind::Rebind>("doh rebind-test result")
+                }
+                SchemaKind::Report => output::schema::<report::Objective>("doh report result"),
+                SchemaKind::Bench => output::schema::<bench::Bench>("doh bench result"),
+                SchemaKind::Diff => output::schema::<diff::Difference>("doh diff result"),
+                SchemaKind::History => output::schema::<history::Sample>("doh history sample"),
+                SchemaKind::MethodTest => {
+                    output::schema::<variant::Comparison>("doh method-test result")
+                }
+                SchemaKind::ApiTest => output::schema::<variant::Comparison>("doh api-test result"),
+                SchemaKind::Explain => {
+                    output::schema::<best::Explanation>("doh --best --explain result")
+                }
+            };
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+            return Ok(());
+        }
+        Some(Command::Servers {
+            action: ServersAction::Refresh { url },
+        }) => return quirks::refresh(&url).await,
+        Some(Command::Servers {
+            action: ServersAction::Rank,
+        }) => return rank::print(&config.servers, &output),
+        Some(Command::Config {
+            action: ConfigAction::Validate,
+        }) => {
+            // loading above already reported any problem
+            let path = config_path.ok_or("no config file found, use --config <PATH>")?;
+            println!("{}: ok, {} server(s)", path.display(), config.servers.len());
+            return Ok(());
+        }
+        Some(Command::Config {
+            action: ConfigAction::Init(_),
+        }) => unreachable!("handled before loading the config"),
+        Some(Command::Completions { .. } | Command::Man | Command::Daemon { .. }) => {
+            unreachable!("handled before loading the config")
+        }
+        None => {}
+    }
+
+    let mut hosts = args.hosts.clone();
+    if let Some(path) = &args.hosts_file {
+        let text =
+            read_input(path).map_err(|e| format!("cannot read hosts file {}: {}", path, e))?;
+        hosts.extend(
+            text.lines()
+                .map(|line| line.split('#').next().unwrap_or_default().trim())
+                .filter(|line| !line.is_empty())
+                .map(String::from),
+        );
+    }
+    // a name given twice would only be queried and listed twice
+    let mut unique = HashSet::new();
+    hosts.retain(|host| unique.insert(host.to_ascii_lowercase()));
+    if hosts.is_empty() {
+        return Err("no hostname to query".into());
+    }
+    // dual-stack hosts show both families unless told otherwise
+    let mut rtypes: Vec<&str> = if !args.rtypes.is_empty() {
+        args.rtypes.iter().map(String::as_str).collect()
+    } else if args.ipv4 {
+        vec!["A"]
+    } else if args.ipv6 {
+        vec!["AAAA"]
+    } else if !config.defaults.rtypes.is_empty() {
+        config.defaults.rtypes.iter().map(String::as_str).collect()
+    } else {
+        vec!["A", "AAAA"]
+    };
+    let mut unique = HashSet::new();
+    rtypes.retain(|rtype| unique.insert(*rtype));
+    let rtypes = &rtypes[..];
+    let probe = if args.no_ping {
+        None
+    } else {
+        Some(probe_settings(&config, args.nat64, args.probe, &transport).await)
+    };
+    let probe = probe.as_ref();
+    if args.tui {
+        let [host] = &hosts[..] else {
+            return Err("--tui shows a single --host".into());
+        };
+        return tui::run(host, &config, rtypes, probe, &transport, &args, &output).await;
+    }
+    let Some(every) = args.watch else {
+        if args.follow_ttl {
+            return Err("--follow-ttl needs --watch or --tui".into());
+        }
+        if let (Some(failure), _) =
+            cycle(&args, &config, &hosts, rtypes, probe, &transport, &output).await?
+        {
+            ranking::save();
+            cache::save();
+            std::process::exit(failure as i32);
+        }
+        return Ok(());
+    };
+    let mut ticker = time::interval(every);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // with --follow-ttl, when the first answer of the last cycle expires
+    let mut due = None;
+    let live = output.is_table() && output.file.is_none() && std::io::stdout().is_terminal();
+    // the servers that take DNS messages, as `doh serve` forwards them
+    let urls: Vec<String> = config
+        .servers
+        .iter()
+        .filter(|s| !system::is_system(&s.url) && s.api != Some(Api::Json))
+        .map(|s| s.url.clone())
+        .collect();
+    loop {
+        let wait = async {
+            match due {
+                Some(due) => countdown(due, live).await,
+                None => {
+                    ticker.tick().await;
+                }
+            }
+        };
+        tokio::select! {
+            _ = wait => {}
+            _ = interrupt::token().cancelled() => break,
+        }
+        // failures are reported, but don't end the watch
+        let decoys = privacy::decoys(args.decoys.unwrap_or(0), &urls, &transport);
+        let ((_, expires), ()) = future::try_join(
+            cycle(&args, &config, &hosts, rtypes, probe, &transport, &output),
+            decoys.map(Ok),
+        )
+        .await?;
+        if interrupt::interrupted() {
+            break;
+        }
+        if args.follow_ttl {
+            due = Some(next_query(expires, every));
+        }
+        if let Some(jitter) = args.jitter {
+            match &mut due {
+                Some(due) => *due += jitter.mul_f64(rand::random::<f64>()),
+                None => ticker.reset_after(privacy::around(every, jitter)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// When to query again with `--follow-ttl`: as the first answer `expires`, at most `every` from
+/// now, and at least a second from now so that answers with a TTL of 0 don't spin.
+fn next_query(expires: Option<Instant>, every: Duration) -> Instant {
+    let now = Instant::now();
+    expires
+        .unwrap_or(now + every)
+        .min(now + every)
+        .max(now + Duration::from_secs(1))
+}
+
+/// Wait until `due`, counting the seconds down below the table when `live`.
+async fn countdown(due: Instant, live: bool) {
+    let due = time::Instant::from_std(due);
+    if !live {
+        time::sleep_until(due).await;
+        return;
+    }
+    let mut ticker = time::interval(Duration::from_secs(1));
+    ticker.tick().await;
+    while time::Instant::now() < due {
+        let left = due.saturating_duration_since(time::Instant::now());
+        print!(
+            "\rfirst answer expires in {}s\x1b[K",
+            left.as_secs_f64().ceil()
+        );
+        let _ = std::io::stdout().flush();
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = time::sleep_until(due) => {}
+        }
+    }
+    print!("\r\x1b[K");
+}
+
+/// Resolve and probe every one of `hosts` once and print the results, and tell how the run
+/// failed, if it did, and when the first answer expires.
+async fn cycle(
+    args: &Cli,
+    config: &config::Config,
+    hosts: &[String],
+    rtypes: &[&str],
+    probe: Option<&ProbeSettings>,
+    transport: &Transport,
+    output: &Output,
+) -> Result<(Option<Failure>, Option<Instant>), Box<dyn std::error::Error>> {
+    let many = hosts.len() > 1;
+    // an address several names share is only pinged once
+    let probes = &Probes::default();
+    let servers = &config.servers;
+    let rounds = args.rounds;
+    // on stderr, and only while nothing else is printed there
+    let spinner = probe
+        .filter(|_| !args.quiet && args.verbose == 0 && std::io::stdout().is_terminal())
+        .filter(|_| std::io::stderr().is_terminal())
+        .map(|probe| progress::Spinner::start(probe.count));
+    let bodies = future::join_all(hosts.iter().map(|host| async move {
+        // an address is looked up in reverse, for the names it has
+        let (name, rtypes) = match host.parse::<IpAddr>() {
+            Ok(addr) => (wire::reverse_name(addr), &["PTR"][..]),
+            Err(_) => (host.clone(), rtypes),
+        };
+        (
+            host,
+            gather(&name, servers, rtypes, probe, probes, transport, rounds).await,
+        )
+    }))
     .await;
+    if let Some(spinner) = spinner {
+        spinner.stop().await;
+    }
+    let resolved: Vec<Resolved> = bodies
+        .into_iter()
+        .map(|(host, bodies)| Resolved::new(host, bodies, many))
+        .collect();
+    let failure = resolved.iter().filter_map(Resolved::failure).max();
+    let limits = (args.fail_if_loss_above, args.fail_if_latency_above);
+    let mut unacceptable = false;
+    if limits != (None, None) {
+        for resolved in &resolved {
+            let acceptable = resolved
+                .rows
+                .iter()
+                .any(|(_, row)| row.probed && row.error.is_none() && within(row, limits));
+            if !acceptable {
+                error!(
+                    "no address of {} within {}",
+                    resolved.host,
+                    describe_limits(limits)
+                );
+                unacceptable = true;
+            }
+        }
+    }
+    let failure = failure.max(unacceptable.then_some(Failure::Check));
+    let expires = resolved
+        .iter()
+        .flat_map(|resolved| &resolved.rows)
+        .filter_map(|(_, row)| row.expires)
+        .min();
+    if let Some(path) = &args.record {
+        let path = path.clone().or_else(dirs::history_file);
+        let path = path.ok_or("no platform data directory for --record, pass a path")?;
+        history::record(&path, &resolved)?;
+    }
+    webhook::post(&resolved).await;
+
+    let hosts_block = output.format == Format::Hosts;
+    if args.best || hosts_block {
+        if probe.is_none() {
+            return Err("--output hosts picks the fastest addresses, which takes pings".into());
+        }
+        if args.explain && hosts_block {
+            return Err("--explain prints JSON, it cannot go with --output hosts".into());
+        }
+        if let Some(every) = args.watch {
+            watch_heading(every, args.follow_ttl, output, false);
+        }
+        let mut missing = false;
+        let mut entries = vec![];
+        let mut explanations = vec![];
+        let max_loss = f32::from(args.max_loss.unwrap_or(best::MAX_LOSS)) / 100.0;
+        for resolved in &resolved {
+            if args.explain {
+                explanations.push(best::explain(resolved.host, &resolved.rows, max_loss));
+            }
+            match best::choose(&resolved.rows, max_loss) {
+                Some(address) => entries.push((String::from(resolved.host), address)),
+                None => {
+                    error!(
+                        "no address of {} answered with acceptable loss",
+                        resolved.host
+                    );
+                    missing = true;
+                }
+            }
+        }
+        if hosts_block {
+            output.emit(hosts::block(&entries, &utc_now()).as_bytes())?;
+        } else if args.explain {
+            output.json(&explanations)?;
+        } else {
+            for (_, address) in &entries {
+                println!("{}", address);
+            }
+        }
+        return Ok((failure.max(missing.then_some(Failure::Check)), expires));
+    }
+    let (owners, geos) = enrich(
+        resolved.iter().flat_map(|r| &r.rows).map(|(_, row)| row),
+        args,
+        transport,
+    )
+    .await?;
+    let owner_of = |r: &Row| {
+        r.answer
+            .data
+            .parse::<IpAddr>()
+            .ok()
+            .and_then(|addr| owners.get(&addr))
+    };
+    let geo_of = |r: &Row| {
+        r.answer
+            .data
+            .parse::<IpAddr>()
+            .ok()
+            .and_then(|addr| geos.get(&addr))
+    };
+    if let Some(every) = args.watch {
+        watch_heading(every, args.follow_ttl, output, output.is_table());
+    }
+    // prefix whatever is said about a host with its name when there are several
+    let about = |host: &str, text: String| {
+        if many {
+            format!("{}: {}", host, text)
+        } else {
+            text
+        }
+    };
+
+    let mut freshness = vec![];
+    let mut summaries = vec![];
+    let mut ok = true;
+    for resolved in &resolved {
+        let Resolved {
+            host,
+            rows,
+            responding,
+            failed,
+            ..
+        } = resolved;
+        let fresh = if args.freshness {
+            compare::freshness(rows)
+        } else {
+            vec![None; rows.len()]
+        };
+        if args.summary {
+            summaries.push(about(host, compare::summary(rows, responding)));
+        }
+        if args.freshness && output.is_table() {
+            let summary = compare::freshness_summary(rows, &fresh, responding);
+            summaries.push(about(host, summary));
+        }
+        freshness.push(fresh);
+        if let Some(mode) = args.assert_consistent {
+            if let Err(mismatch) = compare::check_consistent(rows, responding, failed, mode) {
+                error!(
+                    "{}",
+                    about(host, format!("inconsistent answers: {}", mismatch))
+                );
+                ok = false;
+            }
+        }
+        if let Some(threshold) = args.check {
+            let (report, passed) = compare::check(rows, responding, threshold, &output.paint);
+            if passed {
+                info!("{}", about(host, report));
+            } else {
+                error!("{}", about(host, report));
+            }
+            ok &= passed;
+        }
+        if !args.expect.is_empty() {
+            for (tag, result) in compare::check_expected(rows, responding, failed, &args.expect) {
+                match result {
+                    Ok(()) => info!("✓ {}", about(host, String::from(tag))),
+                    Err(reason) => {
+                        error!("✗ {}", about(host, format!("{}: {}", tag, reason)));
+                        ok = false;
+                    }
+                }
+            }
+        }
+    }
+
+    // the checks saw every answer, the output only shows the ones asked for
+    let mut resolved = resolved;
+    for (resolved, fresh) in resolved.iter_mut().zip(&mut freshness) {
+        arrange(&mut resolved.rows, fresh, args, rtypes);
+    }
+
+    if !output.is_table() {
+        let entries: Vec<Entry> = resolved
+            .iter()
+            .zip(&freshness)
+            .flat_map(|(resolved, freshness)| {
+                resolved
+                    .rows
+                    .iter()
+                    .zip(freshness)
+                    .map(|((tag, r), fresh)| {
+                        let range = compare::range_of(&args.range, &r.answer.data);
+                        let timing = resolved
+                            .timings
+                            .iter()
+                            .find(|(t, _)| t == tag)
+                            .map(|(_, t)| *t);
+                        let (owner, geo) = (owner_of(r), geo_of(r));
+                        Entry::new(resolved.host, tag, r, range, owner, geo, *fresh, timing)
+                    })
+            })
+            .collect();
+        match output.format {
+            Format::Xlsx => xlsx::query(hosts, &entries, output)?,
+            Format::Openmetrics => {
+                let text = metrics::query(&entries, &resolved);
+                output.emit(text.as_bytes())?
+            }
+            _ => {
+                // the servers without answers last, as in the table
+                let failed = resolved.iter().flat_map(|resolved| {
+                    resolved
+                        .errors
+                        .iter()
+                        .map(|(tag, reason)| Entry::failed(resolved.host, tag, reason))
+                });
+                let entries: Vec<Entry> = entries.into_iter().chain(failed).collect();
+                output.json(&entries)?
+            }
+        }
+    } else {
+        let mut sections = vec![];
+        let mut timings = vec![];
+        let mut hidden = 0;
+        // a heading above each host's tables when there are several
+        let titled = |host: &str, table: &Table| {
+            if many {
+                format!("{}\n{}", host, table)
+            } else {
+                table.to_string()
+            }
+        };
+        for (resolved, freshness) in resolved.into_iter().zip(freshness) {
+            if !resolved.timings.is_empty() {
+                let mut table = timing::table(&resolved.timings);
+                output::fit(&mut table, &[]);
+                timings.push(titled(resolved.host, &table));
+            }
+            let (table, cut) = if args.matrix {
+                let (mut table, cut) =
+                    compare::matrix(&resolved.rows, &config.servers, &args.range, output);
+                output::fit(&mut table, &[]);
+                (table, cut)
+            } else {
+                let (mut table, cut) = records(
+                    resolved.rows,
+                    &resolved.errors,
+                    freshness,
+                    &owners,
+                    &geos,
+                    args,
+                    rtypes,
+                    output,
+                );
+                // TTL, then Type, are the first to go on a narrow terminal
+                output::fit(&mut table, &[3, 2]);
+                (table, cut)
+            };
+            hidden += cut;
+            sections.push(titled(resolved.host, &table));
+        }
+        output.print(&partial_note(sections.join("\n\n")), hidden);
+        for table in timings {
+            println!("{}", table);
+        }
+    }
+
+    for summary in summaries {
+        // keep a workbook or metrics on stdout intact
+        if matches!(output.format, Format::Xlsx | Format::Openmetrics) {
+            eprintln!("{}", summary);
+        } else {
+            println!("{}", summary);
+        }
+    }
+    Ok((failure.max((!ok).then_some(Failure::Check)), expires))
+}
+
+/// Whether the address of `row` lost at most the pings, in percent, and took at most the mean
+/// latency, in milliseconds, of `limits`; one that never replied has no latency to go by.
+fn within(row: &Row, (loss, latency): (Option<u8>, Option<u32>)) -> bool {
+    let lossy = loss.is_some_and(|max| row.lost * 100.0 > f32::from(max));
+    let slow = latency.is_some_and(|max| row.mean < 0 || row.mean as u32 > max);
+    !lossy && !slow
+}
+
+/// `limits` as `within` applies them, for messages.
+fn describe_limits((loss, latency): (Option<u8>, Option<u32>)) -> String {
+    let loss = loss.map(|max| format!("{}% loss", max));
+    let latency = latency.map(|max| format!("{}ms mean latency", max));
+    loss.into_iter()
+        .chain(latency)
+        .collect::<Vec<_>>()
+        .join(" and ")
+}
+
+/// Drop the rows `--max-loss`, `--max-latency` and the `--fail-if` thresholds hide, and order the rest for `--sort`, or by
+/// record type when several were queried, keeping `freshness` in step.
+fn arrange(
+    rows: &mut Vec<(&str, Row)>,
+    freshness: &mut Vec<Option<f32>>,
+    args: &Cli,
+    rtypes: &[&str],
+) {
+    // the stricter of each pair
+    let limits = (
+        args.max_loss
+            .into_iter()
+            .chain(args.fail_if_loss_above)
+            .min(),
+        args.max_latency
+            .into_iter()
+            .chain(args.fail_if_latency_above)
+            .min(),
+    );
+    // only pinged addresses have stats to go by
+    let hidden = |row: &Row| row.probed && row.error.is_none() && !within(row, limits);
+    let mut kept: Vec<((&str, Row), Option<f32>)> = rows
+        .drain(..)
+        .zip(freshness.drain(..))
+        .filter(|((_, row), _)| !hidden(row))
+        .collect();
+    if args.group_by == Some(GroupBy::Ip) {
+        kept = merge(kept);
+    }
+    if let Some(key) = args.sort {
+        kept.sort_by(|((a_tag, a), _), ((b_tag, b), _)| compare_rows(key, (a_tag, a), (b_tag, b)));
+    } else if rtypes.len() > 1 {
+        // each type's answers together, in the order given and by server within, the stray
+        // records of other types last and hints with the HTTPS or SVCB records they came from
+        let group = |row: &Row| {
+            rtypes
+                .iter()
+                .position(|t| match wire::type_code(t) {
+                    Some(code) if row.hint => svcb::TYPES.contains(&code),
+                    code => code == Some(row.answer.r#type),
+                })
+                .unwrap_or(rtypes.len())
+        };
+        kept.sort_by_key(|((_, row), _)| group(row));
+    }
+    (*rows, *freshness) = kept.into_iter().unzip();
+}
+
+/// One row per record for `--group-by ip`: the first server's, where it came, listing the others
+/// that returned it too. Their pings were shared already, only the name and TTL may differ.
+fn merge(rows: Vec<((&str, Row), Option<f32>)>) -> Vec<((&str, Row), Option<f32>)> {
+    let mut merged: Vec<((&str, Row), Option<f32>)> = vec![];
+    for ((tag, row), fresh) in rows {
+        let same = merged.iter_mut().find(|((_, r), _)| {
+            (r.answer.r#type, &r.answer.data, r.hint)
+                == (row.answer.r#type, &row.answer.data, row.hint)
+        });
+        match same {
+            Some(((first, kept), _)) => {
+                if tag != *first && !kept.also.iter().any(|t| t == tag) {
+                    kept.also.push(String::from(tag));
+                }
+            }
+            None => merged.push(((tag, row), fresh)),
+        }
+    }
+    merged
+}
+
+/// The order of two server's rows for `--sort key`, ties going by server, type and data.
+fn compare_rows(key: SortKey, (a_tag, a): (&str, &Row), (b_tag, b): (&str, &Row)) -> Ordering {
+    // no latency goes last, whether unanswered or not an address
+    let latency = |row: &Row| if row.mean < 0 { i32::MAX } else { row.mean };
+    let first = match key {
+        SortKey::Latency => latency(a).cmp(&latency(b)).then(a.lost.total_cmp(&b.lost)),
+        SortKey::Loss => a.lost.total_cmp(&b.lost).then(latency(a).cmp(&latency(b))),
+        SortKey::Ttl => a.answer.TTL.cmp(&b.answer.TTL),
+        SortKey::Server => Ordering::Equal,
+    };
+    first
+        .then(a_tag.cmp(b_tag))
+        .then(a.answer.r#type.cmp(&b.answer.r#type))
+        .then(a.answer.data.cmp(&b.answer.data))
+}
+
+/// What the servers answered for one `--host`.
+pub struct Resolved<'a> {
+    pub host: &'a str,
+    pub rows: Vec<(&'a str, Row)>,
+    pub responding: Vec<&'a str>,
+    pub failed: Vec<&'a str>,
+    pub timings: Vec<(&'a str, Timing)>,
+    /// Response code of each responding server.
+    pub statuses: Vec<(&'a str, u32)>,
+    /// Why each server without a single answer has none: its error, or its response code, such as
+    /// NXDOMAIN, and comment.
+    pub errors: Vec<(&'a str, String)>,
+}
 
-    let mut data = vec![];
-    for (tag, items) in bodies {
-        match items {
-            Ok(items) => {
-                for item in items {
-                    data.push(Record::new(tag, item));
+impl<'a> Resolved<'a> {
+    /// Sort the answers `gather` got for `host` by whether the server responded, reporting the
+    /// errors of those that didn't.
+    fn new(host: &'a str, bodies: Vec<(&'a str, Answered)>, many: bool) -> Self {
+        let mut resolved = Self {
+            host,
+            rows: vec![],
+            responding: vec![],
+            failed: vec![],
+            timings: vec![],
+            statuses: vec![],
+            errors: vec![],
+        };
+        for (tag, items) in bodies {
+            match items {
+                Ok((items, timing, status)) => {
+                    resolved.responding.push(tag);
+                    resolved.statuses.push((tag, status.code));
+                    resolved.timings.extend(timing.map(|timing| (tag, timing)));
+                    if items.is_empty() {
+                        resolved.errors.push((tag, status.reason()));
+                    }
+                    resolved
+                        .rows
+                        .extend(items.into_iter().map(|item| (tag, item)));
+                }
+                Err(e) => {
+                    if many {
+                        error!("{}: {} error: {}", host, tag, e);
+                    } else {
+                        error!("{} error: {}", tag, e);
+                    }
+                    resolved.failed.push(tag);
+                    resolved.errors.push((tag, e.to_string()));
                 }
             }
-            Err(e) => eprintln!("{} error: {}", tag, e),
         }
+        resolved
+    }
+
+    /// The machine-readable entry of each answer, without the lookups that more options add.
+    fn entries(&self) -> Vec<Entry> {
+        self.rows
+            .iter()
+            .map(|(tag, row)| {
+                let timing = self.timings.iter().find(|(t, _)| t == tag).map(|(_, t)| *t);
+                Entry::new(self.host, tag, row, None, None, None, None, timing)
+            })
+            .collect()
+    }
+
+    /// How resolving the host failed, if it did: no server gave a usable response, or every one
+    /// that did said the name doesn't exist.
+    fn failure(&self) -> Option<Failure> {
+        let count = |rcode: u32| self.statuses.iter().filter(|(_, s)| *s == rcode).count();
+        match (count(0), count(3)) {
+            (0, 0) => Some(Failure::Unreachable),
+            (0, _) => Some(Failure::Nxdomain),
+            _ => None,
+        }
+    }
+}
+
+/// Why a run failed, by exit code; the highest one wins. Errors exit with 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Failure {
+    /// Every server that gave a usable response said NXDOMAIN.
+    Nxdomain = 2,
+    /// No server gave a usable response: each failed, or answered SERVFAIL, REFUSED and the like.
+    Unreachable = 3,
+    /// A check such as `--check` or `--expect` failed, `--best` found no address, or none was
+    /// within the `--fail-if` thresholds.
+    Check = 4,
+}
+
+/// The records of one host's answers, before they are laid out in a table.
+struct Answers<'a> {
+    data: Vec<Record>,
+    /// How many rows `--limit` left out.
+    hidden: usize,
+    /// Whether any answer is an address.
+    addresses: bool,
+    probed: bool,
+    /// Whether the servers answered over different protocols, or HTTP versions.
+    protocols: bool,
+    args: &'a Cli,
+    rtypes: &'a [&'a str],
+}
+
+impl<'a> Answers<'a> {
+    /// The records of `rows`, then of the servers that `failed`. With no `rtypes`, the rows are
+    /// the addresses of `doh ping-ips`, which answer no query: there is no server, name, type or
+    /// TTL to show.
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        rows: Vec<(&str, Row)>,
+        failed: &[(&str, String)],
+        freshness: Vec<Option<f32>>,
+        owners: &BTreeMap<IpAddr, Owner>,
+        geos: &BTreeMap<IpAddr, Geo>,
+        args: &'a Cli,
+        rtypes: &'a [&'a str],
+        output: &Output,
+    ) -> Self {
+        // nothing to ping in answers to other types, except the odd stray record
+        let probed = rows.iter().any(|(_, r)| r.probed);
+        let addresses = rows
+            .iter()
+            .any(|(_, r)| matches!(r.answer.r#type, A | AAAA));
+        // only worth a column when the servers answered over different protocols, or HTTP versions
+        let protocols = rows
+            .iter()
+            .any(|(_, r)| rows.first().is_some_and(|(_, f)| f.protocol != r.protocol));
+        // the address `--best` would pick, marked wherever it was answered
+        let best = if probed {
+            let max_loss = f32::from(args.max_loss.unwrap_or(best::MAX_LOSS)) / 100.0;
+            best::choose(&rows, max_loss)
+        } else {
+            None
+        };
+        let mut data: Vec<Record> = rows
+            .into_iter()
+            .zip(freshness)
+            .map(|((tag, item), fresh)| {
+                let range = compare::range_of(&args.range, &item.answer.data);
+                let owner = item
+                    .answer
+                    .data
+                    .parse::<IpAddr>()
+                    .ok()
+                    .and_then(|addr| owners.get(&addr));
+                let geo = item
+                    .answer
+                    .data
+                    .parse::<IpAddr>()
+                    .ok()
+                    .and_then(|addr| geos.get(&addr));
+                let winner = best.is_some() && item.answer.data.parse().ok() == best;
+                let mut record = Record::new(tag, item, range, owner, geo, fresh, &output.paint);
+                if let Some(flag) = geo.and_then(Geo::flag_label).filter(|_| args.geo_flags) {
+                    record.country = flag;
+                }
+                if winner {
+                    record.data = output.paint.best(format!("{} ★", record.data));
+                }
+                record
+            })
+            .collect();
+
+        let hidden = output.truncate(&mut data);
+        // the servers without answers last, whatever --limit cut
+        data.extend(
+            failed
+                .iter()
+                .map(|(tag, reason)| Record::failed(tag, reason, &output.paint)),
+        );
+        Self {
+            data,
+            hidden,
+            addresses,
+            probed,
+            protocols,
+            args,
+            rtypes,
+        }
+    }
+
+    /// Whether the table shows `column` of [`Record`], most of them being optional.
+    fn shown(&self, column: usize) -> bool {
+        let (args, probed) = (self.args, self.probed);
+        match column {
+            0..=3 => !self.rtypes.is_empty(),
+            4 => args.follow_ttl,
+            5 => self.rtypes.contains(&"MX"),
+            7 => !args.range.is_empty(),
+            8 => args.rdap,
+            9..=11 => args.geo,
+            12 => args.rounds > 1,
+            13 => args.freshness,
+            14 | 15 => probed,
+            16..=21 => probed && args.stats == StatsLevel::Full,
+            22..=24 => probed && args.probe == Some(ProbeMethod::Https),
+            25 => args.dnssec,
+            26 => self.protocols,
+            _ => true,
+        }
+    }
+
+    /// The headers of the columns shown.
+    fn headers(&self) -> Vec<String> {
+        let mut headers: Vec<String> = Record::headers().into_iter().map(String::from).collect();
+        if !self.addresses {
+            headers[6] = String::from("Data");
+        }
+        if self.args.group_by == Some(GroupBy::Ip) {
+            headers[0] = String::from("Returned by");
+        }
+        self.columns(headers)
+    }
+
+    /// The cells of each record in the columns shown.
+    fn cells(&self) -> Vec<Vec<String>> {
+        self.data
+            .iter()
+            .map(|record| self.columns(record.fields().into_iter().map(String::from).collect()))
+            .collect()
+    }
+
+    fn columns(&self, cells: Vec<String>) -> Vec<String> {
+        cells
+            .into_iter()
+            .enumerate()
+            .filter(|(column, _)| self.shown(*column))
+            .map(|(_, cell)| cell)
+            .collect()
+    }
+
+    /// Where the columns of numbers, Seen to TTFB, ended up among those shown.
+    fn numbers(&self) -> Range<usize> {
+        let position = |column: usize| (0..column).filter(|c| self.shown(*c)).count();
+        position(12)..position(25)
     }
+}
 
-    let table = Table::new(data)
+/// The table of one host's answers, and how many rows `--limit` left out.
+#[allow(clippy::too_many_arguments)]
+fn records(
+    rows: Vec<(&str, Row)>,
+    failed: &[(&str, String)],
+    freshness: Vec<Option<f32>>,
+    owners: &BTreeMap<IpAddr, Owner>,
+    geos: &BTreeMap<IpAddr, Geo>,
+    args: &Cli,
+    rtypes: &[&str],
+    output: &Output,
+) -> (Table, usize) {
+    let answers = Answers::new(rows, failed, freshness, owners, geos, args, rtypes, output);
+    let mut builder = Builder::default();
+    builder.set_columns(answers.headers());
+    for cells in answers.cells() {
+        builder.add_record(cells);
+    }
+    let mut table = builder.build();
+    table
         .with(Style::modern())
-        .with(Modify::new(Columns::single(6)).with(Alignment::right()))
-        .with(Modify::new(Columns::single(5)).with(Alignment::right()))
-        .with(Modify::new(Rows::first()).with(Alignment::center()))
-        .to_string();
-    println!("{}", table);
-    Ok(())
+        .with(Modify::new(Rows::first()).with(Alignment::center()));
+    table.with(Modify::new(Columns::new(answers.numbers())).with(Alignment::right()));
+    (table, answers.hidden)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_command_parses() {
+        // clap only checks how arguments refer to each other when it builds a subcommand
+        Cli::command().debug_assert();
+    }
 }
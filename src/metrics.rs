@@ -0,0 +1,128 @@
+//! `--output openmetrics`: query results as OpenMetrics gauges, so a cron job writing them with
+//! `--output-file` feeds node_exporter's textfile collector without a server of its own.
+use std::fmt::Write;
+
+use crate::{Entry, Resolved};
+
+/// Escape a label value: backslashes, double quotes and newlines.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// One metric family: its metadata, then one sample per `(labels, value)`.
+fn family(
+    text: &mut String,
+    name: &str,
+    unit: Option<&str>,
+    help: &str,
+    samples: &[(String, f64)],
+) {
+    if samples.is_empty() {
+        return;
+    }
+    let _ = writeln!(text, "# TYPE {} gauge", name);
+    if let Some(unit) = unit {
+        let _ = writeln!(text, "# UNIT {} {}", name, unit);
+    }
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    for (labels, value) in samples {
+        let _ = writeln!(text, "{}{} {}", name, labels, value);
+    }
+}
+
+/// Gauges for every probed answer and every server asked for each of the `resolved` hosts.
+pub fn query(entries: &[Entry], resolved: &[Resolved]) -> String {
+    let answer = |e: &Entry| {
+        labels(&[
+            ("host", &e.host),
+            ("server", &e.server),
+            ("address", &e.address),
+        ])
+    };
+    let probed: Vec<&Entry> = entries.iter().filter(|e| e.error.is_none()).collect();
+    let rtt: Vec<(String, f64)> = probed
+        .iter()
+        .filter_map(|e| Some((answer(e), f64::from(e.avg_ms?) / 1000.0)))
+        .collect();
+    let loss: Vec<(String, f64)> = probed
+        .iter()
+        .filter_map(|e| Some((answer(e), f64::from(e.loss?))))
+        .collect();
+    let server = |host: &str, tag: &str| labels(&[("host", host), ("server", tag)]);
+    let mut up: Vec<(String, f64)> = vec![];
+    let mut answers: Vec<(String, f64)> = vec![];
+    let mut request: Vec<(String, f64)> = vec![];
+    for Resolved {
+        host,
+        responding,
+        failed,
+        timings,
+        ..
+    } in resolved
+    {
+        up.extend(responding.iter().map(|tag| (server(host, tag), 1.0)));
+        up.extend(failed.iter().map(|tag| (server(host, tag), 0.0)));
+        answers.extend(responding.iter().map(|tag| {
+            let count = entries
+                .iter()
+                .filter(|e| e.host == *host && e.server == *tag)
+                .count();
+            (server(host, tag), count as f64)
+        }));
+        request.extend(
+            timings
+                .iter()
+                .map(|(tag, timing)| (server(host, tag), timing.total_ms / 1000.0)),
+        );
+    }
+
+    let mut text = String::new();
+    family(
+        &mut text,
+        "doh_answer_rtt_seconds",
+        Some("seconds"),
+        "Mean ICMP round-trip time to the answer.",
+        &rtt,
+    );
+    family(
+        &mut text,
+        "doh_answer_loss_ratio",
+        None,
+        "Share of echo requests to the answer that were lost.",
+        &loss,
+    );
+    family(
+        &mut text,
+        "doh_server_up",
+        None,
+        "Whether the DoH server answered the query.",
+        &up,
+    );
+    family(
+        &mut text,
+        "doh_server_answers",
+        None,
+        "Records returned by the DoH server.",
+        &answers,
+    );
+    family(
+        &mut text,
+        "doh_server_request_seconds",
+        Some("seconds"),
+        "Duration of the DoH request, with --timing and in doh exporter.",
+        &request,
+    );
+    text.push_str("# EOF\n");
+    text
+}
@@ -0,0 +1,67 @@
+//! NAT64 support for IPv6-only networks: discover the prefix (RFC 7050) and probe IPv4 answers
+//! through addresses synthesized from it (RFC 6052), instead of reporting them as unreachable.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+
+use ipnet::Ipv6Net;
+
+/// The name whose only addresses are 192.0.0.170 and 192.0.0.171, so a DNS64 resolver
+/// reveals its prefix when asked for its AAAA records.
+const DISCOVERY_NAME: &str = "ipv4only.arpa";
+const WELL_KNOWN: [Ipv4Addr; 2] = [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+
+/// Parse a `--nat64` prefix; only /96 prefixes are supported.
+pub fn parse_prefix(value: &str) -> Result<Ipv6Addr, String> {
+    let net: Ipv6Net = match value.parse::<Ipv6Addr>() {
+        Ok(addr) => Ipv6Net::new(addr, 96).map_err(|e| e.to_string())?,
+        Err(_) => value
+            .parse()
+            .map_err(|_| format!("`{}` is not an IPv6 prefix", value))?,
+    };
+    if net.prefix_len() != 96 {
+        return Err(format!(
+            "only /96 NAT64 prefixes are supported, got /{}",
+            net.prefix_len()
+        ));
+    }
+    Ok(net.network())
+}
+
+/// Whether the host has a route to the IPv4 internet; connecting a UDP socket sends nothing.
+fn has_ipv4_route() -> bool {
+    UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| socket.connect("192.0.2.1:53"))
+        .is_ok()
+}
+
+/// The NAT64 prefix of an IPv6-only network, asked from the system resolver, which is the one
+/// doing DNS64; `None` when IPv4 is reachable or there is no NAT64.
+pub async fn detect() -> Option<Ipv6Addr> {
+    if has_ipv4_route() {
+        return None;
+    }
+    let addrs = tokio::net::lookup_host((DISCOVERY_NAME, 0)).await.ok()?;
+    for addr in addrs {
+        if let IpAddr::V6(v6) = addr.ip() {
+            let octets = v6.octets();
+            let embedded = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+            if WELL_KNOWN.contains(&embedded) {
+                let mut prefix = octets;
+                prefix[12..].fill(0);
+                return Some(Ipv6Addr::from(prefix));
+            }
+        }
+    }
+    None
+}
+
+/// The address to probe for `addr`: IPv4 addresses are embedded in the NAT64 prefix, if any.
+pub fn target(addr: IpAddr, prefix: Option<Ipv6Addr>) -> IpAddr {
+    match (addr, prefix) {
+        (IpAddr::V4(v4), Some(prefix)) => {
+            let mut octets = prefix.octets();
+            octets[12..].copy_from_slice(&v4.octets());
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => addr,
+    }
+}
@@ -0,0 +1,263 @@
+//! Shared output handling: formats, row limits, colors, terminal width and pager integration.
+use std::io::{self, ErrorKind, IsTerminal, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use clap::ValueEnum;
+use schemars::schema::RootSchema;
+use schemars::{schema_for, JsonSchema};
+use serde::Serialize;
+use serde_json::Value;
+use tabled::object::Columns;
+use tabled::peaker::PriorityMax;
+use tabled::{Disable, Table, Width};
+use terminal_size::{terminal_size, Width as TermWidth};
+use tracing::error;
+
+use crate::color::Paint;
+
+/// Version of the JSON output layout, bumped on every breaking change.
+pub const SCHEMA_VERSION: u32 = 4;
+
+/// A JSON output object tagged with the schema version.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Versioned<T> {
+    pub schema_version: u32,
+    #[serde(flatten)]
+    pub row: T,
+}
+
+/// JSON Schema of the objects emitted for `T`, titled `title`.
+pub fn schema<T: JsonSchema>(title: &str) -> RootSchema {
+    let mut schema = schema_for!(Versioned<T>);
+    schema.schema.metadata().title = Some(String::from(title));
+    schema
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Table,
+    /// A single JSON array
+    Json,
+    /// One JSON object per line
+    Ndjson,
+    /// Comma-separated values with a header row, one column per JSON field
+    Csv,
+    /// Excel workbook
+    Xlsx,
+    /// OpenMetrics text, e.g. for node_exporter's textfile collector
+    Openmetrics,
+    /// `IP hostname` lines for the fastest address of each host, between marker comments
+    Hosts,
+}
+
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub format: Format,
+    /// JSON fields to keep, or to drop when prefixed with `-`; empty keeps everything.
+    pub fields: Vec<String>,
+    /// Maximum number of table rows, 0 for no limit.
+    pub limit: usize,
+    /// Page the output through `$PAGER` when stdout is a terminal.
+    pub pager: bool,
+    pub paint: Paint,
+    /// Where to write the results instead of stdout.
+    pub file: Option<PathBuf>,
+}
+
+impl Output {
+    pub fn is_table(&self) -> bool {
+        self.format == Format::Table
+    }
+
+    /// Write `bytes` to `--output-file` or stdout. The file is replaced atomically, so a collector
+    /// reading it never sees half a run.
+    pub fn emit(&self, bytes: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(path) = &self.file else {
+            return Ok(stdout(bytes)?);
+        };
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        std::fs::write(&temp, bytes)
+            .and_then(|_| std::fs::rename(&temp, path))
+            .map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+        Ok(())
+    }
+
+    /// Print `rows` as JSON or NDJSON, shaped by `--fields`.
+    pub fn json<T: Serialize>(&self, rows: &[T]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut values = vec![];
+        for row in rows {
+            let row = Versioned {
+                schema_version: SCHEMA_VERSION,
+                row,
+            };
+            values.push(self.shape(serde_json::to_value(row)?)?);
+        }
+        let text = match self.format {
+            Format::Ndjson => values.iter().map(|v| format!("{}\n", v)).collect(),
+            Format::Csv => csv(&values),
+            Format::Xlsx => return crate::xlsx::rows(&values, self),
+            Format::Openmetrics => {
+                return Err("--output openmetrics is only available for queries".into())
+            }
+            Format::Hosts => return Err("--output hosts is only available for queries".into()),
+            _ => format!("{}\n", serde_json::to_string_pretty(&values)?),
+        };
+        self.emit(text.as_bytes())
+    }
+
+    /// Apply the `--fields` selection to a single JSON object; `schema_version` always stays.
+    fn shape(&self, value: Value) -> Result<Value, String> {
+        let Value::Object(mut map) = value else {
+            return Ok(value);
+        };
+        for field in &self.fields {
+            let name = field.strip_prefix('-').unwrap_or(field);
+            if !map.contains_key(name) {
+                let known: Vec<&str> = map.keys().map(|k| k.as_str()).collect();
+                return Err(format!(
+                    "unknown field `{}`, expected one of: {}",
+                    name,
+                    known.join(", ")
+                ));
+            }
+        }
+        let include: Vec<&str> = self
+            .fields
+            .iter()
+            .filter(|f| !f.starts_with('-'))
+            .map(|f| f.as_str())
+            .collect();
+        map.retain(|key, _| {
+            key == "schema_version"
+                || ((include.is_empty() || include.contains(&key.as_str()))
+                    && !self.fields.iter().any(|f| f.strip_prefix('-') == Some(key)))
+        });
+        Ok(Value::Object(map))
+    }
+
+    /// Drop rows beyond the limit, returning how many were hidden.
+    pub fn truncate<T>(&self, rows: &mut Vec<T>) -> usize {
+        if self.limit == 0 || rows.len() <= self.limit {
+            return 0;
+        }
+        let hidden = rows.len() - self.limit;
+        rows.truncate(self.limit);
+        hidden
+    }
+
+    /// Print `text` followed by a notice about hidden rows, through the pager if enabled.
+    pub fn print(&self, text: &str, hidden: usize) {
+        let mut text = format!("{}\n", text);
+        if hidden > 0 {
+            text.push_str(&format!("… and {} more rows (use --limit 0)\n", hidden));
+        }
+        if self.file.is_some() {
+            if let Err(e) = self.emit(text.as_bytes()) {
+                error!("{}", e);
+            }
+            return;
+        }
+        if !(self.pager && std::io::stdout().is_terminal() && page(&text)) {
+            if let Err(e) = stdout(text.as_bytes()) {
+                error!("{}", e);
+            }
+        }
+    }
+}
+
+/// Write `bytes` to stdout, a reader that went away, as `head` does, being no error.
+pub fn stdout(bytes: &[u8]) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    match stdout.write_all(bytes).and_then(|()| stdout.flush()) {
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        res => res,
+    }
+}
+
+/// Quote a CSV field when it holds a separator, a quote or a line break (RFC 4180).
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        String::from(value)
+    }
+}
+
+/// `values` as CSV, one column per field in order of first appearance; nulls are empty and
+/// nested values stay JSON.
+fn csv(values: &[Value]) -> String {
+    let mut columns: Vec<&str> = vec![];
+    for value in values {
+        for key in value.as_object().into_iter().flat_map(|map| map.keys()) {
+            if !columns.contains(&key.as_str()) {
+                columns.push(key);
+            }
+        }
+    }
+    let mut text = columns.join(",");
+    text.push('\n');
+    for value in values {
+        let fields: Vec<String> = columns
+            .iter()
+            .map(|key| match value.get(key) {
+                None | Some(Value::Null) => String::new(),
+                Some(Value::String(s)) => csv_field(s),
+                Some(other) => csv_field(&other.to_string()),
+            })
+            .collect();
+        text.push_str(&fields.join(","));
+        text.push('\n');
+    }
+    text
+}
+
+/// Fit `table` into the terminal width: drop the `optional` columns in the given order, then
+/// truncate the widest cells with an ellipsis if it would still wrap.
+pub fn fit(table: &mut Table, optional: &[usize]) {
+    if !std::io::stdout().is_terminal() {
+        return;
+    }
+    let Some((TermWidth(width), _)) = terminal_size() else {
+        return;
+    };
+    let width = width as usize;
+
+    let mut optional = optional.iter().copied();
+    let mut dropped: Vec<usize> = vec![];
+    while table.total_width() > width {
+        let Some(column) = optional.next() else {
+            break;
+        };
+        // account for columns to the left that are already gone
+        let index = column - dropped.iter().filter(|c| **c < column).count();
+        table.with(Disable::column(Columns::single(index)));
+        dropped.push(column);
+    }
+    if table.total_width() > width {
+        table.with(Width::truncate(width).suffix("…").priority::<PriorityMax>());
+    }
+}
+
+/// Send `text` to the pager, returning false if it could not be started.
+fn page(text: &str) -> bool {
+    let pager = std::env::var("PAGER")
+        .unwrap_or_else(|_| String::from(if cfg!(windows) { "more" } else { "less -FRX" }));
+    let mut parts = pager.split_whitespace();
+    let Some(program) = parts.next() else {
+        return false;
+    };
+    let Ok(mut child) = Command::new(program)
+        .args(parts)
+        .stdin(Stdio::piped())
+        .spawn()
+    else {
+        return false;
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // the user quitting the pager early closes the pipe, which is fine
+        let _ = stdin.write_all(text.as_bytes());
+    }
+    child.wait().is_ok()
+}
@@ -0,0 +1,237 @@
+//! DNS messages straight over UDP, TCP or TLS (DoT, RFC 7858) for `udp://`, `tcp://` and `tls://`
+//! server entries, so they can be compared with DoH servers in the same run.
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use reqwest::Url;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
+use tokio_rustls::TlsConnector;
+
+use crate::source::Source;
+use crate::wire::{self, Message};
+use crate::{Lookup, Transport};
+
+/// How long to wait for each step of an exchange.
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// Port of servers given without one.
+const PORT: u16 = 53;
+/// Port of DoT servers given without one.
+const TLS_PORT: u16 = 853;
+
+/// Whether `server` is a DNS entry queried without HTTP rather than a DoH URL.
+pub fn is_plain(server: &str) -> bool {
+    ["udp://", "tcp://", "tls://"]
+        .iter()
+        .any(|scheme| server.starts_with(scheme))
+}
+
+/// Send `query` to `server` over UDP from `source`, for the response as is.
+pub async fn udp(
+    server: SocketAddr,
+    query: &[u8],
+    source: &Source,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let socket = source.udp(server).await?;
+    socket.connect(server).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0; 65_535];
+    let len = time::timeout(TIMEOUT, socket.recv(&mut buf)).await??;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// Send `query` over a stream, with the two-byte length prefix of RFC 1035 §4.2.2.
+async fn framed<S>(mut stream: S, query: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream
+        .write_all(&(query.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(query).await?;
+    let len = time::timeout(TIMEOUT, stream.read_u16()).await?? as usize;
+    let mut buf = vec![0; len];
+    time::timeout(TIMEOUT, stream.read_exact(&mut buf)).await??;
+    Ok(buf)
+}
+
+/// Send `query` to `server` over TCP from `source`.
+pub async fn tcp(
+    server: SocketAddr,
+    query: &[u8],
+    source: &Source,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let stream = time::timeout(TIMEOUT, source.connect(server)).await??;
+    framed(stream, query).await
+}
+
+/// Accepts any certificate, for `--insecure`.
+struct Unchecked;
+
+impl ServerCertVerifier for Unchecked {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// The rustls configuration of DoT connections: the system's certificates and the `--ca-cert`
+/// ones, or none checked with `--insecure`.
+fn config(transport: &Transport) -> Result<Arc<ClientConfig>, Box<dyn std::error::Error>> {
+    // reading the system's certificates takes a while, and they don't change during a run
+    static SYSTEM: OnceLock<Vec<Vec<u8>>> = OnceLock::new();
+    let system = SYSTEM.get_or_init(|| {
+        rustls_native_certs::load_native_certs()
+            .map(|certs| certs.into_iter().map(|cert| cert.0).collect())
+            .unwrap_or_default()
+    });
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(system);
+    for pem in &transport.ca_certs {
+        for der in rustls_pemfile::certs(&mut pem.as_slice())? {
+            roots.add(&Certificate(der))?;
+        }
+    }
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    if transport.insecure {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(Unchecked));
+    }
+    Ok(Arc::new(config))
+}
+
+/// Send `query` to `server` over TLS, checking its certificate against `host`.
+pub async fn tls(
+    server: SocketAddr,
+    host: &str,
+    query: &[u8],
+    transport: &Transport,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let name = ServerName::try_from(host).map_err(|_| format!("invalid TLS name `{}`", host))?;
+    let connector = TlsConnector::from(config(transport)?);
+    let stream = time::timeout(TIMEOUT, transport.source.connect(server)).await??;
+    let stream = time::timeout(TIMEOUT, connector.connect(name, stream)).await??;
+    framed(stream, query).await
+}
+
+/// Send `query` over UDP from `source`, retrying over TCP when the answer is truncated.
+pub async fn ask(
+    server: SocketAddr,
+    query: &[u8],
+    source: &Source,
+) -> Result<Message, Box<dyn std::error::Error>> {
+    let message = wire::parse(&udp(server, query, source).await?)?;
+    if !message.truncated {
+        return Ok(message);
+    }
+    Ok(wire::parse(&tcp(server, query, source).await?)?)
+}
+
+/// Where a `udp://`, `tcp://` or `tls://` server entry points: its URL, the host named in it and
+/// the address to reach it at.
+async fn endpoint(
+    server: &str,
+    transport: &Transport,
+) -> Result<(Url, String, SocketAddr), Box<dyn std::error::Error>> {
+    let url = Url::parse(server)?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| format!("server URL `{}` has no host", server))?
+        // IPv6 literals keep their brackets in URLs
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url.port().unwrap_or(match url.scheme() {
+        "tls" => TLS_PORT,
+        _ => PORT,
+    });
+    let addr = transport
+        .bootstrap
+        .lookup(host, port, &transport.source)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("cannot resolve {}", host))?;
+    let host = String::from(host);
+    Ok((url, host, addr))
+}
+
+/// Send `query` to `server` the way its scheme says, for the response as is, over TCP again
+/// when a UDP one is truncated.
+async fn send(
+    server: &str,
+    query: &[u8],
+    transport: &Transport,
+) -> Result<(Url, Vec<u8>), Box<dyn std::error::Error>> {
+    let (url, host, addr) = endpoint(server, transport).await?;
+    let source = &transport.source;
+    let response = match url.scheme() {
+        "udp" => {
+            let response = udp(addr, query, source).await?;
+            // the TC bit
+            if response.get(2).is_some_and(|flags| flags & 0x02 != 0) {
+                tcp(addr, query, source).await?
+            } else {
+                response
+            }
+        }
+        "tls" => {
+            let name = transport.sni.as_deref().unwrap_or(&host);
+            tls(addr, name, query, transport).await?
+        }
+        _ => tcp(addr, query, source).await?,
+    };
+    Ok((url, response))
+}
+
+/// Relay the DNS message `query` to a `udp://`, `tcp://` or `tls://` server as is, and its
+/// response, for `doh serve`.
+pub async fn forward(
+    query: &[u8],
+    server: &str,
+    transport: &Transport,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let (_, response) = send(server, query, transport).await?;
+    wire::parse(&response)?;
+    Ok(response)
+}
+
+/// Query a `udp://`, `tcp://` or `tls://` server for `hostname`.
+pub async fn lookup(
+    hostname: &str,
+    server: &str,
+    rtype: &str,
+    transport: &Transport,
+) -> Result<Lookup, Box<dyn std::error::Error>> {
+    let code = wire::type_code(rtype).ok_or_else(|| format!("unknown record type `{}`", rtype))?;
+    let mut query = wire::encode(hostname, code as u16)?;
+    transport.edns(&mut query, server.starts_with("tls://"));
+    let (url, response) = send(server, &query, transport).await?;
+    let message = wire::parse(&response)?;
+    Ok(Lookup {
+        status: message.rcode as u32,
+        protocol: match url.scheme() {
+            "udp" => "UDP",
+            "tls" => "DoT",
+            _ => "TCP",
+        },
+        authenticated: message.authenticated,
+        answers: message.answers,
+        comment: None,
+        timing: None,
+    })
+}
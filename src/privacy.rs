@@ -0,0 +1,82 @@
+//! `--jitter` and `--decoys`, for the long-running `--watch` and `doh serve`. Queries sent on a
+//! fixed schedule, to the servers in a fixed order, and only for the names one cares about make
+//! the traffic easy to tell apart: jitter moves each query by a random delay, and decoys hide the
+//! real queries among others for popular names.
+use std::time::Duration;
+
+use futures::future;
+use rand::random;
+use rand::seq::SliceRandom;
+use tracing::debug;
+
+use crate::{forward, wire, Transport};
+
+/// What decoy queries ask for, names popular enough not to stand out.
+const DECOY_NAMES: &[&str] = &[
+    "google.com",
+    "youtube.com",
+    "facebook.com",
+    "wikipedia.org",
+    "amazon.com",
+    "instagram.com",
+    "apple.com",
+    "microsoft.com",
+    "netflix.com",
+    "github.com",
+    "cloudflare.com",
+    "reddit.com",
+    "linkedin.com",
+    "yahoo.com",
+    "bing.com",
+    "whatsapp.com",
+];
+
+/// `every`, moved by a random time of up to `jitter` either way, and at least a second.
+pub fn around(every: Duration, jitter: Duration) -> Duration {
+    (every + jitter)
+        .saturating_sub(jitter.mul_f64(2.0 * random::<f64>()))
+        .max(Duration::from_secs(1))
+}
+
+/// Send `count` A queries for random popular names, each to a random one of `servers` in wire
+/// format, and ignore the answers. They don't count towards the ranking, and wait like the real
+/// queries.
+pub async fn decoys(count: u32, servers: &[String], transport: &Transport) {
+    let transport = &Transport {
+        ranking: false,
+        ..transport.clone()
+    };
+    let queries = (0..count).filter_map(|_| {
+        let mut rng = rand::thread_rng();
+        let name = *DECOY_NAMES.choose(&mut rng)?;
+        let server = servers.choose(&mut rng)?;
+        Some(async move {
+            let query = wire::encode(name, 1).expect("a valid name");
+            if let Err(e) = forward(&query, server, transport).await {
+                debug!("decoy query for {} to {}: {}", name, server, e);
+            }
+        })
+    });
+    future::join_all(queries).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn around_stays_within_jitter() {
+        let (every, jitter) = (Duration::from_secs(60), Duration::from_secs(10));
+        for _ in 0..1000 {
+            let wait = around(every, jitter);
+            assert!(
+                wait >= every - jitter && wait <= every + jitter,
+                "{:?}",
+                wait
+            );
+        }
+        // never so short that the cycles spin
+        let wait = around(Duration::from_secs(1), Duration::from_secs(5));
+        assert!(wait >= Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,88 @@
+//! A spinner on stderr while the addresses are pinged, since a run takes a while before it has
+//! anything to print: `resolved 6 addresses, pinging 2/6 (seq 4/10)`.
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use indicatif::{ProgressBar, ProgressStyle};
+use tokio::task::JoinHandle;
+
+/// Addresses whose pings started, finished, and the furthest echo request any of them sent.
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+static DONE: AtomicUsize = AtomicUsize::new(0);
+static SEQUENCE: AtomicU16 = AtomicU16::new(0);
+
+/// The frames of the spinner, and the blank it ends on.
+const FRAMES: &str = "⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ ";
+const TICK: Duration = Duration::from_millis(100);
+
+pub(crate) fn started() {
+    STARTED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Echo request number `seq`, from 1, was sent.
+pub(crate) fn sent(seq: u16) {
+    SEQUENCE.fetch_max(seq, Ordering::Relaxed);
+}
+
+pub(crate) fn done() {
+    DONE.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Start counting afresh, for a new round of pings.
+pub fn reset() {
+    STARTED.store(0, Ordering::Relaxed);
+    DONE.store(0, Ordering::Relaxed);
+    SEQUENCE.store(0, Ordering::Relaxed);
+}
+
+/// How far the pings are, with `count` echo requests per address.
+pub fn status(count: u16) -> String {
+    let started = STARTED.load(Ordering::Relaxed);
+    if started == 0 {
+        return String::from("resolving");
+    }
+    format!(
+        "resolved {} addresses, pinging {}/{} (seq {}/{})",
+        started,
+        DONE.load(Ordering::Relaxed),
+        started,
+        SEQUENCE.load(Ordering::Relaxed),
+        count
+    )
+}
+
+/// Spins until [`Spinner::stop`], which clears its line.
+pub struct Spinner {
+    bar: ProgressBar,
+    /// Keeps the status of the bar up to date.
+    update: JoinHandle<()>,
+}
+
+impl Spinner {
+    /// Start spinning for a round of `count` echo requests per address.
+    pub fn start(count: u16) -> Self {
+        reset();
+        let bar = ProgressBar::new_spinner().with_style(
+            ProgressStyle::with_template("{spinner} {msg}")
+                .expect("the spinner template is valid")
+                .tick_chars(FRAMES),
+        );
+        bar.enable_steady_tick(TICK);
+        let update = tokio::spawn({
+            let bar = bar.clone();
+            async move {
+                loop {
+                    bar.set_message(status(count));
+                    tokio::time::sleep(TICK).await;
+                }
+            }
+        });
+        Self { bar, update }
+    }
+
+    pub async fn stop(self) {
+        self.update.abort();
+        let _ = self.update.await;
+        self.bar.finish_and_clear();
+    }
+}
@@ -0,0 +1,213 @@
+//! Bulk propagation check: verify a batch of expected records against every DoH server.
+use futures::future;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tabled::builder::Builder;
+use tabled::object::Rows;
+use tabled::{Alignment, Modify, Style};
+use tracing::error;
+
+use crate::config::Server;
+use crate::output::{self, Output};
+use crate::wire::type_code;
+use crate::{query, read_input, Answer, Transport};
+
+/// Whether one server returned the expected record; absent when the query failed.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ServerStatus {
+    pub server: String,
+    pub propagated: Option<bool>,
+}
+
+/// Machine-readable result of a single expected record.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Status {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub r#type: String,
+    pub expected: String,
+    pub servers: Vec<ServerStatus>,
+    pub propagated: bool,
+}
+
+/// A record that is expected to be visible on every server.
+#[derive(Debug, Clone)]
+pub struct Expected {
+    pub name: String,
+    pub r#type: String,
+    pub value: String,
+}
+
+/// Strip a `;` comment, ignoring semicolons inside quoted strings.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..idx],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Parse a zone file or a plain list of `name type expected` lines.
+///
+/// Zone file lines may carry an optional TTL and class before the type, `$ORIGIN` qualifies
+/// relative names, `@` stands for the origin, and a line starting with whitespace reuses the
+/// previous owner name.
+pub fn parse(input: &str) -> Result<Vec<Expected>, String> {
+    let mut records = vec![];
+    let mut origin: Option<String> = None;
+    let mut last_name: Option<String> = None;
+
+    for (idx, raw) in input.lines().enumerate() {
+        let line = strip_comment(raw);
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let mut tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens[0].eq_ignore_ascii_case("$ORIGIN") {
+            origin = tokens.get(1).map(|o| o.trim_end_matches('.').to_string());
+            continue;
+        }
+        if tokens[0].starts_with('$') {
+            continue;
+        }
+
+        let name = if line.starts_with(char::is_whitespace) {
+            last_name
+                .clone()
+                .ok_or_else(|| format!("line {}: missing owner name", idx + 1))?
+        } else {
+            let name = tokens.remove(0);
+            match (name, &origin) {
+                ("@", Some(origin)) => origin.clone(),
+                (name, _) if name.ends_with('.') => name.trim_end_matches('.').to_string(),
+                (name, Some(origin)) => format!("{}.{}", name, origin),
+                (name, None) => name.to_string(),
+            }
+        };
+
+        let pos = tokens
+            .iter()
+            .position(|t| type_code(t).is_some())
+            .ok_or_else(|| format!("line {}: missing record type", idx + 1))?;
+        let value = tokens[pos + 1..].join(" ");
+        if value.is_empty() {
+            return Err(format!("line {}: missing expected value", idx + 1));
+        }
+        records.push(Expected {
+            name: name.clone(),
+            r#type: tokens[pos].to_ascii_uppercase(),
+            value,
+        });
+        last_name = Some(name);
+    }
+    Ok(records)
+}
+
+/// Normalize record data so presentation differences (case, trailing dots, TXT quotes,
+/// spacing) don't count as a mismatch.
+fn normalize(data: &str) -> String {
+    data.split_whitespace()
+        .map(|t| {
+            t.trim_matches('"')
+                .trim_end_matches('.')
+                .to_ascii_lowercase()
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Whether `answers` contains the expected record.
+fn matches(record: &Expected, answers: &[Answer]) -> bool {
+    let code = type_code(&record.r#type);
+    let expected = normalize(&record.value);
+    answers
+        .iter()
+        .any(|a| Some(a.r#type) == code && normalize(&a.data) == expected)
+}
+
+pub async fn run(
+    file: &str,
+    servers: &[Server],
+    transport: &Transport,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let records = parse(&read_input(file)?)?;
+
+    let results = future::join_all(records.iter().map(|record| {
+        future::join_all(servers.iter().map(move |server| async move {
+            match query(&record.name, &server.url, &record.r#type, transport).await {
+                Ok(answers) => Some(matches(record, &answers)),
+                Err(e) => {
+                    error!(
+                        "{} error: {} {}: {}",
+                        server.tag, record.name, record.r#type, e
+                    );
+                    None
+                }
+            }
+        }))
+    }))
+    .await;
+
+    let statuses: Vec<Status> = records
+        .into_iter()
+        .zip(results)
+        .map(|(record, results)| Status {
+            propagated: results.iter().all(|r| *r == Some(true)),
+            servers: servers
+                .iter()
+                .zip(results)
+                .map(|(server, propagated)| ServerStatus {
+                    server: server.tag.clone(),
+                    propagated,
+                })
+                .collect(),
+            name: record.name,
+            r#type: record.r#type,
+            expected: record.value,
+        })
+        .collect();
+
+    if !output.is_table() {
+        return output.json(&statuses);
+    }
+
+    let total = statuses.len();
+    let propagated = statuses.iter().filter(|s| s.propagated).count();
+
+    let mut builder = Builder::default();
+    let mut columns: Vec<String> = vec!["Name".into(), "Type".into(), "Expected".into()];
+    columns.extend(servers.iter().map(|s| s.tag.clone()));
+    columns.push("Propagated".into());
+    builder.set_columns(columns);
+
+    let mut statuses = statuses;
+    let hidden = output.truncate(&mut statuses);
+    for status in statuses {
+        let mut row = vec![status.name, status.r#type, status.expected];
+        row.extend(status.servers.iter().map(|s| {
+            let text = String::from(match s.propagated {
+                Some(true) => "✓",
+                Some(false) => "✗",
+                None => "error",
+            });
+            output.paint.status(s.propagated == Some(true), text)
+        }));
+        let text = String::from(if status.propagated { "yes" } else { "no" });
+        row.push(output.paint.status(status.propagated, text));
+        builder.add_record(row);
+    }
+
+    let mut table = builder.build();
+    table
+        .with(Style::modern())
+        .with(Modify::new(Rows::first()).with(Alignment::center()));
+    output::fit(&mut table, &[]);
+    output.print(&table.to_string(), hidden);
+    println!("{}/{} records propagated to all servers", propagated, total);
+    Ok(())
+}
@@ -0,0 +1,116 @@
+//! What we know about public DoH providers: which APIs they speak and where, extra parameters,
+//! rate limits and known issues. A copy is built in; `doh servers refresh` fetches a newer one.
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{dialect, dirs, Api};
+
+/// Where `doh servers refresh` downloads the database from by default.
+pub const DEFAULT_URL: &str = "https://raw.githubusercontent.com/l1xnan/doh/main/quirks.toml";
+
+const BUILTIN: &str = include_str!("../quirks.toml");
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quirk {
+    /// Endpoint host, or `host:port` when the port matters.
+    pub host: String,
+    /// APIs the endpoint speaks, in the order they should be tried.
+    #[serde(default)]
+    pub transports: Vec<Api>,
+    pub json_path: Option<String>,
+    pub wire_path: Option<String>,
+    /// How its JSON API responses are shaped, when not like Google's.
+    pub json_dialect: Option<dialect::Kind>,
+    /// Query parameters every request needs.
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+    pub max_qps: Option<u32>,
+    #[serde(default)]
+    pub issues: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Database {
+    #[serde(default)]
+    provider: Vec<Quirk>,
+}
+
+/// Parse a database file, as shipped or as downloaded.
+pub fn parse(text: &str) -> Result<Vec<Quirk>, toml::de::Error> {
+    toml::from_str::<Database>(text).map(|db| db.provider)
+}
+
+fn database() -> &'static [Quirk] {
+    static DATABASE: OnceLock<Vec<Quirk>> = OnceLock::new();
+    DATABASE.get_or_init(|| {
+        let refreshed = dirs::quirks_file().and_then(|path| std::fs::read_to_string(path).ok());
+        // a broken download shouldn't break queries, the built-in copy still works
+        refreshed
+            .and_then(|text| parse(&text).ok())
+            .unwrap_or_else(|| parse(BUILTIN).expect("built-in quirks.toml is valid"))
+    })
+}
+
+/// The entry for the endpoint `server`, matching `host:port` before `host`.
+pub fn lookup(server: &str) -> Option<&'static Quirk> {
+    let url = Url::parse(server).ok()?;
+    let host = url.host_str()?;
+    let with_port = url.port().map(|port| format!("{}:{}", host, port));
+    let db = database();
+    with_port
+        .and_then(|key| db.iter().find(|q| q.host == key))
+        .or_else(|| db.iter().find(|q| q.host == host && url.port().is_none()))
+}
+
+impl Quirk {
+    /// The endpoint to send `api` requests to, for a server configured as `server`.
+    pub fn endpoint(&self, server: &str, api: Api) -> String {
+        let path = match api {
+            Api::Json => &self.json_path,
+            Api::Wire => &self.wire_path,
+        };
+        match (path, Url::parse(server)) {
+            (Some(path), Ok(mut url)) => {
+                url.set_path(path);
+                url.to_string()
+            }
+            _ => String::from(server),
+        }
+    }
+
+    /// Known limits and issues, for error messages.
+    pub fn notes(&self) -> Vec<String> {
+        let mut notes = vec![];
+        if let Some(qps) = self.max_qps {
+            notes.push(format!("rate limited to {} queries/s", qps));
+        }
+        notes.extend(self.issues.iter().cloned());
+        notes
+    }
+}
+
+/// Download the database from `url` and install it for later runs.
+pub async fn refresh(url: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let download = async { reqwest::get(url).await?.error_for_status()?.text().await };
+    let text = download
+        .await
+        .map_err(|e| format!("cannot download the quirks database: {}", e))?;
+    let providers =
+        parse(&text).map_err(|e| format!("invalid quirks database at {}: {}", url, e))?;
+    let path = dirs::quirks_file().ok_or("cannot determine the platform data directory")?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, text)?;
+    println!(
+        "Updated {} providers in {}",
+        providers.len(),
+        path.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,71 @@
+//! `doh servers rank`: the scores learned from past runs, see [`ranking`].
+use schemars::JsonSchema;
+use serde::Serialize;
+use tabled::builder::Builder;
+use tabled::object::{Columns, Rows};
+use tabled::{Alignment, Modify, Style};
+
+use crate::config::Server;
+use crate::output::{self, Output};
+use crate::ranking::{self, Score};
+
+/// Machine-readable score of a configured server.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Ranked {
+    pub server: String,
+    pub url: String,
+    pub latency_ms: Option<f64>,
+    pub failure: Option<f64>,
+    pub runs: u32,
+}
+
+/// `doh servers rank`: the configured servers with their scores, best first.
+pub fn print(servers: &[Server], output: &Output) -> Result<(), Box<dyn std::error::Error>> {
+    let scores = ranking::load();
+    let mut ranked: Vec<(&Server, Option<&Score>)> =
+        servers.iter().map(|s| (s, scores.get(&s.url))).collect();
+    if !output.is_table() {
+        let entries: Vec<Ranked> = ranked
+            .iter()
+            .map(|(server, score)| Ranked {
+                server: server.tag.clone(),
+                url: server.url.clone(),
+                latency_ms: score.and_then(|s| s.latency_ms),
+                failure: score.map(|s| s.failure),
+                runs: score.map_or(0, |s| s.runs),
+            })
+            .collect();
+        return output.json(&entries);
+    }
+
+    let mut builder = Builder::default();
+    builder.set_columns(["DoH", "URL", "Latency", "Failures", "Runs"]);
+    let hidden = output.truncate(&mut ranked);
+    for (server, score) in ranked {
+        let (latency, failure, runs) = match score {
+            Some(score) => (
+                score
+                    .latency_ms
+                    .map_or_else(|| String::from("/"), |ms| format!("{:.0}ms", ms)),
+                format!("{:.1}%", score.failure * 100.0),
+                score.runs.to_string(),
+            ),
+            None => (String::from("-"), String::from("-"), String::from("0")),
+        };
+        builder.add_record([
+            server.tag.clone(),
+            server.url.clone(),
+            latency,
+            failure,
+            runs,
+        ]);
+    }
+    let mut table = builder.build();
+    table
+        .with(Style::modern())
+        .with(Modify::new(Columns::new(2..)).with(Alignment::right()))
+        .with(Modify::new(Rows::first()).with(Alignment::center()));
+    output::fit(&mut table, &[1]);
+    output.print(&table.to_string(), hidden);
+    Ok(())
+}
@@ -0,0 +1,110 @@
+//! A score per server that learns across runs: exponentially weighted moving averages of query
+//! latency and failure rate, kept in the platform data directory. Servers are listed best first.
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::config::Server;
+use crate::dirs;
+
+/// Weight of the latest run in the moving averages.
+const ALPHA: f64 = 0.3;
+/// What a failed query costs in the score, as if it had taken this long.
+const FAILURE_PENALTY_MS: f64 = 2000.0;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Score {
+    /// Moving average of successful query latency, in milliseconds.
+    pub latency_ms: Option<f64>,
+    /// Moving average of the failure rate, from 0 to 1.
+    pub failure: f64,
+    /// Runs the score was updated in.
+    pub runs: u32,
+}
+
+impl Score {
+    /// Lower is better.
+    pub fn value(&self) -> f64 {
+        self.latency_ms.unwrap_or(FAILURE_PENALTY_MS) + self.failure * FAILURE_PENALTY_MS
+    }
+}
+
+/// Queries of this run per server URL: latencies of the successful ones, and the failures.
+static OBSERVED: Mutex<BTreeMap<String, (Vec<f64>, u32)>> = Mutex::new(BTreeMap::new());
+
+/// Record one query to `server`; `None` when it failed.
+pub fn observe(server: &str, latency: Option<Duration>) {
+    let mut observed = OBSERVED.lock().unwrap();
+    let (latencies, failures) = observed.entry(String::from(server)).or_default();
+    match latency {
+        Some(latency) => latencies.push(latency.as_secs_f64() * 1000.0),
+        None => *failures += 1,
+    }
+}
+
+/// The stored scores by server URL.
+pub fn load() -> BTreeMap<String, Score> {
+    dirs::ranking_file()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+/// Fold this run's queries into the stored scores.
+pub fn save() {
+    let observed = std::mem::take(&mut *OBSERVED.lock().unwrap());
+    if observed.is_empty() {
+        return;
+    }
+    let mut scores = load();
+    for (server, (latencies, failures)) in observed {
+        let score = scores.entry(server).or_default();
+        let total = latencies.len() as f64 + failures as f64;
+        let failure = failures as f64 / total;
+        let latency =
+            (!latencies.is_empty()).then(|| latencies.iter().sum::<f64>() / latencies.len() as f64);
+        if score.runs == 0 {
+            score.failure = failure;
+            score.latency_ms = latency;
+        } else {
+            score.failure = ALPHA * failure + (1.0 - ALPHA) * score.failure;
+            if let Some(latency) = latency {
+                score.latency_ms = Some(match score.latency_ms {
+                    Some(old) => ALPHA * latency + (1.0 - ALPHA) * old,
+                    None => latency,
+                });
+            }
+        }
+        score.runs += 1;
+    }
+    let Some(path) = dirs::ranking_file() else {
+        return;
+    };
+    let write = || -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, serde_json::to_string(&scores)?)
+    };
+    // losing a run's worth of scores isn't worth failing the command over
+    if let Err(e) = write() {
+        warn!("cannot save server scores to {}: {}", path.display(), e);
+    }
+}
+
+/// Order `servers` best first; servers without a score keep their place after the others.
+pub fn order(servers: &mut [Server]) {
+    let scores = load();
+    servers.sort_by(|a, b| {
+        let score = |s: &Server| scores.get(&s.url).map(Score::value);
+        match (score(a), score(b)) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        }
+    });
+}
@@ -0,0 +1,159 @@
+//! `--rdap`: look up which network each answer belongs to and its abuse contact over RDAP, the
+//! successor of WHOIS, to spot unexpected third-party infrastructure. Results are cached on disk.
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{stream, StreamExt};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{error, warn};
+
+use crate::{dirs, Transport};
+
+/// Redirects each query to the registry (ARIN, RIPE, APNIC, ...) holding the address.
+const BOOTSTRAP: &str = "https://rdap.org/ip/";
+/// Registrations rarely change; refetch a week-old entry.
+const MAX_AGE: Duration = Duration::from_secs(7 * 24 * 3600);
+/// Registries rate-limit aggressively, so only a few lookups run at once.
+const CONCURRENCY: usize = 4;
+
+/// Who holds the network an address belongs to.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Owner {
+    /// The registered network name, e.g. `GOOGLE`.
+    pub network: Option<String>,
+    /// Email of the entity with the `abuse` role.
+    pub abuse: Option<String>,
+}
+
+impl Owner {
+    pub fn label(&self) -> String {
+        let network = self.network.as_deref().unwrap_or("?");
+        match &self.abuse {
+            Some(abuse) => format!("{} ({})", network, abuse),
+            None => String::from(network),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Cached {
+    owner: Owner,
+    /// Seconds since the Unix epoch.
+    fetched: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn load() -> BTreeMap<IpAddr, Cached> {
+    dirs::rdap_cache()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+fn save(cache: &BTreeMap<IpAddr, Cached>) -> Result<(), Box<dyn std::error::Error>> {
+    let path = dirs::rdap_cache().ok_or("cannot determine the platform cache directory")?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_string(cache)?)?;
+    Ok(())
+}
+
+/// The email in a jCard (`["vcard", [["email", {}, "text", "..."], ...]]`).
+fn email(vcard: &Value) -> Option<String> {
+    vcard
+        .get(1)?
+        .as_array()?
+        .iter()
+        .filter(|property| property.get(0).and_then(Value::as_str) == Some("email"))
+        .find_map(|property| property.get(3)?.as_str().map(String::from))
+}
+
+/// The abuse contact among `entities`, which registries nest under the registrant.
+fn abuse(entities: &Value) -> Option<String> {
+    entities.as_array()?.iter().find_map(|entity| {
+        let roles = entity.get("roles").and_then(Value::as_array);
+        let is_abuse = roles.is_some_and(|roles| roles.iter().any(|r| r == "abuse"));
+        let own = is_abuse
+            .then(|| entity.get("vcardArray").and_then(email))
+            .flatten();
+        own.or_else(|| entity.get("entities").and_then(abuse))
+    })
+}
+
+fn parse(network: &Value) -> Owner {
+    Owner {
+        network: network
+            .get("name")
+            .and_then(Value::as_str)
+            .map(String::from),
+        abuse: network.get("entities").and_then(abuse),
+    }
+}
+
+async fn fetch(addr: IpAddr, transport: &Transport) -> Result<Owner, Box<dyn std::error::Error>> {
+    let url = format!("{}{}", BOOTSTRAP, addr);
+    let network: Value = transport
+        .client(&url)?
+        .get(&url)
+        .header("Accept", "application/rdap+json")
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(parse(&network))
+}
+
+/// The owner of every address, from the cache when fresh enough. Failed lookups are reported
+/// and fall back to the cached entry, if any.
+pub async fn lookup(addrs: &[IpAddr], transport: &Transport) -> BTreeMap<IpAddr, Owner> {
+    let mut cache = load();
+    let stale: Vec<IpAddr> = addrs
+        .iter()
+        .filter(|addr| {
+            cache
+                .get(addr)
+                .is_none_or(|c| now().saturating_sub(c.fetched) > MAX_AGE.as_secs())
+        })
+        .copied()
+        .collect();
+    let fetched: Vec<(IpAddr, _)> = stream::iter(stale)
+        .map(|addr| async move { (addr, fetch(addr, transport).await) })
+        .buffer_unordered(CONCURRENCY)
+        .collect()
+        .await;
+
+    let updated = !fetched.is_empty();
+    for (addr, result) in fetched {
+        match result {
+            Ok(owner) => {
+                cache.insert(
+                    addr,
+                    Cached {
+                        owner,
+                        fetched: now(),
+                    },
+                );
+            }
+            Err(e) => error!("rdap error: {}: {}", addr, e),
+        }
+    }
+    if updated {
+        if let Err(e) = save(&cache) {
+            warn!("cannot save the RDAP cache: {}", e);
+        }
+    }
+    addrs
+        .iter()
+        .filter_map(|addr| Some((*addr, cache.get(addr)?.owner.clone())))
+        .collect()
+}
@@ -0,0 +1,154 @@
+//! `doh rebind-test`: query public names that resolve to private addresses and report which
+//! servers filter such answers (DNS rebinding protection) and which pass them through.
+use std::net::IpAddr;
+
+use futures::future;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tabled::builder::Builder;
+use tabled::object::Rows;
+use tabled::{Alignment, Modify, Style};
+use tracing::{error, warn};
+
+use crate::config::Server;
+use crate::output::{self, Output};
+use crate::{exchange, Transport};
+
+/// Range of names every resolver should answer, to tell filtering from breakage.
+const CONTROL: &str = "public";
+
+/// Wildcard DNS names that answer with the address they embed, as `(range, name, address)`.
+const NAMES: &[(&str, &str, &str)] = &[
+    ("loopback", "127.0.0.1.nip.io", "127.0.0.1"),
+    ("private", "10.0.0.1.nip.io", "10.0.0.1"),
+    ("private", "172.16.0.1.nip.io", "172.16.0.1"),
+    ("private", "192.168.0.1.nip.io", "192.168.0.1"),
+    ("link-local", "169.254.169.254.nip.io", "169.254.169.254"),
+    ("cgnat", "100.64.0.1.nip.io", "100.64.0.1"),
+    (CONTROL, "1.1.1.1.nip.io", "1.1.1.1"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The private address came through.
+    Passed,
+    /// The answer was dropped, rewritten, or the name reported missing.
+    Filtered,
+    /// The query failed.
+    Error,
+}
+
+/// Machine-readable result of one name on one server.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Rebind {
+    pub range: String,
+    pub name: String,
+    pub expected: IpAddr,
+    pub server: String,
+    pub outcome: Outcome,
+    /// The DNS response code.
+    pub status: Option<u32>,
+}
+
+pub async fn run(
+    servers: &[Server],
+    transport: &Transport,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let checks = future::join_all(NAMES.iter().flat_map(|(range, name, expected)| {
+        servers.iter().map(move |server| async move {
+            let expected: IpAddr = expected.parse().unwrap();
+            let (outcome, status) = match exchange(name, &server.url, "A", transport).await {
+                Ok(lookup) => {
+                    let passed = lookup
+                        .answers
+                        .iter()
+                        .any(|a| a.data.parse::<IpAddr>().ok() == Some(expected));
+                    let outcome = if passed {
+                        Outcome::Passed
+                    } else {
+                        Outcome::Filtered
+                    };
+                    (outcome, Some(lookup.status))
+                }
+                Err(e) => {
+                    error!("{} error: {}: {}", server.tag, name, e);
+                    (Outcome::Error, None)
+                }
+            };
+            Rebind {
+                range: String::from(*range),
+                name: String::from(*name),
+                expected,
+                server: server.tag.clone(),
+                outcome,
+                status,
+            }
+        })
+    }))
+    .await;
+
+    for check in &checks {
+        if check.range == CONTROL && check.outcome == Outcome::Filtered {
+            warn!(
+                "{} doesn't resolve the control name {}, its other results are unreliable",
+                check.server, check.name
+            );
+        }
+    }
+    if !output.is_table() {
+        return output.json(&checks);
+    }
+
+    let mut builder = Builder::default();
+    let mut columns: Vec<String> = vec!["Range".into(), "Name".into()];
+    columns.extend(servers.iter().map(|s| s.tag.clone()));
+    builder.set_columns(columns);
+    let mut rows: Vec<&[Rebind]> = checks.chunks(servers.len().max(1)).collect();
+    let hidden = output.truncate(&mut rows);
+    for row in rows {
+        let mut record = vec![row[0].range.clone(), row[0].name.clone()];
+        record.extend(row.iter().map(|c| match c.outcome {
+            Outcome::Passed => String::from("passed"),
+            Outcome::Filtered => output.paint.status(true, String::from("filtered")),
+            Outcome::Error => output.paint.status(false, String::from("error")),
+        }));
+        builder.add_record(record);
+    }
+    let mut table = builder.build();
+    table
+        .with(Style::modern())
+        .with(Modify::new(Rows::first()).with(Alignment::center()));
+    output::fit(&mut table, &[]);
+    output.print(&table.to_string(), hidden);
+
+    for server in servers {
+        let private: Vec<&Rebind> = checks
+            .iter()
+            .filter(|c| c.server == server.tag && c.range != CONTROL)
+            .collect();
+        let filtered = private
+            .iter()
+            .filter(|c| c.outcome == Outcome::Filtered)
+            .count();
+        let passed = private
+            .iter()
+            .filter(|c| c.outcome == Outcome::Passed)
+            .count();
+        let verdict = match filtered {
+            0 if passed == 0 => "no answers",
+            0 => "passes private addresses through",
+            n if n == private.len() => "filters private addresses",
+            _ => "filters some private ranges",
+        };
+        println!(
+            "{}: {} ({}/{} filtered)",
+            server.tag,
+            verdict,
+            filtered,
+            private.len()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,356 @@
+//! `doh report`: sample every server for a while, then check query and answer availability and
+//! latency percentiles against declared SLO targets, printing a pass/fail report.
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+use futures::future;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tabled::builder::Builder;
+use tabled::object::{Columns, Rows};
+use tabled::{Alignment, Modify, Style};
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{error, info};
+
+use crate::config::{ProbeSettings, Server};
+use crate::output::{self, Output};
+use crate::{exchange, icmp, icmp_client, interrupt, ping, Transport};
+
+/// A service level objective every resolver and answer is held to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    /// Minimum percentage of successful queries, or of answered pings.
+    Availability(f64),
+    /// Maximum latency at a percentile, in milliseconds.
+    Latency { percentile: f64, max_ms: f64 },
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Availability(min) => write!(f, "availability={}%", min),
+            Target::Latency { percentile, max_ms } => write!(f, "p{}={}ms", percentile, max_ms),
+        }
+    }
+}
+
+/// Parse a duration such as `90`, `30s`, `5m` or `1h`; bare numbers are seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(idx) => value.split_at(idx),
+        None => (value, "s"),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("`{}` is not a duration like 30s, 5m or 1h", value))?;
+    let secs = match unit {
+        "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => {
+            return Err(format!(
+                "unknown unit `{}` in `{}`, use ms, s, m or h",
+                unit, value
+            ))
+        }
+    };
+    if secs <= 0.0 || !secs.is_finite() {
+        return Err(format!("`{}` must be a positive duration", value));
+    }
+    Ok(Duration::from_secs_f64(secs))
+}
+
+/// Parse an `--slo` target: `availability=99.9` (percent) or `p95=200ms`.
+pub fn parse_target(value: &str) -> Result<Target, String> {
+    let (metric, limit) = value.split_once('=').ok_or_else(|| {
+        format!(
+            "expected `availability=PERCENT` or `pNN=LATENCY`, got `{}`",
+            value
+        )
+    })?;
+    if metric == "availability" {
+        let min: f64 = limit
+            .trim_end_matches('%')
+            .parse()
+            .map_err(|_| format!("`{}` is not a percentage", limit))?;
+        if !(0.0..=100.0).contains(&min) {
+            return Err(format!(
+                "availability must be between 0 and 100, got {}",
+                min
+            ));
+        }
+        return Ok(Target::Availability(min));
+    }
+    let percentile: f64 = metric
+        .strip_prefix('p')
+        .and_then(|p| p.parse().ok())
+        .filter(|p| *p > 0.0 && *p <= 100.0)
+        .ok_or_else(|| {
+            format!(
+                "unknown metric `{}`, use availability or p50, p95, ...",
+                metric
+            )
+        })?;
+    // bare latencies are milliseconds, like everywhere else in the output
+    let limit = if limit.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{}ms", limit)
+    } else {
+        String::from(limit)
+    };
+    let max = parse_duration(&limit)?;
+    Ok(Target::Latency {
+        percentile,
+        max_ms: max.as_secs_f64() * 1000.0,
+    })
+}
+
+/// What was measured for one resolver, or one of its answers, over all samples.
+#[derive(Debug, Default)]
+struct Series {
+    attempts: u32,
+    successes: u32,
+    latencies: Vec<f64>,
+}
+
+impl Series {
+    fn availability(&self) -> Option<f64> {
+        (self.attempts > 0).then(|| self.successes as f64 / self.attempts as f64 * 100.0)
+    }
+
+    /// Nearest-rank percentile of the latencies.
+    fn percentile(&self, percentile: f64) -> Option<f64> {
+        let mut sorted = self.latencies.clone();
+        sorted.sort_by(f64::total_cmp);
+        let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+        sorted.get(rank.max(1) - 1).copied()
+    }
+
+    /// The targets this series misses, described.
+    fn missed(&self, targets: &[Target]) -> Vec<String> {
+        targets
+            .iter()
+            .filter_map(|target| match *target {
+                Target::Availability(min) => {
+                    let actual = self.availability().unwrap_or(0.0);
+                    (actual < min).then(|| format!("availability {:.2}% < {}%", actual, min))
+                }
+                Target::Latency { percentile, max_ms } => match self.percentile(percentile) {
+                    Some(actual) if actual <= max_ms => None,
+                    Some(actual) => Some(format!("p{} {:.0}ms > {}ms", percentile, actual, max_ms)),
+                    None => Some(format!("p{} unknown, nothing answered", percentile)),
+                },
+            })
+            .collect()
+    }
+}
+
+/// Machine-readable SLO result of a resolver (no address) or of one of its answers.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Objective {
+    pub server: String,
+    /// The answer's address; absent for the resolver's own queries.
+    pub address: Option<IpAddr>,
+    /// Queries sent to the resolver, or echo requests sent to the address.
+    pub attempts: u32,
+    /// Percentage of successful queries, or of answered echo requests.
+    pub availability: Option<f64>,
+    /// Query latency for the resolver, mean round-trip time per sample for an answer.
+    pub p50_ms: Option<f64>,
+    pub p95_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+    /// Targets that were missed, empty when every target was met.
+    pub missed: Vec<String>,
+}
+
+/// One sample of one server: the query latency if it succeeded, and the ping results of its
+/// IPv4 answers as `(address, mean RTT, loss ratio)`.
+async fn sample(
+    name: &str,
+    server: &Server,
+    client: &Option<icmp::Socket>,
+    probe: &ProbeSettings,
+    transport: &Transport,
+) -> (Option<f64>, Vec<(IpAddr, i32, f32)>) {
+    let start = Instant::now();
+    let lookup = match exchange(name, &server.url, "A", transport).await {
+        Ok(lookup) => lookup,
+        Err(e) => {
+            error!("{} error: {}", server.tag, e);
+            return (None, vec![]);
+        }
+    };
+    let latency = (start.elapsed().as_secs_f64() * 10_000.0).round() / 10.0;
+    let mut addrs: Vec<IpAddr> = lookup
+        .answers
+        .iter()
+        .filter_map(|a| a.data.parse::<Ipv4Addr>().ok())
+        .map(IpAddr::V4)
+        .collect();
+    addrs.sort();
+    addrs.dedup();
+    let pings = future::join_all(addrs.into_iter().map(|addr| async move {
+        let (mean, lost, _) = ping(client.clone(), addr, probe, transport).await.ok()?;
+        Some((addr, mean, lost))
+    }))
+    .await;
+    (Some(latency), pings.into_iter().flatten().collect())
+}
+
+fn format_ms(ms: Option<f64>) -> String {
+    ms.map_or_else(|| String::from("/"), |ms| format!("{:.0}ms", ms))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    name: &str,
+    duration: Duration,
+    every: Duration,
+    targets: &[Target],
+    servers: &[Server],
+    probe: &ProbeSettings,
+    transport: &Transport,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = icmp_client(probe, probe.nat64.is_some(), transport).await;
+    let total = (duration.as_secs_f64() / every.as_secs_f64())
+        .ceil()
+        .max(1.0) as u32;
+    // the resolver's own series first, then one per answer address, in order of appearance
+    let mut series: Vec<(&str, Option<IpAddr>, Series)> = servers
+        .iter()
+        .map(|s| (s.tag.as_str(), None, Series::default()))
+        .collect();
+
+    let mut ticker = time::interval(every);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut samples = 0;
+    for idx in 0..total {
+        let results = tokio::select! {
+            results = async {
+                ticker.tick().await;
+                info!("sample {}/{}", idx + 1, total);
+                let client = &client;
+                future::join_all(servers.iter().map(|server| async move {
+                    (server, sample(name, server, client, probe, transport).await)
+                }))
+                .await
+            } => results,
+            _ = interrupt::token().cancelled() => break,
+        };
+        samples += 1;
+        for (server, (latency, pings)) in results {
+            let tag = server.tag.as_str();
+            let resolver = series
+                .iter_mut()
+                .find(|(t, addr, _)| *t == tag && addr.is_none())
+                .map(|(_, _, s)| s)
+                .expect("every server has a series");
+            resolver.attempts += 1;
+            if let Some(latency) = latency {
+                resolver.successes += 1;
+                resolver.latencies.push(latency);
+            }
+            for (addr, mean, lost) in pings {
+                let idx = match series
+                    .iter()
+                    .position(|(t, a, _)| *t == tag && *a == Some(addr))
+                {
+                    Some(idx) => idx,
+                    None => {
+                        series.push((tag, Some(addr), Series::default()));
+                        series.len() - 1
+                    }
+                };
+                let answer = &mut series[idx].2;
+                let sent = u32::from(probe.count);
+                answer.attempts += sent;
+                answer.successes += ((1.0 - lost) * sent as f32).round() as u32;
+                if mean != -1 {
+                    answer.latencies.push(mean as f64);
+                }
+            }
+        }
+    }
+    // group each resolver's answers under it
+    series.sort_by_key(|(tag, _, _)| servers.iter().position(|s| s.tag == *tag));
+
+    let objectives: Vec<Objective> = series
+        .iter()
+        .map(|(tag, address, s)| Objective {
+            server: String::from(*tag),
+            address: *address,
+            attempts: s.attempts,
+            availability: s.availability(),
+            p50_ms: s.percentile(50.0),
+            p95_ms: s.percentile(95.0),
+            p99_ms: s.percentile(99.0),
+            missed: s.missed(targets),
+        })
+        .collect();
+    let failed = objectives.iter().filter(|o| !o.missed.is_empty()).count();
+
+    if output.is_table() {
+        let mut builder = Builder::default();
+        builder.set_columns([
+            "DoH",
+            "Address",
+            "Attempts",
+            "Available",
+            "p50",
+            "p95",
+            "p99",
+            "Result",
+        ]);
+        let mut rows: Vec<&Objective> = objectives.iter().collect();
+        let hidden = output.truncate(&mut rows);
+        for o in rows {
+            let result = if targets.is_empty() {
+                String::from("-")
+            } else if o.missed.is_empty() {
+                output.paint.status(true, String::from("pass"))
+            } else {
+                output
+                    .paint
+                    .status(false, format!("fail: {}", o.missed.join(", ")))
+            };
+            builder.add_record([
+                o.server.clone(),
+                o.address
+                    .map_or_else(|| String::from("(queries)"), |a| a.to_string()),
+                o.attempts.to_string(),
+                o.availability
+                    .map_or_else(|| String::from("-"), |a| format!("{:.2}%", a)),
+                format_ms(o.p50_ms),
+                format_ms(o.p95_ms),
+                format_ms(o.p99_ms),
+                result,
+            ]);
+        }
+        let mut table = builder.build();
+        table
+            .with(Style::modern())
+            .with(Modify::new(Columns::new(2..7)).with(Alignment::right()))
+            .with(Modify::new(Rows::first()).with(Alignment::center()));
+        output::fit(&mut table, &[]);
+        output.print(&table.to_string(), hidden);
+        if !targets.is_empty() {
+            let targets: Vec<String> = targets.iter().map(Target::to_string).collect();
+            println!(
+                "{} of {} objectives met over {} samples ({})",
+                objectives.len() - failed,
+                objectives.len(),
+                samples,
+                targets.join(", ")
+            );
+        }
+    } else {
+        output.json(&objectives)?;
+    }
+    if failed > 0 {
+        crate::ranking::save();
+        std::process::exit(crate::Failure::Check as i32);
+    }
+    Ok(())
+}
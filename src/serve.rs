@@ -0,0 +1,369 @@
+//! `doh serve`: a local DNS forwarder. Plain DNS queries over UDP and TCP are relayed to the
+//! configured servers, DoH ones in wire format, and their answers cached for as long as their TTLs.
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use futures::future::{self, FutureExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time;
+use tracing::{error, info};
+
+use crate::config::Server;
+use crate::systemd::{Heartbeat, Watchdog};
+use crate::{interrupt, privacy, system, systemd, wire, Transport};
+use doh::Api;
+
+/// Largest UDP response to a query without EDNS.
+const UDP_LIMIT: usize = 512;
+/// Largest UDP response to a query with EDNS, whatever it advertises: the DNS flag day 2020 size.
+const EDNS_UDP_LIMIT: usize = 1232;
+/// Longest a TCP client may stay idle between two queries.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait after failing to take a query, e.g. out of file descriptors, before trying
+/// again instead of spinning on the error.
+const BACKOFF: Duration = Duration::from_millis(100);
+/// Cached answers with every record past this TTL expire sooner.
+const MAX_TTL: u32 = 86_400;
+/// How long NOERROR and NXDOMAIN responses without any record stay cached.
+const NEGATIVE_TTL: u32 = 60;
+/// Weight of the latest query in the per-server latency average of `--strategy fastest`.
+const ALPHA: f64 = 0.3;
+
+/// Which DoH servers a query goes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Strategy {
+    /// Send it to every server and answer with the first response
+    Race,
+    /// Send it to the server that has been fastest so far, and the next ones when it fails
+    Fastest,
+}
+
+/// A question: lowercased name, type and class, and whether the DO bit asks for DNSSEC records,
+/// which only some answers carry.
+type Key = (String, u16, u16, bool);
+
+struct Cached {
+    response: Vec<u8>,
+    stored: Instant,
+    ttl: u32,
+    /// Where the TTL of each record is, to count them down.
+    ttls: Vec<usize>,
+}
+
+struct Forwarder {
+    upstreams: Vec<String>,
+    strategy: Strategy,
+    /// Decoy queries sent along with every query forwarded, see [`privacy::decoys`].
+    decoys: u32,
+    transport: Transport,
+    cache: Mutex<HashMap<Key, Cached>>,
+    /// Moving average of each upstream's latency in milliseconds, by index.
+    latency: Mutex<Vec<Option<f64>>>,
+}
+
+impl Forwarder {
+    /// The cached response to `key`, with the TTLs lowered by the time it spent in the cache.
+    fn cached(&self, key: &Key) -> Option<Vec<u8>> {
+        let mut cache = self.cache.lock().unwrap();
+        let cached = cache.get(key)?;
+        let elapsed = cached.stored.elapsed().as_secs() as u32;
+        if elapsed >= cached.ttl {
+            cache.remove(key);
+            return None;
+        }
+        let mut response = cached.response.clone();
+        for &offset in &cached.ttls {
+            let ttl = u32::from_be_bytes(response[offset..offset + 4].try_into().unwrap());
+            response[offset..offset + 4]
+                .copy_from_slice(&ttl.saturating_sub(elapsed).to_be_bytes());
+        }
+        Some(response)
+    }
+
+    /// Keep a successful or NXDOMAIN `response` for its lowest TTL.
+    fn store(&self, key: Key, response: &[u8]) {
+        let Ok(message) = wire::parse(response) else {
+            return;
+        };
+        if !matches!(message.rcode, 0 | 3) {
+            return;
+        }
+        let Ok(ttls) = wire::ttl_offsets(response) else {
+            return;
+        };
+        let ttl = ttls
+            .iter()
+            .map(|&offset| u32::from_be_bytes(response[offset..offset + 4].try_into().unwrap()))
+            .min()
+            .unwrap_or(NEGATIVE_TTL)
+            .min(MAX_TTL);
+        if ttl == 0 {
+            return;
+        }
+        let cached = Cached {
+            response: response.to_vec(),
+            stored: Instant::now(),
+            ttl,
+            ttls,
+        };
+        let mut cache = self.cache.lock().unwrap();
+        // expired entries are only dropped when looked up again, or here
+        cache.retain(|_, c| c.stored.elapsed().as_secs() < c.ttl as u64);
+        cache.insert(key, cached);
+    }
+
+    /// Query the upstreams in the order `--strategy fastest` tries them.
+    fn by_latency(&self) -> Vec<usize> {
+        let latency = self.latency.lock().unwrap();
+        let mut order: Vec<usize> = (0..self.upstreams.len()).collect();
+        // untried servers go first, so each one gets measured
+        order.sort_by(|&a, &b| {
+            latency[a]
+                .unwrap_or(0.0)
+                .total_cmp(&latency[b].unwrap_or(0.0))
+        });
+        order
+    }
+
+    fn observe(&self, idx: usize, elapsed: Duration) {
+        let ms = elapsed.as_secs_f64() * 1000.0;
+        let mut latency = self.latency.lock().unwrap();
+        latency[idx] = Some(latency[idx].map_or(ms, |old| ALPHA * ms + (1.0 - ALPHA) * old));
+    }
+
+    async fn upstream(&self, query: &[u8]) -> Result<Vec<u8>, String> {
+        match self.strategy {
+            Strategy::Race => {
+                let attempts = self.upstreams.iter().map(|server| {
+                    async move {
+                        crate::forward(query, server, &self.transport)
+                            .await
+                            .map_err(|e| format!("{}: {}", server, e))
+                    }
+                    .boxed()
+                });
+                future::select_ok(attempts)
+                    .await
+                    .map(|(response, _)| response)
+            }
+            Strategy::Fastest => {
+                let mut errors = vec![];
+                for idx in self.by_latency() {
+                    let server = &self.upstreams[idx];
+                    let start = Instant::now();
+                    match crate::forward(query, server, &self.transport).await {
+                        Ok(response) => {
+                            self.observe(idx, start.elapsed());
+                            return Ok(response);
+                        }
+                        Err(e) => {
+                            // a failure counts as a very slow query
+                            self.observe(idx, Duration::from_secs(2));
+                            errors.push(format!("{}: {}", server, e));
+                        }
+                    }
+                }
+                Err(errors.join("; "))
+            }
+        }
+    }
+
+    /// [`Self::upstream`], with the decoy queries sent meanwhile; the response doesn't wait for
+    /// them.
+    async fn decoyed(&self, query: &[u8]) -> Result<Vec<u8>, String> {
+        if self.decoys == 0 {
+            return self.upstream(query).await;
+        }
+        let (servers, transport, count) =
+            (self.upstreams.clone(), self.transport.clone(), self.decoys);
+        tokio::spawn(async move { privacy::decoys(count, &servers, &transport).await });
+        self.upstream(query).await
+    }
+
+    /// The response to `query`, SERVFAIL when no upstream answered; `None` for messages that
+    /// aren't queries at all.
+    async fn answer(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let (name, qtype, qclass, end) = wire::question(query).ok()?;
+        // responses, and anything but a standard query
+        if query[2] & 0x80 != 0 || query[2] & 0x78 != 0 {
+            return None;
+        }
+        let key = (name, qtype, qclass, wire::dnssec_ok(query).ok()?);
+        let mut response = match self.cached(&key) {
+            Some(response) => response,
+            None => match self.decoyed(query).await {
+                Ok(response) => {
+                    self.store(key, &response);
+                    response
+                }
+                Err(e) => {
+                    error!("{} {}: {}", key.0, wire::type_name(qtype as u32), e);
+                    wire::servfail(query, end)
+                }
+            },
+        };
+        response[..2].copy_from_slice(&query[..2]);
+        Some(response)
+    }
+}
+
+/// Largest UDP response to `query`: what its OPT record advertises, up to [`EDNS_UDP_LIMIT`], or
+/// [`UDP_LIMIT`] without EDNS.
+fn udp_limit(query: &[u8]) -> usize {
+    match wire::udp_size(query) {
+        Ok(Some(size)) => usize::from(size).clamp(UDP_LIMIT, EDNS_UDP_LIMIT),
+        _ => UDP_LIMIT,
+    }
+}
+
+async fn udp(socket: Arc<UdpSocket>, forwarder: Arc<Forwarder>, mut heartbeat: Heartbeat) {
+    let mut buf = vec![0; 65_535];
+    loop {
+        heartbeat.beat();
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            _ = heartbeat.due() => continue,
+        };
+        let (len, peer) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                error!("cannot receive a UDP query: {}", e);
+                time::sleep(BACKOFF).await;
+                continue;
+            }
+        };
+        let query = buf[..len].to_vec();
+        let (socket, forwarder) = (socket.clone(), forwarder.clone());
+        tokio::spawn(async move {
+            let Some(mut response) = forwarder.answer(&query).await else {
+                return;
+            };
+            if response.len() > udp_limit(&query) {
+                // truncated: just the question, for the client to retry over TCP
+                if let Ok((_, _, _, end)) = wire::question(&response) {
+                    response.truncate(end);
+                    response[2] |= 0x02;
+                    response[6..12].fill(0);
+                }
+            }
+            let _ = socket.send_to(&response, peer).await;
+        });
+    }
+}
+
+async fn tcp(mut stream: TcpStream, forwarder: Arc<Forwarder>) {
+    loop {
+        let mut len = [0; 2];
+        match time::timeout(IDLE_TIMEOUT, stream.read_exact(&mut len)).await {
+            Ok(Ok(_)) => {}
+            _ => return,
+        }
+        let mut query = vec![0; u16::from_be_bytes(len) as usize];
+        if stream.read_exact(&mut query).await.is_err() {
+            return;
+        }
+        let Some(response) = forwarder.answer(&query).await else {
+            return;
+        };
+        let framed = [&(response.len() as u16).to_be_bytes()[..], &response].concat();
+        if stream.write_all(&framed).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Answer DNS queries on `listen` over UDP and TCP until Ctrl+C.
+pub async fn run(
+    listen: SocketAddr,
+    strategy: Strategy,
+    decoys: u32,
+    servers: &[Server],
+    transport: &Transport,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // the JSON API can't carry arbitrary DNS messages, nor can getaddrinfo
+    let upstreams: Vec<String> = servers
+        .iter()
+        .filter(|s| !system::is_system(&s.url))
+        .filter(|s| s.api != Some(Api::Json))
+        .map(|s| s.url.clone())
+        .collect();
+    if upstreams.is_empty() {
+        return Err(
+            "no server to forward to, system: and JSON-only servers cannot relay DNS messages"
+                .into(),
+        );
+    }
+    // the sockets of a systemd socket unit stand in for --listen
+    let activated = systemd::activated();
+    let socket = match activated.udp {
+        Some(socket) => {
+            socket.set_nonblocking(true)?;
+            UdpSocket::from_std(socket)?
+        }
+        None => UdpSocket::bind(listen)
+            .await
+            .map_err(|e| format!("cannot listen on {}: {}", listen, e))?,
+    };
+    let listener = match activated.tcp {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)?
+        }
+        None => TcpListener::bind(listen)
+            .await
+            .map_err(|e| format!("cannot listen on {}: {}", listen, e))?,
+    };
+    info!(
+        "forwarding DNS queries on {} (UDP) and {} (TCP) to {}",
+        socket.local_addr()?,
+        listener.local_addr()?,
+        upstreams.join(", ")
+    );
+    let forwarder = Arc::new(Forwarder {
+        latency: Mutex::new(vec![None; upstreams.len()]),
+        upstreams,
+        strategy,
+        decoys,
+        transport: transport.clone(),
+        cache: Mutex::new(HashMap::new()),
+    });
+
+    let mut watchdog = Watchdog::from_env();
+    let udp = tokio::spawn(udp(
+        Arc::new(socket),
+        forwarder.clone(),
+        watchdog.heartbeat(),
+    ));
+    let mut heartbeat = watchdog.heartbeat();
+    let tcp = tokio::spawn(async move {
+        loop {
+            heartbeat.beat();
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = heartbeat.due() => continue,
+            };
+            match accepted {
+                Ok((stream, _)) => {
+                    tokio::spawn(tcp(stream, forwarder.clone()));
+                }
+                Err(e) => {
+                    error!("cannot accept a TCP connection: {}", e);
+                    time::sleep(BACKOFF).await;
+                }
+            }
+        }
+    });
+    systemd::ready();
+    tokio::select! {
+        _ = watchdog.watch() => {}
+        _ = interrupt::token().cancelled() => {}
+    }
+    systemd::stopping();
+    udp.abort();
+    tcp.abort();
+    Ok(())
+}
@@ -0,0 +1,213 @@
+//! `--server-list`: the servers of a public list of resolvers, either the curl wiki's table of
+//! DoH servers or dnscrypt-proxy's `public-resolvers.md`, whose DNS stamps carry each address.
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use reqwest::Url;
+use tracing::info;
+
+use crate::config::Server;
+use crate::read_input;
+
+/// The first byte of the DNS stamp of a DoH server.
+const DOH_STAMP: u8 = 0x02;
+
+/// The DoH servers of the list at `location`, a URL or a path, `-` for stdin.
+pub async fn load(location: &str) -> Result<Vec<Server>, Box<dyn std::error::Error>> {
+    let text = if location.starts_with("http://") || location.starts_with("https://") {
+        let download = async {
+            reqwest::get(location)
+                .await?
+                .error_for_status()?
+                .text()
+                .await
+        };
+        download
+            .await
+            .map_err(|e| format!("cannot download the server list: {}", e))?
+    } else {
+        read_input(location).map_err(|e| format!("cannot read server list {}: {}", location, e))?
+    };
+    let servers = parse(&text);
+    if servers.is_empty() {
+        return Err(format!("no DoH server in the server list {}", location).into());
+    }
+    info!("{} servers in the server list {}", servers.len(), location);
+    Ok(servers)
+}
+
+/// Keep the servers whose tag or URL contains any of `filters`, ignoring case, then the first
+/// `max` of them.
+pub fn select(
+    servers: Vec<Server>,
+    filters: &[String],
+    max: Option<usize>,
+) -> Result<Vec<Server>, String> {
+    let filters: Vec<String> = filters.iter().map(|f| f.to_lowercase()).collect();
+    let selected: Vec<Server> = servers
+        .into_iter()
+        .filter(|s| {
+            let (tag, url) = (s.tag.to_lowercase(), s.url.to_lowercase());
+            filters.is_empty() || filters.iter().any(|f| tag.contains(f) || url.contains(f))
+        })
+        .take(max.unwrap_or(usize::MAX))
+        .collect();
+    if selected.is_empty() {
+        return Err(String::from(
+            "no server in the server list matches --server-filter",
+        ));
+    }
+    Ok(selected)
+}
+
+fn parse(text: &str) -> Vec<Server> {
+    let found = if text.contains("sdns://") {
+        stamps(text)
+    } else {
+        table(text)
+    };
+    // a provider with several endpoints gets `-2`, `-3`... after the first
+    let mut servers: Vec<Server> = vec![];
+    for (tag, url) in found {
+        if servers.iter().any(|s| s.url == url) {
+            continue;
+        }
+        let mut unique = tag.clone();
+        let mut count = 1;
+        while servers.iter().any(|s| s.tag == unique) {
+            count += 1;
+            unique = format!("{}-{}", tag, count);
+        }
+        servers.push(Server {
+            tag: unique,
+            url,
+            api: None,
+            proxy: None,
+            ip: None,
+            headers: vec![],
+            json_dialect: None,
+        });
+    }
+    servers
+}
+
+/// `text` lowercased, with runs of anything but letters, digits, `.` and `-` made a `-`.
+fn slug(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.trim().chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() || c == '.' || c == '-' {
+            slug.push(c);
+        } else if !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    String::from(slug.trim_matches('-'))
+}
+
+/// The host of `url`, the tag when nothing better is known.
+fn host(url: &str) -> Option<String> {
+    Url::parse(url).ok()?.host_str().map(String::from)
+}
+
+/// An HTTPS URL, not the target of a Markdown link, each one ending at whitespace or markup.
+fn bare_urls(cell: &str) -> Vec<String> {
+    let mut urls = vec![];
+    let mut rest = cell;
+    while let Some(start) = rest.find("https://") {
+        let linked = rest[..start].ends_with("](");
+        let url = &rest[start..];
+        let end = url
+            .find(|c: char| c.is_whitespace() || "<>()[]|\"'`".contains(c))
+            .unwrap_or(url.len());
+        let candidate = url[..end].trim_end_matches(['.', ',']);
+        if !linked && host(candidate).is_some() {
+            urls.push(String::from(candidate));
+        }
+        rest = &url[end..];
+    }
+    urls
+}
+
+/// The servers of a Markdown table like the curl wiki's: its first column names the provider,
+/// and the first cell with bare URLs has its endpoints. Lines with nothing but a URL are taken
+/// too, for plain lists.
+fn table(text: &str) -> Vec<(String, String)> {
+    let mut found = vec![];
+    for line in text.lines().map(str::trim) {
+        if line.starts_with("https://") {
+            let urls = bare_urls(line);
+            found.extend(urls.into_iter().filter_map(|url| Some((host(&url)?, url))));
+            continue;
+        }
+        if !line.starts_with('|') {
+            continue;
+        }
+        let cells: Vec<&str> = line.trim_matches('|').split('|').collect();
+        let Some(urls) = cells.iter().map(|c| bare_urls(c)).find(|u| !u.is_empty()) else {
+            continue;
+        };
+        // `[Provider](https://provider.example)` is named by its text
+        let name = cells[0].split("](").next().unwrap_or_default();
+        let name = slug(&name.replace(['[', ']', '*', '_'], ""));
+        for url in urls {
+            let tag = if name.is_empty() {
+                host(&url).unwrap_or_default()
+            } else {
+                name.clone()
+            };
+            found.push((tag, url));
+        }
+    }
+    found
+}
+
+/// The resolvers of a dnscrypt-proxy list: a `## name` heading, then a description and the
+/// `sdns://` stamps of the resolver. Only the stamps of DoH servers are taken.
+fn stamps(text: &str) -> Vec<(String, String)> {
+    let mut found = vec![];
+    let mut name = String::new();
+    for line in text.lines().map(str::trim) {
+        if let Some(heading) = line.strip_prefix("## ") {
+            name = slug(heading);
+        } else if let Some(stamp) = line.strip_prefix("sdns://") {
+            if let Some(url) = doh_stamp(stamp) {
+                let tag = if name.is_empty() {
+                    host(&url).unwrap_or_default()
+                } else {
+                    name.clone()
+                };
+                found.push((tag, url));
+            }
+        }
+    }
+    found
+}
+
+/// The URL in a DoH server's DNS stamp: the protocol, 8 bytes of properties, the address, the
+/// certificate hashes, then the host name and the path.
+fn doh_stamp(stamp: &str) -> Option<String> {
+    let bytes = URL_SAFE_NO_PAD.decode(stamp.trim_end_matches('=')).ok()?;
+    if bytes.first() != Some(&DOH_STAMP) {
+        return None;
+    }
+    let mut pos = 9;
+    // one length-prefixed string
+    let string = |pos: &mut usize| -> Option<String> {
+        let len = *bytes.get(*pos)? as usize;
+        let value = bytes.get(*pos + 1..*pos + 1 + len)?;
+        *pos += 1 + len;
+        String::from_utf8(value.to_vec()).ok()
+    };
+    string(&mut pos)?;
+    // the hashes, each length with the high bit set when another follows
+    loop {
+        let len = *bytes.get(pos)?;
+        pos += 1 + (len & 0x7f) as usize;
+        if len & 0x80 == 0 {
+            break;
+        }
+    }
+    let hostname = string(&mut pos)?;
+    let path = string(&mut pos)?;
+    let url = format!("https://{}{}", hostname, path);
+    host(&url).map(|_| url)
+}
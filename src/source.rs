@@ -0,0 +1,189 @@
+//! `--source` and `--interface`: the local address, or the network interface, that DoH
+//! connections, plain DNS queries and probes go out through, for the path of one link on a
+//! machine with several.
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use surge_ping::ConfigBuilder;
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+/// Where everything goes out from: the system's choice unless an address or interface is set.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Source {
+    /// Local addresses to bind to, at most one of each family.
+    addresses: Vec<IpAddr>,
+    /// The interface of `--interface`.
+    interface: Option<String>,
+}
+
+impl Source {
+    /// Send everything of the family of `ip` from it.
+    pub fn set_address(&mut self, ip: IpAddr) {
+        self.addresses.retain(|a| a.is_ipv4() != ip.is_ipv4());
+        self.addresses.push(ip);
+    }
+
+    /// Send everything through the interface called `name`, from its addresses unless
+    /// [`Source::set_address`] gives one of the same family.
+    pub fn set_interface(&mut self, name: &str) -> Result<(), String> {
+        let found = interface_addresses(name)?;
+        if found.is_empty() {
+            return Err(format!("interface {} has no address", name));
+        }
+        // its first address of each family, ignoring IPv6 link-local ones, which need a scope
+        for ip in found {
+            let link_local = matches!(ip, IpAddr::V6(v6) if v6.segments()[0] & 0xffc0 == 0xfe80);
+            if !link_local && !self.addresses.iter().any(|a| a.is_ipv4() == ip.is_ipv4()) {
+                self.addresses.push(ip);
+            }
+        }
+        self.interface = Some(String::from(name));
+        Ok(())
+    }
+
+    /// The local address to reach `target` from, if one was set for its family.
+    pub fn local(&self, target: IpAddr) -> Option<IpAddr> {
+        self.addresses
+            .iter()
+            .find(|a| a.is_ipv4() == target.is_ipv4())
+            .copied()
+    }
+
+    /// The local address for reqwest, which binds one for every server: the IPv4 one when both
+    /// are set, as most servers still have IPv4.
+    pub fn preferred(&self) -> Option<IpAddr> {
+        self.local(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+            .or_else(|| self.local(IpAddr::V6(Ipv6Addr::UNSPECIFIED)))
+    }
+
+    /// Whether an address or interface was set at all.
+    pub fn bound(&self) -> bool {
+        !self.addresses.is_empty() || self.interface.is_some()
+    }
+
+    /// `config` for ICMP echo requests to IPv4 targets, or IPv6 ones with `v6`, bound as set.
+    pub fn icmp(&self, mut config: ConfigBuilder, v6: bool) -> ConfigBuilder {
+        if let Some(ip) = self.local(unspecified(v6)) {
+            config = config.bind(SocketAddr::new(ip, 0));
+        }
+        if let Some(name) = &self.interface {
+            config = config.interface(name);
+        }
+        config
+    }
+
+    /// A TCP connection to `addr`, from the address and interface set.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let ip = self.local(addr.ip());
+        if ip.is_none() && self.interface.is_none() {
+            return TcpStream::connect(addr).await;
+        }
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(name) = &self.interface {
+            socket.bind_device(Some(name.as_bytes()))?;
+        }
+        if let Some(ip) = ip {
+            socket.bind(SocketAddr::new(ip, 0))?;
+        }
+        socket.connect(addr).await
+    }
+
+    /// A UDP socket for talking to `addr`, from the address and interface set.
+    pub async fn udp(&self, addr: SocketAddr) -> io::Result<UdpSocket> {
+        let ip = self.local(addr.ip()).unwrap_or(unspecified(addr.is_ipv6()));
+        let socket = UdpSocket::bind(SocketAddr::new(ip, 0)).await?;
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(name) = &self.interface {
+            socket.bind_device(Some(name.as_bytes()))?;
+        }
+        Ok(socket)
+    }
+
+    /// An unprivileged ICMP datagram socket for IPv4 targets, or IPv6 ones with `v6`, from the
+    /// address and interface set.
+    pub fn icmp_datagram(&self, v6: bool) -> io::Result<UdpSocket> {
+        let socket = if v6 {
+            Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::ICMPV6))?
+        } else {
+            Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4))?
+        };
+        if let Some(ip) = self.local(unspecified(v6)) {
+            socket.bind(&SocketAddr::new(ip, 0).into())?;
+        }
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(name) = &self.interface {
+            socket.bind_device(Some(name.as_bytes()))?;
+        }
+        socket.set_nonblocking(true)?;
+        UdpSocket::from_std(socket.into())
+    }
+}
+
+/// The unspecified address of IPv6, with `v6`, or IPv4.
+fn unspecified(v6: bool) -> IpAddr {
+    match v6 {
+        true => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        false => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    }
+}
+
+/// The addresses of the interface called `name`.
+#[cfg(unix)]
+fn interface_addresses(name: &str) -> Result<Vec<IpAddr>, String> {
+    let mut found = vec![];
+    let mut known = false;
+    // SAFETY: the list getifaddrs allocates is only read until freeifaddrs frees it
+    unsafe {
+        let mut list = std::ptr::null_mut::<libc::ifaddrs>();
+        if libc::getifaddrs(&mut list) != 0 {
+            return Err(format!(
+                "cannot list network interfaces: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        let mut entry = list;
+        while !entry.is_null() {
+            let ifa = &*entry;
+            entry = ifa.ifa_next;
+            if std::ffi::CStr::from_ptr(ifa.ifa_name).to_bytes() != name.as_bytes() {
+                continue;
+            }
+            known = true;
+            if ifa.ifa_addr.is_null() {
+                continue;
+            }
+            match i32::from((*ifa.ifa_addr).sa_family) {
+                libc::AF_INET => {
+                    let addr = &*(ifa.ifa_addr as *const libc::sockaddr_in);
+                    found.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                        addr.sin_addr.s_addr,
+                    ))));
+                }
+                libc::AF_INET6 => {
+                    let addr = &*(ifa.ifa_addr as *const libc::sockaddr_in6);
+                    found.push(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+                }
+                _ => {}
+            }
+        }
+        libc::freeifaddrs(list);
+    }
+    if !known {
+        return Err(format!("no network interface called {}", name));
+    }
+    Ok(found)
+}
+
+#[cfg(not(unix))]
+fn interface_addresses(name: &str) -> Result<Vec<IpAddr>, String> {
+    Err(format!(
+        "cannot bind to interface {}: --interface needs a Unix system, use --source instead",
+        name
+    ))
+}
@@ -0,0 +1,51 @@
+//! HTTPS and SVCB records (RFC 9460), read back from the presentation format servers and
+//! [`wire`](crate::wire) render them in, for their parameters and address hints.
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/// Record type codes of SVCB and HTTPS records.
+pub const TYPES: [u32; 2] = [64, 65];
+
+/// The fields of an HTTPS or SVCB record.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct Svcb {
+    /// 0 for an alias of `target`, otherwise the preference of this endpoint, lowest first.
+    pub priority: u16,
+    /// Where the service is, `.` for the name queried itself.
+    pub target: String,
+    /// Parameters by key, `alpn` = `h2,h3` say, an empty value for a key without one.
+    pub params: BTreeMap<String, String>,
+}
+
+impl Svcb {
+    /// The data of an HTTPS or SVCB answer, `1 . alpn=h2,h3 ipv4hint=192.0.2.1`.
+    pub fn parse(data: &str) -> Option<Self> {
+        let mut parts = data.split_whitespace();
+        let priority = parts.next()?.parse().ok()?;
+        let target = String::from(parts.next()?);
+        let params = parts
+            .map(|param| {
+                let (key, value) = param.split_once('=').unwrap_or((param, ""));
+                (String::from(key), String::from(value.trim_matches('"')))
+            })
+            .collect();
+        Some(Self {
+            priority,
+            target,
+            params,
+        })
+    }
+
+    /// The addresses of `ipv4hint` and `ipv6hint`, in that order.
+    pub fn hints(&self) -> Vec<IpAddr> {
+        ["ipv4hint", "ipv6hint"]
+            .iter()
+            .filter_map(|key| self.params.get(*key))
+            .flat_map(|list| list.split(','))
+            .filter_map(|addr| addr.parse().ok())
+            .collect()
+    }
+}
@@ -0,0 +1,355 @@
+//! systemd integration: readiness and watchdog notifications (`sd_notify`) and socket activation
+//! for `doh serve` and `doh exporter`, and the units `doh daemon` writes to run doh for good.
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::{self, Interval, MissedTickBehavior};
+use tracing::debug;
+#[cfg(unix)]
+use tracing::warn;
+
+/// How long systemd waits for a watchdog notification before restarting `doh serve` or
+/// `doh exporter`; they send one every half of it while their loops keep coming round.
+const WATCHDOG: Duration = Duration::from_secs(30);
+
+/// Tell the service manager about `state`, e.g. `READY=1`, when it asked to be told.
+#[cfg(unix)]
+fn notify(state: &str) {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let sent =
+        UnixDatagram::unbound().and_then(|socket| match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => send_abstract(&socket, name, state),
+            None => socket.send_to(state.as_bytes(), &path),
+        });
+    if let Err(e) = sent {
+        debug!("cannot notify systemd of {}: {}", state, e);
+    }
+}
+
+#[cfg(not(unix))]
+fn notify(_state: &str) {}
+
+/// Send `state` to the socket called `name` in the abstract namespace.
+#[cfg(target_os = "linux")]
+fn send_abstract(
+    socket: &std::os::unix::net::UnixDatagram,
+    name: &[u8],
+    state: &str,
+) -> std::io::Result<usize> {
+    use std::os::linux::net::SocketAddrExt;
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    socket.send_to_addr(state.as_bytes(), &addr)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn send_abstract(
+    _socket: &std::os::unix::net::UnixDatagram,
+    _name: &[u8],
+    _state: &str,
+) -> std::io::Result<usize> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Whether the environment variable `name`, set by systemd, is about this process.
+fn ours(name: &str) -> bool {
+    std::env::var(name).is_ok_and(|pid| pid == std::process::id().to_string())
+}
+
+/// Tell systemd the service is up.
+pub fn ready() {
+    notify("READY=1");
+}
+
+/// systemd's watchdog, fed by the loops doing the service's work: it hears the service is alive
+/// only when every one of them has come round since it last did, so a stuck loop gets it
+/// restarted.
+#[derive(Debug)]
+pub struct Watchdog {
+    /// How often systemd wants to hear from the service, none when it doesn't watch.
+    every: Option<Duration>,
+    loops: Vec<Arc<AtomicBool>>,
+}
+
+impl Watchdog {
+    /// The watchdog systemd set up for this process, if any.
+    pub fn from_env() -> Self {
+        let usec = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|u| u.parse().ok())
+            .filter(|_| std::env::var("WATCHDOG_PID").is_err() || ours("WATCHDOG_PID"));
+        Self {
+            every: usec.map(|usec| Duration::from_micros(usec) / 2),
+            loops: Vec::new(),
+        }
+    }
+
+    /// The heartbeat of one more loop to wait for.
+    pub fn heartbeat(&mut self) -> Heartbeat {
+        let alive = Arc::new(AtomicBool::new(false));
+        self.loops.push(alive.clone());
+        // twice a round, so each round sees a beat
+        let every = self.every.map(|every| {
+            let mut ticker = time::interval(every / 2);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticker
+        });
+        Heartbeat { every, alive }
+    }
+
+    /// Tell systemd the service is alive every round all the loops came round in; never returns.
+    pub async fn watch(self) {
+        let Some(every) = self.every else {
+            return std::future::pending().await;
+        };
+        let mut ticker = time::interval(every);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            // swap every flag back, not just up to the first one that's down
+            let stuck = self
+                .loops
+                .iter()
+                .filter(|alive| !alive.swap(false, Ordering::Relaxed))
+                .count();
+            if stuck == 0 {
+                notify("WATCHDOG=1");
+            } else {
+                debug!(
+                    "not feeding the systemd watchdog, {} loops are stuck",
+                    stuck
+                );
+            }
+        }
+    }
+}
+
+/// What a loop feeds the [`Watchdog`] with.
+#[derive(Debug)]
+pub struct Heartbeat {
+    every: Option<Interval>,
+    alive: Arc<AtomicBool>,
+}
+
+impl Heartbeat {
+    /// The loop came round.
+    pub fn beat(&self) {
+        self.alive.store(true, Ordering::Relaxed);
+    }
+
+    /// Wait until an idle loop should come round anyway, for the watchdog to hear of it; never
+    /// when systemd doesn't watch.
+    pub async fn due(&mut self) {
+        match &mut self.every {
+            Some(ticker) => {
+                ticker.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Tell systemd the service is shutting down.
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+/// The sockets systemd passed with socket activation (`LISTEN_FDS`): the first listening TCP
+/// one and the first UDP one.
+#[derive(Debug, Default)]
+pub struct Activated {
+    pub tcp: Option<TcpListener>,
+    pub udp: Option<UdpSocket>,
+}
+
+/// The sockets passed to this process, none when it wasn't socket activated or they were taken
+/// already. Like `sd_listen_fds(1)`, it unsets the variables passing them, so that no child
+/// process takes them for its own.
+#[cfg(unix)]
+pub fn activated() -> Activated {
+    use std::os::unix::io::FromRawFd;
+
+    use socket2::{Socket, Type};
+
+    /// The first file descriptor passed, after stdin, stdout and stderr.
+    const FIRST: i32 = 3;
+    /// Whether the descriptors have been taken, for them to have a single owner.
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+
+    let mut activated = Activated::default();
+    if !ours("LISTEN_PID") || TAKEN.swap(true, Ordering::SeqCst) {
+        return activated;
+    }
+    let count: i32 = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0);
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    for fd in FIRST..FIRST + count {
+        // SAFETY: systemd hands the descriptors from 3 on over to this process, and TAKEN makes
+        // sure they are wrapped once
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        // systemd passes them inheritable, but they are no business of the processes doh runs
+        if let Err(e) = socket.set_cloexec(true) {
+            warn!(
+                "cannot set close-on-exec on socket {} passed by systemd: {}",
+                fd, e
+            );
+        }
+        match socket.r#type() {
+            Ok(Type::STREAM) if activated.tcp.is_none() => activated.tcp = Some(socket.into()),
+            Ok(Type::DGRAM) if activated.udp.is_none() => activated.udp = Some(socket.into()),
+            _ => warn!("ignoring socket {} passed by systemd", fd),
+        }
+    }
+    activated
+}
+
+#[cfg(not(unix))]
+pub fn activated() -> Activated {
+    Activated::default()
+}
+
+/// What `doh daemon` sets up.
+pub enum Unit {
+    /// `doh serve`, answering on this address.
+    Serve(SocketAddr),
+    /// `doh exporter`, serving metrics on this address.
+    Exporter(SocketAddr),
+    /// Any other command, run by a timer on this interval.
+    Timer(Duration),
+}
+
+/// `arg` quoted for an `ExecStart=` line, where `%` starts a specifier and `$` a variable.
+fn quote(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    let plain =
+        !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || "\"'\\;".contains(c));
+    if plain {
+        escaped
+    } else {
+        format!("\"{}\"", escaped)
+    }
+}
+
+/// The unit files running `exec` with `args` as `unit` does, by file name: a service and a socket
+/// for its port, or a service and its timer.
+pub fn units(
+    name: &str,
+    unit: &Unit,
+    exec: &Path,
+    args: &[String],
+    user: bool,
+) -> Vec<(String, String)> {
+    let command: Vec<String> = std::iter::once(exec.display().to_string())
+        .chain(args.iter().cloned())
+        .map(|arg| quote(&arg))
+        .collect();
+    let command = command.join(" ");
+    let wanted_by = if user {
+        "default.target"
+    } else {
+        "multi-user.target"
+    };
+    let network = "After=network-online.target\nWants=network-online.target\n";
+    match unit {
+        Unit::Serve(listen) | Unit::Exporter(listen) => {
+            let (what, listens) = match unit {
+                Unit::Serve(_) => (
+                    "doh serve, a DNS forwarder to DoH servers",
+                    format!("ListenStream={}\nListenDatagram={}\n", listen, listen),
+                ),
+                _ => (
+                    "doh exporter, DoH server metrics for Prometheus",
+                    format!("ListenStream={}\n", listen),
+                ),
+            };
+            let service = format!(
+                "[Unit]\nDescription={what}\n{network}\n[Service]\nType=notify\nExecStart={command}\n\
+                 SuccessExitStatus=130\nRestart=on-failure\nWatchdogSec={}\n\n[Install]\nWantedBy={wanted_by}\n",
+                WATCHDOG.as_secs()
+            );
+            let socket = format!(
+                "[Unit]\nDescription=Sockets of {what}\n\n[Socket]\n{listens}\n[Install]\n\
+                 WantedBy=sockets.target\n"
+            );
+            vec![
+                (format!("{}.service", name), service),
+                (format!("{}.socket", name), socket),
+            ]
+        }
+        Unit::Timer(every) => {
+            let service = format!(
+                "[Unit]\nDescription=doh {}\n{network}\n[Service]\nType=oneshot\nExecStart={command}\n",
+                args.join(" ").replace('%', "%%")
+            );
+            let timer = format!(
+                "[Unit]\nDescription=Run doh every {:?}\n\n[Timer]\nOnBootSec=1min\n\
+                 OnUnitActiveSec={}s\n\n[Install]\nWantedBy=timers.target\n",
+                every,
+                every.as_secs().max(1)
+            );
+            vec![
+                (format!("{}.service", name), service),
+                (format!("{}.timer", name), timer),
+            ]
+        }
+    }
+}
+
+/// Where unit files go: the user's unit directory with `user`, the system's otherwise.
+pub fn unit_dir(user: bool) -> Option<PathBuf> {
+    match user {
+        true => doh::dirs::systemd_user_dir(),
+        false => Some(PathBuf::from("/etc/systemd/system")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_exec_arguments() {
+        assert_eq!(quote("--host"), "--host");
+        assert_eq!(quote("a b"), "\"a b\"");
+        assert_eq!(quote("50%"), "50%%");
+        assert_eq!(quote("$HOME"), "$$HOME");
+        assert_eq!(quote(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(quote(""), "\"\"");
+    }
+
+    #[test]
+    fn serve_gets_a_socket() {
+        let args = vec![String::from("serve")];
+        let listen = "127.0.0.1:5353".parse().unwrap();
+        let units = units(
+            "doh-serve",
+            &Unit::Serve(listen),
+            Path::new("/usr/bin/doh"),
+            &args,
+            false,
+        );
+        let names: Vec<&str> = units.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["doh-serve.service", "doh-serve.socket"]);
+        assert!(units[0]
+            .1
+            .contains("Type=notify\nExecStart=/usr/bin/doh serve\n"));
+        assert!(units[1]
+            .1
+            .contains("ListenStream=127.0.0.1:5353\nListenDatagram=127.0.0.1:5353\n"));
+    }
+}
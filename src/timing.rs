@@ -0,0 +1,174 @@
+//! `--timing`: split a DoH request into name resolution, TCP connect, TLS handshake and time to
+//! first byte. reqwest doesn't expose these phases, so the connection is driven by hand.
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use hyper::header::{ACCEPT, CONTENT_TYPE, HOST};
+use hyper::{Body, Method, Request};
+use reqwest::Url;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tabled::builder::Builder;
+use tabled::object::{Columns, Rows};
+use tabled::{Alignment, Modify, Style, Table};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_native_tls::TlsConnector;
+
+use crate::{Reply, Transport};
+
+/// How long each phase of one request took, in milliseconds.
+#[derive(Debug, Clone, Copy, Serialize, JsonSchema)]
+pub struct Timing {
+    /// Resolving the server's host name.
+    pub dns_ms: f64,
+    pub connect_ms: f64,
+    /// TLS handshake, absent for plain HTTP servers.
+    pub tls_ms: Option<f64>,
+    /// From sending the request to the first byte of the response.
+    pub ttfb_ms: f64,
+    pub total_ms: f64,
+}
+
+fn millis(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 10_000.0).round() / 10.0
+}
+
+/// Send the request over `stream`, returning the reply and the time to first byte.
+async fn send<S>(
+    stream: S,
+    request: Request<Body>,
+) -> Result<(Reply, Duration), Box<dyn std::error::Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+    tokio::spawn(connection);
+    let sent = Instant::now();
+    let response = sender.send_request(request).await?;
+    let ttfb = sent.elapsed();
+    let status = response.status().as_u16();
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    let reply = Reply {
+        status,
+        // the timed connection only speaks HTTP/1.1
+        protocol: "HTTP/1.1",
+        content_type,
+        body: body.to_vec(),
+    };
+    Ok((reply, ttfb))
+}
+
+/// GET `url`, or POST `body` to it as `accept` too, on a fresh connection, timing every phase.
+pub async fn request(
+    url: &Url,
+    accept: &str,
+    body: Option<Vec<u8>>,
+    transport: &Transport,
+) -> Result<(Reply, Timing), Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    let port = url
+        .port_or_known_default()
+        .ok_or("server URL has no port")?;
+    let addrs: Vec<SocketAddr> = transport
+        .bootstrap
+        .lookup(
+            url.host_str().ok_or("server URL has no host")?,
+            port,
+            &transport.source,
+        )
+        .await?;
+    // the server is connected to, but told about the `--sni` host
+    let host = match &transport.sni {
+        Some(sni) => sni.as_str(),
+        None => url.host_str().ok_or("server URL has no host")?,
+    };
+    let resolved = Instant::now();
+    let mut tcp = Err(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        "could not resolve to any address",
+    ));
+    for addr in &addrs {
+        tcp = transport.source.connect(*addr).await;
+        if tcp.is_ok() {
+            break;
+        }
+    }
+    let tcp = tcp?;
+    let connected = Instant::now();
+
+    let authority = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => String::from(host),
+    };
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => String::from(url.path()),
+    };
+    let method = if body.is_some() {
+        Method::POST
+    } else {
+        Method::GET
+    };
+    let mut request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(HOST, authority)
+        .header(ACCEPT, accept);
+    if body.is_some() {
+        request = request.header(CONTENT_TYPE, accept);
+    }
+    for (name, value) in transport.headers(url.as_str()) {
+        request = request.header(name, value);
+    }
+    let request = request.body(body.map_or_else(Body::empty, Body::from))?;
+
+    let (reply, tls, ttfb) = if url.scheme() == "https" {
+        let connector = TlsConnector::from(transport.tls()?);
+        // the host name is needed for SNI and certificate checks, even when it is an address
+        let stream = connector
+            .connect(host.trim_start_matches('[').trim_end_matches(']'), tcp)
+            .await?;
+        let tls = connected.elapsed();
+        let (reply, ttfb) = send(stream, request).await?;
+        (reply, Some(tls), ttfb)
+    } else {
+        let (reply, ttfb) = send(tcp, request).await?;
+        (reply, None, ttfb)
+    };
+    let timing = Timing {
+        dns_ms: millis(resolved - start),
+        connect_ms: millis(connected - resolved),
+        tls_ms: tls.map(millis),
+        ttfb_ms: millis(ttfb),
+        total_ms: millis(start.elapsed()),
+    };
+    Ok((reply, timing))
+}
+
+/// One row per server with the duration of each phase.
+pub fn table(timings: &[(&str, Timing)]) -> Table {
+    let mut builder = Builder::default();
+    builder.set_columns(["DoH", "DNS", "Connect", "TLS", "TTFB", "Total"]);
+    let format = |ms: f64| format!("{:.1}ms", ms);
+    for (tag, timing) in timings {
+        builder.add_record([
+            String::from(*tag),
+            format(timing.dns_ms),
+            format(timing.connect_ms),
+            timing.tls_ms.map_or_else(|| String::from("-"), format),
+            format(timing.ttfb_ms),
+            format(timing.total_ms),
+        ]);
+    }
+    let mut table = builder.build();
+    table
+        .with(Style::modern())
+        .with(Modify::new(Columns::new(1..)).with(Alignment::right()))
+        .with(Modify::new(Rows::first()).with(Alignment::center()));
+    table
+}
@@ -0,0 +1,200 @@
+//! `doh top`: a live leaderboard of the configured servers, ranked by rolling query latency,
+//! success rate and how often their answers diverge from the other servers'.
+use std::collections::VecDeque;
+use std::io::IsTerminal;
+use std::time::{Duration, Instant};
+
+use futures::future;
+use tabled::builder::Builder;
+use tabled::object::{Columns, Rows};
+use tabled::{Alignment, Modify, Style};
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::config::Server;
+use crate::output::{self, Output};
+use crate::{exchange, interrupt, Transport};
+
+/// Popular names queried in turn when none are given.
+pub const DEFAULT_NAMES: &[&str] = &[
+    "example.com",
+    "wikipedia.org",
+    "github.com",
+    "cloudflare.com",
+];
+/// What a failed query costs in the ranking, as if it had taken this long.
+pub const FAILURE_PENALTY_MS: f64 = 2000.0;
+
+/// One query to one server.
+struct Sample {
+    /// Latency of a successful query, in milliseconds.
+    latency: Option<f64>,
+    /// Whether the answer differed from the majority's; `None` when the query failed.
+    diverged: Option<bool>,
+}
+
+/// The most recent samples of a server.
+struct Window {
+    server: Server,
+    samples: VecDeque<Sample>,
+}
+
+impl Window {
+    fn latencies(&self) -> Vec<f64> {
+        self.samples.iter().filter_map(|s| s.latency).collect()
+    }
+
+    fn mean(&self) -> Option<f64> {
+        let latencies = self.latencies();
+        (!latencies.is_empty()).then(|| latencies.iter().sum::<f64>() / latencies.len() as f64)
+    }
+
+    fn p95(&self) -> Option<f64> {
+        let mut latencies = self.latencies();
+        latencies.sort_by(f64::total_cmp);
+        let rank = (0.95 * latencies.len() as f64).ceil() as usize;
+        latencies.get(rank.max(1) - 1).copied()
+    }
+
+    fn success(&self) -> f64 {
+        let ok = self.samples.iter().filter(|s| s.latency.is_some()).count();
+        ok as f64 / self.samples.len().max(1) as f64
+    }
+
+    fn divergence(&self) -> Option<f64> {
+        let answered: Vec<bool> = self.samples.iter().filter_map(|s| s.diverged).collect();
+        let diverged = answered.iter().filter(|d| **d).count();
+        (!answered.is_empty()).then(|| diverged as f64 / answered.len() as f64)
+    }
+
+    /// Lower is better.
+    fn score(&self) -> f64 {
+        self.mean().unwrap_or(FAILURE_PENALTY_MS) + (1.0 - self.success()) * FAILURE_PENALTY_MS
+    }
+}
+
+/// Query `name` on every server; each result is the latency and the sorted answer data.
+async fn round(
+    name: &str,
+    servers: &[&Server],
+    transport: &Transport,
+) -> Vec<Option<(f64, Vec<String>)>> {
+    future::join_all(servers.iter().map(|server| async move {
+        let start = Instant::now();
+        let lookup = exchange(name, &server.url, "A", transport).await.ok()?;
+        let latency = start.elapsed().as_secs_f64() * 1000.0;
+        let mut data: Vec<String> = lookup.answers.into_iter().map(|a| a.data).collect();
+        data.sort();
+        Some((latency, data))
+    }))
+    .await
+}
+
+/// The answer most servers agree on; `None` on a tie, when there is no majority to diverge from.
+fn consensus(results: &[Option<(f64, Vec<String>)>]) -> Option<&Vec<String>> {
+    let answers: Vec<&Vec<String>> = results.iter().flatten().map(|(_, data)| data).collect();
+    let count = |data: &Vec<String>| answers.iter().filter(|d| **d == data).count();
+    let best = answers.iter().copied().max_by_key(|data| count(data))?;
+    let tied = answers
+        .iter()
+        .any(|data| *data != best && count(data) == count(best));
+    (!tied).then_some(best)
+}
+
+fn format_ms(ms: Option<f64>) -> String {
+    ms.map_or_else(|| String::from("/"), |ms| format!("{:.0}ms", ms))
+}
+
+fn render(windows: &[Window], output: &Output) -> String {
+    let mut builder = Builder::default();
+    builder.set_columns(["#", "DoH", "Avg", "p95", "Success", "Diverged", "Samples"]);
+    for (rank, window) in windows.iter().enumerate() {
+        let success = window.success();
+        let diverged = window.divergence();
+        builder.add_record([
+            (rank + 1).to_string(),
+            window.server.tag.clone(),
+            format_ms(window.mean()),
+            format_ms(window.p95()),
+            output
+                .paint
+                .status(success >= 1.0, format!("{:.0}%", success * 100.0)),
+            diverged.map_or_else(
+                || String::from("-"),
+                |d| output.paint.status(d == 0.0, format!("{:.0}%", d * 100.0)),
+            ),
+            window.samples.len().to_string(),
+        ]);
+    }
+    let mut table = builder.build();
+    table
+        .with(Style::modern())
+        .with(Modify::new(Columns::new(2..)).with(Alignment::right()))
+        .with(Modify::new(Rows::first()).with(Alignment::center()));
+    output::fit(&mut table, &[]);
+    table.to_string()
+}
+
+pub async fn run(
+    names: &[String],
+    every: Duration,
+    size: usize,
+    servers: &[Server],
+    transport: &Transport,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let names: Vec<&str> = if names.is_empty() {
+        DEFAULT_NAMES.to_vec()
+    } else {
+        names.iter().map(String::as_str).collect()
+    };
+    let mut windows: Vec<Window> = servers
+        .iter()
+        .map(|server| Window {
+            server: server.clone(),
+            samples: VecDeque::new(),
+        })
+        .collect();
+    // redraw in place on a terminal, append one table per round otherwise
+    let redraw = std::io::stdout().is_terminal();
+
+    let mut ticker = time::interval(every);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    for name in names.iter().cycle() {
+        let results = tokio::select! {
+            results = async {
+                ticker.tick().await;
+                let servers: Vec<&Server> = windows.iter().map(|w| &w.server).collect();
+                round(name, &servers, transport).await
+            } => results,
+            _ = interrupt::token().cancelled() => break,
+        };
+        let majority = consensus(&results).cloned();
+        for (window, result) in windows.iter_mut().zip(results) {
+            let sample = match result {
+                Some((latency, data)) => Sample {
+                    latency: Some(latency),
+                    diverged: majority.as_ref().map(|m| *m != data),
+                },
+                None => Sample {
+                    latency: None,
+                    diverged: None,
+                },
+            };
+            window.samples.push_back(sample);
+            if window.samples.len() > size {
+                window.samples.pop_front();
+            }
+        }
+        windows.sort_by(|a, b| a.score().total_cmp(&b.score()));
+
+        let table = render(&windows, output);
+        if redraw {
+            print!("\x1b[2J\x1b[H");
+        }
+        println!(
+            "doh top: last {} queries per server, now {} (Ctrl+C to quit)\n{}",
+            size, name, table
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,233 @@
+//! `doh trace`: resolve a name iteratively from the root servers over plain DNS, like
+//! `dig +trace`, showing the delegation and glue at each step.
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use tracing::{error, warn};
+
+use crate::output::Output;
+use crate::plain;
+use crate::wire::{self, Message};
+use crate::{interrupt, Answer, Transport};
+
+/// The root servers (IANA root hints, IPv4 only).
+const ROOT_SERVERS: &[(&str, &str)] = &[
+    ("a.root-servers.net.", "198.41.0.4"),
+    ("b.root-servers.net.", "170.247.170.2"),
+    ("c.root-servers.net.", "192.33.4.12"),
+    ("d.root-servers.net.", "199.7.91.13"),
+    ("e.root-servers.net.", "192.203.230.10"),
+    ("f.root-servers.net.", "192.5.5.241"),
+    ("g.root-servers.net.", "192.112.36.4"),
+    ("h.root-servers.net.", "198.97.190.53"),
+    ("i.root-servers.net.", "192.36.148.17"),
+    ("j.root-servers.net.", "192.58.128.30"),
+    ("k.root-servers.net.", "193.0.14.129"),
+    ("l.root-servers.net.", "199.7.83.42"),
+    ("m.root-servers.net.", "202.12.27.33"),
+];
+
+/// Give up on delegation chains longer than this.
+const MAX_STEPS: usize = 16;
+
+#[derive(Debug, Clone, Copy, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Section {
+    Answer,
+    Authority,
+    Additional,
+}
+
+impl Section {
+    fn label(self) -> &'static str {
+        match self {
+            Section::Answer => "answer",
+            Section::Authority => "authority",
+            Section::Additional => "additional",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct TraceRecord {
+    pub section: Section,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub r#type: u32,
+    pub ttl: u32,
+    pub data: String,
+}
+
+/// One server asked along the way.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Step {
+    /// The zone the server was asked as an authority for.
+    pub zone: String,
+    pub server: String,
+    pub address: IpAddr,
+    pub rtt_ms: u64,
+    pub rcode: String,
+    pub records: Vec<TraceRecord>,
+}
+
+fn records(message: &Message) -> Vec<TraceRecord> {
+    let sections = [
+        (Section::Answer, &message.answers),
+        (Section::Authority, &message.authority),
+        (Section::Additional, &message.additional),
+    ];
+    sections
+        .into_iter()
+        .flat_map(|(section, answers)| {
+            answers.iter().map(move |a| TraceRecord {
+                section,
+                name: a.name.clone(),
+                r#type: a.r#type,
+                ttl: a.TTL,
+                data: a.data.clone(),
+            })
+        })
+        .collect()
+}
+
+/// The name servers `message` delegates to, below `zone`, with their addresses.
+async fn referral(message: &Message, zone: &str) -> Option<(String, Vec<(String, IpAddr)>)> {
+    let ns: Vec<&Answer> = message
+        .authority
+        .iter()
+        .filter(|a| a.r#type == 2 && a.name != zone && is_below(&a.name, zone))
+        .collect();
+    let child = ns.first()?.name.clone();
+    let mut servers = vec![];
+    for record in &ns {
+        let glue = message
+            .additional
+            .iter()
+            .filter(|a| a.r#type == 1 && a.name.eq_ignore_ascii_case(&record.data))
+            .filter_map(|a| a.data.parse().ok());
+        servers.extend(glue.map(|ip| (record.data.clone(), ip)));
+    }
+    if servers.is_empty() {
+        // out-of-zone name servers come without glue, look them up from here
+        for record in &ns {
+            let host = record.data.trim_end_matches('.');
+            if let Ok(addrs) = tokio::net::lookup_host((host, 53)).await {
+                servers.extend(
+                    addrs
+                        .filter(|a| a.is_ipv4())
+                        .map(|a| (record.data.clone(), a.ip())),
+                );
+            }
+        }
+    }
+    Some((child, servers))
+}
+
+/// Whether `name` is `zone` or a name inside it.
+fn is_below(name: &str, zone: &str) -> bool {
+    let (name, zone) = (name.to_ascii_lowercase(), zone.to_ascii_lowercase());
+    zone == "." || name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+pub async fn run(
+    name: &str,
+    rtype: &str,
+    roots: &[IpAddr],
+    transport: &Transport,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let code = wire::type_code(rtype).ok_or_else(|| format!("unknown record type `{}`", rtype))?;
+    let query = wire::query(name, code as u16, false)?;
+    let mut zone = String::from(".");
+    let mut servers: Vec<(String, IpAddr)> = if roots.is_empty() {
+        ROOT_SERVERS
+            .iter()
+            .map(|(host, ip)| (String::from(*host), ip.parse().unwrap()))
+            .collect()
+    } else {
+        roots.iter().map(|ip| (ip.to_string(), *ip)).collect()
+    };
+
+    let mut steps = vec![];
+    let mut done = false;
+    for _ in 0..MAX_STEPS {
+        let mut answered = None;
+        for (host, addr) in &servers {
+            let start = Instant::now();
+            let result = tokio::select! {
+                res = plain::ask(SocketAddr::new(*addr, 53), &query, &transport.source) => res,
+                _ = interrupt::token().cancelled() => return Err("interrupted".into()),
+            };
+            match result {
+                Ok(message) => {
+                    answered = Some((host.clone(), *addr, message, start.elapsed()));
+                    break;
+                }
+                Err(e) => error!("{} ({}) error: {}", host, addr, e),
+            }
+        }
+        let Some((host, addr, message, rtt)) = answered else {
+            return Err(format!("no server for {} answered", zone).into());
+        };
+        steps.push(Step {
+            zone: zone.clone(),
+            server: host,
+            address: addr,
+            rtt_ms: rtt.as_millis() as u64,
+            rcode: wire::rcode_name(message.rcode),
+            records: records(&message),
+        });
+        if !message.answers.is_empty() || message.rcode != 0 {
+            done = true;
+            break;
+        }
+        match referral(&message, &zone).await {
+            Some((child, next)) if !next.is_empty() => {
+                zone = child;
+                servers = next;
+            }
+            Some((child, _)) => {
+                return Err(
+                    format!("cannot find an address for any name server of {}", child).into(),
+                )
+            }
+            // no delegation and no answer: the name exists without this type
+            None => {
+                done = true;
+                break;
+            }
+        }
+    }
+    if !done {
+        warn!("stopped after {} delegation steps", MAX_STEPS);
+    }
+
+    if !output.is_table() {
+        return output.json(&steps);
+    }
+    let mut text = String::new();
+    for step in &steps {
+        text.push_str(&format!(
+            ";; {} via {} ({}) in {}ms: {}\n",
+            step.zone, step.server, step.address, step.rtt_ms, step.rcode
+        ));
+        for record in &step.records {
+            // EDNS OPT pseudo-records carry no data
+            if record.r#type == 41 {
+                continue;
+            }
+            text.push_str(&format!(
+                "{:<11}{} {} {} {}\n",
+                record.section.label(),
+                record.name,
+                record.ttl,
+                wire::type_name(record.r#type),
+                record.data
+            ));
+        }
+    }
+    output.print(text.trim_end(), 0);
+    Ok(())
+}
@@ -0,0 +1,391 @@
+//! `--tui`: the results table of one host in the terminal, filled in as each server's pings
+//! complete, with keys to query again, switch the record type, sort, and copy an address; with
+//! `--follow-ttl`, it queries again by itself as the first answer expires.
+use std::io::{self, Stdout, Write};
+use std::time::{Duration, Instant};
+
+use ansi_to_tui::IntoText;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{cursor, execute};
+use futures::future::{FutureExt, LocalBoxFuture};
+use futures::stream::{FuturesUnordered, StreamExt};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::{Line, Text};
+use ratatui::widgets::{Block, Cell, Paragraph, Row as TableRow, Table, TableState};
+use ratatui::{Frame, Terminal};
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::config::{Config, ProbeSettings};
+use crate::output::Output;
+use crate::{
+    compare_rows, get_ip, interrupt, progress, Answered, Answers, Cli, Probes, Row, SortKey,
+    Transport,
+};
+
+/// What `t` cycles through.
+const TYPES: &[&[&str]] = &[
+    &["A", "AAAA"],
+    &["A"],
+    &["AAAA"],
+    &["CNAME"],
+    &["MX"],
+    &["TXT"],
+];
+/// What `s` cycles through, after the order the servers answered in.
+const SORTS: &[SortKey] = &[
+    SortKey::Latency,
+    SortKey::Loss,
+    SortKey::Ttl,
+    SortKey::Server,
+];
+/// How often the progress is redrawn while pings are outstanding.
+const TICK: Duration = Duration::from_millis(200);
+
+enum Key {
+    Query,
+    Type,
+    Sort,
+    Up,
+    Down,
+    Copy,
+    Quit,
+    Interrupt,
+}
+
+impl Key {
+    fn of(event: KeyEvent) -> Option<Self> {
+        if event.kind != KeyEventKind::Press {
+            return None;
+        }
+        Some(match event.code {
+            // raw mode keeps Ctrl+C from sending a signal, it's read as a key
+            KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => Key::Interrupt,
+            KeyCode::Char('r') => Key::Query,
+            KeyCode::Char('t') => Key::Type,
+            KeyCode::Char('s') => Key::Sort,
+            KeyCode::Char('c') => Key::Copy,
+            KeyCode::Char('q') | KeyCode::Esc => Key::Quit,
+            KeyCode::Char('k') | KeyCode::Up => Key::Up,
+            KeyCode::Char('j') | KeyCode::Down => Key::Down,
+            _ => return None,
+        })
+    }
+}
+
+/// The terminal in the alternate screen and raw mode, until dropped.
+struct Screen(Terminal<CrosstermBackend<Stdout>>);
+
+impl Screen {
+    fn open() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen, cursor::Hide)?;
+        Ok(Self(Terminal::new(CrosstermBackend::new(io::stdout()))?))
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), cursor::Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+struct State<'a> {
+    host: &'a str,
+    /// Index in `TYPES`.
+    types: usize,
+    /// Index in `SORTS`, `None` for the order the servers answered in.
+    sort: Option<usize>,
+    rows: Vec<(&'a str, Row)>,
+    errors: Vec<(&'a str, String)>,
+    servers: usize,
+    /// Servers that haven't answered yet.
+    pending: usize,
+    selected: usize,
+    message: String,
+    /// With `--follow-ttl`, when the first answer expires and the next round starts.
+    due: Option<Instant>,
+}
+
+impl State<'_> {
+    fn sort(&mut self) {
+        if let Some(key) = self.sort.map(|idx| SORTS[idx]) {
+            self.rows
+                .sort_by(|(a_tag, a), (b_tag, b)| compare_rows(key, (a_tag, a), (b_tag, b)));
+        }
+    }
+}
+
+/// Query every server for `host` again, one future per server.
+fn round<'a>(
+    state: &mut State<'a>,
+    config: &'a Config,
+    probe: Option<&'a ProbeSettings>,
+    probes: &'a Probes,
+    transport: &'a Transport,
+) -> FuturesUnordered<LocalBoxFuture<'a, (&'a str, Answered)>> {
+    // pinged afresh
+    probes.lock().unwrap().clear();
+    progress::reset();
+    state.rows.clear();
+    state.errors.clear();
+    state.pending = config.servers.len();
+    state.selected = 0;
+    state.due = None;
+    let (host, rtypes) = (state.host, TYPES[state.types]);
+    config
+        .servers
+        .iter()
+        .map(|server| {
+            async move {
+                let answered = get_ip(host, &server.url, rtypes, probe, probes, transport, 1).await;
+                (server.tag.as_str(), answered)
+            }
+            .boxed_local()
+        })
+        .collect()
+}
+
+/// The cells of the table, colored the way the other tables are.
+fn text(cell: &str) -> Text<'static> {
+    cell.into_text()
+        .unwrap_or_else(|_| Text::raw(String::from(cell)))
+}
+
+fn draw(
+    frame: &mut Frame,
+    state: &State,
+    args: &Cli,
+    output: &Output,
+    probe: Option<&ProbeSettings>,
+) {
+    let sort = state.sort.map_or("as answered", |idx| match SORTS[idx] {
+        SortKey::Latency => "latency",
+        SortKey::Loss => "loss",
+        SortKey::Ttl => "TTL",
+        SortKey::Server => "server",
+    });
+    let waiting = format!("waiting for {} of {} servers", state.pending, state.servers);
+    let status = match (state.pending, probe) {
+        (0, _) => match state.due {
+            Some(due) => {
+                let left = due.saturating_duration_since(Instant::now());
+                format!("querying again in {}s", left.as_secs_f64().ceil())
+            }
+            None => String::from("done"),
+        },
+        (_, Some(probe)) => format!("{}, {}", waiting, progress::status(probe.count)),
+        (_, None) => waiting,
+    };
+    let heading = format!(
+        "{} · type {} · sorted by {} · {}",
+        state.host,
+        TYPES[state.types].join(", "),
+        sort,
+        status
+    );
+    let [heading_area, table_area, errors_area, help_area, message_area] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Min(3),
+        Constraint::Length(state.errors.len() as u16),
+        Constraint::Length(1),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    frame.render_widget(Line::raw(heading), heading_area);
+
+    let rows = state.rows.iter().map(|(tag, row)| (*tag, row.clone()));
+    let freshness = vec![None; state.rows.len()];
+    let empty = (Default::default(), Default::default());
+    let answers = Answers::new(
+        rows.collect(),
+        &[],
+        freshness,
+        &empty.0,
+        &empty.1,
+        args,
+        TYPES[state.types],
+        output,
+    );
+    let headers = answers.headers();
+    let cells: Vec<Vec<Text>> = answers
+        .cells()
+        .iter()
+        .map(|record| {
+            record
+                .iter()
+                .enumerate()
+                .map(|(column, cell)| {
+                    if answers.numbers().contains(&column) {
+                        text(cell).right_aligned()
+                    } else {
+                        text(cell)
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    // each column as wide as its widest cell, the layout squeezing them when there's no room
+    let widths: Vec<Constraint> = headers
+        .iter()
+        .enumerate()
+        .map(|(column, header)| {
+            let widest = cells.iter().map(|record| record[column].width()).max();
+            Constraint::Length(widest.unwrap_or(0).max(header.chars().count()) as u16)
+        })
+        .collect();
+    let header = TableRow::new(headers.iter().map(|h| Cell::from(h.clone())))
+        .style(Style::new().add_modifier(Modifier::BOLD));
+    let table = Table::new(cells.into_iter().map(TableRow::new), widths)
+        .header(header)
+        .block(Block::bordered())
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+    let mut selection = TableState::default().with_selected(Some(state.selected));
+    frame.render_stateful_widget(table, table_area, &mut selection);
+
+    let errors: Vec<Line> = state
+        .errors
+        .iter()
+        .map(|(tag, error)| Line::raw(format!("{} error: {}", tag, error)))
+        .collect();
+    frame.render_widget(Paragraph::new(errors), errors_area);
+    frame.render_widget(
+        Line::raw("r query again · t record type · s sort · ↑/↓ select · c copy address · q quit"),
+        help_area,
+    );
+    frame.render_widget(Line::raw(state.message.clone()), message_area);
+}
+
+pub async fn run(
+    host: &str,
+    config: &Config,
+    rtypes: &[&str],
+    probe: Option<&ProbeSettings>,
+    transport: &Transport,
+    args: &Cli,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::IsTerminal;
+
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        return Err("--tui needs a terminal".into());
+    }
+    let output = &Output {
+        limit: 0,
+        ..output.clone()
+    };
+    let probes = &Probes::default();
+    let mut state = State {
+        host,
+        types: TYPES.iter().position(|t| *t == rtypes).unwrap_or(0),
+        sort: args
+            .sort
+            .and_then(|key| SORTS.iter().position(|s| *s == key)),
+        rows: vec![],
+        errors: vec![],
+        servers: config.servers.len(),
+        pending: 0,
+        selected: 0,
+        message: String::new(),
+        due: None,
+    };
+    let mut screen = Screen::open()?;
+    let mut events = EventStream::new();
+    let mut ticker = time::interval(TICK);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    // the countdowns of --follow-ttl go by the second
+    let mut clock = time::interval(Duration::from_secs(1));
+    clock.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut pending = round(&mut state, config, probe, probes, transport);
+    loop {
+        screen
+            .0
+            .draw(|frame| draw(frame, &state, args, output, probe))?;
+        let due = time::Instant::from_std(state.due.unwrap_or_else(Instant::now));
+        let key = tokio::select! {
+            Some((tag, answered)) = pending.next() => {
+                state.pending -= 1;
+                match answered {
+                    Ok((rows, _, status)) if rows.is_empty() => state.errors.push((tag, status.reason())),
+                    Ok((rows, ..)) => {
+                        state.rows.extend(rows.into_iter().map(|row| (tag, row)));
+                        state.sort();
+                    }
+                    Err(e) => state.errors.push((tag, e.to_string())),
+                }
+                if state.pending == 0 && args.follow_ttl {
+                    // at least a second apart, for answers with a TTL of 0
+                    let soonest = Instant::now() + Duration::from_secs(1);
+                    state.due = state
+                        .rows
+                        .iter()
+                        .filter_map(|(_, row)| row.expires)
+                        .min()
+                        .map(|expires| expires.max(soonest));
+                }
+                continue;
+            }
+            _ = ticker.tick(), if state.pending > 0 => continue,
+            _ = time::sleep_until(due), if state.due.is_some() => {
+                drop(pending);
+                pending = round(&mut state, config, probe, probes, transport);
+                continue;
+            }
+            _ = clock.tick(), if state.due.is_some() => continue,
+            event = events.next() => match event {
+                Some(Ok(Event::Key(event))) => match Key::of(event) {
+                    Some(key) => Some(key),
+                    None => continue,
+                },
+                // resized, or the like: drawn again
+                Some(Ok(_)) => continue,
+                Some(Err(_)) | None => None,
+            },
+            _ = interrupt::token().cancelled() => break,
+        };
+        state.message.clear();
+        match key {
+            None | Some(Key::Quit) => break,
+            Some(Key::Interrupt) => {
+                interrupt::token().cancel();
+                break;
+            }
+            Some(Key::Query) => {
+                drop(pending);
+                pending = round(&mut state, config, probe, probes, transport);
+            }
+            Some(Key::Type) => {
+                state.types = (state.types + 1) % TYPES.len();
+                drop(pending);
+                pending = round(&mut state, config, probe, probes, transport);
+            }
+            Some(Key::Sort) => {
+                state.sort = match state.sort {
+                    None => Some(0),
+                    Some(idx) if idx + 1 < SORTS.len() => Some(idx + 1),
+                    Some(_) => None,
+                };
+                state.sort();
+            }
+            Some(Key::Up) => state.selected = state.selected.saturating_sub(1),
+            Some(Key::Down) => {
+                state.selected = (state.selected + 1).min(state.rows.len().saturating_sub(1))
+            }
+            Some(Key::Copy) => match state.rows.get(state.selected) {
+                Some((_, row)) => {
+                    // OSC 52 puts it on the clipboard of the terminal, even over SSH
+                    print!("\x1b]52;c;{}\x07", STANDARD.encode(&row.answer.data));
+                    io::stdout().flush()?;
+                    state.message = format!("copied {}", row.answer.data);
+                }
+                None => state.message = String::from("nothing to copy"),
+            },
+        }
+    }
+    Ok(())
+}
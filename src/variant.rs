@@ -0,0 +1,262 @@
+//! `doh method-test` and `doh api-test`: ask each DoH server the same queries two ways, which
+//! differ only in how the request is sent, and compare the latency and the answers of each way.
+//! CDNs in front of some servers cache GET responses, which POST ones bypass, and the JSON API of
+//! some providers answers from other, sometimes staler, caches than their RFC 8484 endpoint.
+use std::collections::BTreeSet;
+use std::time::Instant;
+
+use futures::future;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tabled::builder::Builder;
+use tabled::object::{Columns, Rows};
+use tabled::{Alignment, Modify, Style};
+use tracing::warn;
+
+use crate::config::Server;
+use crate::output::{self, Output};
+use crate::{exchange, interrupt, plain, system, Api, Stats, Transport};
+
+/// One way of asking the servers, such as `GET`, and the transport sending queries that way.
+pub struct Way {
+    pub name: &'static str,
+    pub transport: Transport,
+}
+
+impl Way {
+    /// Query `servers` through `api` only, POSTing wire-format queries with `post`.
+    pub fn new(
+        name: &'static str,
+        transport: &Transport,
+        servers: &[Server],
+        api: Api,
+        post: bool,
+    ) -> Self {
+        let mut transport = Transport {
+            post,
+            ..transport.clone()
+        };
+        for server in servers {
+            transport.servers.pin(&server.url, api);
+        }
+        Self { name, transport }
+    }
+}
+
+/// How one server answered one way.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Side {
+    pub way: String,
+    /// Queries sent, fewer than asked for when interrupted.
+    pub queries: usize,
+    pub failures: usize,
+    pub p50_ms: Option<f64>,
+    pub mean_ms: Option<f64>,
+    /// The DNS response codes, once each.
+    pub statuses: Vec<u32>,
+    /// Every record data answered, once each, in order.
+    pub answers: Vec<String>,
+    /// The lowest TTL answered, which a cache in the way counts down between queries.
+    pub min_ttl: Option<u32>,
+    /// The error of the last failed query.
+    pub error: Option<String>,
+}
+
+/// How the two ways compare on one server.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Comparison {
+    pub server: String,
+    pub url: String,
+    pub sides: Vec<Side>,
+    /// The second way's p50 minus the first's, in milliseconds.
+    pub difference_ms: Option<f64>,
+    /// How the answers differ, absent when they agree.
+    pub divergence: Option<String>,
+}
+
+/// Where `sides` disagree: the response codes, the records, or one failing throughout.
+fn divergence(sides: &[Side]) -> Option<String> {
+    let [a, b] = sides else {
+        return None;
+    };
+    match (a.failures == a.queries, b.failures == b.queries) {
+        (true, true) => return None,
+        (true, false) => return Some(format!("{} failed", a.way)),
+        (false, true) => return Some(format!("{} failed", b.way)),
+        (false, false) => {}
+    }
+    if a.statuses != b.statuses {
+        return Some(String::from("response codes"));
+    }
+    let (ours, theirs): (BTreeSet<&String>, BTreeSet<&String>) =
+        (a.answers.iter().collect(), b.answers.iter().collect());
+    let only = |one: &Side, of: &BTreeSet<&String>, other: &BTreeSet<&String>| {
+        let only: Vec<&str> = of.difference(other).map(|s| s.as_str()).collect();
+        (!only.is_empty()).then(|| format!("only {}: {}", one.way, only.join(", ")))
+    };
+    let differences: Vec<String> = [only(a, &ours, &theirs), only(b, &theirs, &ours)]
+        .into_iter()
+        .flatten()
+        .collect();
+    (!differences.is_empty()).then(|| differences.join("; "))
+}
+
+/// Query `server` for `host` `queries` times each way, taking turns so both see the same
+/// conditions.
+async fn compare(
+    host: &str,
+    rtype: &str,
+    queries: u32,
+    server: &Server,
+    ways: &[Way],
+) -> Comparison {
+    let mut latencies = vec![vec![]; ways.len()];
+    let mut sides: Vec<Side> = ways
+        .iter()
+        .map(|way| Side {
+            way: String::from(way.name),
+            queries: 0,
+            failures: 0,
+            p50_ms: None,
+            mean_ms: None,
+            statuses: vec![],
+            answers: vec![],
+            min_ttl: None,
+            error: None,
+        })
+        .collect();
+    'queries: for _ in 0..queries {
+        for ((way, side), latencies) in ways.iter().zip(&mut sides).zip(&mut latencies) {
+            let start = Instant::now();
+            let lookup = tokio::select! {
+                lookup = exchange(host, &server.url, rtype, &way.transport) => lookup,
+                _ = interrupt::token().cancelled() => break 'queries,
+            };
+            side.queries += 1;
+            match lookup {
+                Ok(lookup) => {
+                    latencies.push(start.elapsed().as_secs_f64() * 1000.0);
+                    if !side.statuses.contains(&lookup.status) {
+                        side.statuses.push(lookup.status);
+                    }
+                    for answer in lookup.answers {
+                        side.min_ttl = Some(side.min_ttl.map_or(answer.TTL, |t| t.min(answer.TTL)));
+                        if !side.answers.contains(&answer.data) {
+                            side.answers.push(answer.data);
+                        }
+                    }
+                }
+                Err(_) if interrupt::interrupted() => {
+                    side.queries -= 1;
+                    break 'queries;
+                }
+                Err(e) => {
+                    side.failures += 1;
+                    side.error = Some(e.to_string());
+                }
+            }
+        }
+    }
+    for (side, latencies) in sides.iter_mut().zip(&mut latencies) {
+        latencies.sort_by(f64::total_cmp);
+        side.p50_ms = Stats::percentile(latencies, 50.0);
+        side.mean_ms =
+            (!latencies.is_empty()).then(|| latencies.iter().sum::<f64>() / latencies.len() as f64);
+        side.statuses.sort_unstable();
+        side.answers.sort();
+    }
+    let difference_ms = match &sides[..] {
+        [a, b] => a.p50_ms.zip(b.p50_ms).map(|(a, b)| b - a),
+        _ => None,
+    };
+    Comparison {
+        server: server.tag.clone(),
+        url: server.url.clone(),
+        divergence: divergence(&sides),
+        difference_ms,
+        sides,
+    }
+}
+
+fn format_ms(ms: Option<f64>) -> String {
+    ms.map_or_else(|| String::from("/"), |ms| format!("{:.0}ms", ms))
+}
+
+/// Compare the two `ways` on every DoH server, returning whether their answers all agreed.
+pub async fn run(
+    host: &str,
+    rtype: &str,
+    queries: u32,
+    servers: &[Server],
+    ways: [Way; 2],
+    output: &Output,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let servers: Vec<&Server> = servers
+        .iter()
+        .filter(|server| {
+            let doh = !plain::is_plain(&server.url) && !system::is_system(&server.url);
+            if !doh {
+                warn!("{} is not a DoH server, skipping it", server.tag);
+            }
+            doh
+        })
+        .collect();
+    if servers.is_empty() {
+        return Err("no DoH server to compare".into());
+    }
+    let ways = &ways;
+    let mut results = future::join_all(
+        servers
+            .iter()
+            .map(|server| compare(host, rtype, queries, server, ways)),
+    )
+    .await;
+    let agreed = results.iter().all(|r| r.divergence.is_none());
+    if !output.is_table() {
+        output.json(&results)?;
+        return Ok(agreed);
+    }
+
+    let mut columns = vec![String::from("DoH")];
+    for way in ways {
+        columns.push(format!("{} p50", way.name));
+        columns.push(format!("{} failed", way.name));
+        columns.push(format!("{} TTL", way.name));
+    }
+    columns.extend([String::from("Difference"), String::from("Answers")]);
+    let mut builder = Builder::default();
+    builder.set_columns(columns);
+    let hidden = output.truncate(&mut results);
+    for result in results {
+        let mut record = vec![result.server];
+        for side in &result.sides {
+            record.push(format_ms(side.p50_ms));
+            record.push(output.paint.status(
+                side.failures == 0,
+                format!("{}/{}", side.failures, side.queries),
+            ));
+            record.push(
+                side.min_ttl
+                    .map_or_else(|| String::from("-"), |t| t.to_string()),
+            );
+        }
+        record.push(
+            result
+                .difference_ms
+                .map_or_else(|| String::from("/"), |ms| format!("{:+.0}ms", ms)),
+        );
+        record.push(match result.divergence {
+            None => String::from("same"),
+            Some(divergence) => output.paint.status(false, divergence),
+        });
+        builder.add_record(record);
+    }
+    let mut table = builder.build();
+    table
+        .with(Style::modern())
+        .with(Modify::new(Columns::new(1..3 * ways.len() + 2)).with(Alignment::right()))
+        .with(Modify::new(Rows::first()).with(Alignment::center()));
+    output::fit(&mut table, &[]);
+    output.print(&table.to_string(), hidden);
+    Ok(agreed)
+}
@@ -0,0 +1,219 @@
+//! `--post-url`: send the results of a run, and of every `--watch` cycle or exporter round, to an
+//! HTTP endpoint, as the JSON entries or as a Slack or Discord message, so that a watched name
+//! whose answers change or whose best latency degrades raises an alert.
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use clap::ValueEnum;
+use reqwest::Url;
+use tracing::{debug, warn};
+
+use crate::{Entry, Resolved, Row};
+
+/// Longest a post may take before it is given up on.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// How many times the best latency of the last round it may take before it counts as degraded...
+const DEGRADED_RATIO: f64 = 1.5;
+/// ...and by how many milliseconds it must have grown at least, so that jitter doesn't count.
+const DEGRADED_MS: i32 = 20;
+
+/// What is posted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PostFormat {
+    /// The entries `--output json` prints
+    Json,
+    /// A Slack incoming webhook message, `{"text": ...}`
+    Slack,
+    /// A Discord webhook message, `{"content": ...}`
+    Discord,
+}
+
+/// When results are posted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PostOn {
+    /// After every run, cycle or round
+    Always,
+    /// Only when a name's answers changed or its best latency degraded since the last one
+    Change,
+}
+
+/// Where and how results are posted.
+pub struct Webhook {
+    pub url: String,
+    pub format: PostFormat,
+    /// Body to post instead, with `{{text}}` and `{{json}}` filled in.
+    pub template: Option<String>,
+    pub on: PostOn,
+}
+
+static WEBHOOK: OnceLock<(Webhook, reqwest::Client)> = OnceLock::new();
+/// What every name resolved to when last posted about, to tell what changed.
+static LAST: Mutex<BTreeMap<String, Seen>> = Mutex::new(BTreeMap::new());
+
+/// The answers of one name in one round.
+struct Seen {
+    addresses: BTreeSet<String>,
+    /// Mean latency of the fastest address, when any replied.
+    best: Option<i32>,
+}
+
+/// Parse a `--post-url` value: an `http://` or `https://` URL.
+pub fn parse_url(value: &str) -> Result<String, String> {
+    let url = Url::parse(value).map_err(|e| format!("invalid URL `{}`: {}", value, e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!(
+            "expected an http:// or https:// URL, got `{}`",
+            value
+        ));
+    }
+    Ok(String::from(value))
+}
+
+/// Post the results of every round to `webhook` from now on.
+pub fn set(webhook: Webhook) -> Result<(), Box<dyn std::error::Error>> {
+    let client = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+    let _ = WEBHOOK.set((webhook, client));
+    Ok(())
+}
+
+/// Whether the address of `row` was pinged and replied.
+fn replied(row: &Row) -> bool {
+    row.probed && row.error.is_none() && row.mean >= 0
+}
+
+/// A line about `resolved` for the message, and whether it changed since `last`: its fastest
+/// address and how many servers answered, then what changed.
+fn describe(resolved: &Resolved, seen: &Seen, last: Option<&Seen>) -> (String, bool) {
+    let servers = resolved.responding.len() + resolved.failed.len();
+    let fastest = resolved
+        .rows
+        .iter()
+        .filter(|(_, row)| replied(row))
+        .min_by_key(|(_, row)| row.mean);
+    let mut line = match fastest {
+        Some((tag, row)) => format!(
+            "{}: best {}, {}ms from {}",
+            resolved.host, row.answer.data, row.mean, tag
+        ),
+        None if resolved.responding.is_empty() => format!("{}: no server answered", resolved.host),
+        None => format!("{}: no address replied", resolved.host),
+    };
+    line.push_str(&format!(
+        "; {} answer(s) from {}/{} servers",
+        seen.addresses.len(),
+        resolved.responding.len(),
+        servers
+    ));
+    let Some(last) = last else {
+        return (line, false);
+    };
+    let mut changed = false;
+    let added = seen
+        .addresses
+        .difference(&last.addresses)
+        .map(|a| format!("+{}", a));
+    let removed = last
+        .addresses
+        .difference(&seen.addresses)
+        .map(|a| format!("-{}", a));
+    let changes: Vec<String> = added.chain(removed).collect();
+    if !changes.is_empty() {
+        line.push_str(&format!("; answers changed: {}", changes.join(" ")));
+        changed = true;
+    }
+    match (last.best, seen.best) {
+        (Some(before), Some(now))
+            if f64::from(now) > f64::from(before) * DEGRADED_RATIO
+                && now - before >= DEGRADED_MS =>
+        {
+            line.push_str(&format!("; best latency up from {}ms", before));
+            changed = true;
+        }
+        (Some(_), None) => {
+            line.push_str("; no address replies any more");
+            changed = true;
+        }
+        _ => {}
+    }
+    (line, changed)
+}
+
+/// `template` with `{{text}}` replaced by `text`, escaped for a JSON string, and `{{json}}` by
+/// the entries.
+fn fill(template: &str, text: &str, json: &str) -> String {
+    let escaped = serde_json::to_string(text).unwrap_or_default();
+    let escaped = &escaped[1..escaped.len() - 1];
+    template
+        .replace("{{text}}", escaped)
+        .replace("{{json}}", json)
+}
+
+/// Post the results of this round, unless `--post-on change` and nothing changed; a failure is
+/// only warned about, as it says nothing about the names.
+pub async fn post(resolved: &[Resolved<'_>]) {
+    let Some((webhook, client)) = WEBHOOK.get() else {
+        return;
+    };
+    let mut lines = vec![];
+    let mut changed = false;
+    {
+        let mut last = LAST.lock().unwrap();
+        for resolved in resolved {
+            let seen = Seen {
+                addresses: resolved
+                    .rows
+                    .iter()
+                    .map(|(_, row)| row.answer.data.clone())
+                    .collect(),
+                best: resolved
+                    .rows
+                    .iter()
+                    .filter(|(_, row)| replied(row))
+                    .map(|(_, row)| row.mean)
+                    .min(),
+            };
+            let host = resolved.host.to_ascii_lowercase();
+            let (line, change) = describe(resolved, &seen, last.get(&host));
+            lines.push(line);
+            changed |= change;
+            last.insert(host, seen);
+        }
+    }
+    if webhook.on == PostOn::Change && !changed {
+        debug!("nothing changed, not posting to {}", webhook.url);
+        return;
+    }
+
+    // the servers without answers last, as in `--output json`
+    let failed = resolved.iter().flat_map(|resolved| {
+        resolved
+            .errors
+            .iter()
+            .map(|(tag, reason)| Entry::failed(resolved.host, tag, reason))
+    });
+    let entries: Vec<Entry> = resolved
+        .iter()
+        .flat_map(Resolved::entries)
+        .chain(failed)
+        .collect();
+    let json = serde_json::to_string(&entries).unwrap_or_default();
+    let text = lines.join("\n");
+    let body = match (&webhook.template, webhook.format) {
+        (Some(template), _) => fill(template, &text, &json),
+        (None, PostFormat::Json) => json,
+        (None, PostFormat::Slack) => serde_json::json!({ "text": text }).to_string(),
+        (None, PostFormat::Discord) => serde_json::json!({ "content": text }).to_string(),
+    };
+    let sent = client
+        .post(&webhook.url)
+        .header("Content-Type", "application/json")
+        .body(body)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status);
+    match sent {
+        Ok(_) => debug!("posted the results to {}", webhook.url),
+        Err(e) => warn!("cannot post the results to {}: {}", webhook.url, e),
+    }
+}
@@ -0,0 +1,703 @@
+//! RFC 8484 wire format: plain DNS messages, for servers that don't speak the JSON API.
+use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ipnet::IpNet;
+
+use crate::Answer;
+
+/// Numeric code of a record type mnemonic, as used in `Answer.r#type`.
+pub fn type_code(rtype: &str) -> Option<u32> {
+    let code = match rtype.to_ascii_uppercase().as_str() {
+        "A" => 1,
+        "NS" => 2,
+        "CNAME" => 5,
+        "SOA" => 6,
+        "PTR" => 12,
+        "MX" => 15,
+        "TXT" => 16,
+        "AAAA" => 28,
+        "SRV" => 33,
+        "SVCB" => 64,
+        "HTTPS" => 65,
+        "CAA" => 257,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Parse a `--type` value: a record type mnemonic we know, in any case.
+pub fn parse_type(value: &str) -> Result<String, String> {
+    type_code(value)
+        .map(|_| value.to_ascii_uppercase())
+        .ok_or_else(|| {
+            format!(
+                "unknown record type `{}`, use A, AAAA, CNAME, MX, NS, PTR, SOA, SRV, TXT, ...",
+                value
+            )
+        })
+}
+
+/// Mnemonic of a record type code, `TYPE<n>` for unknown ones (RFC 3597).
+pub fn type_name(code: u32) -> String {
+    let name = match code {
+        1 => "A",
+        2 => "NS",
+        5 => "CNAME",
+        6 => "SOA",
+        12 => "PTR",
+        15 => "MX",
+        16 => "TXT",
+        28 => "AAAA",
+        33 => "SRV",
+        41 => "OPT",
+        43 => "DS",
+        46 => "RRSIG",
+        47 => "NSEC",
+        48 => "DNSKEY",
+        50 => "NSEC3",
+        64 => "SVCB",
+        65 => "HTTPS",
+        257 => "CAA",
+        _ => return format!("TYPE{}", code),
+    };
+    String::from(name)
+}
+
+/// Mnemonic of a response code.
+pub fn rcode_name(rcode: u8) -> String {
+    let name = match rcode {
+        0 => "NOERROR",
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        _ => return format!("RCODE{}", rcode),
+    };
+    String::from(name)
+}
+
+/// A recursive query for `name`; the ID is 0 so responses stay cacheable (RFC 8484 §4.1).
+pub fn encode(name: &str, qtype: u16) -> Result<Vec<u8>, String> {
+    query(name, qtype, true)
+}
+
+/// A query for `name`, asking the server to recurse or not.
+pub fn query(name: &str, qtype: u16, recursive: bool) -> Result<Vec<u8>, String> {
+    let flags = if recursive { 0x01 } else { 0x00 };
+    let mut message = vec![0, 0, flags, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    let labels = name.trim_end_matches('.');
+    // the root itself has no labels
+    for label in labels.split('.').filter(|_| !labels.is_empty()) {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid name `{}`", name));
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&1u16.to_be_bytes());
+    Ok(message)
+}
+
+/// The name PTR records of `addr` are found under, in `in-addr.arpa` or `ip6.arpa`.
+pub fn reverse_name(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(addr) => {
+            let [a, b, c, d] = addr.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(addr) => {
+            let mut name = String::new();
+            for byte in addr.octets().iter().rev() {
+                let _ = write!(name, "{:x}.{:x}.", byte & 0x0f, byte >> 4);
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+/// EDNS option of the network a query is asked on behalf of (RFC 7871).
+const CLIENT_SUBNET: u16 = 8;
+/// EDNS option of zeros making a message a less telling size (RFC 7830).
+const PADDING: u16 = 12;
+
+/// Append an EDNS OPT record (RFC 6891) to `message`, with the DO bit asking for DNSSEC records
+/// when `dnssec` is set, a client subnet option for `subnet`, and padding to a multiple of
+/// `padding` bytes.
+pub fn edns(message: &mut Vec<u8>, dnssec: bool, subnet: Option<IpNet>, padding: Option<usize>) {
+    let additional = u16::from_be_bytes([message[10], message[11]]) + 1;
+    message[10..12].copy_from_slice(&additional.to_be_bytes());
+    // root name, type OPT, 4096-byte UDP payload, no extended rcode, version 0
+    message.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0]);
+    message.push(if dnssec { 0x80 } else { 0 });
+    message.push(0);
+    let mut options = vec![];
+    if let Some(subnet) = subnet {
+        let (family, addr) = match subnet.trunc().addr() {
+            IpAddr::V4(addr) => (1u16, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (2u16, addr.octets().to_vec()),
+        };
+        // only as many address bytes as the prefix covers, scope 0 as in queries
+        let len = usize::from(subnet.prefix_len()).div_ceil(8);
+        options.extend_from_slice(&CLIENT_SUBNET.to_be_bytes());
+        options.extend_from_slice(&(4 + len as u16).to_be_bytes());
+        options.extend_from_slice(&family.to_be_bytes());
+        options.extend_from_slice(&[subnet.prefix_len(), 0]);
+        options.extend_from_slice(&addr[..len]);
+    }
+    if let Some(block) = padding.filter(|block| *block > 0) {
+        // the whole message a multiple of `block`, with the option length and its own header
+        let len = message.len() + 2 + options.len() + 4;
+        let pad = (block - len % block) % block;
+        options.extend_from_slice(&PADDING.to_be_bytes());
+        options.extend_from_slice(&(pad as u16).to_be_bytes());
+        options.resize(options.len() + pad, 0);
+    }
+    message.extend_from_slice(&(options.len() as u16).to_be_bytes());
+    message.extend_from_slice(&options);
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or("truncated DNS message")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// A possibly compressed domain name, with a trailing dot.
+    fn name(&mut self) -> Result<String, String> {
+        let mut name = String::new();
+        let mut pos = self.pos;
+        let mut jumped = false;
+        // every pointer must go backwards, which also rules out loops
+        let mut limit = pos;
+        loop {
+            let len = *self.buf.get(pos).ok_or("truncated DNS message")? as usize;
+            match len {
+                0 => {
+                    pos += 1;
+                    break;
+                }
+                len if len & 0xC0 == 0xC0 => {
+                    let low = *self.buf.get(pos + 1).ok_or("truncated DNS message")? as usize;
+                    let target = (len & 0x3F) << 8 | low;
+                    if target >= limit {
+                        return Err(String::from("bad name compression pointer"));
+                    }
+                    if !jumped {
+                        self.pos = pos + 2;
+                        jumped = true;
+                    }
+                    limit = target;
+                    pos = target;
+                }
+                len => {
+                    let label = self
+                        .buf
+                        .get(pos + 1..pos + 1 + len)
+                        .ok_or("truncated DNS message")?;
+                    name.push_str(&String::from_utf8_lossy(label));
+                    name.push('.');
+                    pos += 1 + len;
+                }
+            }
+        }
+        if !jumped {
+            self.pos = pos;
+        }
+        if name.is_empty() {
+            name.push('.');
+        }
+        Ok(name)
+    }
+}
+
+/// Name of an SVCB parameter key (RFC 9460 §14.3.2), `keyN` for unknown ones.
+pub fn svc_key(key: u16) -> String {
+    let name = match key {
+        0 => "mandatory",
+        1 => "alpn",
+        2 => "no-default-alpn",
+        3 => "port",
+        4 => "ipv4hint",
+        5 => "ech",
+        6 => "ipv6hint",
+        7 => "dohpath",
+        _ => return format!("key{}", key),
+    };
+    String::from(name)
+}
+
+/// An SVCB parameter in presentation format, `alpn=h2,h3` say.
+fn svc_param(key: u16, value: &[u8]) -> Result<String, String> {
+    let malformed = || format!("malformed {} parameter", svc_key(key));
+    let list = |values: Vec<String>| values.join(",");
+    let value = match key {
+        0 => list(
+            value
+                .chunks(2)
+                .map(|c| (c.len() == 2).then(|| svc_key(u16::from_be_bytes([c[0], c[1]]))))
+                .collect::<Option<_>>()
+                .ok_or_else(malformed)?,
+        ),
+        1 => {
+            let mut reader = Reader { buf: value, pos: 0 };
+            let mut ids = vec![];
+            while reader.pos < value.len() {
+                let len = reader.u8()? as usize;
+                ids.push(String::from_utf8_lossy(reader.take(len)?).into_owned());
+            }
+            list(ids)
+        }
+        2 if value.is_empty() => return Ok(svc_key(key)),
+        3 if value.len() == 2 => u16::from_be_bytes([value[0], value[1]]).to_string(),
+        4 if value.len().is_multiple_of(4) => list(
+            value
+                .chunks(4)
+                .map(|b| Ipv4Addr::new(b[0], b[1], b[2], b[3]).to_string())
+                .collect(),
+        ),
+        5 => STANDARD.encode(value),
+        6 if value.len().is_multiple_of(16) => list(
+            value
+                .chunks(16)
+                .map(|b| Ipv6Addr::from(<[u8; 16]>::try_from(b).unwrap()).to_string())
+                .collect(),
+        ),
+        7 => String::from_utf8_lossy(value).into_owned(),
+        2..=6 => return Err(malformed()),
+        _ => {
+            // anything but plain printable characters as \DDD (RFC 1035 §5.1)
+            let mut text = String::from("\"");
+            for &byte in value {
+                match byte {
+                    b'"' | b'\\' => {
+                        text.push('\\');
+                        text.push(byte as char);
+                    }
+                    0x21..=0x7e => text.push(byte as char),
+                    _ => {
+                        let _ = write!(text, "\\{:03}", byte);
+                    }
+                }
+            }
+            text.push('"');
+            text
+        }
+    };
+    Ok(format!("{}={}", svc_key(key), value))
+}
+
+/// Record data the JSON API gave in the generic encoding of RFC 3597, `\# 4 7f000001` say, the way
+/// other types are rendered; `None` when it isn't in that encoding.
+pub fn present(rtype: u32, data: &str) -> Option<String> {
+    let mut parts = data.split_whitespace();
+    if parts.next()? != "\\#" {
+        return None;
+    }
+    let len: usize = parts.next()?.parse().ok()?;
+    let hex: String = parts.collect();
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    if bytes.len() != len {
+        return None;
+    }
+    rdata(
+        &mut Reader {
+            buf: &bytes,
+            pos: 0,
+        },
+        rtype as u16,
+        len,
+    )
+    .ok()
+}
+
+/// Render record data the way the JSON API does.
+fn rdata(reader: &mut Reader, rtype: u16, len: usize) -> Result<String, String> {
+    let end = reader.pos + len;
+    let data = match rtype {
+        1 if len == 4 => {
+            let b = reader.take(4)?;
+            Ipv4Addr::new(b[0], b[1], b[2], b[3]).to_string()
+        }
+        28 if len == 16 => {
+            let b: [u8; 16] = reader.take(16)?.try_into().unwrap();
+            Ipv6Addr::from(b).to_string()
+        }
+        2 | 5 | 12 => reader.name()?,
+        15 => {
+            let preference = reader.u16()?;
+            format!("{} {}", preference, reader.name()?)
+        }
+        16 => {
+            let mut strings = vec![];
+            while reader.pos < end {
+                let len = reader.u8()? as usize;
+                strings.push(format!(
+                    "\"{}\"",
+                    String::from_utf8_lossy(reader.take(len)?)
+                ));
+            }
+            strings.join(" ")
+        }
+        6 => {
+            let (mname, rname) = (reader.name()?, reader.name()?);
+            let values = [
+                reader.u32()?,
+                reader.u32()?,
+                reader.u32()?,
+                reader.u32()?,
+                reader.u32()?,
+            ];
+            let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+            format!("{} {} {}", mname, rname, values.join(" "))
+        }
+        33 => {
+            let (priority, weight, port) = (reader.u16()?, reader.u16()?, reader.u16()?);
+            format!("{} {} {} {}", priority, weight, port, reader.name()?)
+        }
+        64 | 65 => {
+            let priority = reader.u16()?;
+            let mut text = format!("{} {}", priority, reader.name()?);
+            while reader.pos < end {
+                let key = reader.u16()?;
+                let len = reader.u16()? as usize;
+                text.push(' ');
+                text.push_str(&svc_param(key, reader.take(len)?)?);
+            }
+            text
+        }
+        257 => {
+            let flags = reader.u8()?;
+            let tag_len = reader.u8()? as usize;
+            let tag = String::from_utf8_lossy(reader.take(tag_len)?).into_owned();
+            let value = String::from_utf8_lossy(reader.take(end.saturating_sub(reader.pos))?);
+            format!("{} {} \"{}\"", flags, tag, value)
+        }
+        _ => {
+            // RFC 3597 generic encoding for everything else
+            let mut hex = String::new();
+            for byte in reader.take(len)? {
+                let _ = write!(hex, "{:02x}", byte);
+            }
+            format!("\\# {} {}", len, hex)
+        }
+    };
+    if reader.pos != end {
+        return Err(format!("malformed type {} record", rtype));
+    }
+    Ok(data)
+}
+
+/// The parts of a DNS response we look at.
+pub struct Message {
+    pub rcode: u8,
+    /// The TC bit: the answer didn't fit in a UDP datagram.
+    pub truncated: bool,
+    /// The AD bit: the server validated the answers with DNSSEC.
+    pub authenticated: bool,
+    pub answers: Vec<Answer>,
+    pub authority: Vec<Answer>,
+    pub additional: Vec<Answer>,
+}
+
+fn records(reader: &mut Reader, count: u16) -> Result<Vec<Answer>, String> {
+    let mut records = vec![];
+    for _ in 0..count {
+        let name = reader.name()?;
+        let rtype = reader.u16()?;
+        let _class = reader.u16()?;
+        let ttl = reader.u32()?;
+        let len = reader.u16()? as usize;
+        let data = rdata(reader, rtype, len)?;
+        records.push(Answer {
+            name,
+            r#type: rtype as u32,
+            TTL: ttl,
+            data,
+        });
+    }
+    Ok(records)
+}
+
+pub fn parse(buf: &[u8]) -> Result<Message, String> {
+    let mut reader = Reader { buf, pos: 0 };
+    let _id = reader.u16()?;
+    let flags = reader.u16()?;
+    if flags & 0x8000 == 0 {
+        return Err(String::from("not a DNS response"));
+    }
+    let questions = reader.u16()?;
+    let answers = reader.u16()?;
+    let authority = reader.u16()?;
+    let additional = reader.u16()?;
+    for _ in 0..questions {
+        reader.name()?;
+        reader.take(4)?;
+    }
+    let truncated = flags & 0x0200 != 0;
+    let answers = records(&mut reader, answers)?;
+    // a truncated message may stop anywhere after the answers
+    let authority =
+        records(&mut reader, authority).or_else(|e| truncated.then(Vec::new).ok_or(e))?;
+    let additional =
+        records(&mut reader, additional).or_else(|e| truncated.then(Vec::new).ok_or(e))?;
+    Ok(Message {
+        rcode: (flags & 0x000F) as u8,
+        truncated,
+        authenticated: flags & 0x0020 != 0,
+        answers,
+        authority,
+        additional,
+    })
+}
+
+/// The first question of a query: its lowercased name, type and class, and where the question
+/// section ends.
+pub fn question(buf: &[u8]) -> Result<(String, u16, u16, usize), String> {
+    let mut reader = Reader { buf, pos: 4 };
+    if reader.u16()? != 1 {
+        return Err(String::from("expected a single question"));
+    }
+    reader.pos = 12;
+    let name = reader.name()?.to_ascii_lowercase();
+    let (qtype, qclass) = (reader.u16()?, reader.u16()?);
+    Ok((name, qtype, qclass, reader.pos))
+}
+
+/// OPT pseudo-records, whose TTL field holds EDNS flags instead.
+const OPT: u16 = 41;
+
+/// Where the TTL of every record of `buf` is, to age a cached response.
+pub fn ttl_offsets(buf: &[u8]) -> Result<Vec<usize>, String> {
+    let mut reader = Reader { buf, pos: 4 };
+    let questions = reader.u16()?;
+    let records = [reader.u16()?, reader.u16()?, reader.u16()?];
+    for _ in 0..questions {
+        reader.name()?;
+        reader.take(4)?;
+    }
+    let mut offsets = vec![];
+    for _ in 0..records.iter().map(|&n| usize::from(n)).sum::<usize>() {
+        reader.name()?;
+        let rtype = reader.u16()?;
+        reader.take(2)?;
+        if rtype != OPT {
+            offsets.push(reader.pos);
+        }
+        reader.take(4)?;
+        let len = reader.u16()? as usize;
+        reader.take(len)?;
+    }
+    Ok(offsets)
+}
+
+/// The class and TTL fields of the OPT record of `buf`, if it has one: the largest UDP payload its
+/// sender accepts, then the extended rcode, the version and the flags (RFC 6891).
+fn opt(buf: &[u8]) -> Result<Option<(u16, &[u8])>, String> {
+    let mut reader = Reader { buf, pos: 4 };
+    let questions = reader.u16()?;
+    let records = [reader.u16()?, reader.u16()?, reader.u16()?];
+    for _ in 0..questions {
+        reader.name()?;
+        reader.take(4)?;
+    }
+    for _ in 0..records.iter().map(|&n| usize::from(n)).sum::<usize>() {
+        reader.name()?;
+        let rtype = reader.u16()?;
+        let class = reader.u16()?;
+        let ttl = reader.take(4)?;
+        if rtype == OPT {
+            return Ok(Some((class, ttl)));
+        }
+        let len = reader.u16()? as usize;
+        reader.take(len)?;
+    }
+    Ok(None)
+}
+
+/// Whether the OPT record of `buf` sets the DO bit, asking for DNSSEC records (RFC 3225).
+pub fn dnssec_ok(buf: &[u8]) -> Result<bool, String> {
+    // extended rcode, version, then the flags, DO first
+    Ok(opt(buf)?.is_some_and(|(_, ttl)| ttl[2] & 0x80 != 0))
+}
+
+/// The largest UDP response the sender of `buf` accepts, as its OPT record says; none without
+/// one.
+pub fn udp_size(buf: &[u8]) -> Result<Option<u16>, String> {
+    Ok(opt(buf)?.map(|(size, _)| size))
+}
+
+/// A SERVFAIL response to `query`, whose question section ends at `end`.
+pub fn servfail(query: &[u8], end: usize) -> Vec<u8> {
+    let mut message = query[..end].to_vec();
+    // QR and RA set, RD kept from the query, RCODE 2
+    message[2] = 0x80 | (query[2] & 0x01);
+    message[3] = 0x80 | 2;
+    message[6..12].fill(0);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response to `example.com. A` with one answer, its name compressed to the question's.
+    fn response() -> Vec<u8> {
+        let mut message = query("example.com", 1, true).unwrap();
+        message[2] |= 0x80;
+        message[7] = 1;
+        message.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+        message
+    }
+
+    /// A response whose only answer, of type `rtype`, is named by `name` and holds `data`.
+    fn answer(name: &[u8], rtype: u16, data: &[u8]) -> Vec<u8> {
+        let mut message = vec![0, 0, 0x80, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+        message.extend_from_slice(name);
+        message.extend_from_slice(&rtype.to_be_bytes());
+        message.extend_from_slice(&[0, 1, 0, 0, 0, 60]);
+        message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        message.extend_from_slice(data);
+        message
+    }
+
+    #[test]
+    fn parses_a_compressed_answer() {
+        let message = parse(&response()).unwrap();
+        assert_eq!(message.rcode, 0);
+        assert!(!message.truncated);
+        assert_eq!(message.answers.len(), 1);
+        assert_eq!(message.answers[0].name, "example.com.");
+        assert_eq!(message.answers[0].TTL, 60);
+        assert_eq!(message.answers[0].data, "192.0.2.1");
+    }
+
+    #[test]
+    fn rejects_every_truncation() {
+        let message = response();
+        for len in 0..message.len() {
+            assert!(parse(&message[..len]).is_err(), "{} bytes parsed", len);
+            assert!(ttl_offsets(&message[..len]).is_err(), "{} bytes read", len);
+        }
+    }
+
+    #[test]
+    fn rejects_queries() {
+        let message = query("example.com", 1, true).unwrap();
+        assert_eq!(parse(&message).err().unwrap(), "not a DNS response");
+    }
+
+    #[test]
+    fn rejects_compression_loops() {
+        // a pointer to itself, one forwards, and two pointing at each other
+        let own = answer(&[0xC0, 12], 1, &[192, 0, 2, 1]);
+        let forward = answer(&[0xC0, 40], 1, &[192, 0, 2, 1]);
+        let mut mutual = answer(&[0xC0, 14, 0xC0, 12], 1, &[192, 0, 2, 1]);
+        for message in [&own, &forward, &mutual] {
+            assert_eq!(
+                parse(message).err().unwrap(),
+                "bad name compression pointer"
+            );
+        }
+        // the same goes for pointers in record data
+        mutual = answer(&[0], 5, &[0xC0, 23]);
+        assert_eq!(
+            parse(&mutual).err().unwrap(),
+            "bad name compression pointer"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_records() {
+        // MX data running past its length, and TXT strings longer than theirs
+        let mx = answer(&[0], 15, &[0, 10, 0, 0xff]);
+        assert_eq!(parse(&mx).err().unwrap(), "malformed type 15 record");
+        let mut txt = answer(&[0], 16, &[5, b'a']);
+        txt.extend_from_slice(b"bcde");
+        assert_eq!(parse(&txt).err().unwrap(), "malformed type 16 record");
+        let cut = answer(&[0], 16, &[5, b'a']);
+        assert!(parse(&cut).is_err());
+    }
+
+    #[test]
+    fn renders_unknown_types_generically() {
+        let message = parse(&answer(&[0], 99, &[0xde, 0xad])).unwrap();
+        assert_eq!(message.answers[0].data, "\\# 2 dead");
+    }
+
+    #[test]
+    fn tolerates_cut_sections_when_truncated() {
+        let mut message = response();
+        // one authority record announced but missing
+        message[9] = 1;
+        assert!(parse(&message).is_err());
+        message[2] |= 0x02;
+        let parsed = parse(&message).unwrap();
+        assert!(parsed.truncated);
+        assert_eq!(parsed.answers.len(), 1);
+        assert!(parsed.authority.is_empty());
+    }
+
+    #[test]
+    fn reads_the_question() {
+        let message = query("Example.COM.", 28, true).unwrap();
+        let (name, qtype, qclass, end) = question(&message).unwrap();
+        assert_eq!((name.as_str(), qtype, qclass), ("example.com.", 28, 1));
+        assert_eq!(end, message.len());
+        assert!(question(&message[..end - 1]).is_err());
+        assert!(question(&[]).is_err());
+    }
+
+    #[test]
+    fn finds_the_do_bit() {
+        let mut message = query("example.com", 1, true).unwrap();
+        assert!(!dnssec_ok(&message).unwrap());
+        assert_eq!(udp_size(&message).unwrap(), None);
+        edns(&mut message, true, None, Some(128));
+        assert_eq!(message.len() % 128, 0);
+        assert!(dnssec_ok(&message).unwrap());
+        assert_eq!(udp_size(&message).unwrap(), Some(4096));
+        // the flags are all it reads of the OPT record
+        assert!(dnssec_ok(&message[..message.len() - 8]).is_ok());
+        assert!(dnssec_ok(&message[..20]).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_names() {
+        assert!(query("a..b", 1, true).is_err());
+        assert!(query(&"a".repeat(64), 1, true).is_err());
+        assert_eq!(query(".", 1, true).unwrap().len(), 17);
+    }
+}
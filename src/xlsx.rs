@@ -0,0 +1,199 @@
+//! `--output xlsx`: results as an Excel workbook on stdout, for teams that pass them around as
+//! spreadsheets. Query results get a sheet per host and a color-scaled summary sheet; the other
+//! commands get one sheet with their JSON fields as columns.
+use std::io::IsTerminal;
+
+use rust_xlsxwriter::{
+    ColNum, ConditionalFormat3ColorScale, Format, Workbook, Worksheet, XlsxError,
+};
+use serde_json::Value;
+
+use crate::output::Output;
+use crate::Entry;
+
+/// Excel's limit on sheet names.
+const MAX_SHEET_NAME: usize = 31;
+
+/// Write `workbook` out, unless that would dump it on the terminal.
+fn write(workbook: &mut Workbook, output: &Output) -> Result<(), Box<dyn std::error::Error>> {
+    if output.file.is_none() && std::io::stdout().is_terminal() {
+        return Err("an xlsx workbook is binary, use --output-file or redirect stdout".into());
+    }
+    output.emit(&workbook.save_to_buffer()?)
+}
+
+/// A sheet name Excel accepts: no `[]:*?/\` and at most 31 characters.
+fn sheet_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if "[]:*?/\\".contains(c) { '_' } else { c })
+        .take(MAX_SHEET_NAME)
+        .collect()
+}
+
+fn header(sheet: &mut Worksheet, columns: &[&str]) -> Result<(), XlsxError> {
+    let bold = Format::new().set_bold();
+    for (col, name) in columns.iter().enumerate() {
+        sheet.write_string_with_format(0, col as ColNum, *name, &bold)?;
+    }
+    sheet.set_freeze_panes(1, 0)?;
+    Ok(())
+}
+
+/// Color `col` from green at its lowest to red at its highest value, as for latency and loss.
+fn scale(sheet: &mut Worksheet, rows: usize, col: ColNum) -> Result<(), XlsxError> {
+    if rows == 0 {
+        return Ok(());
+    }
+    let scale = ConditionalFormat3ColorScale::new()
+        .set_minimum_color("63BE7B")
+        .set_maximum_color("F8696B");
+    sheet.add_conditional_format(1, col, rows as u32, col, &scale)?;
+    Ok(())
+}
+
+fn write_optional(
+    sheet: &mut Worksheet,
+    row: u32,
+    col: ColNum,
+    value: Option<f64>,
+) -> Result<(), XlsxError> {
+    if let Some(value) = value {
+        sheet.write_number(row, col, value)?;
+    }
+    Ok(())
+}
+
+/// Query results of `hosts`: a summary per host and server, then every answer on a sheet per host.
+pub fn query(
+    hosts: &[String],
+    entries: &[Entry],
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut workbook = Workbook::new();
+
+    let mut servers: Vec<(&str, &str)> = vec![];
+    for entry in entries {
+        if !servers.contains(&(entry.host.as_str(), entry.server.as_str())) {
+            servers.push((&entry.host, &entry.server));
+        }
+    }
+    let sheet = workbook.add_worksheet();
+    sheet.set_name("Summary")?;
+    header(
+        sheet,
+        &["Host", "Server", "Answers", "Avg (ms)", "Loss (%)"],
+    )?;
+    for (idx, (host, server)) in servers.iter().enumerate() {
+        let row = idx as u32 + 1;
+        let answered: Vec<&Entry> = entries
+            .iter()
+            .filter(|e| e.host == *host && e.server == *server)
+            .collect();
+        let probed: Vec<&Entry> = answered
+            .iter()
+            .copied()
+            .filter(|e| e.error.is_none())
+            .collect();
+        let mean = |values: Vec<f64>| {
+            (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+        };
+        sheet.write_string(row, 0, *host)?;
+        sheet.write_string(row, 1, *server)?;
+        sheet.write_number(row, 2, answered.len() as f64)?;
+        let avg = mean(
+            probed
+                .iter()
+                .filter_map(|e| e.avg_ms)
+                .map(f64::from)
+                .collect(),
+        );
+        write_optional(sheet, row, 3, avg)?;
+        let loss = mean(
+            probed
+                .iter()
+                .filter_map(|e| e.loss)
+                .map(f64::from)
+                .collect(),
+        );
+        write_optional(sheet, row, 4, loss.map(|l| l * 100.0))?;
+    }
+    scale(sheet, servers.len(), 3)?;
+    scale(sheet, servers.len(), 4)?;
+    sheet.autofit();
+
+    for host in hosts {
+        answers(&mut workbook, host, entries)?;
+    }
+    write(&mut workbook, output)
+}
+
+/// A sheet with every answer for `host`.
+fn answers(workbook: &mut Workbook, host: &str, entries: &[Entry]) -> Result<(), XlsxError> {
+    let entries: Vec<&Entry> = entries.iter().filter(|e| e.host == host).collect();
+    let sheet = workbook.add_worksheet();
+    sheet.set_name(sheet_name(host))?;
+    header(
+        sheet,
+        &[
+            "Server", "Name", "Type", "TTL", "Address", "Avg (ms)", "Loss (%)", "Error",
+        ],
+    )?;
+    for (idx, entry) in entries.iter().enumerate() {
+        let row = idx as u32 + 1;
+        sheet.write_string(row, 0, &entry.server)?;
+        sheet.write_string(row, 1, &entry.name)?;
+        sheet.write_number(row, 2, entry.r#type)?;
+        sheet.write_number(row, 3, entry.ttl)?;
+        sheet.write_string(row, 4, &entry.address)?;
+        write_optional(sheet, row, 5, entry.avg_ms.map(f64::from))?;
+        write_optional(sheet, row, 6, entry.loss.map(|l| f64::from(l) * 100.0))?;
+        if let Some(error) = &entry.error {
+            sheet.write_string(row, 7, error)?;
+        }
+    }
+    scale(sheet, entries.len(), 5)?;
+    scale(sheet, entries.len(), 6)?;
+    sheet.autofit();
+    Ok(())
+}
+
+/// Rows of any other command, one column per JSON field in order of first appearance.
+pub fn rows(values: &[Value], output: &Output) -> Result<(), Box<dyn std::error::Error>> {
+    let mut columns: Vec<&str> = vec![];
+    for value in values {
+        for key in value.as_object().into_iter().flat_map(|map| map.keys()) {
+            if !columns.contains(&key.as_str()) {
+                columns.push(key);
+            }
+        }
+    }
+
+    let mut workbook = Workbook::new();
+    let sheet = workbook.add_worksheet();
+    sheet.set_name("Results")?;
+    header(sheet, &columns)?;
+    for (idx, value) in values.iter().enumerate() {
+        let row = idx as u32 + 1;
+        for (col, key) in columns.iter().enumerate() {
+            let col = col as ColNum;
+            match value.get(key) {
+                None | Some(Value::Null) => {}
+                Some(Value::Bool(b)) => {
+                    sheet.write_boolean(row, col, *b)?;
+                }
+                Some(Value::Number(n)) => {
+                    sheet.write_number(row, col, n.as_f64().unwrap_or_default())?;
+                }
+                Some(Value::String(s)) => {
+                    sheet.write_string(row, col, s)?;
+                }
+                // nested values stay readable as JSON
+                Some(other) => {
+                    sheet.write_string(row, col, other.to_string())?;
+                }
+            }
+        }
+    }
+    sheet.autofit();
+    write(&mut workbook, output)
+}